@@ -8,8 +8,14 @@
 //! - unchanged
 //! - crabify
 //! - csv
+//! - stats
+//!
+//! Without arguments the commands are read interactively as `<command> <input>`. Huge files are processed line by
+//! line with constant memory use by `transtext <command> <input-file> [output-file] [--progress-mb N]`, which
+//! supports lowercase, uppercase, no-spaces and unchanged.
 
 mod operations;
+mod stream;
 
 use operations::Operation;
 use std::error::Error;
@@ -63,6 +69,7 @@ fn transtext(rx: &mpsc::Receiver<Input>) -> Result<Output, Box<dyn Error>> {
         Operation::Unchanged => operations::unchanged(&received.input),
         Operation::Crabify => operations::crabify(&received.input),
         Operation::Csv => operations::csv(&received.input),
+        Operation::Stats => operations::stats(&received.input),
     }?;
 
     Ok(Output {
@@ -84,6 +91,15 @@ fn handle_command(rx: mpsc::Receiver<Input>) {
 }
 
 fn main() {
+    let arguments: Vec<String> = std::env::args().skip(1).collect();
+    if !arguments.is_empty() {
+        if let Err(err_msg) = stream::FileArguments::parse(&arguments).and_then(stream::run) {
+            eprintln!("Processing Error: {err_msg}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let (tx, rx) = mpsc::channel();
 
     let input = thread::spawn(move || {
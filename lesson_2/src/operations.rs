@@ -1,4 +1,6 @@
+use rayon::prelude::*;
 use slug;
+use std::collections::HashSet;
 use std::error::Error;
 use std::fmt;
 use std::fs::File;
@@ -14,6 +16,7 @@ pub enum Operation {
     Unchanged,
     Crabify,
     Csv,
+    Stats,
 }
 
 impl FromStr for Operation {
@@ -28,6 +31,7 @@ impl FromStr for Operation {
             "unchanged" => Ok(Operation::Unchanged),
             "crabify" => Ok(Operation::Crabify),
             "csv" => Ok(Operation::Csv),
+            "stats" => Ok(Operation::Stats),
             _ => Err(From::from(format!("Unknown argument: {s}!"))),
         }
     }
@@ -58,9 +62,62 @@ pub fn crabify(s: &str) -> Result<String, Box<dyn Error>> {
 }
 
 pub fn csv(s: &str) -> Result<String, Box<dyn Error>> {
+    let input = read_file(s)?;
+    Ok(parse_csv(&input)?.to_string())
+}
+
+/// Computes the statistics of every column of the CSV file, the columns are processed in parallel.
+pub fn stats(s: &str) -> Result<String, Box<dyn Error>> {
+    let input = read_file(s)?;
+    let table = parse_csv(&input)?;
+    let stats: Vec<Vec<String>> = (0..table.header.len())
+        .into_par_iter()
+        .map(|i| column_stats(table.header[i], table.rows.iter().map(|row| row[i])))
+        .collect();
+    let rows = stats
+        .iter()
+        .map(|column| column.iter().map(String::as_str).collect())
+        .collect();
+    let header = vec!["column", "count", "distinct", "min", "max", "mean"];
+    Ok(Csv { header, rows }.to_string())
+}
+
+/// Returns the row of the statistics table, min, max and mean are numeric if all the values are numbers.
+fn column_stats<'a>(name: &str, values: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let values: Vec<&str> = values.map(str::trim).filter(|v| !v.is_empty()).collect();
+    let distinct = values.iter().collect::<HashSet<_>>().len();
+    let numbers: Option<Vec<f64>> = values.iter().map(|v| v.parse().ok()).collect();
+    let (min, max, mean) = match numbers {
+        Some(numbers) if !numbers.is_empty() => {
+            let min = numbers.iter().copied().fold(f64::INFINITY, f64::min);
+            let max = numbers.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            let mean = numbers.iter().sum::<f64>() / numbers.len() as f64;
+            (min.to_string(), max.to_string(), format!("{mean:.2}"))
+        }
+        _ => {
+            let min = values.iter().min().map_or("-", |v| v);
+            let max = values.iter().max().map_or("-", |v| v);
+            (min.to_string(), max.to_string(), "-".to_string())
+        }
+    };
+    vec![
+        name.to_string(),
+        values.len().to_string(),
+        distinct.to_string(),
+        min,
+        max,
+        mean,
+    ]
+}
+
+fn read_file(s: &str) -> Result<String, Box<dyn Error>> {
     let mut file = File::open(s.trim())?;
     let mut input = String::new();
     file.read_to_string(&mut input)?;
+    Ok(input)
+}
+
+fn parse_csv(input: &str) -> Result<Csv<'_>, Box<dyn Error>> {
     let mut lines = input.lines();
     let header: Vec<&str> = lines.next().ok_or("Missing header!")?.split(",").collect();
     let rows: Vec<Vec<&str>> = lines.into_iter().map(|e| e.split(",").collect()).collect();
@@ -74,7 +131,7 @@ pub fn csv(s: &str) -> Result<String, Box<dyn Error>> {
             )));
         }
     }
-    Ok(Csv { header, rows }.to_string())
+    Ok(Csv { header, rows })
 }
 
 struct Csv<'a> {
@@ -125,3 +182,26 @@ impl fmt::Display for Csv<'_> {
         write!(f, "{}", output)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_column_stats() {
+        let ages = ["31", "25", "", "31"];
+        assert_eq!(
+            column_stats("age", ages.into_iter()),
+            ["age", "3", "2", "25", "31", "29.00"]
+        );
+        let cities = ["Praha", "Brno", "4"];
+        assert_eq!(
+            column_stats("city", cities.into_iter()),
+            ["city", "3", "3", "4", "Praha", "-"]
+        );
+        assert_eq!(
+            column_stats("empty", [""].into_iter()),
+            ["empty", "0", "0", "-", "-", "-"]
+        );
+    }
+}
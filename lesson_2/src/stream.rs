@@ -0,0 +1,177 @@
+//! Streaming processing of huge files.
+//!
+//! The file is read line by line into a reused buffer and every transformed line is written right away, so the
+//! memory use doesn't depend on the file size. The progress is reported to stderr every `progress_mb` megabytes.
+
+use crate::operations::Operation;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+/// Default number of megabytes between the progress reports.
+pub const PROGRESS_MB: u64 = 100;
+
+const MB: u64 = 1024 * 1024;
+
+/// Arguments of the file mode: `<operation> <input-file> [output-file] [--progress-mb N]`.
+pub struct FileArguments {
+    pub operation: Operation,
+    pub input: String,
+    pub output: Option<String>,
+    pub progress_mb: u64,
+}
+
+impl FileArguments {
+    pub fn parse(arguments: &[String]) -> Result<FileArguments, Box<dyn Error>> {
+        let mut positional = Vec::new();
+        let mut progress_mb = PROGRESS_MB;
+        let mut arguments = arguments.iter();
+        while let Some(argument) = arguments.next() {
+            if argument == "--progress-mb" {
+                let value = arguments.next().ok_or("Missing value of --progress-mb!")?;
+                progress_mb = value
+                    .parse()
+                    .ok()
+                    .filter(|value| *value > 0)
+                    .ok_or(format!("Invalid value of --progress-mb: {value}!"))?;
+            } else {
+                positional.push(argument.as_str());
+            }
+        }
+        let (operation, input, output) = match positional[..] {
+            [operation, input] => (operation, input, None),
+            [operation, input, output] => (operation, input, Some(output.to_string())),
+            _ => {
+                return Err(From::from(
+                    "Usage: <operation> <input-file> [output-file] [--progress-mb N]",
+                ))
+            }
+        };
+        Ok(FileArguments {
+            operation: operation.parse()?,
+            input: input.to_string(),
+            output,
+            progress_mb,
+        })
+    }
+}
+
+/// Returns the transformation of a single line for the operations which can stream.
+fn line_transform(operation: &Operation) -> Option<fn(&str) -> String> {
+    match operation {
+        Operation::Lowercase => Some(str::to_lowercase),
+        Operation::Uppercase => Some(str::to_uppercase),
+        Operation::NoSpaces => Some(|line| line.replace(' ', "")),
+        Operation::Unchanged => Some(str::to_string),
+        _ => None,
+    }
+}
+
+/// Transforms the lines of the reader into the writer.
+///
+/// Line endings are kept as they are, `progress` is called with the number of processed bytes every `progress_mb`
+/// megabytes.
+///
+/// Returns the number of processed bytes.
+pub fn process<R: BufRead, W: Write>(
+    operation: &Operation,
+    mut reader: R,
+    mut writer: W,
+    progress_mb: u64,
+    mut progress: impl FnMut(u64),
+) -> Result<u64, Box<dyn Error>> {
+    let transform = line_transform(operation).ok_or(format!(
+        "Operation {operation:?} can't process files line by line!"
+    ))?;
+    let mut line = String::new();
+    let mut processed = 0;
+    let mut next_report = progress_mb * MB;
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line)?;
+        if read == 0 {
+            break;
+        }
+        let content = line.trim_end_matches(['\r', '\n']);
+        writer.write_all(transform(content).as_bytes())?;
+        writer.write_all(&line.as_bytes()[content.len()..])?;
+        processed += read as u64;
+        if processed >= next_report {
+            progress(processed);
+            next_report += progress_mb * MB;
+        }
+    }
+    writer.flush()?;
+    Ok(processed)
+}
+
+/// Runs the file mode, writing to the output file or to stdout.
+pub fn run(arguments: FileArguments) -> Result<(), Box<dyn Error>> {
+    let reader = BufReader::new(File::open(&arguments.input)?);
+    let report = |processed| eprintln!("Processed {} MB", processed / MB);
+    let writer: Box<dyn Write> = match &arguments.output {
+        Some(output) => Box::new(BufWriter::new(File::create(output)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    let processed = process(
+        &arguments.operation,
+        reader,
+        writer,
+        arguments.progress_mb,
+        report,
+    )?;
+    eprintln!("Done, processed {processed} bytes");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_lines() {
+        let input = "Hello World\r\n  Keep Indent\nlast";
+        let mut output = Vec::new();
+        let processed = process(
+            &Operation::Uppercase,
+            input.as_bytes(),
+            &mut output,
+            1,
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(processed, input.len() as u64);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HELLO WORLD\r\n  KEEP INDENT\nLAST"
+        );
+
+        let mut output = Vec::new();
+        process(
+            &Operation::NoSpaces,
+            "a b c\n".as_bytes(),
+            &mut output,
+            1,
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(output, b"abc\n");
+        assert!(process(&Operation::Csv, "a".as_bytes(), Vec::new(), 1, |_| {}).is_err());
+    }
+
+    #[test]
+    fn test_progress() {
+        let input = "x".repeat(1023) + "\n";
+        let input = input.repeat(2560);
+        let mut reports = Vec::new();
+        process(
+            &Operation::Lowercase,
+            input.as_bytes(),
+            io::sink(),
+            1,
+            |processed| reports.push(processed / MB),
+        )
+        .unwrap();
+        assert_eq!(reports, [1, 2]);
+    }
+}
@@ -0,0 +1,548 @@
+//! Framing of the messages for [`FramedRead`](tokio_util::codec::FramedRead) and
+//! [`FramedWrite`](tokio_util::codec::FramedWrite).
+//!
+//! Every message is sent as a frame, the length of the serialized message as a big-endian `u32` followed by the
+//! bincode of the message. A frame longer than the limit of the codec or failing to deserialize doesn't end the
+//! stream, it is decoded as `Err` item, so the reader can answer it and keep reading the next frames. Only the errors
+//! of the connection are the errors of the stream, [`flatten`] merges both for the readers handling them the same.
+//!
+//! The highest bit of the length marks a frame with the CRC32 of the message, a big-endian `u32` between the length
+//! and the message. The decoder verifies every such frame, the encoder only adds the checksums when turned on by
+//! [`MessageCodec::set_checksum`]. The peers agree on them by the `checksum` capability, so a peer which doesn't know
+//! the checksums never receives them.
+//!
+//! The second highest bit marks a frame with the gzipped message, the length and the checksum are of the compressed
+//! bytes. The decoder decompresses every such frame within its limit, the encoder compresses the messages of at least
+//! [`COMPRESSION_THRESHOLD`] bytes only when turned on by [`MessageCodec::set_compression`], the peers agree on it by
+//! the `compression` capability.
+//!
+//! A codec [`with_format`](MessageCodec::with_format) [`WireFormat::Json`] frames the messages as JSON lines instead,
+//! every message is a line of compact JSON. The JSON lines carry no checksums, a line longer than the limit is
+//! dropped up to its end and the empty lines are skipped.
+
+use std::io::{Read, Write};
+
+use bytes::{Buf, BufMut, BytesMut};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{Message, MessageError, WireFormat};
+
+/// Length of the prefix of every frame.
+pub const LENGTH_PREFIX: usize = 4;
+/// Length of the checksum following the length prefix of a checksummed frame.
+pub const CHECKSUM_LEN: usize = 4;
+/// Bit of the length prefix marking a checksummed frame.
+pub const CHECKSUM_FLAG: u32 = 1 << 31;
+/// Bit of the length prefix marking a frame with the compressed message.
+pub const COMPRESSED_FLAG: u32 = 1 << 30;
+/// Maximal length of the serialized message, the highest bits of the length prefix are the [`CHECKSUM_FLAG`] and the
+/// [`COMPRESSED_FLAG`].
+pub const MAX_LENGTH: usize = (COMPRESSED_FLAG - 1) as usize;
+/// Minimal length of a compressed message, the shorter ones are not worth it.
+pub const COMPRESSION_THRESHOLD: usize = 64 * 1024;
+
+/// Codec of the length-prefixed messages.
+///
+/// # Example
+///
+/// ```
+/// use bytes::BytesMut;
+/// use chat::codec::MessageCodec;
+/// use chat::{Message, MessageType};
+/// use tokio_util::codec::{Decoder, Encoder};
+///
+/// let mut codec = MessageCodec::default();
+/// let mut buffer = BytesMut::new();
+/// let msg = Message::from("user", MessageType::text("Hello"));
+/// codec.encode(&msg, &mut buffer).unwrap();
+/// assert_eq!(codec.decode(&mut buffer).unwrap().unwrap().unwrap(), msg);
+/// assert!(buffer.is_empty());
+/// ```
+#[derive(Debug, Clone)]
+pub struct MessageCodec {
+    /// Maximal length of the serialized message in bytes.
+    max: usize,
+    /// Bytes of a too large frame still to be dropped.
+    skipping: usize,
+    /// Whether the encoded frames carry the checksum.
+    checksum: bool,
+    /// Whether a checksummed frame was decoded.
+    received_checksum: bool,
+    /// Whether the big messages are compressed.
+    compression: bool,
+    /// Whether a compressed frame was decoded.
+    received_compressed: bool,
+    format: WireFormat,
+    /// A too long JSON line is dropped up to its end.
+    skipping_line: bool,
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        MessageCodec::with_max(MAX_LENGTH)
+    }
+}
+
+impl MessageCodec {
+    /// Creates a codec decoding the messages of at most `max` bytes.
+    ///
+    /// A bigger frame is decoded as [`MessageError::TooLarge`] and its bytes are dropped as they arrive, so it is
+    /// never buffered whole.
+    pub fn with_max(max: usize) -> MessageCodec {
+        MessageCodec {
+            max,
+            skipping: 0,
+            checksum: false,
+            received_checksum: false,
+            compression: false,
+            received_compressed: false,
+            format: WireFormat::Bincode,
+            skipping_line: false,
+        }
+    }
+
+    /// Returns the codec framing the messages in the wire format, [`WireFormat::Bincode`] by default.
+    pub fn with_format(mut self, format: WireFormat) -> MessageCodec {
+        self.format = format;
+        self
+    }
+
+    /// Turns the checksums of the encoded frames on or off, only for a peer announcing the `checksum` capability.
+    pub fn set_checksum(&mut self, checksum: bool) {
+        self.checksum = checksum;
+    }
+
+    /// Returns true if the peer sent a checksummed frame, so it verifies the checksums too.
+    pub fn received_checksum(&self) -> bool {
+        self.received_checksum
+    }
+
+    /// Turns the compression of the big messages on or off, only for a peer announcing the `compression` capability.
+    pub fn set_compression(&mut self, compression: bool) {
+        self.compression = compression;
+    }
+
+    /// Returns true if the peer sent a compressed frame, so it decompresses them too.
+    pub fn received_compressed(&self) -> bool {
+        self.received_compressed
+    }
+
+    /// Drops the buffered bytes of the skipped frame, returns true if all of them were dropped.
+    fn skip(&mut self, src: &mut BytesMut) -> bool {
+        let dropped = self.skipping.min(src.len());
+        src.advance(dropped);
+        self.skipping -= dropped;
+        self.skipping == 0
+    }
+
+    /// Decodes a JSON line.
+    fn decode_line(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> Result<Option<Result<Message, MessageError>>, MessageError> {
+        loop {
+            let Some(end) = src.iter().position(|byte| *byte == b'\n') else {
+                if self.skipping_line {
+                    src.clear();
+                } else if src.len() > self.max {
+                    let length = src.len();
+                    src.clear();
+                    self.skipping_line = true;
+                    let max = self.max;
+                    return Ok(Some(Err(MessageError::TooLarge { length, max })));
+                }
+                return Ok(None);
+            };
+            let line = src.split_to(end + 1);
+            if std::mem::take(&mut self.skipping_line) || line.trim_ascii().is_empty() {
+                continue;
+            }
+            if line.len() > self.max {
+                let max = self.max;
+                return Ok(Some(Err(MessageError::TooLarge {
+                    length: line.len(),
+                    max,
+                })));
+            }
+            return Ok(Some(WireFormat::Json.deserialize(&line)));
+        }
+    }
+}
+
+impl Decoder for MessageCodec {
+    /// The decoded message, or the reason why the frame was dropped.
+    type Item = Result<Message, MessageError>;
+    type Error = MessageError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, MessageError> {
+        if self.format == WireFormat::Json {
+            return self.decode_line(src);
+        }
+        if !self.skip(src) {
+            return Ok(None);
+        }
+        let Some(prefix) = src.get(..LENGTH_PREFIX) else {
+            src.reserve(LENGTH_PREFIX);
+            return Ok(None);
+        };
+        let prefix = u32::from_be_bytes(prefix.try_into().expect("prefix has 4 bytes"));
+        let checksummed = prefix & CHECKSUM_FLAG != 0;
+        let compressed = prefix & COMPRESSED_FLAG != 0;
+        let length = (prefix & !(CHECKSUM_FLAG | COMPRESSED_FLAG)) as usize;
+        let checksum_len = if checksummed { CHECKSUM_LEN } else { 0 };
+        if length > self.max {
+            src.advance(LENGTH_PREFIX);
+            self.skipping = checksum_len + length;
+            self.skip(src);
+            let max = self.max;
+            return Ok(Some(Err(MessageError::TooLarge { length, max })));
+        }
+        let header = LENGTH_PREFIX + checksum_len;
+        if src.len() < header + length {
+            src.reserve(header + length - src.len());
+            return Ok(None);
+        }
+        src.advance(LENGTH_PREFIX);
+        if checksummed {
+            self.received_checksum = true;
+            let expected = src.get_u32();
+            let actual = crc32fast::hash(&src[..length]);
+            if actual != expected {
+                src.advance(length);
+                return Ok(Some(Err(MessageError::ChecksumMismatch {
+                    expected,
+                    actual,
+                })));
+            }
+        }
+        let body = src.split_to(length);
+        if compressed {
+            self.received_compressed = true;
+            let decoded = decompress(&body, self.max).and_then(|message| {
+                Message::deserialized_message(&message).map_err(MessageError::from)
+            });
+            return Ok(Some(decoded));
+        }
+        Ok(Some(
+            Message::deserialized_message(&body).map_err(MessageError::from),
+        ))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, MessageError> {
+        match self.decode(src)? {
+            Some(decoded) => Ok(Some(decoded)),
+            None if src.is_empty() && self.skipping == 0 => Ok(None),
+            // The last JSON line without the line end.
+            None if self.format == WireFormat::Json && !self.skipping_line => {
+                let line = src.split();
+                match line.trim_ascii().is_empty() {
+                    true => Ok(None),
+                    false => Ok(Some(WireFormat::Json.deserialize(&line))),
+                }
+            }
+            // The connection closed in the middle of a frame.
+            None => Err(MessageError::UnexpectedEof),
+        }
+    }
+}
+
+/// Merges the item of a stream decoded by the [`MessageCodec`] and the error of its frame, the end of the stream is
+/// [`MessageError::UnexpectedEof`].
+///
+/// # Example
+///
+/// ```
+/// use chat::codec;
+/// use chat::MessageError;
+/// assert!(matches!(codec::flatten(None), Err(MessageError::UnexpectedEof)));
+/// ```
+pub fn flatten(
+    item: Option<Result<Result<Message, MessageError>, MessageError>>,
+) -> Result<Message, MessageError> {
+    item.unwrap_or(Err(MessageError::UnexpectedEof))
+        .and_then(|decoded| decoded)
+}
+
+impl Encoder<&Message> for MessageCodec {
+    type Error = MessageError;
+
+    fn encode(&mut self, item: &Message, dst: &mut BytesMut) -> Result<(), MessageError> {
+        if self.format == WireFormat::Json {
+            // The compact JSON escapes the line ends in the strings.
+            dst.extend_from_slice(&WireFormat::Json.serialize(item)?);
+            dst.put_u8(b'\n');
+            return Ok(());
+        }
+        let mut message = item.serialized_message()?;
+        let mut flags = 0;
+        if self.compression && message.len() >= COMPRESSION_THRESHOLD {
+            if let Some(compressed) = compress(&message) {
+                message = compressed;
+                flags |= COMPRESSED_FLAG;
+            }
+        }
+        if message.len() > MAX_LENGTH {
+            return Err(MessageError::TooLarge {
+                length: message.len(),
+                max: MAX_LENGTH,
+            });
+        }
+        let length = message.len() as u32 | flags;
+        if self.checksum {
+            dst.reserve(LENGTH_PREFIX + CHECKSUM_LEN + message.len());
+            dst.put_u32(length | CHECKSUM_FLAG);
+            dst.put_u32(crc32fast::hash(&message));
+        } else {
+            dst.reserve(LENGTH_PREFIX + message.len());
+            dst.put_u32(length);
+        }
+        dst.extend_from_slice(&message);
+        Ok(())
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = MessageError;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), MessageError> {
+        self.encode(&item, dst)
+    }
+}
+
+/// Gzips the serialized message.
+///
+/// # Returns
+///
+/// The compressed message or `None` if it isn't shorter, e.g. an already compressed image.
+pub fn compress(message: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(message).ok()?;
+    let compressed = encoder.finish().ok()?;
+    (compressed.len() < message.len()).then_some(compressed)
+}
+
+/// Decompresses the gzipped message of at most `max` bytes.
+///
+/// # Errors
+///
+/// This function will return [`MessageError::TooLarge`] if the message is longer than `max`, without decompressing
+/// the rest of it, or [`MessageError::Decompression`] if the data isn't valid gzip.
+pub fn decompress(body: &[u8], max: usize) -> Result<Vec<u8>, MessageError> {
+    let mut message = Vec::new();
+    GzDecoder::new(body)
+        .take((max as u64).saturating_add(1))
+        .read_to_end(&mut message)
+        .map_err(MessageError::Decompression)?;
+    if message.len() > max {
+        return Err(MessageError::TooLarge {
+            length: message.len(),
+            max,
+        });
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MessageType;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    fn encoded(messages: &[Message]) -> BytesMut {
+        let mut codec = MessageCodec::default();
+        let mut buffer = BytesMut::new();
+        for message in messages {
+            codec.encode(message, &mut buffer).unwrap();
+        }
+        buffer
+    }
+
+    #[test]
+    fn test_partial_frames() {
+        let hello = Message::from("slava", MessageType::text("hello"));
+        let bytes = encoded(&[hello.clone(), hello.clone()]);
+        assert_eq!(&bytes[..bytes.len() / 2], &hello.frame().unwrap()[..]);
+        let mut codec = MessageCodec::default();
+        let mut buffer = BytesMut::new();
+        let mut decoded = Vec::new();
+        // The frames arrive byte by byte.
+        for byte in bytes {
+            buffer.put_u8(byte);
+            if let Some(message) = codec.decode(&mut buffer).unwrap() {
+                decoded.push(message.unwrap());
+            }
+        }
+        assert_eq!(decoded, [hello.clone(), hello]);
+        assert!(codec.decode_eof(&mut buffer).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_too_large_is_skipped() {
+        let big = Message::from("slava", MessageType::text("x".repeat(100)));
+        let small = Message::from("slava", MessageType::text("hi"));
+        let mut bytes = encoded(&[big, small.clone()]);
+        let mut codec = MessageCodec::with_max(60);
+        let mut buffer = bytes.split_to(30);
+        assert!(matches!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(Err(MessageError::TooLarge { max: 60, .. }))
+        ));
+        assert!(buffer.is_empty());
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        buffer.unsplit(bytes);
+        assert_eq!(codec.decode(&mut buffer).unwrap().unwrap().unwrap(), small);
+    }
+
+    #[test]
+    fn test_invalid_frames() {
+        let mut buffer = BytesMut::from(&[0, 0, 0, 2, 255, 255, 0, 0][..]);
+        let mut codec = MessageCodec::default();
+        assert!(matches!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(Err(MessageError::DeSerializationError(_)))
+        ));
+        // Half of the length prefix left when the connection closed.
+        assert!(matches!(
+            codec.decode_eof(&mut buffer),
+            Err(MessageError::UnexpectedEof)
+        ));
+    }
+
+    #[test]
+    fn test_checksum() {
+        let hello = Message::from("slava", MessageType::text("hello"));
+        let mut codec = MessageCodec::default();
+        codec.set_checksum(true);
+        let mut buffer = BytesMut::new();
+        codec.encode(&hello, &mut buffer).unwrap();
+        codec.encode(&hello, &mut buffer).unwrap();
+        let frame_len = buffer.len() / 2;
+        assert_eq!(frame_len, hello.frame().unwrap().len() + CHECKSUM_LEN);
+        // A bit flipped on the way in the first message.
+        buffer[frame_len - 1] ^= 1;
+
+        let mut decoder = MessageCodec::default();
+        assert!(!decoder.received_checksum());
+        assert!(matches!(
+            decoder.decode(&mut buffer).unwrap(),
+            Some(Err(MessageError::ChecksumMismatch { .. }))
+        ));
+        assert!(decoder.received_checksum());
+        assert_eq!(
+            decoder.decode(&mut buffer).unwrap().unwrap().unwrap(),
+            hello
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let hello = Message::from("nc", MessageType::text("hello\nthere"));
+        let mut codec = MessageCodec::with_max(140).with_format(WireFormat::Json);
+        let mut buffer = BytesMut::new();
+        codec.encode(&hello, &mut buffer).unwrap();
+        assert_eq!(buffer.iter().filter(|byte| **byte == b'\n').count(), 1);
+        buffer.extend_from_slice(b"\r\n{\"nickname\":\"nc\",\"message\":{\"Text\":\"hi\"}}\r\n");
+        buffer.extend_from_slice(&[b'x'; 150]);
+        buffer.extend_from_slice(b"\nnot json\n{\"nickname\":\"nc\",\"message\":\"Admitted\"}");
+
+        assert_eq!(codec.decode(&mut buffer).unwrap().unwrap().unwrap(), hello);
+        let short = codec.decode(&mut buffer).unwrap().unwrap().unwrap();
+        assert_eq!(short.message, MessageType::text("hi"));
+        assert!(matches!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(Err(MessageError::TooLarge { max: 140, .. }))
+        ));
+        assert!(matches!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(Err(MessageError::JsonError(_)))
+        ));
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        let last = codec.decode_eof(&mut buffer).unwrap().unwrap().unwrap();
+        assert_eq!(last.message, MessageType::Admitted);
+        assert!(codec.decode_eof(&mut buffer).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_compression() {
+        let big = Message::from("slava", MessageType::text("meow ".repeat(20_000)));
+        let small = Message::from("slava", MessageType::text("hello"));
+        let mut codec = MessageCodec::default();
+        codec.set_compression(true);
+        codec.set_checksum(true);
+        let mut buffer = BytesMut::new();
+        codec.encode(&big, &mut buffer).unwrap();
+        let big_len = buffer.len();
+        assert!(big_len < big.frame().unwrap().len() / 10);
+        let prefix = u32::from_be_bytes(buffer[..LENGTH_PREFIX].try_into().unwrap());
+        assert_eq!(prefix & COMPRESSED_FLAG, COMPRESSED_FLAG);
+        codec.encode(&small, &mut buffer).unwrap();
+        assert_eq!(
+            buffer.len() - big_len,
+            small.frame().unwrap().len() + CHECKSUM_LEN
+        );
+
+        let mut decoder = MessageCodec::default();
+        assert_eq!(decoder.decode(&mut buffer).unwrap().unwrap().unwrap(), big);
+        assert!(decoder.received_compressed());
+        assert_eq!(
+            decoder.decode(&mut buffer).unwrap().unwrap().unwrap(),
+            small
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_decompression_limit() {
+        let big = Message::from("slava", MessageType::text("x".repeat(100_000)));
+        let mut codec = MessageCodec::default();
+        codec.set_compression(true);
+        let mut buffer = BytesMut::new();
+        codec.encode(&big, &mut buffer).unwrap();
+        codec.encode(&big, &mut buffer).unwrap();
+        // The compressed frame fits into the limit, the decompressed message doesn't.
+        let mut decoder = MessageCodec::with_max(50_000);
+        assert!(matches!(
+            decoder.decode(&mut buffer).unwrap(),
+            Some(Err(MessageError::TooLarge {
+                length: 50_001,
+                max: 50_000
+            }))
+        ));
+        assert!(matches!(
+            decoder.decode(&mut buffer).unwrap(),
+            Some(Err(MessageError::TooLarge { .. }))
+        ));
+        assert!(buffer.is_empty());
+        assert!(compress(b"short").is_none());
+        assert!(matches!(
+            decompress(b"not gzip", 100),
+            Err(MessageError::Decompression(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_framed() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut writer = FramedWrite::new(client, MessageCodec::default());
+        let messages: Vec<Message> = (0..10)
+            .map(|i| Message::from("slava", MessageType::text(i.to_string().repeat(i * 10))))
+            .collect();
+        let sent = messages.clone();
+        tokio::spawn(async move {
+            for message in &sent {
+                writer.send(message).await.unwrap();
+            }
+        });
+        let reader = FramedRead::new(server, MessageCodec::default());
+        let received: Vec<Message> = reader
+            .map(|message| message.unwrap().unwrap())
+            .collect()
+            .await;
+        assert_eq!(received, messages);
+    }
+}
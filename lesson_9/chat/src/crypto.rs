@@ -0,0 +1,266 @@
+//! End-to-end encryption of the texts, code, images and files, built with the `e2e` feature.
+//!
+//! Every user has a [`KeyPair`] of X25519 keys and announces the public one by a [`MessageType::PublicKey`], the
+//! server keeps the latest key of every nickname and sends them to the joining clients. [`KeyPair::seal`] encrypts the
+//! bincode of the message by ChaCha20-Poly1305 with a random content key and the nickname of the sender as the
+//! associated data, so the server can't pass the message off as another user's. The content key is encrypted for every
+//! recipient by the key derived by HKDF-SHA256 from the X25519 secret shared by the sender and the recipient, salted by
+//! the nonce of the message, so it is unique for every message. [`KeyPair::open`] reverses it. The server only relays
+//! and stores the [`Sealed`] message, its history shows the message type only.
+//!
+//! The keys aren't verified by anybody, a client trusts the first key of a nickname and warns when it changes, the
+//! users compare the [`fingerprint`]s to be sure.
+//!
+//! # Example
+//!
+//! ```
+//! use chat::crypto::{CryptoError, KeyPair};
+//! use chat::MessageType;
+//!
+//! let (slava, eva, bob) = (KeyPair::generate(), KeyPair::generate(), KeyPair::generate());
+//! let message = MessageType::text("Only for Eva");
+//! let sealed = slava.seal("slava", &message, &[eva.public()]).unwrap();
+//! assert_eq!(eva.open("slava", &sealed).unwrap(), message);
+//! assert_eq!(bob.open("slava", &sealed), Err(CryptoError::NotForMe));
+//! // The server changed the sender.
+//! assert_eq!(eva.open("bob", &sealed), Err(CryptoError::Tampered));
+//! ```
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::{MessageType, Recipient, Sealed};
+
+/// Context of the derived keys, so they differ from the keys of any other use of the same X25519 keys.
+const KEY_INFO: &[u8] = b"chat e2e content key";
+
+/// Errors of the encryption and decryption.
+#[derive(Error, Debug, PartialEq)]
+pub enum CryptoError {
+    #[error("only texts, code, images and files are encrypted")]
+    Unsupported,
+    #[error("the message isn't encrypted for this key")]
+    NotForMe,
+    #[error("the message was changed on the way or the sender's key is wrong")]
+    Tampered,
+    #[error("the key of {0} is invalid")]
+    InvalidKey(String),
+    #[error("the decrypted message is invalid: {0}")]
+    Invalid(String),
+}
+
+/// X25519 key pair of the user, the secret key never leaves the client.
+#[derive(Clone)]
+pub struct KeyPair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl KeyPair {
+    /// Generates a new random key pair.
+    pub fn generate() -> KeyPair {
+        KeyPair::from_secret(StaticSecret::random_from_rng(OsRng).to_bytes())
+    }
+
+    /// Restores the key pair from the saved secret key.
+    pub fn from_secret(secret: [u8; 32]) -> KeyPair {
+        let secret = StaticSecret::from(secret);
+        let public = PublicKey::from(&secret);
+        KeyPair { secret, public }
+    }
+
+    /// Returns the secret key to be saved.
+    pub fn secret(&self) -> [u8; 32] {
+        self.secret.to_bytes()
+    }
+
+    /// Returns the public key announced to the other users.
+    pub fn public(&self) -> [u8; 32] {
+        self.public.to_bytes()
+    }
+
+    /// Encrypts the message of the sender for the recipients with the public keys.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the message isn't a text, code, an image or a file, or a recipient's key
+    /// is invalid.
+    pub fn seal(
+        &self,
+        sender: &str,
+        message: &MessageType,
+        recipients: &[[u8; 32]],
+    ) -> Result<Sealed, CryptoError> {
+        if !is_sealable(message) {
+            return Err(CryptoError::Unsupported);
+        }
+        let plaintext = bincode::serialize(message)
+            .map_err(|err_msg| CryptoError::Invalid(err_msg.to_string()))?;
+        let content_key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = ChaCha20Poly1305::new(&content_key)
+            .encrypt(&nonce, payload(sender, &plaintext))
+            .map_err(|_| CryptoError::Invalid("the message is too long".to_string()))?;
+        let recipients = recipients
+            .iter()
+            .map(|recipient| {
+                let wrapping =
+                    self.wrapping_key(&self.public, &PublicKey::from(*recipient), &nonce)?;
+                let wrapped_key = ChaCha20Poly1305::new(&wrapping)
+                    .encrypt(&Nonce::default(), content_key.as_slice())
+                    .map_err(|_| CryptoError::InvalidKey(fingerprint(recipient)))?;
+                Ok(Recipient {
+                    key: *recipient,
+                    wrapped_key,
+                })
+            })
+            .collect::<Result<_, CryptoError>>()?;
+        Ok(Sealed {
+            sender_key: self.public(),
+            recipients,
+            nonce: nonce.into(),
+            ciphertext,
+        })
+    }
+
+    /// Decrypts the message of the sender encrypted for this key pair.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the message isn't encrypted for this key, it was changed, the sender
+    /// differs or the decrypted message isn't a text, code, an image or a file.
+    pub fn open(&self, sender: &str, sealed: &Sealed) -> Result<MessageType, CryptoError> {
+        let recipient = sealed
+            .recipients
+            .iter()
+            .find(|recipient| recipient.key == self.public())
+            .ok_or(CryptoError::NotForMe)?;
+        let nonce = Nonce::from(sealed.nonce);
+        let sender_key = PublicKey::from(sealed.sender_key);
+        let wrapping = self.wrapping_key(&sender_key, &self.public, &nonce)?;
+        let content_key = ChaCha20Poly1305::new(&wrapping)
+            .decrypt(&Nonce::default(), recipient.wrapped_key.as_slice())
+            .map_err(|_| CryptoError::Tampered)?;
+        let content_key = Key::from_exact_iter(content_key).ok_or(CryptoError::Tampered)?;
+        let plaintext = ChaCha20Poly1305::new(&content_key)
+            .decrypt(&nonce, payload(sender, &sealed.ciphertext))
+            .map_err(|_| CryptoError::Tampered)?;
+        let message: MessageType = bincode::deserialize(&plaintext)
+            .map_err(|err_msg| CryptoError::Invalid(err_msg.to_string()))?;
+        if !is_sealable(&message) {
+            return Err(CryptoError::Unsupported);
+        }
+        Ok(message)
+    }
+
+    /// Derives the key encrypting the content key of the message with the nonce from the sender to the recipient.
+    ///
+    /// Either side computes it, the sender from the recipient's public key and the recipient from the sender's one.
+    fn wrapping_key(
+        &self,
+        sender: &PublicKey,
+        recipient: &PublicKey,
+        nonce: &Nonce,
+    ) -> Result<Key, CryptoError> {
+        let other = if sender == &self.public {
+            recipient
+        } else {
+            sender
+        };
+        let shared = self.secret.diffie_hellman(other);
+        // A key of a low order point gives a secret everybody knows.
+        if !shared.was_contributory() {
+            return Err(CryptoError::InvalidKey(fingerprint(other.as_bytes())));
+        }
+        let info = [KEY_INFO, sender.as_bytes(), recipient.as_bytes()].concat();
+        let mut key = Key::default();
+        Hkdf::<Sha256>::new(Some(nonce.as_slice()), shared.as_bytes())
+            .expand(&info, &mut key)
+            .expect("32 bytes are a valid length of HKDF-SHA256");
+        Ok(key)
+    }
+}
+
+/// Returns the content bound to the nickname of the sender.
+fn payload<'a>(sender: &'a str, content: &'a [u8]) -> Payload<'a, 'a> {
+    Payload {
+        msg: content,
+        aad: sender.as_bytes(),
+    }
+}
+
+/// Returns true for the messages encrypted end to end, the texts, code, images and files.
+pub fn is_sealable(message: &MessageType) -> bool {
+    matches!(
+        message,
+        MessageType::Text(_)
+            | MessageType::Code { .. }
+            | MessageType::Image(_)
+            | MessageType::File { .. }
+    )
+}
+
+/// Returns the fingerprint of the public key the users compare, the first 8 bytes of its SHA-256 in groups.
+///
+/// # Example
+///
+/// ```
+/// use chat::crypto::fingerprint;
+/// assert_eq!(fingerprint(&[0; 32]), "66687aad f862bd77");
+/// ```
+pub fn fingerprint(key: &[u8; 32]) -> String {
+    let digest = Sha256::digest(key);
+    let hex: Vec<String> = digest[..8]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    format!("{} {}", hex[..4].concat(), hex[4..].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let (slava, eva, bob) = (
+            KeyPair::generate(),
+            KeyPair::generate(),
+            KeyPair::generate(),
+        );
+        let file = MessageType::file("notes.txt", b"secret notes");
+        let sealed = slava
+            .seal("slava", &file, &[eva.public(), bob.public()])
+            .unwrap();
+        assert_eq!(sealed.sender_key, slava.public());
+        assert_eq!(sealed.recipients.len(), 2);
+        assert_eq!(eva.open("slava", &sealed).unwrap(), file);
+        assert_eq!(bob.open("slava", &sealed).unwrap(), file);
+        assert_eq!(slava.open("slava", &sealed), Err(CryptoError::NotForMe));
+
+        let restored = KeyPair::from_secret(eva.secret());
+        assert_eq!(restored.public(), eva.public());
+        assert_eq!(restored.open("slava", &sealed).unwrap(), file);
+
+        let mut tampered = sealed.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert_eq!(eva.open("slava", &tampered), Err(CryptoError::Tampered));
+        let mut forged = sealed.clone();
+        forged.sender_key = bob.public();
+        assert_eq!(eva.open("slava", &forged), Err(CryptoError::Tampered));
+
+        let ping = MessageType::Ping { server_time: 42 };
+        assert!(matches!(
+            slava.seal("slava", &ping, &[eva.public()]),
+            Err(CryptoError::Unsupported)
+        ));
+        assert!(matches!(
+            slava.seal("slava", &MessageType::text("hi"), &[[0; 32]]),
+            Err(CryptoError::InvalidKey(_))
+        ));
+    }
+}
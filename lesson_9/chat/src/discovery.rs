@@ -0,0 +1,173 @@
+//! Discovery of the servers for the local use.
+//!
+//! A server started with `--port 0` binds a free port chosen by the system, so several servers run side by side
+//! without picking ports by hand. The server writes the port it listens on to the [`port_file`], where a client on the
+//! same machine finds it. Built with the `mdns` feature the server also advertises itself on the LAN as
+//! [`SERVICE_TYPE`] by [`advertise`] and the clients find it by [`browse`], so nobody types the addresses.
+
+#[cfg(feature = "mdns")]
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "mdns")]
+use std::time::{Duration, Instant};
+
+use crate::Address;
+
+/// Name of the file with the port of the local server.
+pub const PORT_FILE: &str = "chat-server.port";
+/// DNS-SD service type the servers are advertised as.
+pub const SERVICE_TYPE: &str = "_chat._tcp.local.";
+
+/// Returns the path of the [`PORT_FILE`] in `$XDG_RUNTIME_DIR`, or in the temporary directory without it.
+pub fn port_file() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join(PORT_FILE)
+}
+
+/// Writes the port to the file.
+///
+/// # Errors
+///
+/// This function will return an error if the file can't be written.
+pub fn write_port(path: &Path, port: u16) -> io::Result<()> {
+    fs::write(path, format!("{port}\n"))
+}
+
+/// Reads the port from the file, `None` if it is missing or doesn't hold a port.
+///
+/// # Example
+///
+/// ```
+/// use chat::discovery;
+/// let path = std::env::temp_dir().join("chat-doctest.port");
+/// discovery::write_port(&path, 40123).unwrap();
+/// assert_eq!(discovery::read_port(&path), Some(40123));
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+pub fn read_port(path: &Path) -> Option<u16> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Server found by the discovery.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Found {
+    /// Name of the server, e.g. from its config.
+    pub name: String,
+    pub hostname: String,
+    pub port: u16,
+}
+
+impl Found {
+    /// Returns the address to connect to.
+    pub fn address(&self) -> Address {
+        Address::new(self.hostname.clone(), self.port.to_string())
+    }
+}
+
+/// Advertisement of the server on the LAN, withdrawn when dropped.
+#[cfg(feature = "mdns")]
+pub struct Advertisement {
+    daemon: mdns_sd::ServiceDaemon,
+}
+
+#[cfg(feature = "mdns")]
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Advertises the server with the name listening on the port on all the interfaces.
+///
+/// # Errors
+///
+/// This function will return an error if the mDNS daemon can't start, e.g. without a network.
+#[cfg(feature = "mdns")]
+pub fn advertise(name: &str, port: u16) -> Result<Advertisement, mdns_sd::Error> {
+    let daemon = mdns_sd::ServiceDaemon::new()?;
+    // The host name only has to be unique on the LAN, the addresses are filled in by the daemon.
+    let host: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    let service = mdns_sd::ServiceInfo::new(
+        SERVICE_TYPE,
+        name,
+        &format!("{host}.local."),
+        "",
+        port,
+        None::<HashMap<String, String>>,
+    )?
+    .enable_addr_auto();
+    daemon.register(service)?;
+    Ok(Advertisement { daemon })
+}
+
+/// Looks for the servers advertised on the LAN for the timeout, blocking the thread.
+///
+/// # Errors
+///
+/// This function will return an error if the mDNS daemon can't start, e.g. without a network.
+#[cfg(feature = "mdns")]
+pub fn browse(timeout: Duration) -> Result<Vec<Found>, mdns_sd::Error> {
+    let daemon = mdns_sd::ServiceDaemon::new()?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+    let deadline = Instant::now() + timeout;
+    let mut found: Vec<Found> = Vec::new();
+    while let Ok(event) = events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        let mdns_sd::ServiceEvent::ServiceResolved(service) = event else {
+            continue;
+        };
+        // IPv4 first, an IPv6 link-local address needs the interface to connect.
+        let mut addresses: Vec<_> = service.get_addresses().iter().copied().collect();
+        addresses.sort_by_key(|address| !address.is_ipv4());
+        let Some(address) = addresses.first() else {
+            continue;
+        };
+        let name = service
+            .get_fullname()
+            .trim_end_matches(SERVICE_TYPE)
+            .trim_end_matches('.');
+        let server = Found {
+            name: name.to_string(),
+            hostname: address.to_string(),
+            port: service.get_port(),
+        };
+        // A server is resolved again as its addresses arrive, the IPv4 one replaces an IPv6 one.
+        match found.iter_mut().find(|known| known.name == server.name) {
+            Some(known) if address.is_ipv4() => *known = server,
+            Some(_) => {}
+            None => found.push(server),
+        }
+    }
+    let _ = daemon.shutdown();
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_port_file() {
+        assert!(port_file().ends_with(PORT_FILE));
+        let path = std::env::temp_dir().join("chat-test-discovery.port");
+        assert_eq!(read_port(&path.with_extension("missing")), None);
+        write_port(&path, 0).unwrap();
+        assert_eq!(read_port(&path), Some(0));
+        fs::write(&path, "not a port").unwrap();
+        assert_eq!(read_port(&path), None);
+        fs::remove_file(&path).unwrap();
+
+        let found = Found {
+            name: "Slava's chat".to_string(),
+            hostname: "192.168.1.7".to_string(),
+            port: 40123,
+        };
+        assert_eq!(found.address().to_string(), "192.168.1.7:40123");
+    }
+}
@@ -0,0 +1,252 @@
+//! Keepalive of the connections.
+//!
+//! A connection dropped silently, e.g. by a NAT forgetting it or a laptop going to sleep, looks open for a long time,
+//! the socket reports no error and [`Message::read`] waits forever. The server sends a [`MessageType::Ping`] every
+//! [`PING_INTERVAL`] by [`ping_loop`] and a client answers it by the [`pong`], so both sides receive something at least
+//! every interval. A connection without any byte for [`DEAD_AFTER`], three missed pings, is dead: [`Idle`] watches the
+//! read half of a connection and [`Idle::stalled`] completes once it is silent that long, [`read_timeout`] bounds a
+//! single read the same way. Bytes of a big frame still arriving count, so a slow upload isn't a dead connection.
+//!
+//! A client answers the pings only of a server announcing the `keepalive` capability, an older server doesn't know the
+//! Pong. The server in turn expects the answers only from a client that has sent a Pong already.
+//!
+//! # Example
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use chat::keepalive::{self, Idle};
+//! use chat::MessageType;
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let (mut server, client) = tokio::io::duplex(1024);
+//! let idle = Idle::default();
+//! let mut client = idle.watch(client);
+//! keepalive::ping().send(&mut server).await.unwrap();
+//!
+//! let ping = keepalive::read_timeout(&mut client, keepalive::DEAD_AFTER).await.unwrap();
+//! let pong = keepalive::pong("slava", &ping.message).unwrap();
+//! assert!(matches!(pong.message, MessageType::Pong { .. }));
+//! assert!(idle.elapsed() < Duration::from_secs(1));
+//! // The server went silent.
+//! assert!(keepalive::read_timeout(&mut client, Duration::from_millis(10)).await.is_err());
+//! # }
+//! ```
+
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+use crate::{unix_millis, Message, MessageError, MessageType};
+
+/// Interval of the pings of the server.
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// Silence after which a connection is dead, three missed pings.
+pub const DEAD_AFTER: Duration = Duration::from_secs(90);
+
+/// Returns a Ping of the server with its current time.
+pub fn ping() -> Message {
+    Message::system(MessageType::Ping {
+        server_time: unix_millis(),
+    })
+}
+
+/// Returns the Pong of the client with the nickname answering the message, `None` if it isn't a Ping.
+pub fn pong<S: AsRef<str>>(nickname: S, message: &MessageType) -> Option<Message> {
+    let MessageType::Ping { server_time } = message else {
+        return None;
+    };
+    let pong = MessageType::Pong {
+        server_time: *server_time,
+    };
+    Some(Message::from(nickname, pong))
+}
+
+/// Sends a [`ping`] every interval until `send` returns false. The first ping goes out after one interval, the Welcome
+/// message of a new connection carries the time anyway.
+pub async fn ping_loop<F: FnMut(Message) -> bool>(interval: Duration, mut send: F) {
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if !send(ping()) {
+            return;
+        }
+    }
+}
+
+/// Reads a message from the stream like [`Message::read`], giving up if it doesn't arrive in time.
+///
+/// # Errors
+///
+/// This function will return the [`io::ErrorKind::TimedOut`] error if no message arrived within the timeout, or any
+/// error of [`Message::read`].
+pub async fn read_timeout<T: AsyncReadExt + Unpin>(
+    stream: T,
+    timeout: Duration,
+) -> Result<Message, MessageError> {
+    tokio::time::timeout(timeout, Message::read(stream))
+        .await
+        .map_err(|_| {
+            let reason = format!("no message within {} s", timeout.as_secs_f32());
+            io::Error::new(io::ErrorKind::TimedOut, reason)
+        })?
+}
+
+/// Time of the last byte received on a connection, shared by its reading loop and the watchdog.
+#[derive(Clone)]
+pub struct Idle {
+    last: Arc<Mutex<Instant>>,
+}
+
+impl Default for Idle {
+    fn default() -> Self {
+        Idle {
+            last: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+}
+
+impl Idle {
+    /// Wraps the read half of a new connection, recording the arrival of every byte.
+    pub fn watch<R>(&self, reader: R) -> Watched<R> {
+        self.touch();
+        Watched {
+            reader,
+            idle: self.clone(),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+
+    /// Returns the time since the last byte arrived.
+    pub fn elapsed(&self) -> Duration {
+        self.last
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .elapsed()
+    }
+
+    /// Waits until nothing arrived for the window, never returns without a window.
+    ///
+    /// # Returns
+    ///
+    /// The duration of the stall.
+    pub async fn stalled(&self, window: Option<Duration>) -> Duration {
+        let Some(window) = window else {
+            return std::future::pending().await;
+        };
+        loop {
+            let elapsed = self.elapsed();
+            if elapsed >= window {
+                return elapsed;
+            }
+            tokio::time::sleep(window - elapsed).await;
+        }
+    }
+}
+
+/// Read half of the connection watched by [`Idle`].
+pub struct Watched<R> {
+    reader: R,
+    idle: Idle,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Watched<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.reader).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            self.idle.touch();
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_stalled() {
+        let (mut server, client) = tokio::io::duplex(64);
+        let idle = Idle::default();
+        let mut reader = idle.watch(client);
+        let timeout = Some(Duration::from_millis(200));
+
+        tokio::spawn(async move {
+            // Every byte arrives within the window, it is no stall.
+            for _ in 0..4 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                server.write_all(b"x").await.unwrap();
+            }
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+        let reading = async {
+            let mut bytes = [0u8; 4];
+            reader.read_exact(&mut bytes).await.unwrap();
+            std::future::pending::<()>().await
+        };
+        let started = Instant::now();
+        let stall = tokio::select! {
+            stall = idle.stalled(timeout) => stall,
+            _ = reading => unreachable!(),
+        };
+        assert!(stall >= Duration::from_millis(200));
+        assert!(started.elapsed() >= Duration::from_millis(550));
+    }
+
+    #[tokio::test]
+    async fn test_ping_loop() {
+        let (mut server, mut client) = tokio::io::duplex(1024);
+        let (sender, mut pings) = tokio::sync::mpsc::unbounded_channel();
+        let mut left = 2;
+        tokio::spawn(ping_loop(Duration::from_millis(20), move |ping| {
+            let _ = sender.send(ping);
+            left -= 1;
+            left > 0
+        }));
+        while let Some(ping) = pings.recv().await {
+            assert!(ping.system);
+            ping.send(&mut server).await.unwrap();
+            let ping = read_timeout(&mut client, DEAD_AFTER).await.unwrap();
+            pong("slava", &ping.message)
+                .unwrap()
+                .send(&mut client)
+                .await
+                .unwrap();
+            let pong = read_timeout(&mut server, DEAD_AFTER).await.unwrap();
+            assert_eq!(pong.nickname, "slava");
+            assert_eq!(
+                pong.message,
+                MessageType::Pong {
+                    server_time: match ping.message {
+                        MessageType::Ping { server_time } => server_time,
+                        _ => unreachable!(),
+                    }
+                }
+            );
+        }
+        assert!(pong("slava", &MessageType::text("hi")).is_none());
+
+        let read = read_timeout(&mut client, Duration::from_millis(20)).await;
+        match read {
+            Err(MessageError::IOError(err_msg)) => {
+                assert_eq!(err_msg.kind(), io::ErrorKind::TimedOut)
+            }
+            read => panic!("{read:?}"),
+        }
+    }
+}
@@ -1,10 +1,25 @@
+pub mod codec;
+#[cfg(feature = "e2e")]
+pub mod crypto;
+pub mod discovery;
+pub mod keepalive;
+pub mod report;
+pub mod testing;
+pub mod testvectors;
+pub mod time;
+pub mod transfer;
+
 use std::marker::Unpin;
-use std::{env, io};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{env, fmt, io};
 
-use bincode::Error as BincodeError;
+use bincode::{Error as BincodeError, Options};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::codec::Encoder;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
 
 const HOSTNAME: &str = "localhost";
 const PORT: &str = "11111";
@@ -21,6 +36,81 @@ pub struct Address {
 pub struct Message {
     pub nickname: String,
     pub message: MessageType,
+    /// Metadata attached by the server, e.g. `("lang", "eng")`. Clients may render or ignore them.
+    #[serde(default)]
+    pub annotations: Vec<(String, String)>,
+    /// Unix time of sending in milliseconds, the `sent_at` of the message. It is the field the server already reads
+    /// for the acknowledgements and stores, so a separate `sent_at` would only duplicate it in every frame.
+    ///
+    /// [`Message::from`] sets the time of creation. The client replaces it when the message leaves: `Link::send`
+    /// stamps the time of sending, `Link::resend` keeps the stamp of the first attempt and the messages queued over
+    /// a reconnection get the time of the flush. The server keeps a stamp within the tolerated clock skew and
+    /// replaces a missing or skewed one by its own time, flagged by [`TIME_ADJUSTED_ANNOTATION`]. The receiving end
+    /// renders it in its local time, see [`time`].
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// The message is authored by the server, e.g. a notice about a joined user. The server rejects client messages
+    /// with the flag, so it can't be impersonated.
+    #[serde(default)]
+    pub system: bool,
+    /// Room of the conversation, the server delivers the message only to the clients who joined it. Every client
+    /// starts in the [`DEFAULT_ROOM`].
+    #[serde(default = "default_room")]
+    pub room: String,
+}
+
+/// [`Message`] as framed by the first released clients and server, only the nickname and the message.
+///
+/// Decoded last, and only if it takes the whole frame, so a damaged frame of a later layout isn't read as this one.
+#[derive(Deserialize)]
+struct MessageV0 {
+    nickname: String,
+    message: MessageType,
+}
+
+impl MessageV0 {
+    /// Upgrades the message to the current layout, without annotations or a timestamp, in the [`DEFAULT_ROOM`].
+    fn upgrade(self) -> Message {
+        Message {
+            nickname: self.nickname,
+            message: self.message,
+            annotations: Vec::new(),
+            timestamp: None,
+            system: false,
+            room: default_room(),
+        }
+    }
+}
+
+/// [`Message`] as framed by the protocol version 1, before the rooms.
+///
+/// Bincode doesn't apply the serde defaults to the missing trailing fields, so a frame of an older client or server is
+/// decoded by this layout when the current one fails, see [`Message::deserialized_message`].
+#[derive(Deserialize)]
+struct MessageV1 {
+    nickname: String,
+    message: MessageType,
+    annotations: Vec<(String, String)>,
+    timestamp: Option<u64>,
+    system: bool,
+}
+
+impl MessageV1 {
+    /// Upgrades the message to the current layout, in the [`DEFAULT_ROOM`].
+    fn upgrade(self) -> Message {
+        Message {
+            nickname: self.nickname,
+            message: self.message,
+            annotations: self.annotations,
+            timestamp: self.timestamp,
+            system: self.system,
+            room: default_room(),
+        }
+    }
+}
+
+fn default_room() -> String {
+    DEFAULT_ROOM.to_string()
 }
 
 /// Enum representing different types of messages.
@@ -35,18 +125,473 @@ pub enum MessageType {
         name: String,
         content: Vec<u8>,
     },
+    /// Big file stored by the server and announced instead of its content, downloaded over HTTP from the `url`.
+    ///
+    /// The `url` carries a signed token expiring after a while. It is either absolute, or only the path when the
+    /// server doesn't know its public address, then it is relative to the HTTP port of the chat server.
+    Attachment {
+        name: String,
+        size: u64,
+        url: String,
+    },
+    /// Code snippet with the name of its language.
+    Code {
+        lang: String,
+        source: String,
+    },
+    /// Error reported by the server to the sender of a rejected message.
+    ServerError {
+        code: ErrorCode,
+    },
+    /// Request for a page of stored messages older than the `before` id (or the latest ones).
+    HistoryRequest {
+        before: Option<i64>,
+        limit: u32,
+    },
+    /// Page of stored messages ordered from the oldest, sent back for a HistoryRequest.
+    History(Vec<HistoryEntry>),
+    /// Request for the stored messages containing the query, ignoring case and accents.
+    SearchRequest {
+        query: String,
+        limit: u32,
+    },
+    /// Stored messages matching a SearchRequest, ordered from the oldest.
+    SearchResults(Vec<HistoryEntry>),
+    /// Synthetic payload measuring the transfer speed, acknowledged by the server and never delivered to others.
+    Bench {
+        id: u32,
+        payload: Vec<u8>,
+    },
+    /// Acknowledgement of the received Bench message with the payload size.
+    BenchAck {
+        id: u32,
+        size: u64,
+    },
+    /// Marker sent after the messages queued while offline, acknowledged once the server has received all of them.
+    Sync {
+        id: u32,
+    },
+    /// Acknowledgement of the Sync message, every message sent before it was received by the server.
+    SyncAck {
+        id: u32,
+    },
+    /// Position of the connection in the waiting queue of a full server, `1` is the next one to be admitted.
+    ServerFull {
+        position: usize,
+    },
+    /// The connection left the waiting queue and is served now.
+    Admitted,
+    /// First message of every connection describing the server.
+    Welcome {
+        server_name: String,
+        version: String,
+        motd: Option<String>,
+        /// Enabled features, e.g. `history`, `search` or `bench`.
+        capabilities: Vec<String>,
+        limits: ServerLimits,
+        /// Unix time of the server in milliseconds.
+        server_time: u64,
+    },
+    /// Unix time of the server in milliseconds, sent periodically so the clients keep track of their clock skew and
+    /// notice a dead connection, see [`keepalive`].
+    Ping {
+        server_time: u64,
+    },
+    /// Poll with its options, sent with the `id` 0 by its author and announced to everybody with the `id` assigned by
+    /// the server.
+    Poll {
+        id: i64,
+        question: String,
+        options: Vec<String>,
+    },
+    /// Vote for the `option` of the poll, given by its text or its number starting at 1.
+    Vote {
+        poll: i64,
+        option: String,
+    },
+    /// Request of the poll author to close the poll.
+    ClosePoll {
+        poll: i64,
+    },
+    /// Numbers of votes for every option of the poll, the final ones if the poll is `closed`.
+    PollResults {
+        id: i64,
+        question: String,
+        votes: Vec<(String, u32)>,
+        closed: bool,
+    },
+    /// Request of a fresh [`MessageType::RosterSnapshot`], e.g. after the client missed a delta.
+    RosterRequest,
+    /// Nicknames of the active users, sent to a client when it joins and on request. The `version` is the one of the
+    /// last delta included.
+    RosterSnapshot {
+        version: u64,
+        users: Vec<String>,
+    },
+    /// Change of the active users announced to everybody, the `version` is one more than the previous delta's.
+    RosterDelta {
+        version: u64,
+        change: RosterChange,
+    },
+    /// Change of the sender's email digest of the mentions they missed while offline, answered by a system notice.
+    Digest(DigestSetting),
+    /// Start of a file of `size` bytes streamed in [`MessageType::FileChunk`]s, see [`transfer`]. The `id` is chosen by
+    /// the sender and tells its concurrent transfers apart.
+    FileStart {
+        id: u32,
+        name: String,
+        size: u64,
+    },
+    /// Part of the streamed file starting at the `offset`, the chunks are sent in order.
+    FileChunk {
+        id: u32,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// End of the streamed file with the CRC32 of its whole content.
+    FileEnd {
+        id: u32,
+        checksum: u32,
+    },
+    /// Report of the stored message with the id to the moderators of the server.
+    Report {
+        message: i64,
+        reason: String,
+    },
+    /// Request to receive the messages of the room, see [`is_valid_room`].
+    Join {
+        room: String,
+    },
+    /// Request to stop receiving the messages of the room.
+    Leave {
+        room: String,
+    },
+    /// Notice of the server about the chat, e.g. `bob left the chat`. Only the server sends it, flagged as
+    /// [`Message::system`].
+    System(String),
+    /// Direct message delivered only to the user with the nickname `to`, never stored, see
+    /// [`MessageType::is_no_persist`].
+    Whisper {
+        to: String,
+        text: String,
+    },
+    /// Acknowledgement of a message flagged by the [`ACK_ANNOTATION`], sent to its sender once the message is broadcast
+    /// and stored with the id. `sent_at` repeats the timestamp the sender stamped the message with.
+    Ack {
+        message_id: i64,
+        sent_at: u64,
+    },
+    /// Answer of a client to the Ping, repeating its `server_time`, so the server notices a dead connection of a quiet
+    /// client, see [`keepalive`].
+    Pong {
+        server_time: u64,
+    },
+    /// Public key of the sender for the end-to-end encryption, kept by the server and sent to every joining client.
+    PublicKey {
+        key: [u8; 32],
+    },
+    /// Text, code, image or file encrypted by the sender for the recipients, the server only relays and stores it.
+    Encrypted(Sealed),
+}
+
+/// Message encrypted end to end, carried by [`MessageType::Encrypted`] and made by the `crypto` module.
+///
+/// The message is encrypted once by a random content key, which is encrypted for every recipient by the key both
+/// derive from the sender's and the recipient's keys.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Sealed {
+    /// Public key of the sender.
+    pub sender_key: [u8; 32],
+    /// Content key encrypted for every recipient.
+    pub recipients: Vec<Recipient>,
+    /// Nonce of the content, unique for every message.
+    pub nonce: [u8; 12],
+    /// Encrypted bincode of the text, code, image or file message.
+    pub ciphertext: Vec<u8>,
+}
+
+/// Content key of a [`Sealed`] message encrypted for the recipient with the public key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Recipient {
+    pub key: [u8; 32],
+    pub wrapped_key: Vec<u8>,
+}
+
+/// Setting of the email digest carried by [`MessageType::Digest`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum DigestSetting {
+    /// Address receiving the digests.
+    Email(String),
+    /// Sends the digest every day at the minute of the day in UTC, e.g. `480` for 08:00.
+    Daily { minute: u16 },
+    /// Stops the digests and forgets the address.
+    Off,
+}
+
+/// Change of the active users carried by [`MessageType::RosterDelta`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RosterChange {
+    Joined(String),
+    Left(String),
+    Renamed { from: String, to: String },
+}
+
+/// Represents a message stored on the server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub id: i64,
+    pub nickname: String,
+    pub msg_type: String,
+    pub message: String,
+    /// Unix time of sending in milliseconds in the server's clock, unknown for messages stored by older servers.
+    pub timestamp: Option<u64>,
+}
+
+/// Limits announced by the server in the Welcome message.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ServerLimits {
+    /// Maximal size of a single image or file in bytes.
+    pub max_attachment: u64,
+    /// Maximal number of messages in a history page or search results.
+    pub max_history: u32,
+}
+
+/// Enum representing errors reported by the server.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    /// The server has too much attachment data in flight.
+    Overloaded,
+    /// The sender is muted by the anti-spam heuristics for the given number of seconds.
+    Muted { seconds: u64 },
+    /// The server is full and its waiting queue too.
+    QueueFull,
+    /// The poll doesn't have a question or 2 to [`MAX_POLL_OPTIONS`] different options.
+    InvalidPoll,
+    /// No poll has the id.
+    UnknownPoll { id: i64 },
+    /// The poll doesn't have the voted option.
+    InvalidVote { id: i64 },
+    /// The poll was closed, it doesn't accept any more votes.
+    PollClosed { id: i64 },
+    /// Only the author can close the poll.
+    NotPollAuthor { id: i64 },
+    /// The message of `size` bytes is bigger than the `max` the server accepts.
+    TooLarge { size: u64, max: u64 },
+    /// The server couldn't decode the message, e.g. it was sent by an incompatible client.
+    InvalidMessage,
+    /// The message was delivered, but storing it in the history failed.
+    NotStored,
+    /// The server couldn't answer the request because of an internal error.
+    Unavailable,
+    /// The nickname is reserved for the server, see [`is_reserved`].
+    ReservedNickname,
+    /// The digest email address is invalid, the time is out of the day or the digest has no address yet.
+    InvalidDigest,
+    /// No stored message has the id.
+    UnknownMessage { id: i64 },
+    /// The report has no reason or a reason longer than [`MAX_REPORT_REASON`].
+    InvalidReport,
+    /// The room name is empty, longer than [`MAX_ROOM_NAME`] or has other characters than letters, digits, `-` and
+    /// `_`.
+    InvalidRoom,
+    /// The sender hasn't joined the room of the message or the room it leaves.
+    NotInRoom,
+}
+
+/// Maximal number of the options of a poll.
+pub const MAX_POLL_OPTIONS: usize = 10;
+/// Maximal length of the reason of a report in characters.
+pub const MAX_REPORT_REASON: usize = 500;
+/// Room every client is in after connecting.
+pub const DEFAULT_ROOM: &str = "general";
+/// Maximal length of a room name in characters.
+pub const MAX_ROOM_NAME: usize = 32;
+/// Key of the annotation carrying the color index of the sender's nickname.
+pub const COLOR_ANNOTATION: &str = "color";
+/// Number of the nickname colors, the color annotation is an index below it.
+pub const NICKNAME_COLORS: u8 = 12;
+/// Key of the annotation flagging a timestamp replaced by the server, the value is the clock skew of the sender like
+/// `+93s`.
+pub const TIME_ADJUSTED_ANNOTATION: &str = "time_adjusted";
+/// Key of the annotation asking the server for a [`MessageType::Ack`] of the message, removed before the message is
+/// delivered. Only a server with the `acks` capability acknowledges the messages.
+pub const ACK_ANNOTATION: &str = "ack";
+/// Nickname of the messages authored by the server, no user can claim it.
+pub const SYSTEM_NICKNAME: &str = "server";
+
+/// Returns the current Unix time in milliseconds.
+///
+/// # Example
+///
+/// ```
+/// // 2024-01-01
+/// assert!(chat::unix_millis() > 1_704_067_200_000);
+/// ```
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64)
+}
+
+/// Serialization of the messages on the wire.
+///
+/// Bincode is compact and used by the Rust client and server. JSON lines, one message per line, let quick test clients
+/// in any language, or just `nc` with `jq`, talk to the server.
+///
+/// # Example
+///
+/// ```
+/// use chat::{Message, MessageType, WireFormat};
+/// let mut msg = Message::from("nc", MessageType::text("Hello"));
+/// msg.timestamp = Some(1_718_028_207_000);
+/// let json = WireFormat::Json.serialize(&msg).unwrap();
+/// assert_eq!(
+///     String::from_utf8(json.clone()).unwrap(),
+///     r#"{"nickname":"nc","message":{"Text":"Hello"},"annotations":[],"timestamp":1718028207000,"system":false,"room":"general"}"#
+/// );
+/// assert_eq!(WireFormat::Json.deserialize(&json).unwrap(), msg);
+/// let short = br#"{"nickname":"nc","message":{"Text":"Hello"}}"#;
+/// assert_eq!(WireFormat::Json.deserialize(short).unwrap(), Message { timestamp: None, ..msg });
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum WireFormat {
+    #[default]
+    Bincode,
+    Json,
+}
+
+impl WireFormat {
+    /// Serializes the message, without the framing.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the message can't be serialized.
+    pub fn serialize(&self, message: &Message) -> Result<Vec<u8>, MessageError> {
+        Ok(match self {
+            WireFormat::Bincode => bincode::serialize(message)?,
+            WireFormat::Json => serde_json::to_vec(message)?,
+        })
+    }
+
+    /// Deserializes the message, without the framing.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the bytes aren't a valid message.
+    pub fn deserialize(&self, bytes: &[u8]) -> Result<Message, MessageError> {
+        Ok(match self {
+            WireFormat::Bincode => Message::deserialized_message(bytes)?,
+            WireFormat::Json => serde_json::from_slice(bytes)?,
+        })
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum MessageError {
     #[error("de/serialization error")]
     DeSerializationError(#[from] BincodeError),
+    #[error("invalid JSON message: {0}")]
+    JsonError(#[from] serde_json::Error),
     #[error("unexpected disconnection")]
     UnexpectedEof,
+    #[error("message of {length} bytes exceeds the limit of {max} bytes")]
+    TooLarge { length: usize, max: usize },
+    #[error("checksum {actual:08x} of the message doesn't match {expected:08x}")]
+    ChecksumMismatch { expected: u32, actual: u32 },
+    #[error("invalid compressed message: {0}")]
+    Decompression(io::Error),
     #[error(transparent)]
     IOError(#[from] io::Error),
 }
 
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Overloaded => write!(f, "server is overloaded, try it later"),
+            Self::Muted { seconds } => write!(f, "you are muted for spamming, wait {seconds} s"),
+            Self::QueueFull => write!(f, "server is full, try it later"),
+            Self::InvalidPoll => write!(
+                f,
+                "a poll needs a question and 2 to {MAX_POLL_OPTIONS} different options"
+            ),
+            Self::UnknownPoll { id } => write!(f, "there is no poll #{id}"),
+            Self::InvalidVote { id } => write!(f, "poll #{id} has no such option"),
+            Self::PollClosed { id } => write!(f, "poll #{id} is closed"),
+            Self::NotPollAuthor { id } => write!(f, "only the author can close poll #{id}"),
+            Self::TooLarge { size, max } => write!(
+                f,
+                "message of {size} bytes is bigger than the limit of {max} bytes"
+            ),
+            Self::InvalidMessage => {
+                write!(f, "server couldn't read your message, update the client")
+            }
+            Self::NotStored => write!(f, "message was delivered, but it is missing in the history"),
+            Self::Unavailable => write!(f, "server couldn't answer the request, try it later"),
+            Self::ReservedNickname => write!(f, "the nickname is reserved for the server"),
+            Self::InvalidDigest => write!(
+                f,
+                "the digest needs a valid email address first and a time of the day"
+            ),
+            Self::UnknownMessage { id } => write!(f, "there is no message #{id}"),
+            Self::InvalidReport => write!(
+                f,
+                "a report needs a reason of at most {MAX_REPORT_REASON} characters"
+            ),
+            Self::InvalidRoom => write!(
+                f,
+                "a room name has 1 to {MAX_ROOM_NAME} letters, digits, - or _"
+            ),
+            Self::NotInRoom => write!(f, "you haven't joined the room"),
+        }
+    }
+}
+
+/// Checks the room name: 1 to [`MAX_ROOM_NAME`] ASCII letters, digits, `-` or `_`.
+///
+/// # Example
+///
+/// ```
+/// assert!(chat::is_valid_room("rust-help"));
+/// assert!(!chat::is_valid_room("no spaces"));
+/// assert!(!chat::is_valid_room(""));
+/// ```
+pub fn is_valid_room(room: &str) -> bool {
+    (1..=MAX_ROOM_NAME).contains(&room.len())
+        && room
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Folds the text for case and accent insensitive matching.
+///
+/// The text is decomposed (NFKD), the combining marks are removed and the rest is lowercased.
+///
+/// # Example
+///
+/// ```
+/// assert_eq!(chat::fold("Uživatel"), "uzivatel");
+/// assert_eq!(chat::fold("ÉCOLE ﬁ"), "ecole fi");
+/// ```
+pub fn fold(text: &str) -> String {
+    text.nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Checks whether the nickname is reserved for the server, case and accent insensitive.
+///
+/// # Example
+///
+/// ```
+/// assert!(chat::is_reserved("Server"));
+/// assert!(chat::is_reserved(" sérver "));
+/// assert!(!chat::is_reserved("servers"));
+/// ```
+pub fn is_reserved(nickname: &str) -> bool {
+    fold(nickname.trim()) == SYSTEM_NICKNAME
+}
+
 impl Address {
     /// Creates a new Address with the specified hostname and port.
     ///
@@ -81,6 +626,19 @@ impl Address {
             port: PORT.to_string(),
         }
     }
+
+    /// Returns the hostname of the address.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chat::Address;
+    /// assert_eq!(Address::default().hostname(), "localhost");
+    /// ```
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
     /// Parses command-line arguments to create an Address.
     ///
     /// If the correct number of arguments is not provided, it returns a default Address.
@@ -92,7 +650,22 @@ impl Address {
     ///
     pub fn parse_arguments() -> Address {
         let arguments: Vec<String> = env::args().collect();
+        Address::from_arguments(&arguments)
+    }
 
+    /// Creates an Address from the program arguments, the program name first.
+    ///
+    /// If the arguments are not exactly the hostname and the port, it returns a default Address.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chat::Address;
+    /// let arguments = ["server".to_string(), "0.0.0.0".to_string(), "10000".to_string()];
+    /// assert_eq!(Address::from_arguments(&arguments).to_string(), "0.0.0.0:10000");
+    /// assert_eq!(Address::from_arguments(&arguments[..1]).to_string(), "localhost:11111");
+    /// ```
+    pub fn from_arguments(arguments: &[String]) -> Address {
         match arguments.len() {
             3 => Address::new(
                 arguments.get(1).unwrap_or(&HOSTNAME.into()).clone(),
@@ -172,12 +745,203 @@ impl MessageType {
         MessageType::Image(data.to_vec())
     }
 
+    /// Creates a Code type MessageType.
+    ///
+    /// # Arguments
+    ///
+    /// - `lang` - A string slice that holds the language of the code, e.g. `rust`.
+    /// - `source` - A string slice that holds the source code.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chat::MessageType;
+    /// let msg = MessageType::code("rust", "fn main() {}");
+    /// ```
+    pub fn code<S: AsRef<str>, T: AsRef<str>>(lang: S, source: T) -> Self {
+        MessageType::Code {
+            lang: lang.as_ref().into(),
+            source: source.as_ref().into(),
+        }
+    }
+
+    /// Creates a HistoryRequest type MessageType.
+    ///
+    /// # Arguments
+    ///
+    /// - `before` - Id of the oldest message already known, `None` for the latest messages.
+    /// - `limit` - Maximal number of messages in the page.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chat::MessageType;
+    /// let msg = MessageType::history_request(Some(42), 20);
+    /// ```
+    pub fn history_request(before: Option<i64>, limit: u32) -> Self {
+        MessageType::HistoryRequest { before, limit }
+    }
+
+    /// Creates a SearchRequest type MessageType.
+    ///
+    /// # Arguments
+    ///
+    /// - `query` - Text to search for, matched by [`fold`].
+    /// - `limit` - Maximal number of the returned messages.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chat::MessageType;
+    /// let msg = MessageType::search_request("uzivatel", 20);
+    /// assert_eq!(msg.get_type_and_message(), ("SearchRequest", "uzivatel".to_string()));
+    /// ```
+    pub fn search_request<S: AsRef<str>>(query: S, limit: u32) -> Self {
+        MessageType::SearchRequest {
+            query: query.as_ref().to_string(),
+            limit,
+        }
+    }
+
+    /// Creates a Poll type MessageType sent by its author.
+    ///
+    /// # Arguments
+    ///
+    /// - `question` - The question of the poll.
+    /// - `options` - The options to vote for.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chat::MessageType;
+    /// let msg = MessageType::poll("Lunch?", &["pizza", "sushi"]);
+    /// assert_eq!(msg.get_type_and_message(), ("Poll", "Lunch?".to_string()));
+    /// ```
+    pub fn poll<S: AsRef<str>>(question: S, options: &[S]) -> Self {
+        MessageType::Poll {
+            id: 0,
+            question: question.as_ref().to_string(),
+            options: options
+                .iter()
+                .map(|option| option.as_ref().to_string())
+                .collect(),
+        }
+    }
+
+    /// Creates a Vote type MessageType.
+    ///
+    /// # Arguments
+    ///
+    /// - `poll` - The id of the poll.
+    /// - `option` - The text or the number of the option, starting at 1.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chat::MessageType;
+    /// let msg = MessageType::vote(3, "sushi");
+    /// assert_eq!(msg.get_type_and_message(), ("Vote", "sushi".to_string()));
+    /// ```
+    pub fn vote<S: AsRef<str>>(poll: i64, option: S) -> Self {
+        MessageType::Vote {
+            poll,
+            option: option.as_ref().to_string(),
+        }
+    }
+
+    /// Creates a Join type MessageType.
+    ///
+    /// # Arguments
+    ///
+    /// - `room` - The name of the room, see [`is_valid_room`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chat::MessageType;
+    /// let msg = MessageType::join("rust");
+    /// assert_eq!(msg.get_type_and_message(), ("Join", "rust".to_string()));
+    /// ```
+    pub fn join<S: AsRef<str>>(room: S) -> Self {
+        MessageType::Join {
+            room: room.as_ref().to_string(),
+        }
+    }
+
+    /// Creates a Leave type MessageType.
+    ///
+    /// # Arguments
+    ///
+    /// - `room` - The name of the joined room.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chat::MessageType;
+    /// let msg = MessageType::leave("rust");
+    /// assert_eq!(msg.get_type_and_message(), ("Leave", "rust".to_string()));
+    /// ```
+    pub fn leave<S: AsRef<str>>(room: S) -> Self {
+        MessageType::Leave {
+            room: room.as_ref().to_string(),
+        }
+    }
+
+    /// Creates a Whisper type MessageType.
+    ///
+    /// # Arguments
+    ///
+    /// - `to` - The nickname of the recipient.
+    /// - `text` - The whispered text.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chat::MessageType;
+    /// let msg = MessageType::whisper("eva", "just between us");
+    /// assert_eq!(msg.get_type_and_message(), ("Whisper", "just between us".to_string()));
+    /// assert!(msg.is_no_persist());
+    /// ```
+    pub fn whisper<S: AsRef<str>, T: AsRef<str>>(to: S, text: T) -> Self {
+        MessageType::Whisper {
+            to: to.as_ref().to_string(),
+            text: text.as_ref().to_string(),
+        }
+    }
+
+    /// Creates a new Bench message with a zeroed payload.
+    ///
+    /// # Arguments
+    ///
+    /// - `id` - Id of the benchmark run, repeated in the BenchAck.
+    /// - `size` - Size of the payload in bytes.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chat::MessageType;
+    /// let msg = MessageType::bench(1, 1024);
+    /// assert_eq!(msg.get_type_and_message(), ("Bench", "1024".to_string()));
+    /// ```
+    pub fn bench(id: u32, size: usize) -> Self {
+        MessageType::Bench {
+            id,
+            payload: vec![0; size],
+        }
+    }
+
     /// Retrieves the type and message content from the MessageType enum.
     ///
     /// # Returns
     ///
-    /// A tuple where the first element is a string slice representing the type of message ("Text", "Image", or "File"),
-    /// and the second element is a String containing the message content or the file name.
+    /// A tuple where the first element is a string slice representing the type of message ("Text", "Image", "File",
+    /// "Code", "ServerError", "HistoryRequest", "History", "SearchRequest", "SearchResults", "Bench", "BenchAck",
+    /// "Sync", "SyncAck", "ServerFull", "Admitted", "Welcome", "Ping", "Pong", the poll types, the roster types,
+    /// "Digest", "PublicKey" or "Encrypted"), and the second element is a String containing the message content, the
+    /// file name, the source code, the error description, the search query, the number of messages, the payload size,
+    /// the sync id or the acknowledged message id, the queue position, the server name, the server time, the poll
+    /// question, the roster version, the digest setting, the room or the notice, empty for the keys and the encrypted
+    /// messages.
     ///
     /// # Example
     ///
@@ -193,12 +957,86 @@ impl MessageType {
             Self::Text(text) => ("Text", text.clone()),
             Self::Image(_) => ("Image", "".to_string()),
             Self::File { name, content: _ } => ("File", name.clone()),
+            Self::Attachment { name, .. } => ("Attachment", name.clone()),
+            Self::Code { lang: _, source } => ("Code", source.clone()),
+            Self::ServerError { code } => ("ServerError", code.to_string()),
+            Self::HistoryRequest { before: _, limit } => ("HistoryRequest", limit.to_string()),
+            Self::History(entries) => ("History", entries.len().to_string()),
+            Self::SearchRequest { query, limit: _ } => ("SearchRequest", query.clone()),
+            Self::SearchResults(entries) => ("SearchResults", entries.len().to_string()),
+            Self::Bench { id: _, payload } => ("Bench", payload.len().to_string()),
+            Self::BenchAck { id: _, size } => ("BenchAck", size.to_string()),
+            Self::Sync { id } => ("Sync", id.to_string()),
+            Self::SyncAck { id } => ("SyncAck", id.to_string()),
+            Self::ServerFull { position } => ("ServerFull", position.to_string()),
+            Self::Admitted => ("Admitted", "".to_string()),
+            Self::Welcome { server_name, .. } => ("Welcome", server_name.clone()),
+            Self::Ping { server_time } => ("Ping", server_time.to_string()),
+            Self::Poll { question, .. } => ("Poll", question.clone()),
+            Self::Vote { option, .. } => ("Vote", option.clone()),
+            Self::ClosePoll { poll } => ("ClosePoll", poll.to_string()),
+            Self::PollResults { question, .. } => ("PollResults", question.clone()),
+            Self::RosterRequest => ("RosterRequest", "".to_string()),
+            Self::RosterSnapshot { version, .. } => ("RosterSnapshot", version.to_string()),
+            Self::RosterDelta { version, .. } => ("RosterDelta", version.to_string()),
+            Self::Digest(setting) => ("Digest", format!("{setting:?}")),
+            Self::FileStart { name, .. } => ("FileStart", name.clone()),
+            Self::FileChunk { offset, .. } => ("FileChunk", offset.to_string()),
+            Self::FileEnd { id, .. } => ("FileEnd", id.to_string()),
+            Self::Report { reason, .. } => ("Report", reason.clone()),
+            Self::Join { room } => ("Join", room.clone()),
+            Self::Leave { room } => ("Leave", room.clone()),
+            Self::System(text) => ("System", text.clone()),
+            Self::Whisper { text, .. } => ("Whisper", text.clone()),
+            Self::Ack { message_id, .. } => ("Ack", message_id.to_string()),
+            Self::Pong { server_time } => ("Pong", server_time.to_string()),
+            Self::PublicKey { .. } => ("PublicKey", "".to_string()),
+            Self::Encrypted(_) => ("Encrypted", "".to_string()),
+        }
+    }
+
+    /// Returns the size of the attachment carried by the message.
+    ///
+    /// # Returns
+    ///
+    /// The length of the image or file content, of the chunk of a streamed file or of the encrypted message, zero for
+    /// other types of message.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chat::MessageType;
+    /// let file_data = vec![0u8; 10];
+    /// assert_eq!(MessageType::file("test.txt", &file_data).attachment_size(), 10);
+    /// assert_eq!(MessageType::text("Hello").attachment_size(), 0);
+    /// ```
+    pub fn attachment_size(&self) -> usize {
+        match self {
+            Self::Image(content) | Self::File { content, .. } => content.len(),
+            Self::FileChunk { data, .. } => data.len(),
+            Self::Encrypted(sealed) => sealed.ciphertext.len(),
+            _ => 0,
         }
     }
+
+    /// Returns true for the messages flagged no-persist, which the server delivers but never stores, neither in the
+    /// database nor in the blob store of the attachments. Only [`MessageType::Whisper`] is flagged.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chat::MessageType;
+    /// assert!(MessageType::whisper("eva", "psst").is_no_persist());
+    /// assert!(!MessageType::text("Hello").is_no_persist());
+    /// ```
+    pub fn is_no_persist(&self) -> bool {
+        matches!(self, Self::Whisper { .. })
+    }
 }
 
 impl Message {
-    /// Creates a new Message with the specified nickname and Message.
+    /// Creates a new Message with the specified nickname and Message, stamped with the current time, see
+    /// [`Message::timestamp`].
     ///
     /// # Arguments
     ///
@@ -213,14 +1051,87 @@ impl Message {
     /// let m = MessageType::text("Hello");
     /// let msg = Message::from("user", m);
     /// assert_eq!(msg.nickname, "user");
+    /// assert!(msg.timestamp.unwrap() <= chat::unix_millis());
     /// ```
     pub fn from<S: AsRef<str>>(nickname: S, message: MessageType) -> Self {
         Message {
             nickname: nickname.as_ref().into(),
             message,
+            annotations: Vec::new(),
+            timestamp: Some(unix_millis()),
+            system: false,
+            room: default_room(),
+        }
+    }
+
+    /// Creates a new Message with the specified nickname and Message in the room.
+    ///
+    /// # Arguments
+    ///
+    /// - `room` - A string slice that holds the room, see [`is_valid_room`].
+    /// - `nickaname` - A string slice that holds the nickname.
+    /// - `message` - A MessageType.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chat::{Message, MessageType};
+    /// let msg = Message::in_room("rust", "user", MessageType::text("Hello"));
+    /// assert_eq!(msg.room, "rust");
+    /// assert_eq!(Message::from("user", MessageType::text("Hello")).room, chat::DEFAULT_ROOM);
+    /// ```
+    pub fn in_room<R: AsRef<str>, S: AsRef<str>>(
+        room: R,
+        nickname: S,
+        message: MessageType,
+    ) -> Self {
+        Message {
+            room: room.as_ref().into(),
+            ..Message::from(nickname, message)
+        }
+    }
+
+    /// Creates a Message authored by the server, e.g. a notice or a reply.
+    ///
+    /// # Arguments
+    ///
+    /// - `message` - A MessageType.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chat::{Message, MessageType};
+    /// let msg = Message::system(MessageType::text("slava joined"));
+    /// assert_eq!(msg.nickname, chat::SYSTEM_NICKNAME);
+    /// assert!(msg.system);
+    /// ```
+    pub fn system(message: MessageType) -> Self {
+        Message {
+            system: true,
+            ..Message::from(SYSTEM_NICKNAME, message)
         }
     }
 
+    /// Attaches the annotation to the Message.
+    ///
+    /// # Arguments
+    ///
+    /// - `key` - Name of the annotation, e.g. `lang`.
+    /// - `value` - Value of the annotation.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chat::{Message, MessageType};
+    /// let mut msg = Message::from("user", MessageType::text("Hello"));
+    /// msg.annotate("lang", "eng");
+    /// assert_eq!(msg.annotations, vec![("lang".to_string(), "eng".to_string())]);
+    /// ```
+    pub fn annotate<S: AsRef<str>, T: AsRef<str>>(&mut self, key: S, value: T) {
+        self.annotations
+            .push((key.as_ref().to_string(), value.as_ref().to_string()));
+    }
+
     /// Send a Message over the TcpStream.
     ///
     ///
@@ -228,15 +1139,68 @@ impl Message {
     ///
     /// - `stream` - mutable TcpStream.
     ///
-    pub async fn send<T: AsyncWriteExt + Unpin>(&self, mut stream: T) -> Result<(), MessageError> {
-        let message = self.serialized_message()?;
-        let message_length = message.len() as u32;
-        let mut full_message = message_length.to_be_bytes().to_vec();
-        full_message.extend(message);
-        stream.write_all(&full_message).await?;
+    /// # Example
+    ///
+    /// ```
+    /// use chat::testing::spawn_inproc_server;
+    /// use chat::{Message, MessageType};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let server = spawn_inproc_server();
+    /// let mut slava = server.connect("slava").into_inner();
+    /// let mut eva = server.connect("eva");
+    /// let msg = Message::from("slava", MessageType::text("Hello"));
+    /// msg.send(&mut slava).await.unwrap();
+    /// assert_eq!(eva.recv().await.unwrap(), msg);
+    /// # }
+    /// ```
+    pub async fn send<T: AsyncWriteExt + Unpin>(&self, stream: T) -> Result<(), MessageError> {
+        self.send_as(stream, WireFormat::Bincode).await
+    }
+
+    /// Sends the Message framed in the wire format, see [`codec::MessageCodec`].
+    ///
+    /// # Arguments
+    ///
+    /// - `stream` - The stream to write to.
+    /// - `format` - The wire format of the connection.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the message can't be serialized or written.
+    pub async fn send_as<T: AsyncWriteExt + Unpin>(
+        &self,
+        mut stream: T,
+        format: WireFormat,
+    ) -> Result<(), MessageError> {
+        let mut frame = bytes::BytesMut::new();
+        codec::MessageCodec::default()
+            .with_format(format)
+            .encode(self, &mut frame)?;
+        stream.write_all(&frame).await?;
         Ok(())
     }
 
+    /// Serializes the Message to the frame sent over the TcpStream, the serialized message prefixed by its length.
+    ///
+    /// A frame serialized once can be written to many streams.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chat::{Message, MessageType};
+    /// let msg = Message::from("user", MessageType::text("Hello"));
+    /// let frame = msg.frame().unwrap();
+    /// let length = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+    /// assert_eq!(Message::deserialized_message(&frame[4..4 + length]).unwrap(), msg);
+    /// ```
+    pub fn frame(&self) -> Result<Vec<u8>, MessageError> {
+        let mut frame = bytes::BytesMut::new();
+        codec::MessageCodec::default().encode(self, &mut frame)?;
+        Ok(frame.to_vec())
+    }
+
     /// Read a Message from the TcpStream.
     ///
     ///
@@ -244,7 +1208,44 @@ impl Message {
     ///
     /// - `stream` - mutable TcpStream.
     ///
-    pub async fn read<T: AsyncReadExt + Unpin>(mut stream: T) -> Result<Self, MessageError> {
+    /// # Example
+    ///
+    /// ```
+    /// use chat::testing::spawn_inproc_server;
+    /// use chat::{Message, MessageType};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let server = spawn_inproc_server();
+    /// let mut slava = server.connect("slava");
+    /// let mut eva = server.connect("eva").into_inner();
+    /// slava.send(MessageType::text("Hello")).await.unwrap();
+    /// let msg = Message::read(&mut eva).await.unwrap();
+    /// assert_eq!(msg.message, MessageType::text("Hello"));
+    /// # }
+    /// ```
+    pub async fn read<T: AsyncReadExt + Unpin>(stream: T) -> Result<Self, MessageError> {
+        Message::read_limited(stream, usize::MAX).await
+    }
+
+    /// Reads a Message of at most `max` bytes from the stream.
+    ///
+    /// A bigger message is read and dropped, so the stream stays usable for the next message. A checksummed frame is
+    /// verified and a compressed one decompressed, see [`codec`].
+    ///
+    /// # Arguments
+    ///
+    /// - `stream` - The stream to read from.
+    /// - `max` - Maximal length of the serialized message in bytes.
+    ///
+    /// # Errors
+    ///
+    /// This function will return [`MessageError::TooLarge`] if the message is longer than `max`, or an error if
+    /// reading or decoding the message fails.
+    pub async fn read_limited<T: AsyncReadExt + Unpin>(
+        mut stream: T,
+        max: usize,
+    ) -> Result<Self, MessageError> {
         let mut length_bytes = [0u8; 4];
         match stream.read_exact(&mut length_bytes).await {
             Ok(_) => Ok(()),
@@ -253,10 +1254,35 @@ impl Message {
             }
             Err(err_msg) => Err(MessageError::IOError(err_msg)),
         }?;
-        let message_length = u32::from_be_bytes(length_bytes) as usize;
-        let mut buf = vec![0u8; message_length];
+        let prefix = u32::from_be_bytes(length_bytes);
+        let checksum_len = match prefix & codec::CHECKSUM_FLAG {
+            0 => 0,
+            _ => codec::CHECKSUM_LEN,
+        };
+        let message_length = (prefix & !(codec::CHECKSUM_FLAG | codec::COMPRESSED_FLAG)) as usize;
+        if message_length > max {
+            let mut rest = (&mut stream).take((checksum_len + message_length) as u64);
+            tokio::io::copy(&mut rest, &mut tokio::io::sink()).await?;
+            return Err(MessageError::TooLarge {
+                length: message_length,
+                max,
+            });
+        }
+        let mut buf = vec![0u8; checksum_len + message_length];
         stream.read_exact(&mut buf).await?;
-        Ok(Message::deserialized_message(&buf)?)
+        let (checksum, body) = buf.split_at(checksum_len);
+        if let Ok(checksum) = <[u8; codec::CHECKSUM_LEN]>::try_from(checksum) {
+            let expected = u32::from_be_bytes(checksum);
+            let actual = crc32fast::hash(body);
+            if actual != expected {
+                return Err(MessageError::ChecksumMismatch { expected, actual });
+            }
+        }
+        if prefix & codec::COMPRESSED_FLAG != 0 {
+            let message = codec::decompress(body, max)?;
+            return Ok(Message::deserialized_message(&message)?);
+        }
+        Ok(Message::deserialized_message(body)?)
     }
     /// Serializes the Message to a vector of bytes.
     ///
@@ -269,9 +1295,9 @@ impl Message {
     ///
     /// ```
     /// use chat::{Message, MessageType};
-    /// let msg = Message { nickname: "user".to_string(), message: MessageType::Text("Hello".to_string()) };
+    /// let msg = Message { nickname: "user".to_string(), message: MessageType::Text("Hello".to_string()), annotations: vec![], timestamp: None, system: false, room: "general".to_string() };
     /// let serialized_msg = msg.serialized_message().unwrap();
-    /// let msg_bytes: Vec<u8> = vec![4, 0, 0, 0, 0, 0, 0, 0, 117, 115, 101, 114, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 72, 101, 108, 108, 111];
+    /// let msg_bytes: Vec<u8> = vec![4, 0, 0, 0, 0, 0, 0, 0, 117, 115, 101, 114, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 72, 101, 108, 108, 111, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 103, 101, 110, 101, 114, 97, 108];
     /// assert_eq!(serialized_msg, msg_bytes);
     /// ```
     pub fn serialized_message(&self) -> Result<Vec<u8>, BincodeError> {
@@ -279,6 +1305,9 @@ impl Message {
     }
     /// Deserializes a vector of bytes to a Message.
     ///
+    /// A message of the protocol version 1, which has no room, is read into the [`DEFAULT_ROOM`], as is a message of
+    /// the first released clients with only the nickname and the message.
+    ///
     /// # Arguments
     ///
     /// - `input` - A byte slice that holds the serialized message.
@@ -292,13 +1321,24 @@ impl Message {
     ///
     /// ```
     /// use chat::{Message, MessageType};
-    /// let bytes: Vec<u8> = vec![4, 0, 0, 0, 0, 0, 0, 0, 117, 115, 101, 114, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 72, 101, 108, 108, 111];
+    /// let bytes: Vec<u8> = vec![4, 0, 0, 0, 0, 0, 0, 0, 117, 115, 101, 114, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 72, 101, 108, 108, 111, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
     /// let deserialized_msg = Message::deserialized_message(&bytes).unwrap();
-    /// let msg = Message { nickname: "user".to_string(), message: MessageType::Text("Hello".to_string()) };
-    /// assert_eq!(deserialized_msg.nickname, msg.nickname);
+    /// let msg = Message { nickname: "user".to_string(), message: MessageType::Text("Hello".to_string()), annotations: vec![], timestamp: None, system: false, room: "general".to_string() };
+    /// assert_eq!(deserialized_msg, msg);
     /// ```
     pub fn deserialized_message(input: &[u8]) -> Result<Message, BincodeError> {
-        bincode::deserialize(input)
+        bincode::deserialize(input).or_else(|err_msg| {
+            bincode::deserialize::<MessageV1>(input)
+                .map(MessageV1::upgrade)
+                .or_else(|_| {
+                    bincode::DefaultOptions::new()
+                        .with_fixint_encoding()
+                        .reject_trailing_bytes()
+                        .deserialize::<MessageV0>(input)
+                        .map(MessageV0::upgrade)
+                })
+                .map_err(|_| err_msg)
+        })
     }
 }
 
@@ -332,6 +1372,10 @@ mod tests {
         let msg = Message {
             nickname: "slava".to_string(),
             message: MessageType::Text("Hello".to_string()),
+            annotations: Vec::new(),
+            timestamp: None,
+            system: false,
+            room: DEFAULT_ROOM.to_string(),
         };
         assert_eq!(msg.nickname, "slava");
         match msg.message {
@@ -346,6 +1390,10 @@ mod tests {
         let msg = Message {
             nickname: "slava".to_string(),
             message: MessageType::Image(image_data.clone()),
+            annotations: Vec::new(),
+            timestamp: None,
+            system: false,
+            room: DEFAULT_ROOM.to_string(),
         };
         assert_eq!(msg.nickname, "slava");
         match msg.message {
@@ -364,6 +1412,10 @@ mod tests {
                 name: file_name.clone(),
                 content: file_content.clone(),
             },
+            annotations: Vec::new(),
+            timestamp: None,
+            system: false,
+            room: DEFAULT_ROOM.to_string(),
         };
         assert_eq!(msg.nickname, "slava");
         match msg.message {
@@ -378,14 +1430,279 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_message_attachment() {
+        let message = MessageType::Attachment {
+            name: "video.mp4".to_string(),
+            size: 50_000_000,
+            url: "/attachments/ab12?expires=1700000000&token=cd34".to_string(),
+        };
+        let msg = Message::from("slava", message);
+        let serialized = msg.serialized_message().unwrap();
+        assert_eq!(Message::deserialized_message(&serialized).unwrap(), msg);
+        assert_eq!(
+            msg.message.get_type_and_message(),
+            ("Attachment", "video.mp4".to_string())
+        );
+        assert_eq!(msg.message.attachment_size(), 0);
+    }
+
+    #[test]
+    fn test_message_code() {
+        let message = MessageType::code("rust", "fn main() {}");
+        assert_eq!(
+            message,
+            MessageType::Code {
+                lang: "rust".to_string(),
+                source: "fn main() {}".to_string(),
+            }
+        );
+        let (message_type, content) = message.get_type_and_message();
+        assert_eq!(message_type, "Code");
+        assert_eq!(content, "fn main() {}");
+    }
+
+    #[test]
+    fn test_message_history() {
+        let request = MessageType::history_request(None, 20);
+        assert_eq!(
+            request.get_type_and_message(),
+            ("HistoryRequest", "20".to_string())
+        );
+        let entry = HistoryEntry {
+            id: 1,
+            nickname: "slava".to_string(),
+            msg_type: "Text".to_string(),
+            message: "Hello".to_string(),
+            timestamp: Some(1_718_000_000_000),
+        };
+        let msg = Message::from("server", MessageType::History(vec![entry]));
+        let serialized = msg.serialized_message().unwrap();
+        assert_eq!(Message::deserialized_message(&serialized).unwrap(), msg);
+        assert_eq!(
+            msg.message.get_type_and_message(),
+            ("History", "1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_message_bench() {
+        let message = MessageType::bench(7, 16);
+        assert_eq!(message.get_type_and_message(), ("Bench", "16".to_string()));
+        assert_eq!(message.attachment_size(), 0);
+        let ack = Message::from("server", MessageType::BenchAck { id: 7, size: 16 });
+        let serialized = ack.serialized_message().unwrap();
+        assert_eq!(Message::deserialized_message(&serialized).unwrap(), ack);
+    }
+
+    #[test]
+    fn test_message_sync() {
+        let sync = Message::from("slava", MessageType::Sync { id: 3 });
+        assert_eq!(
+            sync.message.get_type_and_message(),
+            ("Sync", "3".to_string())
+        );
+        let serialized = sync.serialized_message().unwrap();
+        assert_eq!(Message::deserialized_message(&serialized).unwrap(), sync);
+    }
+
+    #[test]
+    fn test_message_roster() {
+        let change = RosterChange::Renamed {
+            from: "slava".to_string(),
+            to: "eva".to_string(),
+        };
+        let delta = Message::system(MessageType::RosterDelta { version: 7, change });
+        assert_eq!(
+            delta.message.get_type_and_message(),
+            ("RosterDelta", "7".to_string())
+        );
+        let serialized = delta.serialized_message().unwrap();
+        assert_eq!(Message::deserialized_message(&serialized).unwrap(), delta);
+    }
+
+    #[test]
+    fn test_message_digest() {
+        let digest = Message::from(
+            "slava",
+            MessageType::Digest(DigestSetting::Daily { minute: 480 }),
+        );
+        assert_eq!(
+            digest.message.get_type_and_message(),
+            ("Digest", "Daily { minute: 480 }".to_string())
+        );
+        let serialized = digest.serialized_message().unwrap();
+        assert_eq!(Message::deserialized_message(&serialized).unwrap(), digest);
+    }
+
+    #[test]
+    fn test_message_welcome() {
+        let welcome = MessageType::Welcome {
+            server_name: "Rust chat".to_string(),
+            version: "0.7.0".to_string(),
+            motd: None,
+            capabilities: vec!["history".to_string()],
+            limits: ServerLimits {
+                max_attachment: 1024,
+                max_history: 100,
+            },
+            server_time: 1_718_000_000_000,
+        };
+        assert_eq!(
+            welcome.get_type_and_message(),
+            ("Welcome", "Rust chat".to_string())
+        );
+        let msg = Message::from("server", welcome);
+        let serialized = msg.serialized_message().unwrap();
+        assert_eq!(Message::deserialized_message(&serialized).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_message_server_error() {
+        let message = MessageType::ServerError {
+            code: ErrorCode::Overloaded,
+        };
+        let (message_type, content) = message.get_type_and_message();
+        assert_eq!(message_type, "ServerError");
+        assert_eq!(content, "server is overloaded, try it later");
+        assert_eq!(message.attachment_size(), 0);
+    }
+
     #[test]
     fn test_message_serialization() {
         let msg = Message {
             nickname: "slava.".to_string(),
             message: MessageType::Text("Hello".to_string()),
+            annotations: Vec::new(),
+            timestamp: None,
+            system: false,
+            room: DEFAULT_ROOM.to_string(),
         };
         let serialized = bincode::serialize(&msg).unwrap();
         let deserialized: Message = bincode::deserialize(&serialized).unwrap();
         assert_eq!(msg, deserialized);
     }
+
+    #[test]
+    fn test_message_annotations() {
+        let mut msg = Message::from("slava", MessageType::text("Hello"));
+        assert!(msg.annotations.is_empty());
+        msg.annotate("lang", "eng");
+        msg.annotate("link", "example.com");
+        let serialized = msg.serialized_message().unwrap();
+        let deserialized = Message::deserialized_message(&serialized).unwrap();
+        assert_eq!(deserialized.annotations.len(), 2);
+        assert_eq!(deserialized, msg);
+    }
+
+    #[test]
+    fn test_message_system() {
+        let msg = Message::system(MessageType::text("slava joined"));
+        let serialized = msg.serialized_message().unwrap();
+        let deserialized = Message::deserialized_message(&serialized).unwrap();
+        assert!(deserialized.system);
+        assert!(is_reserved(&deserialized.nickname));
+        assert!(!Message::from("slava", MessageType::text("Hi")).system);
+        assert!(is_reserved("SERVER"));
+        assert!(!is_reserved("server2"));
+        assert_eq!(
+            ErrorCode::ReservedNickname.to_string(),
+            "the nickname is reserved for the server"
+        );
+    }
+
+    #[test]
+    fn test_message_room() {
+        let msg = Message::in_room("rust", "slava", MessageType::text("Hi"));
+        let serialized = msg.serialized_message().unwrap();
+        assert_eq!(Message::deserialized_message(&serialized).unwrap(), msg);
+
+        // The fields of the version 1 message, serialized the same way as the struct.
+        let annotations: Vec<(String, String)> = Vec::new();
+        let timestamp = Some(1_718_000_000_000_u64);
+        let old = bincode::serialize(&(
+            "slava",
+            MessageType::text("Hi"),
+            annotations,
+            timestamp,
+            false,
+        ))
+        .unwrap();
+        let upgraded = Message::deserialized_message(&old).unwrap();
+        assert_eq!(upgraded.room, DEFAULT_ROOM);
+        assert_eq!(upgraded.timestamp, Some(1_718_000_000_000));
+        assert!(Message::deserialized_message(&old[..old.len() - 1]).is_err());
+
+        assert_eq!(
+            MessageType::join("rust"),
+            MessageType::Join {
+                room: "rust".to_string()
+            }
+        );
+        for room in ["general", "rust_2024", "a"] {
+            assert!(is_valid_room(room), "{room}");
+        }
+        let long = "r".repeat(MAX_ROOM_NAME + 1);
+        for room in ["", "two words", "#rust", "čeština", long.as_str()] {
+            assert!(!is_valid_room(room), "{room}");
+        }
+    }
+
+    #[test]
+    fn test_first_release_frames() {
+        // The frame of the first released client, before the annotations.
+        let first: Vec<u8> = vec![
+            4, 0, 0, 0, 0, 0, 0, 0, 117, 115, 101, 114, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 72,
+            101, 108, 108, 111,
+        ];
+        let upgraded = Message::deserialized_message(&first).unwrap();
+        assert_eq!(
+            (upgraded.nickname.as_str(), &upgraded.message),
+            ("user", &MessageType::text("Hello"))
+        );
+        assert_eq!(upgraded.timestamp, None);
+        assert_eq!(upgraded.room, DEFAULT_ROOM);
+        let file = bincode::serialize(&("user", MessageType::file("a.txt", b"abc"))).unwrap();
+        let upgraded = Message::deserialized_message(&file).unwrap();
+        assert_eq!(upgraded.message, MessageType::file("a.txt", b"abc"));
+        assert!(Message::deserialized_message(&first[..first.len() - 1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_limited() {
+        let big = Message::from("slava", MessageType::file("big.bin", &[7; 1000]));
+        let small = Message::from("slava", MessageType::text("Hello"));
+        let mut frames = big.frame().unwrap();
+        frames.extend(small.frame().unwrap());
+        frames.extend([0, 0, 0, 2, 0xff, 0xff]);
+        let mut stream = frames.as_slice();
+
+        let error = Message::read_limited(&mut stream, 100).await.unwrap_err();
+        assert!(matches!(error, MessageError::TooLarge { max: 100, .. }));
+        let read = Message::read_limited(&mut stream, 100).await.unwrap();
+        assert_eq!(read, small);
+        let error = Message::read_limited(&mut stream, 100).await.unwrap_err();
+        assert!(matches!(error, MessageError::DeSerializationError(_)));
+        let error = Message::read_limited(&mut stream, 100).await.unwrap_err();
+        assert!(matches!(error, MessageError::UnexpectedEof));
+    }
+
+    #[tokio::test]
+    async fn test_read_compressed() {
+        let big = Message::from("slava", MessageType::file("big.txt", &[b'a'; 100_000]));
+        let mut codec = codec::MessageCodec::default();
+        codec.set_compression(true);
+        codec.set_checksum(true);
+        let mut frames = bytes::BytesMut::new();
+        codec.encode(&big, &mut frames).unwrap();
+        codec.encode(&big, &mut frames).unwrap();
+        let mut stream = &frames[..];
+
+        assert_eq!(Message::read(&mut stream).await.unwrap(), big);
+        let error = Message::read_limited(&mut stream, 50_000)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, MessageError::TooLarge { max: 50_000, .. }));
+        assert!(stream.is_empty());
+    }
 }
@@ -0,0 +1,199 @@
+//! Friendly reports of the common failures of the chat binaries.
+//!
+//! [`diagnose`] walks the chain of the error causes and recognizes the failures with a known fix. [`Report`] renders
+//! the error with a hint how to fix it and a link to the docs. The raw causes are shown only when the program was
+//! started with `--verbose`, see [`take_verbose`].
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::MessageError;
+
+const DOCS: &str = "https://github.com/Slavaqq/rust_hello_world/tree/main/lesson_9";
+/// Argument showing the raw causes of the errors.
+pub const VERBOSE: &str = "--verbose";
+
+static SHOW_CAUSES: AtomicBool = AtomicBool::new(false);
+
+/// Common failure with a known fix.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Failure {
+    AddressInUse,
+    DatabaseLocked,
+    ConnectionRefused,
+    PermissionDenied,
+    NoSoundDevice,
+}
+
+impl Failure {
+    /// Returns the suggested fix.
+    pub fn hint(&self) -> &'static str {
+        match self {
+            Self::AddressInUse => {
+                "another program already listens on the port, stop it or start the server on another port, \
+                 e.g. `server localhost 11112` (the metrics always listen on port 3001)"
+            }
+            Self::DatabaseLocked => {
+                "another process holds the database server.db, stop the other server or wait for the running \
+                 `maintain` command to finish"
+            }
+            Self::ConnectionRefused => {
+                "no server listens on the address, start the server first or check the hostname and the port"
+            }
+            Self::PermissionDenied => {
+                "the file or directory isn't writable, run the program from a directory you can write to \
+                 (the client saves downloads to images/ and files/, the server its database server.db)"
+            }
+            Self::NoSoundDevice => {
+                "no audio output device was found, connect one or turn the notification off with `.sound off`"
+            }
+        }
+    }
+
+    /// Returns the link to the docs of the failure.
+    pub fn docs(&self) -> String {
+        let page = match self {
+            Self::AddressInUse | Self::DatabaseLocked => "server#troubleshooting",
+            Self::ConnectionRefused | Self::PermissionDenied => "client#troubleshooting",
+            Self::NoSoundDevice => "client#notification-sound",
+        };
+        format!("{DOCS}/{page}")
+    }
+}
+
+/// Recognizes the failure in the chain of the error causes.
+///
+/// # Example
+///
+/// ```
+/// use chat::report::{diagnose, Failure};
+/// let error = std::io::Error::from(std::io::ErrorKind::AddrInUse);
+/// assert_eq!(diagnose(&error), Some(Failure::AddressInUse));
+/// ```
+pub fn diagnose(error: &(dyn Error + 'static)) -> Option<Failure> {
+    let mut current = Some(error);
+    while let Some(error) = current {
+        let io_error = match error.downcast_ref::<MessageError>() {
+            Some(MessageError::IOError(io_error)) => Some(io_error),
+            _ => error.downcast_ref::<io::Error>(),
+        };
+        let failure = match io_error.map(io::Error::kind) {
+            Some(io::ErrorKind::AddrInUse) => Some(Failure::AddressInUse),
+            Some(io::ErrorKind::ConnectionRefused) => Some(Failure::ConnectionRefused),
+            Some(io::ErrorKind::PermissionDenied) => Some(Failure::PermissionDenied),
+            _ if error.to_string().contains("database is locked") => Some(Failure::DatabaseLocked),
+            _ => None,
+        };
+        if failure.is_some() {
+            return failure;
+        }
+        current = error.source();
+    }
+    None
+}
+
+/// Removes the [`VERBOSE`] argument, the reports show the raw causes if it was present.
+///
+/// # Returns
+///
+/// True if the argument was present.
+pub fn take_verbose(arguments: &mut Vec<String>) -> bool {
+    let count = arguments.len();
+    arguments.retain(|argument| argument != VERBOSE);
+    let verbose = arguments.len() != count;
+    SHOW_CAUSES.store(verbose, Ordering::Relaxed);
+    verbose
+}
+
+/// Error rendered with the hint of its failure.
+pub struct Report<'a> {
+    error: &'a (dyn Error + 'static),
+    failure: Option<Failure>,
+}
+
+impl<'a> Report<'a> {
+    /// Creates the report of the error, recognizing its failure by [`diagnose`].
+    pub fn new(error: &'a (dyn Error + 'static)) -> Report<'a> {
+        Report {
+            error,
+            failure: diagnose(error),
+        }
+    }
+
+    /// Creates the report of the error caused by the known failure.
+    pub fn with_failure(error: &'a (dyn Error + 'static), failure: Failure) -> Report<'a> {
+        Report {
+            error,
+            failure: Some(failure),
+        }
+    }
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)?;
+        if let Some(failure) = self.failure {
+            write!(f, "\n  hint: {}", failure.hint())?;
+            write!(f, "\n  docs: {}", failure.docs())?;
+        }
+        let mut source = self.error.source();
+        if !SHOW_CAUSES.load(Ordering::Relaxed) && source.is_some() {
+            write!(f, "\n  run with {VERBOSE} to see the causes")?;
+            return Ok(());
+        }
+        while let Some(cause) = source {
+            write!(f, "\n  caused by: {cause}")?;
+            source = cause.source();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Context(&'static str, io::Error);
+
+    impl fmt::Display for Context {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl Error for Context {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(&self.1)
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let error = Context(
+            "Binding error for address: localhost:11111",
+            io::Error::new(io::ErrorKind::AddrInUse, "Address already in use"),
+        );
+        let report = Report::new(&error).to_string();
+        assert!(report
+            .starts_with("Binding error for address: localhost:11111\n  hint: another program"));
+        assert!(report.contains(
+            "docs: https://github.com/Slavaqq/rust_hello_world/tree/main/lesson_9/server"
+        ));
+        assert!(report.ends_with("run with --verbose to see the causes"));
+        let mut arguments = vec!["server".to_string(), VERBOSE.to_string()];
+        assert!(take_verbose(&mut arguments));
+        assert_eq!(arguments, ["server"]);
+        let report = Report::new(&error).to_string();
+        assert!(report.ends_with("caused by: Address already in use"));
+        assert!(!take_verbose(&mut arguments));
+
+        let error = MessageError::IOError(io::ErrorKind::ConnectionRefused.into());
+        assert_eq!(diagnose(&error), Some(Failure::ConnectionRefused));
+        let error = io::Error::other("error returned from database: (code: 5) database is locked");
+        assert_eq!(diagnose(&error), Some(Failure::DatabaseLocked));
+        assert_eq!(diagnose(&io::Error::other("other")), None);
+    }
+}
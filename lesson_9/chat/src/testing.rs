@@ -0,0 +1,191 @@
+//! In-process chat server for the examples, the doc tests and the tests of the clients.
+//!
+//! [`spawn_inproc_server`] returns a server living in the tasks of the current tokio runtime, its clients are
+//! connected by in-memory [`DuplexStream`]s instead of sockets, so nothing binds a port and the tests can run in
+//! parallel. The server relays every message of a client to all the other connected clients, framed the same way as
+//! the real server does. It has no login, rooms, history, persistence or limits, it stands in for the connection only.
+//!
+//! # Example
+//!
+//! ```
+//! use chat::testing::spawn_inproc_server;
+//! use chat::MessageType;
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let server = spawn_inproc_server();
+//! let mut slava = server.connect("slava");
+//! let mut eva = server.connect("eva");
+//! slava.send(MessageType::text("Hello")).await.unwrap();
+//! let received = eva.recv().await.unwrap();
+//! assert_eq!(received.nickname, "slava");
+//! assert_eq!(received.message, MessageType::text("Hello"));
+//! # }
+//! ```
+//!
+//! The stream of a client can be framed by [`MessageCodec`](crate::codec::MessageCodec) as any connection:
+//!
+//! ```
+//! use chat::codec::{self, MessageCodec};
+//! use chat::testing::spawn_inproc_server;
+//! use chat::{Message, MessageType};
+//! use futures_util::{SinkExt, StreamExt};
+//! use tokio_util::codec::Framed;
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let server = spawn_inproc_server();
+//! let mut bot = Framed::new(server.connect("bot").into_inner(), MessageCodec::default());
+//! let mut slava = server.connect("slava");
+//! slava.send(MessageType::text("ping")).await.unwrap();
+//! let ping = codec::flatten(bot.next().await).unwrap();
+//! assert_eq!(ping.message, MessageType::text("ping"));
+//! bot.send(&Message::from("bot", MessageType::text("pong"))).await.unwrap();
+//! assert_eq!(slava.recv().await.unwrap().message, MessageType::text("pong"));
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{DuplexStream, ReadHalf};
+use tokio::sync::mpsc;
+
+use crate::{Message, MessageError, MessageType};
+
+/// Bytes buffered in each direction of a connection.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+type Peers = Arc<Mutex<HashMap<usize, mpsc::UnboundedSender<Message>>>>;
+
+/// Handle of the in-process server, see [`spawn_inproc_server`].
+#[derive(Debug, Clone, Default)]
+pub struct InProcServer {
+    peers: Peers,
+    next_id: Arc<Mutex<usize>>,
+}
+
+/// Client connected to the [`InProcServer`].
+#[derive(Debug)]
+pub struct InProcClient {
+    nickname: String,
+    stream: DuplexStream,
+}
+
+/// Returns a chat server running in the current tokio runtime, the clients connect to it by [`InProcServer::connect`].
+pub fn spawn_inproc_server() -> InProcServer {
+    InProcServer::default()
+}
+
+impl InProcServer {
+    /// Connects a new client sending its messages as `nickname`.
+    ///
+    /// The client is disconnected when dropped.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if called outside of a tokio runtime.
+    pub fn connect(&self, nickname: &str) -> InProcClient {
+        let (client, server) = tokio::io::duplex(BUFFER_SIZE);
+        let (reader, mut writer) = tokio::io::split(server);
+        let (outbox, mut inbox) = mpsc::unbounded_channel::<Message>();
+        let id = {
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            *next_id
+        };
+        self.peers.lock().unwrap().insert(id, outbox);
+        tokio::spawn(async move {
+            while let Some(message) = inbox.recv().await {
+                if message.send(&mut writer).await.is_err() {
+                    break;
+                }
+            }
+        });
+        tokio::spawn(relay(id, reader, self.peers.clone()));
+        InProcClient {
+            nickname: nickname.to_string(),
+            stream: client,
+        }
+    }
+
+    /// Returns the number of the connected clients.
+    pub fn clients(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+}
+
+/// Relays the messages of the client to all the other clients until it disconnects.
+async fn relay(id: usize, mut reader: ReadHalf<DuplexStream>, peers: Peers) {
+    while let Ok(message) = Message::read(&mut reader).await {
+        for (peer, outbox) in peers.lock().unwrap().iter() {
+            if *peer != id {
+                let _ = outbox.send(message.clone());
+            }
+        }
+    }
+    peers.lock().unwrap().remove(&id);
+}
+
+impl InProcClient {
+    /// Sends the message as the nickname of the client.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the message can't be serialized or the server is gone.
+    pub async fn send(&mut self, message: MessageType) -> Result<(), MessageError> {
+        Message::from(&self.nickname, message)
+            .send(&mut self.stream)
+            .await
+    }
+
+    /// Waits for the next message of the other clients.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the message can't be read or decoded.
+    pub async fn recv(&mut self) -> Result<Message, MessageError> {
+        Message::read(&mut self.stream).await
+    }
+
+    /// Returns the nickname of the client.
+    pub fn nickname(&self) -> &str {
+        &self.nickname
+    }
+
+    /// Returns the stream of the client, e.g. to be framed by [`MessageCodec`](crate::codec::MessageCodec).
+    pub fn into_inner(self) -> DuplexStream {
+        self.stream
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_inproc_server() {
+        let server = spawn_inproc_server();
+        let mut slava = server.connect("slava");
+        let mut eva = server.connect("eva");
+        let adam = server.connect("adam");
+        assert_eq!(server.clients(), 3);
+
+        slava.send(MessageType::text("Hello")).await.unwrap();
+        let received = eva.recv().await.unwrap();
+        assert_eq!(
+            (received.nickname.as_str(), received.message),
+            ("slava", MessageType::text("Hello"))
+        );
+        eva.send(MessageType::text("Hi")).await.unwrap();
+        assert_eq!(slava.recv().await.unwrap().nickname, "eva");
+
+        let mut adam = adam.into_inner();
+        assert_eq!(Message::read(&mut adam).await.unwrap().nickname, "slava");
+        assert_eq!(Message::read(&mut adam).await.unwrap().nickname, "eva");
+        drop(adam);
+        while server.clients() > 2 {
+            tokio::task::yield_now().await;
+        }
+    }
+}
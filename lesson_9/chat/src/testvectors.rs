@@ -0,0 +1,519 @@
+//! Canonical frames of every message type, checked against the fixture of the protocol version.
+//!
+//! Clients and servers of different versions only understand each other while the frames stay byte for byte the same,
+//! and bincode gives no warning when a reordered field or variant changes them. [`vectors`] builds a sample of every
+//! message type, error code and roster change, [`render`] writes their frames as a fixture and [`verify`] compares a
+//! fixture with the current encoding, decoding every frame back.
+//!
+//! The fixture of [`VERSION`] is checked in as `testvectors/v<VERSION>.txt` and the tests fail on any difference. A
+//! message type or an error code added at the end keeps the existing frames, its vectors are just added to the fixture
+//! by running the tests with `UPDATE_VECTORS=1`. A deliberate change of an existing frame increments [`VERSION`] and
+//! adds the new fixture the same way, while the old one stays as the record of the previous version.
+//!
+//! Version 2 appended the room to every message. Bincode ignores the trailing bytes, so the older clients read the
+//! new frames, and [`Message::deserialized_message`] reads the frames of version 1 into the default room. The
+//! `tests/compat` suite enforces both directions against a frozen copy of the version 1 schema, a new version freezes
+//! the schema it replaces there too. The frames of the first released clients, with only the nickname and the message,
+//! are kept in `testvectors/v0.txt` and checked there against their frozen schema.
+
+use std::fmt::Write;
+
+use thiserror::Error;
+
+use crate::{
+    DigestSetting, ErrorCode, HistoryEntry, Message, MessageError, MessageType, Recipient,
+    RosterChange, Sealed, ServerLimits,
+};
+
+/// Version of the frame format described by the vectors.
+pub const VERSION: u32 = 2;
+
+/// Difference between a fixture and the current encoding.
+#[derive(Error, Debug, PartialEq)]
+pub enum VectorError {
+    #[error("line {line} is not `name hex-frame`")]
+    InvalidLine { line: usize },
+    #[error("vector {name} is missing in the fixture")]
+    Missing { name: String },
+    #[error("vector {name} of the fixture is no longer generated")]
+    Unknown { name: String },
+    #[error("frame of {name} changed from {expected} to {actual}")]
+    Changed {
+        name: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("frame of {name} doesn't decode back to the message: {reason}")]
+    Undecodable { name: String, reason: String },
+}
+
+/// Builds the named sample messages, at least one of every message type, error code, roster change and digest
+/// setting.
+pub fn vectors() -> Vec<(String, Message)> {
+    let entry = HistoryEntry {
+        id: 42,
+        nickname: "eva".to_string(),
+        msg_type: "Text".to_string(),
+        message: "Ahoj".to_string(),
+        timestamp: Some(1_704_067_200_000),
+    };
+    let samples = vec![
+        ("Text", MessageType::text("Hello")),
+        ("Image", MessageType::image(&[0x89, 0x50, 0x4e, 0x47])),
+        ("File", MessageType::file("a.txt", b"abc")),
+        (
+            "Attachment",
+            MessageType::Attachment {
+                name: "big.bin".to_string(),
+                size: 1 << 32,
+                url: "/attachments/7?token=x".to_string(),
+            },
+        ),
+        ("Code", MessageType::code("rust", "fn main() {}")),
+        ("HistoryRequest", MessageType::history_request(Some(7), 20)),
+        (
+            "HistoryRequest.latest",
+            MessageType::history_request(None, 20),
+        ),
+        ("History", MessageType::History(vec![entry.clone()])),
+        ("SearchRequest", MessageType::search_request("ahoj", 20)),
+        ("SearchResults", MessageType::SearchResults(vec![entry])),
+        ("Bench", MessageType::bench(3, 4)),
+        ("BenchAck", MessageType::BenchAck { id: 3, size: 4 }),
+        ("Sync", MessageType::Sync { id: 5 }),
+        ("SyncAck", MessageType::SyncAck { id: 5 }),
+        ("ServerFull", MessageType::ServerFull { position: 2 }),
+        ("Admitted", MessageType::Admitted),
+        (
+            "Welcome",
+            MessageType::Welcome {
+                server_name: "lesson 9".to_string(),
+                version: "0.7.0".to_string(),
+                motd: Some("Be nice".to_string()),
+                capabilities: vec!["history".to_string(), "roster".to_string()],
+                limits: ServerLimits {
+                    max_attachment: 10 << 20,
+                    max_history: 100,
+                },
+                server_time: 1_704_067_200_000,
+            },
+        ),
+        (
+            "Ping",
+            MessageType::Ping {
+                server_time: 1_704_067_200_000,
+            },
+        ),
+        ("Poll", MessageType::poll("Lunch?", &["pizza", "sushi"])),
+        ("Vote", MessageType::vote(9, "sushi")),
+        ("ClosePoll", MessageType::ClosePoll { poll: 9 }),
+        (
+            "PollResults",
+            MessageType::PollResults {
+                id: 9,
+                question: "Lunch?".to_string(),
+                votes: vec![("pizza".to_string(), 1), ("sushi".to_string(), 2)],
+                closed: true,
+            },
+        ),
+        ("RosterRequest", MessageType::RosterRequest),
+        (
+            "RosterSnapshot",
+            MessageType::RosterSnapshot {
+                version: 4,
+                users: vec!["eva".to_string(), "slava".to_string()],
+            },
+        ),
+        (
+            "FileStart",
+            MessageType::FileStart {
+                id: 2,
+                name: "big.bin".to_string(),
+                size: 1 << 32,
+            },
+        ),
+        (
+            "FileChunk",
+            MessageType::FileChunk {
+                id: 2,
+                offset: 65_536,
+                data: vec![1, 2, 3],
+            },
+        ),
+        (
+            "FileEnd",
+            MessageType::FileEnd {
+                id: 2,
+                checksum: 0xcbf4_3926,
+            },
+        ),
+        (
+            "Report",
+            MessageType::Report {
+                message: 42,
+                reason: "spam".to_string(),
+            },
+        ),
+        ("Join", MessageType::join("rust")),
+        ("Leave", MessageType::leave("rust")),
+    ];
+    let codes = [
+        ("Overloaded", ErrorCode::Overloaded),
+        ("Muted", ErrorCode::Muted { seconds: 30 }),
+        ("QueueFull", ErrorCode::QueueFull),
+        ("InvalidPoll", ErrorCode::InvalidPoll),
+        ("UnknownPoll", ErrorCode::UnknownPoll { id: 9 }),
+        ("InvalidVote", ErrorCode::InvalidVote { id: 9 }),
+        ("PollClosed", ErrorCode::PollClosed { id: 9 }),
+        ("NotPollAuthor", ErrorCode::NotPollAuthor { id: 9 }),
+        ("TooLarge", ErrorCode::TooLarge { size: 20, max: 10 }),
+        ("InvalidMessage", ErrorCode::InvalidMessage),
+        ("NotStored", ErrorCode::NotStored),
+        ("Unavailable", ErrorCode::Unavailable),
+        ("ReservedNickname", ErrorCode::ReservedNickname),
+        ("InvalidDigest", ErrorCode::InvalidDigest),
+        ("UnknownMessage", ErrorCode::UnknownMessage { id: 42 }),
+        ("InvalidReport", ErrorCode::InvalidReport),
+        ("InvalidRoom", ErrorCode::InvalidRoom),
+        ("NotInRoom", ErrorCode::NotInRoom),
+    ];
+    let changes = [
+        ("Joined", RosterChange::Joined("eva".to_string())),
+        ("Left", RosterChange::Left("eva".to_string())),
+        (
+            "Renamed",
+            RosterChange::Renamed {
+                from: "eva".to_string(),
+                to: "ana".to_string(),
+            },
+        ),
+    ];
+    let settings = [
+        (
+            "Email",
+            DigestSetting::Email("slava@example.com".to_string()),
+        ),
+        ("Daily", DigestSetting::Daily { minute: 480 }),
+        ("Off", DigestSetting::Off),
+    ];
+    let mut vectors: Vec<(String, Message)> = samples
+        .into_iter()
+        .map(|(name, message)| (name.to_string(), unstamped(Message::from("slava", message))))
+        .collect();
+    for (name, code) in codes {
+        let message = unstamped(Message::system(MessageType::ServerError { code }));
+        vectors.push((format!("ServerError.{name}"), message));
+    }
+    for (name, change) in changes {
+        let message = unstamped(Message::system(MessageType::RosterDelta {
+            version: 5,
+            change,
+        }));
+        vectors.push((format!("RosterDelta.{name}"), message));
+    }
+    for (name, setting) in settings {
+        let message = unstamped(Message::from("slava", MessageType::Digest(setting)));
+        vectors.push((format!("Digest.{name}"), message));
+    }
+    let mut annotated = Message::from("slava", MessageType::text("Ahoj"));
+    annotated.annotate("color", "3");
+    annotated.timestamp = Some(1_704_067_200_000);
+    vectors.push(("Text.annotated".to_string(), annotated));
+    let in_room = unstamped(Message::in_room("rust", "slava", MessageType::text("Ahoj")));
+    vectors.push(("Text.room".to_string(), in_room));
+    let notice = unstamped(Message::system(MessageType::System(
+        "eva left the chat".to_string(),
+    )));
+    vectors.push(("System".to_string(), notice));
+    let whisper = unstamped(Message::from("slava", MessageType::whisper("eva", "psst")));
+    vectors.push(("Whisper".to_string(), whisper));
+    let ack = MessageType::Ack {
+        message_id: 42,
+        sent_at: 1_704_067_200_000,
+    };
+    vectors.push(("Ack".to_string(), unstamped(Message::system(ack))));
+    let pong = MessageType::Pong {
+        server_time: 1_704_067_200_000,
+    };
+    vectors.push(("Pong".to_string(), unstamped(Message::from("slava", pong))));
+    let public_key = MessageType::PublicKey { key: [7; 32] };
+    vectors.push((
+        "PublicKey".to_string(),
+        unstamped(Message::from("slava", public_key)),
+    ));
+    let sealed = Sealed {
+        sender_key: [7; 32],
+        recipients: vec![Recipient {
+            key: [9; 32],
+            wrapped_key: vec![1, 2, 3],
+        }],
+        nonce: [5; 12],
+        ciphertext: vec![4, 5, 6],
+    };
+    let encrypted = MessageType::Encrypted(sealed);
+    vectors.push((
+        "Encrypted".to_string(),
+        unstamped(Message::from("slava", encrypted)),
+    ));
+    vectors
+}
+
+/// Returns the message without the time of building the vectors, so its frame stays the same.
+fn unstamped(message: Message) -> Message {
+    Message {
+        timestamp: None,
+        ..message
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Renders the fixture of the vectors, a line `name hex-frame` for every vector.
+///
+/// # Errors
+///
+/// This function will return an error if a message can't be serialized.
+pub fn render(vectors: &[(String, Message)]) -> Result<String, MessageError> {
+    let mut fixture =
+        format!("# Frames of the chat protocol version {VERSION}: name, then the frame in hex.\n");
+    for (name, message) in vectors {
+        fixture.push_str(&format!("{name} {}\n", to_hex(&message.frame()?)));
+    }
+    Ok(fixture)
+}
+
+/// Parses the fixture into the named frames, skipping the empty lines and the `#` comments.
+///
+/// # Errors
+///
+/// This function will return [`VectorError::InvalidLine`] for a line without a name and a valid hex frame.
+pub fn parse(fixture: &str) -> Result<Vec<(String, Vec<u8>)>, VectorError> {
+    let mut frames = Vec::new();
+    for (index, line) in fixture.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let frame = line
+            .split_once(' ')
+            .and_then(|(name, hex)| Some((name.to_string(), from_hex(hex.trim())?)))
+            .ok_or(VectorError::InvalidLine { line: index + 1 })?;
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+/// Decodes the frame, checking the length prefix.
+fn decode(frame: &[u8]) -> Result<Message, String> {
+    let (length, message) = frame
+        .split_first_chunk::<4>()
+        .ok_or("the frame has no length prefix")?;
+    if u32::from_be_bytes(*length) as usize != message.len() {
+        return Err("the length prefix doesn't match the frame".to_string());
+    }
+    Message::deserialized_message(message).map_err(|err_msg| err_msg.to_string())
+}
+
+/// Compares the fixture with the frames of the current [`vectors`].
+///
+/// # Returns
+///
+/// Every difference found, empty if the fixture matches the current encoding and every frame decodes back to its
+/// message.
+pub fn verify(fixture: &str) -> Vec<VectorError> {
+    let frames = match parse(fixture) {
+        Ok(frames) => frames,
+        Err(error) => return vec![error],
+    };
+    let vectors = vectors();
+    let mut errors = Vec::new();
+    for (name, message) in &vectors {
+        let Some((_, expected)) = frames.iter().find(|(frame_name, _)| frame_name == name) else {
+            errors.push(VectorError::Missing { name: name.clone() });
+            continue;
+        };
+        let actual = message.frame().unwrap_or_default();
+        if &actual != expected {
+            errors.push(VectorError::Changed {
+                name: name.clone(),
+                expected: to_hex(expected),
+                actual: to_hex(&actual),
+            });
+        }
+        match decode(expected) {
+            Ok(decoded) if &decoded == message => (),
+            Ok(decoded) => errors.push(VectorError::Undecodable {
+                name: name.clone(),
+                reason: format!("decoded {decoded:?}"),
+            }),
+            Err(reason) => errors.push(VectorError::Undecodable {
+                name: name.clone(),
+                reason,
+            }),
+        }
+    }
+    for (name, _) in &frames {
+        if !vectors.iter().any(|(vector_name, _)| vector_name == name) {
+            errors.push(VectorError::Unknown { name: name.clone() });
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Number of the variants of [`MessageType`], see [`variant`].
+    const VARIANTS: usize = 38;
+
+    /// Returns the index of the message type below [`VARIANTS`].
+    ///
+    /// The match has no wildcard, so a new message type doesn't compile until it is counted here and gets a vector.
+    fn variant(message: &MessageType) -> usize {
+        match message {
+            MessageType::Text(_) => 0,
+            MessageType::Image(_) => 1,
+            MessageType::File { .. } => 2,
+            MessageType::Attachment { .. } => 3,
+            MessageType::Code { .. } => 4,
+            MessageType::ServerError { .. } => 5,
+            MessageType::HistoryRequest { .. } => 6,
+            MessageType::History(_) => 7,
+            MessageType::SearchRequest { .. } => 8,
+            MessageType::SearchResults(_) => 9,
+            MessageType::Bench { .. } => 10,
+            MessageType::BenchAck { .. } => 11,
+            MessageType::Sync { .. } => 12,
+            MessageType::SyncAck { .. } => 13,
+            MessageType::ServerFull { .. } => 14,
+            MessageType::Admitted => 15,
+            MessageType::Welcome { .. } => 16,
+            MessageType::Ping { .. } => 17,
+            MessageType::Poll { .. } => 18,
+            MessageType::Vote { .. } => 19,
+            MessageType::ClosePoll { .. } => 20,
+            MessageType::PollResults { .. } => 21,
+            MessageType::RosterRequest => 22,
+            MessageType::RosterSnapshot { .. } => 23,
+            MessageType::RosterDelta { .. } => 24,
+            MessageType::Digest(_) => 25,
+            MessageType::FileStart { .. } => 26,
+            MessageType::FileChunk { .. } => 27,
+            MessageType::FileEnd { .. } => 28,
+            MessageType::Report { .. } => 29,
+            MessageType::Join { .. } => 30,
+            MessageType::Leave { .. } => 31,
+            MessageType::System(_) => 32,
+            MessageType::Whisper { .. } => 33,
+            MessageType::Ack { .. } => 34,
+            MessageType::Pong { .. } => 35,
+            MessageType::PublicKey { .. } => 36,
+            MessageType::Encrypted(_) => 37,
+        }
+    }
+
+    fn fixture_path() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(format!("testvectors/v{VERSION}.txt"))
+    }
+
+    #[test]
+    fn test_vectors_cover_all_types() {
+        let vectors = vectors();
+        let mut covered = [false; VARIANTS];
+        for (_, message) in &vectors {
+            covered[variant(&message.message)] = true;
+        }
+        let missing: Vec<usize> = (0..VARIANTS).filter(|&index| !covered[index]).collect();
+        assert!(
+            missing.is_empty(),
+            "message types {missing:?} have no vector"
+        );
+        for (index, (name, _)) in vectors.iter().enumerate() {
+            assert!(!name.contains(' '), "{name}");
+            assert!(
+                vectors[..index].iter().all(|(other, _)| other != name),
+                "duplicate vector {name}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_fixture() {
+        let path = fixture_path();
+        if std::env::var_os("UPDATE_VECTORS").is_some() {
+            std::fs::write(&path, render(&vectors()).unwrap()).unwrap();
+        }
+        let fixture = std::fs::read_to_string(&path).unwrap();
+        let errors = verify(&fixture);
+        assert!(
+            errors.is_empty(),
+            "the frames of protocol version {VERSION} changed, if it is deliberate regenerate the fixture with \
+             UPDATE_VECTORS=1, incrementing testvectors::VERSION first if an existing frame changed:\n{}",
+            errors.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n")
+        );
+    }
+
+    #[test]
+    fn test_previous_fixture() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join(format!("testvectors/v{}.txt", VERSION - 1));
+        let fixture = std::fs::read_to_string(path).unwrap();
+        let vectors = vectors();
+        for (name, frame) in parse(&fixture).unwrap() {
+            let (_, message) = vectors
+                .iter()
+                .find(|(vector_name, _)| *vector_name == name)
+                .unwrap();
+            assert_eq!(decode(&frame).as_ref(), Ok(message), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let fixture = render(&vectors()).unwrap();
+        assert_eq!(verify(&fixture), []);
+        assert_eq!(
+            parse("# comment\n\nText 0a0b").unwrap(),
+            [("Text".to_string(), vec![10, 11])]
+        );
+        assert_eq!(
+            parse("Text 0a0").unwrap_err(),
+            VectorError::InvalidLine { line: 1 }
+        );
+
+        let changed = fixture.replacen("Admitted 00", "Admitted 01", 1);
+        let errors = verify(&changed);
+        assert!(
+            matches!(errors.as_slice(), [VectorError::Changed { name, .. }, _] if name == "Admitted")
+        );
+        let renamed = fixture.replacen("\nSync ", "\nSynchronize ", 1);
+        assert_eq!(
+            verify(&renamed),
+            [
+                VectorError::Missing {
+                    name: "Sync".into()
+                },
+                VectorError::Unknown {
+                    name: "Synchronize".into()
+                }
+            ]
+        );
+    }
+}
@@ -0,0 +1,97 @@
+//! Local time of the message timestamps, see [`Message::timestamp`](crate::Message::timestamp).
+//!
+//! The timestamps are Unix times in milliseconds, the receiving end renders them in its time zone. The dates are
+//! converted by the rules of the time zone, so a daylight saving time change doesn't shift them.
+
+use jiff::tz::TimeZone;
+use jiff::Timestamp;
+
+/// Milliseconds of a minute.
+pub const MINUTE: i64 = 60 * 1000;
+/// Milliseconds of an hour.
+pub const HOUR: i64 = 60 * MINUTE;
+/// Milliseconds of a day.
+pub const DAY: i64 = 24 * HOUR;
+/// Format of the date and the time of a message, e.g. `2024-06-10 14:03`.
+pub const DATE_FORMAT: &str = "%Y-%m-%d %H:%M";
+
+/// Formats the Unix time in milliseconds in the time zone by the `strftime` format, `?` for a time out of range.
+///
+/// # Example
+///
+/// ```
+/// use chat::time::format_time;
+/// use jiff::tz::TimeZone;
+/// assert_eq!(format_time(1_718_028_207_000, &TimeZone::UTC, "%H:%M:%S"), "14:03:27");
+/// ```
+pub fn format_time(millis: i64, time_zone: &TimeZone, format: &str) -> String {
+    match Timestamp::from_millisecond(millis) {
+        Ok(timestamp) => timestamp
+            .to_zoned(time_zone.clone())
+            .strftime(format)
+            .to_string(),
+        Err(_) => "?".to_string(),
+    }
+}
+
+/// Formats the timestamp of a message in the local time zone by the `strftime` format.
+///
+/// # Example
+///
+/// ```
+/// use chat::{Message, MessageType};
+/// let msg = Message::from("user", MessageType::text("Hello"));
+/// let time = chat::time::format_local(msg.timestamp.unwrap(), chat::time::DATE_FORMAT);
+/// assert_eq!(time.len(), "2024-06-10 14:03".len());
+/// ```
+pub fn format_local(timestamp: u64, format: &str) -> String {
+    format_time(timestamp as i64, &TimeZone::system(), format)
+}
+
+/// Renders the age of the time at `now`, e.g. `5 min ago`, the date and the time of the ones older than a day.
+pub fn format_age(millis: i64, now: i64, time_zone: &TimeZone) -> String {
+    // A time slightly in the future is a rest of the skew, not a message from the future.
+    match now - millis {
+        ..MINUTE => "just now".to_string(),
+        age @ ..HOUR => format!("{} min ago", age / MINUTE),
+        age @ ..DAY => format!("{} h ago", age / HOUR),
+        _ => format_time(millis, time_zone, DATE_FORMAT),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_time() {
+        let millis = 1_718_028_207_000;
+        assert_eq!(format_time(millis, &TimeZone::UTC, "%H:%M:%S"), "14:03:27");
+        let prague = TimeZone::fixed(jiff::tz::offset(2));
+        assert_eq!(
+            format_time(millis, &prague, DATE_FORMAT),
+            "2024-06-10 16:03"
+        );
+        assert_eq!(format_time(i64::MAX, &TimeZone::UTC, "%H:%M"), "?");
+
+        // 2024-03-31 00:30 UTC, the clocks in Prague move from 02:00 CET to 03:00 CEST in the next hour.
+        let before = 1_711_845_000_000;
+        let prague = TimeZone::posix("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        assert_eq!(format_time(before, &prague, "%H:%M %Z"), "01:30 CET");
+        assert_eq!(
+            format_time(before + HOUR, &prague, "%H:%M %Z"),
+            "03:30 CEST"
+        );
+    }
+
+    #[test]
+    fn test_format_age() {
+        let now = 1_718_028_207_000;
+        let utc = TimeZone::UTC;
+        assert_eq!(format_age(now - 59_000, now, &utc), "just now");
+        assert_eq!(format_age(now + 1_500, now, &utc), "just now");
+        assert_eq!(format_age(now - 5 * MINUTE, now, &utc), "5 min ago");
+        assert_eq!(format_age(now - 3 * HOUR - 1, now, &utc), "3 h ago");
+        assert_eq!(format_age(now - DAY, now, &utc), "2024-06-09 14:03");
+    }
+}
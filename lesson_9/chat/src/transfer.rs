@@ -0,0 +1,368 @@
+//! Files streamed in chunks.
+//!
+//! A file sent as a single [`MessageType::File`] is read, serialized and buffered whole by the sender, the server and
+//! every receiver. A streamed file is announced by [`MessageType::FileStart`] with its name and size, its content
+//! follows in [`MessageType::FileChunk`]s of [`CHUNK_SIZE`] bytes and [`MessageType::FileEnd`] closes it with the CRC32
+//! of the content. Nobody holds more than a chunk in memory and the progress is known after every chunk.
+//!
+//! [`Chunks`] reads a file and produces the messages, [`Transfers`] writes the received chunks of the senders to their
+//! sinks, e.g. files, and checks that nothing was lost on the way.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use thiserror::Error;
+
+use crate::MessageType;
+
+/// Number of the file bytes in a chunk.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Problem of a received transfer.
+#[derive(Error, Debug)]
+pub enum TransferError {
+    #[error("no transfer {id} was started")]
+    Unknown { id: u32 },
+    #[error("chunk at {offset} came instead of the one at {expected}")]
+    OutOfOrder { offset: u64, expected: u64 },
+    #[error("the chunks are longer than the announced {size} bytes")]
+    TooLong { size: u64 },
+    #[error("only {received} of {size} bytes were received")]
+    Incomplete { received: u64, size: u64 },
+    #[error("checksum {actual:08x} of the received file differs from {expected:08x}")]
+    ChecksumMismatch { expected: u32, actual: u32 },
+    #[error("writing the file failed: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Messages streaming the content of a reader, [`MessageType::FileStart`] first and [`MessageType::FileEnd`] last.
+///
+/// # Example
+///
+/// ```
+/// use chat::transfer::{Chunks, Transfers};
+/// use chat::MessageType;
+///
+/// let content = vec![7; 100_000];
+/// let mut transfers = Transfers::default();
+/// for message in Chunks::new(1, "data.bin", content.len() as u64, &content[..]) {
+///     match message.unwrap() {
+///         MessageType::FileStart { id, name, size } => transfers.start("slava", id, &name, size, Vec::new()),
+///         MessageType::FileChunk { id, offset, data } => {
+///             transfers.chunk("slava", id, offset, &data).unwrap();
+///         }
+///         MessageType::FileEnd { id, checksum } => {
+///             let received = transfers.finish("slava", id, checksum).unwrap();
+///             assert_eq!(received.sink, content);
+///         }
+///         _ => unreachable!(),
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Chunks<R> {
+    id: u32,
+    start: Option<MessageType>,
+    reader: io::Take<R>,
+    size: u64,
+    sent: u64,
+    chunk_size: usize,
+    hasher: crc32fast::Hasher,
+    done: bool,
+}
+
+impl<R: Read> Chunks<R> {
+    /// Streams `size` bytes of the reader as the file `name`.
+    pub fn new(id: u32, name: &str, size: u64, reader: R) -> Chunks<R> {
+        let start = MessageType::FileStart {
+            id,
+            name: name.to_string(),
+            size,
+        };
+        Chunks {
+            id,
+            start: Some(start),
+            reader: reader.take(size),
+            size,
+            sent: 0,
+            chunk_size: CHUNK_SIZE,
+            hasher: crc32fast::Hasher::new(),
+            done: false,
+        }
+    }
+
+    /// Changes the number of the file bytes in a chunk from [`CHUNK_SIZE`].
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Chunks<R> {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Returns the number of the bytes read into the chunks so far and the size of the file.
+    pub fn progress(&self) -> (u64, u64) {
+        (self.sent, self.size)
+    }
+
+    fn next_chunk(&mut self) -> io::Result<MessageType> {
+        let mut data = Vec::with_capacity(self.chunk_size);
+        (&mut self.reader)
+            .take(self.chunk_size as u64)
+            .read_to_end(&mut data)?;
+        if data.is_empty() {
+            self.done = true;
+            if self.sent < self.size {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("the file ended after {} of {} bytes", self.sent, self.size),
+                ));
+            }
+            let checksum = self.hasher.clone().finalize();
+            return Ok(MessageType::FileEnd {
+                id: self.id,
+                checksum,
+            });
+        }
+        self.hasher.update(&data);
+        let offset = self.sent;
+        self.sent += data.len() as u64;
+        Ok(MessageType::FileChunk {
+            id: self.id,
+            offset,
+            data,
+        })
+    }
+}
+
+impl<R: Read> Iterator for Chunks<R> {
+    type Item = io::Result<MessageType>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(start) = self.start.take() {
+            return Some(Ok(start));
+        }
+        if self.done {
+            return None;
+        }
+        let chunk = self.next_chunk();
+        if chunk.is_err() {
+            self.done = true;
+        }
+        Some(chunk)
+    }
+}
+
+/// File being received.
+#[derive(Debug)]
+pub struct Incoming<W> {
+    pub name: String,
+    pub size: u64,
+    pub received: u64,
+    pub sink: W,
+    hasher: crc32fast::Hasher,
+}
+
+/// Files being received from the senders, keyed by the sender's nickname and the transfer id.
+#[derive(Debug)]
+pub struct Transfers<W> {
+    incoming: HashMap<(String, u32), Incoming<W>>,
+}
+
+impl<W> Default for Transfers<W> {
+    fn default() -> Self {
+        Transfers {
+            incoming: HashMap::new(),
+        }
+    }
+}
+
+impl<W: Write> Transfers<W> {
+    /// Starts receiving the file into the sink, replacing an unfinished transfer of the same id.
+    pub fn start(&mut self, sender: &str, id: u32, name: &str, size: u64, sink: W) {
+        let incoming = Incoming {
+            name: name.to_string(),
+            size,
+            received: 0,
+            sink,
+            hasher: crc32fast::Hasher::new(),
+        };
+        self.incoming.insert((sender.to_string(), id), incoming);
+    }
+
+    /// Writes the chunk to the sink of the transfer.
+    ///
+    /// # Returns
+    ///
+    /// The transfer with the number of the bytes received so far.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the transfer is unknown, the chunk doesn't continue the received part,
+    /// exceeds the announced size or can't be written. The failed transfer is dropped.
+    pub fn chunk(
+        &mut self,
+        sender: &str,
+        id: u32,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<&Incoming<W>, TransferError> {
+        let key = (sender.to_string(), id);
+        let incoming = self
+            .incoming
+            .get_mut(&key)
+            .ok_or(TransferError::Unknown { id })?;
+        if let Err(err_msg) = incoming.write(offset, data) {
+            self.incoming.remove(&key);
+            return Err(err_msg);
+        }
+        Ok(&self.incoming[&key])
+    }
+
+    /// Finishes the transfer, checking that the whole file was received unchanged.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the transfer is unknown, incomplete, its checksum differs or the sink
+    /// can't be flushed.
+    pub fn finish(
+        &mut self,
+        sender: &str,
+        id: u32,
+        checksum: u32,
+    ) -> Result<Incoming<W>, TransferError> {
+        let mut incoming = self
+            .incoming
+            .remove(&(sender.to_string(), id))
+            .ok_or(TransferError::Unknown { id })?;
+        if incoming.received != incoming.size {
+            return Err(TransferError::Incomplete {
+                received: incoming.received,
+                size: incoming.size,
+            });
+        }
+        let actual = incoming.hasher.clone().finalize();
+        if actual != checksum {
+            return Err(TransferError::ChecksumMismatch {
+                expected: checksum,
+                actual,
+            });
+        }
+        incoming.sink.flush()?;
+        Ok(incoming)
+    }
+
+    /// Drops the unfinished transfers of the sender, e.g. when it disconnected.
+    ///
+    /// # Returns
+    ///
+    /// The dropped transfers, so their partial files can be removed.
+    pub fn abandon(&mut self, sender: &str) -> Vec<Incoming<W>> {
+        let keys: Vec<(String, u32)> = self
+            .incoming
+            .keys()
+            .filter(|(nickname, _)| nickname == sender)
+            .cloned()
+            .collect();
+        keys.iter()
+            .filter_map(|key| self.incoming.remove(key))
+            .collect()
+    }
+}
+
+impl<W: Write> Incoming<W> {
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), TransferError> {
+        if offset != self.received {
+            return Err(TransferError::OutOfOrder {
+                offset,
+                expected: self.received,
+            });
+        }
+        if self.received + data.len() as u64 > self.size {
+            return Err(TransferError::TooLong { size: self.size });
+        }
+        self.sink.write_all(data)?;
+        self.hasher.update(data);
+        self.received += data.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(content: &[u8], chunk_size: usize) -> Vec<MessageType> {
+        Chunks::new(3, "a.bin", content.len() as u64, content)
+            .with_chunk_size(chunk_size)
+            .collect::<io::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_chunks() {
+        let content: Vec<u8> = (0..250).collect();
+        let messages = stream(&content, 100);
+        assert_eq!(messages.len(), 5);
+        assert_eq!(
+            messages[0],
+            MessageType::FileStart {
+                id: 3,
+                name: "a.bin".to_string(),
+                size: 250
+            }
+        );
+        let MessageType::FileChunk { offset, data, .. } = &messages[3] else {
+            panic!("expected a chunk, found {:?}", messages[3]);
+        };
+        assert_eq!((*offset, data.len()), (200, 50));
+        let checksum = crc32fast::hash(&content);
+        assert_eq!(messages[4], MessageType::FileEnd { id: 3, checksum });
+
+        let empty = stream(&[], 100);
+        assert!(matches!(empty[1], MessageType::FileEnd { .. }));
+        let mut short = Chunks::new(1, "b.bin", 10, &[1, 2, 3][..]);
+        let (_, chunk) = (short.next(), short.next());
+        assert_eq!(short.progress(), (3, 10));
+        assert!(chunk.unwrap().is_ok());
+        assert!(short.next().unwrap().is_err());
+        assert!(short.next().is_none());
+    }
+
+    #[test]
+    fn test_transfers() {
+        let content: Vec<u8> = (0..250).collect();
+        let checksum = crc32fast::hash(&content);
+        let mut transfers = Transfers::default();
+        transfers.start("slava", 3, "a.bin", 250, Vec::new());
+        transfers.start("eva", 3, "b.bin", 10, Vec::new());
+        let incoming = transfers.chunk("slava", 3, 0, &content[..200]).unwrap();
+        assert_eq!((incoming.received, incoming.size), (200, 250));
+        assert!(matches!(
+            transfers.finish("slava", 3, checksum),
+            Err(TransferError::Incomplete { received: 200, .. })
+        ));
+
+        transfers.start("slava", 3, "a.bin", 250, Vec::new());
+        transfers.chunk("slava", 3, 0, &content[..200]).unwrap();
+        transfers.chunk("slava", 3, 200, &content[200..]).unwrap();
+        assert!(matches!(
+            transfers.finish("slava", 3, checksum + 1),
+            Err(TransferError::ChecksumMismatch { .. })
+        ));
+
+        transfers.start("slava", 3, "a.bin", 250, Vec::new());
+        assert!(matches!(
+            transfers.chunk("slava", 3, 100, &content[100..]),
+            Err(TransferError::OutOfOrder { expected: 0, .. })
+        ));
+        assert!(matches!(
+            transfers.chunk("slava", 3, 0, &content),
+            Err(TransferError::Unknown { id: 3 })
+        ));
+        assert!(matches!(
+            transfers.chunk("eva", 3, 0, &content),
+            Err(TransferError::TooLong { size: 10 })
+        ));
+        transfers.start("slava", 4, "c.bin", 1, Vec::new());
+        assert_eq!(transfers.abandon("slava").len(), 1);
+        assert!(transfers.incoming.is_empty());
+    }
+}
@@ -0,0 +1,199 @@
+//! Compatibility of the message schema between the protocol versions.
+//!
+//! The schema of the previous protocol version is kept as a frozen copy, [`v1`], and the tests encode the messages by
+//! one schema and decode them by the other in the directions the protocol promises:
+//!
+//! - the current peers read the bincode frames and the JSON lines of the version 1 peers into the default room,
+//! - the version 1 peers read the current frames and JSON lines of the message types they know, ignoring the room, and
+//!   fail on the new ones instead of decoding them as something else.
+//!
+//! The schema of the first released clients, [`v0`], with only the nickname and the message, is kept the same way with
+//! its fixture `testvectors/v0.txt`, the current peers read its frames and it reads the current texts, images and files.
+//!
+//! The messages of the two schemas are compared as JSON values without the room, so the frozen copy needs no
+//! conversion. A new protocol version freezes the then previous schema the same way, next to this one.
+
+mod v0;
+mod v1;
+
+use std::fs;
+use std::path::Path;
+
+use bytes::BytesMut;
+use chat::codec::MessageCodec;
+use chat::testvectors::{self, vectors};
+use chat::{Message, MessageType, WireFormat, DEFAULT_ROOM};
+use serde_json::Value;
+use tokio_util::codec::Decoder;
+
+/// Vectors of the message types and error codes added since the version 1, unknown to it.
+const UNKNOWN_TO_V1: [&str; 10] = [
+    "Join",
+    "Leave",
+    "ServerError.InvalidRoom",
+    "ServerError.NotInRoom",
+    "System",
+    "Whisper",
+    "Ack",
+    "Pong",
+    "PublicKey",
+    "Encrypted",
+];
+
+/// Returns the message of the current schema as JSON, without the room unknown to the version 1.
+fn without_room(message: &Message) -> Value {
+    let mut value = serde_json::to_value(message).unwrap();
+    value.as_object_mut().unwrap().remove("room");
+    value
+}
+
+/// Returns the frames of the fixture, without the length prefix, decoded by the frozen schema.
+fn fixture<T: serde::de::DeserializeOwned>(file: &str) -> Vec<(String, Vec<u8>, T)> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("testvectors")
+        .join(file);
+    let fixture = fs::read_to_string(path).unwrap();
+    testvectors::parse(&fixture)
+        .unwrap()
+        .into_iter()
+        .map(|(name, frame)| {
+            let bytes = frame[4..].to_vec();
+            let message = bincode::deserialize(&bytes)
+                .unwrap_or_else(|err_msg| panic!("{name} isn't a message of {file}: {err_msg}"));
+            (name, bytes, message)
+        })
+        .collect()
+}
+
+/// Returns the frames of the version 1 fixture decoded by the frozen schema.
+fn v1_fixture() -> Vec<(String, Vec<u8>, v1::Message)> {
+    fixture("v1.txt")
+}
+
+/// Returns the frames of the first release fixture decoded by the frozen schema.
+fn v0_fixture() -> Vec<(String, Vec<u8>, v0::Message)> {
+    fixture("v0.txt")
+}
+
+#[test]
+fn test_frozen_schema_matches_fixture() {
+    for (name, bytes, message) in v1_fixture() {
+        assert_eq!(bincode::serialize(&message).unwrap(), bytes, "{name}");
+    }
+}
+
+#[test]
+fn test_current_reads_v1_frames() {
+    for (name, bytes, old) in v1_fixture() {
+        let message = Message::deserialized_message(&bytes).unwrap();
+        assert_eq!(message.room, DEFAULT_ROOM, "{name}");
+        assert_eq!(
+            without_room(&message),
+            serde_json::to_value(&old).unwrap(),
+            "{name}"
+        );
+        assert_eq!(WireFormat::Bincode.deserialize(&bytes).unwrap(), message);
+
+        let mut frame = BytesMut::from(&(bytes.len() as u32).to_be_bytes()[..]);
+        frame.extend_from_slice(&bytes);
+        let decoded = MessageCodec::default().decode(&mut frame).unwrap();
+        assert_eq!(decoded.unwrap().unwrap(), message, "{name}");
+    }
+}
+
+#[test]
+fn test_v1_reads_current_frames() {
+    for (name, message) in vectors() {
+        let bytes = message.serialized_message().unwrap();
+        let decoded = bincode::deserialize::<v1::Message>(&bytes);
+        if UNKNOWN_TO_V1.contains(&name.as_str()) {
+            assert!(decoded.is_err(), "{name} decoded as {decoded:?}");
+            continue;
+        }
+        let decoded = decoded.unwrap_or_else(|err_msg| panic!("{name}: {err_msg}"));
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            without_room(&message),
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn test_json_between_versions() {
+    for (name, _, old) in v1_fixture() {
+        let line = serde_json::to_vec(&old).unwrap();
+        let message = WireFormat::Json.deserialize(&line).unwrap();
+        assert_eq!(message.room, DEFAULT_ROOM, "{name}");
+        assert_eq!(
+            without_room(&message),
+            serde_json::to_value(&old).unwrap(),
+            "{name}"
+        );
+    }
+    for (name, message) in vectors() {
+        if UNKNOWN_TO_V1.contains(&name.as_str()) {
+            continue;
+        }
+        let line = WireFormat::Json.serialize(&message).unwrap();
+        let decoded: v1::Message = serde_json::from_slice(&line).unwrap();
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            without_room(&message),
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn test_current_reads_v0_frames() {
+    let fixture = v0_fixture();
+    assert_eq!(fixture.len(), 3);
+    for (name, bytes, old) in fixture {
+        assert_eq!(bincode::serialize(&old).unwrap(), bytes, "{name}");
+        let message = Message::deserialized_message(&bytes)
+            .unwrap_or_else(|err_msg| panic!("{name}: {err_msg}"));
+        assert_eq!(
+            (message.room.as_str(), message.timestamp, message.system),
+            (DEFAULT_ROOM, None, false),
+            "{name}"
+        );
+        assert_eq!(
+            serde_json::to_value(&message.nickname).unwrap(),
+            serde_json::to_value(&old.nickname).unwrap(),
+            "{name}"
+        );
+        assert_eq!(
+            serde_json::to_value(&message.message).unwrap(),
+            serde_json::to_value(&old.message).unwrap(),
+            "{name}"
+        );
+        let mut frame = BytesMut::from(&(bytes.len() as u32).to_be_bytes()[..]);
+        frame.extend_from_slice(&bytes);
+        let decoded = MessageCodec::default().decode(&mut frame).unwrap();
+        assert_eq!(decoded.unwrap().unwrap(), message, "{name}");
+    }
+}
+
+#[test]
+fn test_v0_reads_current_frames() {
+    for (name, message) in vectors() {
+        let bytes = message.serialized_message().unwrap();
+        let decoded = bincode::deserialize::<v0::Message>(&bytes);
+        let known = matches!(
+            message.message,
+            MessageType::Text(_) | MessageType::Image(_) | MessageType::File { .. }
+        );
+        if !known {
+            assert!(decoded.is_err(), "{name} decoded as {decoded:?}");
+            continue;
+        }
+        let decoded = decoded.unwrap_or_else(|err_msg| panic!("{name}: {err_msg}"));
+        assert_eq!(decoded.nickname, message.nickname, "{name}");
+        assert_eq!(
+            serde_json::to_value(&decoded.message).unwrap(),
+            serde_json::to_value(&message.message).unwrap(),
+            "{name}"
+        );
+    }
+}
@@ -0,0 +1,19 @@
+//! Frozen copy of the message schema of the first released clients and server, before the protocol versions.
+//!
+//! The clients of the first release send only the nickname and the message of three types. Never change the types,
+//! the tests check the current decoder still reads their frames.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Message {
+    pub nickname: String,
+    pub message: MessageType,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum MessageType {
+    Text(String),
+    Image(Vec<u8>),
+    File { name: String, content: Vec<u8> },
+}
@@ -0,0 +1,174 @@
+//! Frozen copy of the message schema of the protocol version 1, before the rooms.
+//!
+//! The types mirror the field and variant order of `chat` at that version, which is all bincode sees. Never change them,
+//! the tests compare the current schema with this copy.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Message {
+    pub nickname: String,
+    pub message: MessageType,
+    #[serde(default)]
+    pub annotations: Vec<(String, String)>,
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    #[serde(default)]
+    pub system: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum MessageType {
+    Text(String),
+    Image(Vec<u8>),
+    File {
+        name: String,
+        content: Vec<u8>,
+    },
+    Attachment {
+        name: String,
+        size: u64,
+        url: String,
+    },
+    Code {
+        lang: String,
+        source: String,
+    },
+    ServerError {
+        code: ErrorCode,
+    },
+    HistoryRequest {
+        before: Option<i64>,
+        limit: u32,
+    },
+    History(Vec<HistoryEntry>),
+    SearchRequest {
+        query: String,
+        limit: u32,
+    },
+    SearchResults(Vec<HistoryEntry>),
+    Bench {
+        id: u32,
+        payload: Vec<u8>,
+    },
+    BenchAck {
+        id: u32,
+        size: u64,
+    },
+    Sync {
+        id: u32,
+    },
+    SyncAck {
+        id: u32,
+    },
+    ServerFull {
+        position: usize,
+    },
+    Admitted,
+    Welcome {
+        server_name: String,
+        version: String,
+        motd: Option<String>,
+        capabilities: Vec<String>,
+        limits: ServerLimits,
+        server_time: u64,
+    },
+    Ping {
+        server_time: u64,
+    },
+    Poll {
+        id: i64,
+        question: String,
+        options: Vec<String>,
+    },
+    Vote {
+        poll: i64,
+        option: String,
+    },
+    ClosePoll {
+        poll: i64,
+    },
+    PollResults {
+        id: i64,
+        question: String,
+        votes: Vec<(String, u32)>,
+        closed: bool,
+    },
+    RosterRequest,
+    RosterSnapshot {
+        version: u64,
+        users: Vec<String>,
+    },
+    RosterDelta {
+        version: u64,
+        change: RosterChange,
+    },
+    Digest(DigestSetting),
+    FileStart {
+        id: u32,
+        name: String,
+        size: u64,
+    },
+    FileChunk {
+        id: u32,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    FileEnd {
+        id: u32,
+        checksum: u32,
+    },
+    Report {
+        message: i64,
+        reason: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum DigestSetting {
+    Email(String),
+    Daily { minute: u16 },
+    Off,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RosterChange {
+    Joined(String),
+    Left(String),
+    Renamed { from: String, to: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub id: i64,
+    pub nickname: String,
+    pub msg_type: String,
+    pub message: String,
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ServerLimits {
+    pub max_attachment: u64,
+    pub max_history: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    Overloaded,
+    Muted { seconds: u64 },
+    QueueFull,
+    InvalidPoll,
+    UnknownPoll { id: i64 },
+    InvalidVote { id: i64 },
+    PollClosed { id: i64 },
+    NotPollAuthor { id: i64 },
+    TooLarge { size: u64, max: u64 },
+    InvalidMessage,
+    NotStored,
+    Unavailable,
+    ReservedNickname,
+    InvalidDigest,
+    UnknownMessage { id: i64 },
+    InvalidReport,
+}
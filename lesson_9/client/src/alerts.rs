@@ -0,0 +1,222 @@
+//! Silent notifications accompanying or replacing the sound.
+//!
+//! The terminal bell, a screen flash (inverse video for [`FLASH_DURATION`]) and a counter of the unread messages in the
+//! terminal title serve users in silent environments or without an audio device. They are selected by the `.alerts`
+//! command or the `alerts` list in `client.json` and combine with each other and with the sound. The counter is reset
+//! by any input of the user. The flash and the counter are skipped when the output is not a terminal.
+
+use std::fmt;
+use std::io::{IsTerminal, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::assets::SoundEvent;
+
+/// Duration of the screen flash.
+pub const FLASH_DURATION: Duration = Duration::from_millis(100);
+/// Terminal title without unread messages.
+pub const TITLE: &str = "chat";
+const FLASH_ON: &str = "\x1b[?5h";
+const FLASH_OFF: &str = "\x1b[?5l";
+
+/// Notification besides the sound.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Alert {
+    /// Ring the terminal bell.
+    Bell,
+    /// Flash the screen in inverse video.
+    Flash,
+    /// Count the unread messages in the terminal title.
+    Counter,
+}
+
+impl FromStr for Alert {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bell" => Ok(Alert::Bell),
+            "flash" => Ok(Alert::Flash),
+            "counter" => Ok(Alert::Counter),
+            _ => Err(anyhow!(
+                "Invalid alert {s}, use bell, flash, counter or off!"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bell => write!(f, "bell"),
+            Self::Flash => write!(f, "flash"),
+            Self::Counter => write!(f, "counter"),
+        }
+    }
+}
+
+/// Parses the arguments of the `.alerts` command, e.g. `flash counter`, `off` selects none.
+///
+/// # Errors
+///
+/// This function will return an error for an unknown alert or no arguments.
+pub fn parse_alerts(arguments: &str) -> Result<Vec<Alert>> {
+    if arguments.trim() == "off" {
+        return Ok(Vec::new());
+    }
+    let mut alerts = Vec::new();
+    for word in arguments.split_whitespace() {
+        let alert = Alert::from_str(word)?;
+        if !alerts.contains(&alert) {
+            alerts.push(alert);
+        }
+    }
+    if alerts.is_empty() {
+        return Err(anyhow!("No alerts, use bell, flash, counter or off!"));
+    }
+    Ok(alerts)
+}
+
+/// Describes the selected alerts, e.g. `flash, counter`.
+pub fn describe(alerts: &[Alert]) -> String {
+    if alerts.is_empty() {
+        return "off".to_string();
+    }
+    let names: Vec<String> = alerts.iter().map(ToString::to_string).collect();
+    names.join(", ")
+}
+
+/// Selected alerts with the unread counter, shared by the reading and writing loops.
+#[derive(Clone, Default)]
+pub struct Alerts {
+    selected: Arc<Mutex<Vec<Alert>>>,
+    unread: Arc<AtomicUsize>,
+}
+
+impl Alerts {
+    pub fn new(selected: Vec<Alert>) -> Alerts {
+        Alerts {
+            selected: Arc::new(Mutex::new(selected)),
+            unread: Default::default(),
+        }
+    }
+
+    /// Replaces the selected alerts.
+    pub fn set(&self, selected: Vec<Alert>) {
+        let counted = self.has(Alert::Counter);
+        *self.selected.lock().unwrap_or_else(|e| e.into_inner()) = selected;
+        if counted && !self.has(Alert::Counter) {
+            self.unread.store(0, Ordering::SeqCst);
+            set_title(&title(0));
+        }
+    }
+
+    fn has(&self, alert: Alert) -> bool {
+        self.selected
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(&alert)
+    }
+
+    /// Fires the selected alerts for the event, only the messages and the files are counted as unread.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The notified event.
+    /// * `rang` - The sound already rang the terminal bell, so it doesn't ring twice.
+    pub fn notify(&self, event: SoundEvent, rang: bool) {
+        if self.has(Alert::Bell) && !rang {
+            crate::sound::bell();
+        }
+        if self.has(Alert::Flash) {
+            flash();
+        }
+        if self.has(Alert::Counter) && event != SoundEvent::Error {
+            let unread = self.unread.fetch_add(1, Ordering::SeqCst) + 1;
+            set_title(&title(unread));
+        }
+    }
+
+    /// Resets the unread counter after the user's input.
+    pub fn mark_read(&self) {
+        if self.unread.swap(0, Ordering::SeqCst) > 0 {
+            set_title(&title(0));
+        }
+    }
+}
+
+/// Returns the terminal title with the number of the unread messages, e.g. `(3) chat`.
+fn title(unread: usize) -> String {
+    match unread {
+        0 => TITLE.to_string(),
+        unread => format!("({unread}) {TITLE}"),
+    }
+}
+
+fn set_title(title: &str) {
+    if std::io::stdout().is_terminal() {
+        print!("\x1b]0;{title}\x07");
+        let _ = std::io::stdout().flush();
+    }
+}
+
+fn flash() {
+    if !std::io::stdout().is_terminal() {
+        return;
+    }
+    print!("{FLASH_ON}");
+    let _ = std::io::stdout().flush();
+    thread::spawn(|| {
+        thread::sleep(FLASH_DURATION);
+        print!("{FLASH_OFF}");
+        let _ = std::io::stdout().flush();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_alerts() {
+        assert_eq!(
+            parse_alerts("flash counter flash").unwrap(),
+            [Alert::Flash, Alert::Counter]
+        );
+        assert_eq!(parse_alerts(" off ").unwrap(), []);
+        assert!(parse_alerts("beep").is_err());
+        assert!(parse_alerts("").is_err());
+        assert_eq!(describe(&[Alert::Bell, Alert::Counter]), "bell, counter");
+        assert_eq!(describe(&[]), "off");
+    }
+
+    fn unread(alerts: &Alerts) -> usize {
+        alerts.unread.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn test_unread_counter() {
+        let alerts = Alerts::new(vec![Alert::Counter]);
+        alerts.notify(SoundEvent::Message, false);
+        alerts.notify(SoundEvent::File, false);
+        alerts.notify(SoundEvent::Error, false);
+        assert_eq!(unread(&alerts), 2);
+        assert_eq!(title(unread(&alerts)), "(2) chat");
+        alerts.mark_read();
+        assert_eq!(unread(&alerts), 0);
+        assert_eq!(title(0), "chat");
+
+        alerts.notify(SoundEvent::Message, false);
+        alerts.set(vec![Alert::Flash]);
+        assert_eq!(unread(&alerts), 0);
+        alerts.notify(SoundEvent::Message, false);
+        assert_eq!(unread(&alerts), 0);
+    }
+}
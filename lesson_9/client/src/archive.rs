@@ -0,0 +1,163 @@
+//! Directories shared as `.tar.gz` archives.
+//!
+//! The `.dir` command packs a directory into a single File message, the `.extract` command unpacks a received archive
+//! into the download directory. Both directions are limited by [`MAX_ARCHIVE_BYTES`] and [`MAX_ENTRIES`], so a huge
+//! directory or a malicious archive can't fill the disk.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+/// Maximal total size of the archived files.
+pub const MAX_ARCHIVE_BYTES: u64 = 100 * 1024 * 1024;
+/// Maximal number of the archived files and directories.
+pub const MAX_ENTRIES: usize = 10_000;
+/// Extension of the archives.
+pub const EXTENSION: &str = ".tar.gz";
+
+/// Packs the directory.
+///
+/// # Returns
+///
+/// The archive name `<dir>.tar.gz` and its content.
+///
+/// # Errors
+///
+/// This function will return an error if the directory can't be read or exceeds the limits.
+pub fn pack(dir: &Path) -> Result<(String, Vec<u8>)> {
+    if !dir.is_dir() {
+        return Err(anyhow!("{} is not a directory!", dir.display()));
+    }
+    let name = dir
+        .canonicalize()?
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("directory")
+        .to_string();
+    let (entries, size) = measure(dir)?;
+    check_limits(entries, size)?;
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    builder.follow_symlinks(false);
+    builder
+        .append_dir_all(&name, dir)
+        .with_context(|| format!("Packing {} failed!", dir.display()))?;
+    let content = builder.into_inner()?.finish()?;
+    Ok((format!("{name}{EXTENSION}"), content))
+}
+
+/// Unpacks the archive into a directory next to it named by the archive without the extension.
+///
+/// Entries pointing outside of the directory are skipped.
+///
+/// # Returns
+///
+/// The directory with the unpacked files.
+///
+/// # Errors
+///
+/// This function will return an error if the archive is invalid or exceeds the limits.
+pub fn unpack(archive: &Path) -> Result<PathBuf> {
+    let name = archive
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(EXTENSION))
+        .ok_or_else(|| anyhow!("{} is not a {EXTENSION} archive!", archive.display()))?;
+    let destination = archive.with_file_name(name);
+    fs::create_dir_all(&destination)?;
+    let file = fs::File::open(archive)?;
+    let mut tar = tar::Archive::new(GzDecoder::new(file));
+    let (mut entries, mut size) = (0, 0);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        entries += 1;
+        size += entry.header().size()?;
+        check_limits(entries, size)?;
+        entry.unpack_in(&destination)?;
+    }
+    Ok(destination)
+}
+
+/// Returns true if the file name looks like an archive created by [`pack`].
+pub fn is_archive(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(EXTENSION)
+}
+
+fn measure(dir: &Path) -> Result<(usize, u64)> {
+    let (mut entries, mut size) = (0, 0);
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in
+            fs::read_dir(&dir).with_context(|| format!("Reading {} failed!", dir.display()))?
+        {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            entries += 1;
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            } else {
+                size += metadata.len();
+            }
+            check_limits(entries, size)?;
+        }
+    }
+    Ok((entries, size))
+}
+
+fn check_limits(entries: usize, size: u64) -> Result<()> {
+    if entries > MAX_ENTRIES {
+        return Err(anyhow!("Too many entries, the limit is {MAX_ENTRIES}!"));
+    }
+    if size > MAX_ARCHIVE_BYTES {
+        return Err(anyhow!(
+            "Too much data, the limit is {} MB!",
+            MAX_ARCHIVE_BYTES / 1024 / 1024
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack_and_unpack() {
+        let root = std::env::temp_dir().join(format!("chat-archive-{}", std::process::id()));
+        let source = root.join("notes");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("a.txt"), "hello").unwrap();
+        fs::write(source.join("sub/b.txt"), "world").unwrap();
+
+        let (name, content) = pack(&source).unwrap();
+        assert_eq!(name, "notes.tar.gz");
+        let downloads = root.join("FILES");
+        fs::create_dir_all(&downloads).unwrap();
+        let archive = downloads.join(&name);
+        fs::write(&archive, content).unwrap();
+        assert!(is_archive(&archive));
+
+        let destination = unpack(&archive).unwrap();
+        assert_eq!(destination, downloads.join("notes"));
+        assert_eq!(
+            fs::read_to_string(destination.join("notes/a.txt")).unwrap(),
+            "hello"
+        );
+        assert_eq!(
+            fs::read_to_string(destination.join("notes/sub/b.txt")).unwrap(),
+            "world"
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_limits() {
+        assert!(check_limits(MAX_ENTRIES, MAX_ARCHIVE_BYTES).is_ok());
+        assert!(check_limits(MAX_ENTRIES + 1, 0).is_err());
+        assert!(check_limits(1, MAX_ARCHIVE_BYTES + 1).is_err());
+        assert!(pack(Path::new("does-not-exist")).is_err());
+    }
+}
@@ -0,0 +1,297 @@
+//! Themes and sound packs loaded at runtime from the [`ASSETS_DIR`] directory.
+//!
+//! `assets/theme.json` changes the colors and the symbols of the output and `assets/sounds.json` maps the events to
+//! the notification sounds in the directory, both files only hold the changed keys. They are loaded at the start and
+//! again by the `.reload-assets` command, so a theme or a sound pack can be tried without restarting or rebuilding the
+//! client. An invalid theme is reported and replaced by the default one, an invalid sound falls back to the default
+//! sound of its event.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use crate::highlight;
+use crate::sound::SOUND_FILE;
+
+/// Directory of the theme and the sound pack.
+pub const ASSETS_DIR: &str = "assets";
+/// Theme file in the [`ASSETS_DIR`].
+pub const THEME_FILE: &str = "theme.json";
+/// Sound pack file in the [`ASSETS_DIR`].
+pub const SOUNDS_FILE: &str = "sounds.json";
+/// Maximal length of a symbol in characters.
+pub const MAX_SYMBOL_LEN: usize = 8;
+
+/// Colors and symbols of the output.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Theme {
+    /// Color of the inline code.
+    pub code: u8,
+    /// Colors of the nicknames, indexed by the color annotation of the server.
+    pub nicknames: Vec<u8>,
+    /// Name of the syntax highlighting theme of the shared code.
+    pub highlight: String,
+    /// Separator between the sender and the message.
+    pub arrow: String,
+    /// Marker of the server errors.
+    pub error: String,
+    /// Marker of the notices of the server, e.g. a joined user, shown instead of the sender.
+    pub system: String,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            code: 36,
+            nicknames: vec![31, 32, 33, 34, 35, 36, 91, 92, 93, 94, 95, 96],
+            highlight: highlight::DEFAULT_THEME.to_string(),
+            arrow: " --> ".to_string(),
+            error: "!".to_string(),
+            system: "***".to_string(),
+        }
+    }
+}
+
+impl Theme {
+    /// Checks the colors and the symbols.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a color is not a terminal foreground color (30-37 or 90-97), there are
+    /// not [`chat::NICKNAME_COLORS`] nickname colors, the highlighting theme is unknown or a symbol is blank, too long
+    /// or contains control characters.
+    fn validate(&self) -> Result<()> {
+        let colors = std::iter::once(&self.code).chain(&self.nicknames);
+        if let Some(color) = colors.copied().find(|color| !is_color(*color)) {
+            return Err(anyhow!("invalid color {color}, use 30-37 or 90-97"));
+        }
+        if self.nicknames.len() != chat::NICKNAME_COLORS as usize {
+            return Err(anyhow!(
+                "nicknames need {} colors, found {}",
+                chat::NICKNAME_COLORS,
+                self.nicknames.len()
+            ));
+        }
+        if !highlight::has_theme(&self.highlight) {
+            return Err(anyhow!("unknown highlight theme {}", self.highlight));
+        }
+        for symbol in [&self.arrow, &self.error, &self.system] {
+            let valid = !symbol.trim().is_empty()
+                && symbol.chars().count() <= MAX_SYMBOL_LEN
+                && !symbol.chars().any(char::is_control);
+            if !valid {
+                return Err(anyhow!("invalid symbol {symbol:?}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_color(color: u8) -> bool {
+    matches!(color, 30..=37 | 90..=97)
+}
+
+fn current() -> &'static RwLock<Arc<Theme>> {
+    static THEME: OnceLock<RwLock<Arc<Theme>>> = OnceLock::new();
+    THEME.get_or_init(Default::default)
+}
+
+/// Returns the current theme.
+pub fn theme() -> Arc<Theme> {
+    current().read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+fn set_theme(theme: Theme) {
+    *current().write().unwrap_or_else(|e| e.into_inner()) = Arc::new(theme);
+}
+
+/// Event played by the notification sound.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum SoundEvent {
+    /// A text or code message was received.
+    Message,
+    /// An image or a file was received.
+    File,
+    /// The server rejected a message.
+    Error,
+}
+
+/// Sound files of the events.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SoundPack {
+    sounds: HashMap<SoundEvent, PathBuf>,
+}
+
+impl SoundPack {
+    /// Returns the sound of the event, [`SOUND_FILE`] if the pack doesn't have it.
+    pub fn sound(&self, event: SoundEvent) -> &Path {
+        self.sounds
+            .get(&event)
+            .map_or(Path::new(SOUND_FILE), PathBuf::as_path)
+    }
+}
+
+/// Theme and sound pack with the problems found while loading them.
+#[derive(Debug, Default)]
+pub struct Assets {
+    pub theme: Theme,
+    pub sounds: SoundPack,
+    /// Files found in the directory.
+    pub loaded: Vec<&'static str>,
+    /// Invalid files and sounds replaced by the defaults.
+    pub problems: Vec<String>,
+}
+
+impl Assets {
+    /// Loads the theme and the sound pack from the directory, missing files use the defaults.
+    pub fn load(dir: &Path) -> Assets {
+        let mut assets = Assets::default();
+        match read_json::<Theme>(&dir.join(THEME_FILE)) {
+            Ok(None) => (),
+            Ok(Some(theme)) => match theme.validate() {
+                Ok(()) => {
+                    assets.theme = theme;
+                    assets.loaded.push(THEME_FILE);
+                }
+                Err(err_msg) => assets.problem(THEME_FILE, err_msg),
+            },
+            Err(err_msg) => assets.problem(THEME_FILE, err_msg),
+        }
+        match read_json::<HashMap<SoundEvent, String>>(&dir.join(SOUNDS_FILE)) {
+            Ok(None) => (),
+            Ok(Some(sounds)) => {
+                for (event, file) in sounds {
+                    let path = dir.join(&file);
+                    match check_sound(&path) {
+                        Ok(()) => {
+                            assets.sounds.sounds.insert(event, path);
+                        }
+                        Err(err_msg) => assets.problem(SOUNDS_FILE, err_msg),
+                    }
+                }
+                assets.loaded.push(SOUNDS_FILE);
+            }
+            Err(err_msg) => assets.problem(SOUNDS_FILE, err_msg),
+        }
+        assets
+    }
+
+    /// Makes the theme current and returns the sound pack.
+    pub fn apply(self) -> SoundPack {
+        set_theme(self.theme);
+        self.sounds
+    }
+
+    fn problem(&mut self, file: &str, err_msg: anyhow::Error) {
+        self.problems
+            .push(format!("Invalid {file}, using defaults: {err_msg:#}"));
+    }
+}
+
+impl fmt::Display for Assets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.loaded.as_slice() {
+            [] => write!(f, "No assets in {ASSETS_DIR}/, using defaults."),
+            loaded => write!(f, "Loaded {} from {ASSETS_DIR}/.", loaded.join(" and ")),
+        }
+    }
+}
+
+/// Reads the JSON file, a missing file is `None`.
+fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<Option<T>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err_msg) if err_msg.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err_msg) => return Err(err_msg.into()),
+    };
+    Ok(Some(serde_json::from_str(&content)?))
+}
+
+/// Checks that the sound is an existing WAV file, the only format the player decodes.
+fn check_sound(path: &Path) -> Result<()> {
+    let is_wav = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("wav"));
+    if !is_wav {
+        return Err(anyhow!("{} is not a WAV file", path.display()));
+    }
+    let metadata =
+        std::fs::metadata(path).with_context(|| format!("missing {}", path.display()))?;
+    if !metadata.is_file() {
+        return Err(anyhow!("{} is not a file", path.display()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assets_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chat-assets-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_default_theme_is_valid() {
+        Theme::default().validate().unwrap();
+        let assets = Assets::load(Path::new("missing-assets-dir"));
+        assert_eq!(assets.theme, Theme::default());
+        assert!(assets.loaded.is_empty() && assets.problems.is_empty());
+    }
+
+    #[test]
+    fn test_load_assets() {
+        let dir = assets_dir("valid");
+        let theme = r#"{"arrow": " > ", "code": 93, "highlight": "Solarized (dark)"}"#;
+        std::fs::write(dir.join(THEME_FILE), theme).unwrap();
+        std::fs::write(dir.join("ping.wav"), b"RIFF").unwrap();
+        let sounds = r#"{"message": "ping.wav", "error": "missing.wav", "file": "notes.txt"}"#;
+        std::fs::write(dir.join(SOUNDS_FILE), sounds).unwrap();
+
+        let assets = Assets::load(&dir);
+        assert_eq!(assets.theme.arrow, " > ");
+        assert_eq!(assets.theme.code, 93);
+        assert_eq!(assets.theme.nicknames, Theme::default().nicknames);
+        assert_eq!(assets.loaded, [THEME_FILE, SOUNDS_FILE]);
+        assert_eq!(assets.problems.len(), 2);
+        assert_eq!(
+            assets.sounds.sound(SoundEvent::Message),
+            dir.join("ping.wav")
+        );
+        assert_eq!(
+            assets.sounds.sound(SoundEvent::Error),
+            Path::new(SOUND_FILE)
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_theme() {
+        let dir = assets_dir("invalid");
+        for theme in [
+            r#"{"code": 12}"#,
+            r#"{"nicknames": [31, 32]}"#,
+            r#"{"highlight": "no-such-theme"}"#,
+            r#"{"arrow": "   "}"#,
+            r#"{"error": "\u001b[31m!"}"#,
+            r#"{"system": ""}"#,
+            r#"{"colour": 31}"#,
+            "not json",
+        ] {
+            std::fs::write(dir.join(THEME_FILE), theme).unwrap();
+            let assets = Assets::load(&dir);
+            assert_eq!(assets.theme, Theme::default(), "{theme}");
+            assert_eq!(assets.problems.len(), 1, "{theme}");
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+//! Downloads of the big files delivered by the server over HTTP.
+//!
+//! The server announces a big file by an Attachment message with a signed download URL instead of its content. The
+//! file is downloaded in the background to `<name>.part` and renamed when it is complete. A broken download is retried
+//! up to [`MAX_ATTEMPTS`] times, resuming from the already received bytes with a range request.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use reqwest::{header, StatusCode};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+/// Port of the HTTP server of the chat server, used for the download URLs without an address.
+pub const HTTP_PORT: u16 = 3001;
+/// Maximal number of attempts to download a file.
+pub const MAX_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// HTTP client downloading the attachments from the connected server.
+#[derive(Clone)]
+pub struct Fetcher {
+    client: reqwest::Client,
+    hostname: String,
+}
+
+impl Fetcher {
+    /// Creates the fetcher of the server running on `hostname`.
+    pub fn new(hostname: &str) -> Fetcher {
+        Fetcher {
+            client: reqwest::Client::new(),
+            hostname: hostname.to_string(),
+        }
+    }
+
+    /// Returns the absolute URL, a path is resolved against the HTTP port of the chat server.
+    pub fn resolve(&self, url: &str) -> String {
+        if url.starts_with('/') {
+            format!("http://{}:{HTTP_PORT}{url}", self.hostname)
+        } else {
+            url.to_string()
+        }
+    }
+
+    /// Downloads the file of `size` bytes from the `url` to the `path`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the server refuses the download, e.g. the link expired, if all the
+    /// attempts fail or if the file can't be written.
+    pub async fn download(&self, url: &str, path: &Path, size: u64) -> Result<()> {
+        let url = self.resolve(url);
+        let part = part_path(path);
+        let mut attempt = 1;
+        while let Err(err_msg) = self.fetch(&url, &part).await {
+            if attempt >= MAX_ATTEMPTS || !err_msg.is::<reqwest::Error>() {
+                return Err(err_msg);
+            }
+            attempt += 1;
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+        let received = fs::metadata(&part).await?.len();
+        if received != size {
+            return Err(anyhow!("Received {received} of {size} bytes!"));
+        }
+        fs::rename(&part, path).await?;
+        Ok(())
+    }
+
+    /// Downloads the rest of the file, appending to the received part.
+    async fn fetch(&self, url: &str, part: &Path) -> Result<()> {
+        let received = fs::metadata(part)
+            .await
+            .map_or(0, |metadata| metadata.len());
+        let mut request = self.client.get(url);
+        if received > 0 {
+            request = request.header(header::RANGE, format!("bytes={received}-"));
+        }
+        let mut response = request.send().await?;
+        let mut file = match response.status() {
+            StatusCode::OK => fs::File::create(part).await?,
+            StatusCode::PARTIAL_CONTENT => OpenOptions::new().append(true).open(part).await?,
+            // The part is already complete.
+            StatusCode::RANGE_NOT_SATISFIABLE if received > 0 => return Ok(()),
+            status => return Err(anyhow!("The server refused the download: {status}!")),
+        };
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+/// Returns the path of the partially downloaded file.
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let fetcher = Fetcher::new("chat.local");
+        assert_eq!(
+            fetcher.resolve("/attachments/ab?expires=1&token=cd"),
+            "http://chat.local:3001/attachments/ab?expires=1&token=cd"
+        );
+        assert_eq!(
+            fetcher.resolve("http://files.example.com/attachments/ab"),
+            "http://files.example.com/attachments/ab"
+        );
+        assert_eq!(
+            part_path(Path::new("FILES/video.mp4")),
+            Path::new("FILES/video.mp4.part")
+        );
+    }
+}
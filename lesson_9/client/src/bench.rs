@@ -0,0 +1,173 @@
+//! Transfer benchmark run by the `.bench` command.
+//!
+//! Every run sends a synthetic payload of the given size to the server and waits for its acknowledgement. The payload
+//! is never delivered to other clients, so the benchmark can run against a live server.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::sync::mpsc;
+
+use chat::{Message, MessageType};
+
+use crate::connection::Link;
+
+/// Maximal payload size in megabytes.
+pub const MAX_SIZE_MB: usize = 1024;
+/// Time to wait for the acknowledgement of a single run.
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(60);
+
+const MEGABYTE: usize = 1024 * 1024;
+
+/// Measurement of a single run.
+#[derive(Debug, Clone, Copy)]
+pub struct Run {
+    /// Time to write the payload to the socket.
+    pub upload: Duration,
+    /// Time from the end of the upload to the acknowledgement.
+    pub ack: Duration,
+}
+
+impl Run {
+    fn total(&self) -> Duration {
+        self.upload + self.ack
+    }
+}
+
+/// Results of all the runs.
+#[derive(Debug)]
+pub struct Summary {
+    pub size: usize,
+    pub runs: Vec<Run>,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let megabytes = self.size as f64 / MEGABYTE as f64;
+        for (index, run) in self.runs.iter().enumerate() {
+            writeln!(
+                f,
+                "run {}: {:.1} MB in {:.3} s, {:.1} MB/s, ack {:.1} ms",
+                index + 1,
+                megabytes,
+                run.total().as_secs_f64(),
+                megabytes / run.total().as_secs_f64(),
+                run.ack.as_secs_f64() * 1000.0
+            )?;
+        }
+        let total: Duration = self.runs.iter().map(Run::total).sum();
+        let acks: Vec<f64> = self
+            .runs
+            .iter()
+            .map(|run| run.ack.as_secs_f64() * 1000.0)
+            .collect();
+        let min = acks.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = acks.iter().copied().fold(0.0, f64::max);
+        write!(
+            f,
+            "bench: {} runs of {:.1} MB, {:.1} MB/s, ack min/avg/max {:.1}/{:.1}/{:.1} ms",
+            self.runs.len(),
+            megabytes,
+            megabytes * self.runs.len() as f64 / total.as_secs_f64(),
+            min,
+            acks.iter().sum::<f64>() / acks.len() as f64,
+            max
+        )
+    }
+}
+
+/// Parses the `.bench` arguments `<size-mb> [--loop N]`.
+///
+/// # Returns
+///
+/// The payload size in megabytes and the number of runs.
+///
+/// # Errors
+///
+/// This function will return an error if the size or the number of runs is missing or invalid.
+pub fn parse_arguments(arguments: &str) -> Result<(usize, u32)> {
+    let mut arguments = arguments.split_whitespace();
+    let size: usize = arguments
+        .next()
+        .ok_or(anyhow!("Missing size, use .bench <size-mb> [--loop N]!"))?
+        .parse()
+        .context("Invalid size!")?;
+    if size == 0 || size > MAX_SIZE_MB {
+        return Err(anyhow!("Size must be between 1 and {MAX_SIZE_MB} MB!"));
+    }
+    let count = match (arguments.next(), arguments.next()) {
+        (None, _) => 1,
+        (Some("--loop"), Some(count)) => count.parse().context("Invalid number of runs!")?,
+        _ => {
+            return Err(anyhow!(
+                "Invalid arguments, use .bench <size-mb> [--loop N]!"
+            ))
+        }
+    };
+    if count == 0 {
+        return Err(anyhow!("Number of runs must be at least 1!"));
+    }
+    Ok((size, count))
+}
+
+/// Runs the benchmark.
+///
+/// # Arguments
+///
+/// * `link` - The connection to the server, the runs fail instead of waiting for a reconnection.
+/// * `nickname` - The user's nickname.
+/// * `size_mb` - Payload size in megabytes.
+/// * `count` - Number of runs.
+/// * `acks` - Ids of the acknowledged payloads, forwarded by the reading loop.
+///
+/// # Errors
+///
+/// This function will return an error if sending fails or the server doesn't acknowledge a run in [`ACK_TIMEOUT`].
+pub async fn run(
+    link: &Link,
+    nickname: &str,
+    size_mb: usize,
+    count: u32,
+    acks: &mut mpsc::UnboundedReceiver<u32>,
+) -> Result<Summary> {
+    let size = size_mb * MEGABYTE;
+    let mut runs = Vec::new();
+    for id in 1..=count {
+        let message = Message::from(nickname, MessageType::bench(id, size));
+        let start = Instant::now();
+        link.send_now(&message).await?;
+        let uploaded = Instant::now();
+        tokio::time::timeout(ACK_TIMEOUT, async {
+            while let Some(ack) = acks.recv().await {
+                if ack == id {
+                    return Ok(());
+                }
+            }
+            Err(anyhow!("Connection closed!"))
+        })
+        .await
+        .map_err(|_| anyhow!("Run {id} was not acknowledged in {ACK_TIMEOUT:?}!"))??;
+        runs.push(Run {
+            upload: uploaded - start,
+            ack: uploaded.elapsed(),
+        });
+    }
+    Ok(Summary { size, runs })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_arguments() {
+        assert_eq!(parse_arguments("10").unwrap(), (10, 1));
+        assert_eq!(parse_arguments(" 5 --loop 3 ").unwrap(), (5, 3));
+        assert!(parse_arguments("").is_err());
+        assert!(parse_arguments("0").is_err());
+        assert!(parse_arguments("10 --loop").is_err());
+        assert!(parse_arguments("10 --loop 0").is_err());
+        assert!(parse_arguments("10 5").is_err());
+    }
+}
@@ -0,0 +1,405 @@
+//! Local bookmarks of the stored messages in [`BOOKMARKS_FILE`].
+//!
+//! The `.bookmark` command remembers a message by its id from the history or the search results, with an optional
+//! note. The sender, the text and the date are copied from the last shown pages, so `.bookmarks` lists them without
+//! asking the server. `.bookmarks <id>` fetches the messages around the bookmarked one with a HistoryRequest and
+//! `.bookmarks export` writes the bookmarks to a Markdown file. The bookmarks never leave the computer, the server
+//! doesn't know who the user is.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+
+use anyhow::{anyhow, Context, Result};
+use chat::{HistoryEntry, MessageType};
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Clock;
+use crate::files;
+
+/// Path of the bookmarks.
+pub const BOOKMARKS_FILE: &str = "bookmarks.toml";
+/// Number of the messages before and after the bookmarked one fetched by `.bookmarks <id>`.
+pub const CONTEXT: u32 = 5;
+/// Number of the last shown messages the bookmarks are copied from.
+const SEEN: usize = 500;
+
+/// Bookmarked message.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Bookmark {
+    pub id: i64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub note: String,
+    /// Sender, text and date of the message, unknown if it wasn't shown before bookmarking.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nickname: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+}
+
+/// Bookmarks ordered by the message id.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Bookmarks {
+    #[serde(default)]
+    bookmarks: Vec<Bookmark>,
+}
+
+/// Action of the `.bookmark` and `.bookmarks` commands.
+#[derive(Debug, PartialEq)]
+pub enum BookmarkCommand {
+    Add {
+        id: i64,
+        note: String,
+    },
+    Remove(i64),
+    List,
+    /// Shows the messages around the bookmarked one.
+    Context(i64),
+    Export(PathBuf),
+}
+
+fn current() -> &'static RwLock<Arc<Bookmarks>> {
+    static BOOKMARKS: OnceLock<RwLock<Arc<Bookmarks>>> = OnceLock::new();
+    BOOKMARKS.get_or_init(|| RwLock::new(Arc::new(Bookmarks::load())))
+}
+
+fn seen() -> &'static Mutex<VecDeque<Bookmark>> {
+    static SEEN_ENTRIES: OnceLock<Mutex<VecDeque<Bookmark>>> = OnceLock::new();
+    SEEN_ENTRIES.get_or_init(Mutex::default)
+}
+
+/// Remembers the shown messages, so a bookmark of one of them gets its sender, text and date.
+///
+/// # Arguments
+///
+/// * `entries` - The shown page of the history or the search results.
+/// * `clock` - The clock rendering the dates of the messages.
+pub fn remember(entries: &[HistoryEntry], clock: &Clock) {
+    let mut seen = seen().lock().unwrap_or_else(|e| e.into_inner());
+    for entry in entries {
+        seen.retain(|bookmark| bookmark.id != entry.id);
+        seen.push_back(Bookmark {
+            id: entry.id,
+            note: String::new(),
+            nickname: Some(entry.nickname.clone()),
+            text: Some(entry.message.clone()),
+            date: entry
+                .timestamp
+                .map(|timestamp| clock.render_date(timestamp)),
+        });
+    }
+    while seen.len() > SEEN {
+        seen.pop_front();
+    }
+    // The bookmarks added before their messages were shown get the sender, text and date now.
+    let mut bookmarks = Bookmarks::clone(&current().read().unwrap_or_else(|e| e.into_inner()));
+    let mut filled = false;
+    for bookmark in bookmarks
+        .bookmarks
+        .iter_mut()
+        .filter(|bookmark| bookmark.text.is_none())
+    {
+        if let Some(shown) = seen.iter().find(|shown| shown.id == bookmark.id) {
+            let note = std::mem::take(&mut bookmark.note);
+            *bookmark = Bookmark {
+                note,
+                ..shown.clone()
+            };
+            filled = true;
+        }
+    }
+    if filled {
+        match bookmarks.write(Path::new(BOOKMARKS_FILE)) {
+            Ok(()) => *current().write().unwrap_or_else(|e| e.into_inner()) = Arc::new(bookmarks),
+            Err(err_msg) => eprintln!("{err_msg:#}"),
+        }
+    }
+}
+
+/// Returns the note of the bookmarked message, an empty one for a bookmark without a note.
+pub fn note(id: i64) -> Option<String> {
+    let bookmarks = current().read().unwrap_or_else(|e| e.into_inner());
+    bookmarks.get(id).map(|bookmark| bookmark.note.clone())
+}
+
+/// Returns the request of the messages around the bookmarked one, the ids of the deleted messages make it shorter.
+pub fn context_request(id: i64) -> MessageType {
+    MessageType::history_request(Some(id + CONTEXT as i64 + 1), 2 * CONTEXT + 1)
+}
+
+impl Bookmarks {
+    /// Loads the bookmarks from [`BOOKMARKS_FILE`].
+    ///
+    /// Returns no bookmarks if the file doesn't exist or is invalid.
+    pub fn load() -> Bookmarks {
+        match Bookmarks::read(Path::new(BOOKMARKS_FILE)) {
+            Ok(bookmarks) => bookmarks,
+            Err(_) if !Path::new(BOOKMARKS_FILE).exists() => Bookmarks::default(),
+            Err(err_msg) => {
+                eprintln!("{err_msg:#}, starting without bookmarks.");
+                Bookmarks::default()
+            }
+        }
+    }
+
+    fn read(path: &Path) -> Result<Bookmarks> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Reading bookmarks {} failed", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("Invalid bookmarks {}", path.display()))
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        let content = toml::to_string_pretty(self)?;
+        fs::write(path, content)
+            .with_context(|| format!("Saving bookmarks {} failed!", path.display()))
+    }
+
+    fn get(&self, id: i64) -> Option<&Bookmark> {
+        self.bookmarks.iter().find(|bookmark| bookmark.id == id)
+    }
+
+    /// Adds the bookmark or replaces the one of the same message.
+    fn add(&mut self, bookmark: Bookmark) {
+        self.bookmarks.retain(|known| known.id != bookmark.id);
+        let index = self
+            .bookmarks
+            .partition_point(|known| known.id < bookmark.id);
+        self.bookmarks.insert(index, bookmark);
+    }
+
+    /// Runs the `.bookmark` or `.bookmarks` command, saves the changed bookmarks and makes them current.
+    ///
+    /// # Returns
+    ///
+    /// The text printed to the user.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a file can't be written, or the removed message isn't bookmarked.
+    pub fn run(command: BookmarkCommand) -> Result<String> {
+        let mut bookmarks = Bookmarks::clone(&current().read().unwrap_or_else(|e| e.into_inner()));
+        let output = match command {
+            BookmarkCommand::List => return Ok(bookmarks.to_string()),
+            BookmarkCommand::Context(id) => {
+                return Err(anyhow!("Bookmark #{id} is fetched from the server!"))
+            }
+            BookmarkCommand::Export(path) => {
+                fs::write(&path, bookmarks.to_markdown())
+                    .with_context(|| format!("Exporting bookmarks {} failed!", path.display()))?;
+                return Ok(format!(
+                    "{} bookmarks exported to {}.",
+                    bookmarks.bookmarks.len(),
+                    path.display()
+                ));
+            }
+            BookmarkCommand::Add { id, note } => {
+                let seen = seen().lock().unwrap_or_else(|e| e.into_inner());
+                let shown = seen.iter().find(|bookmark| bookmark.id == id).cloned();
+                drop(seen);
+                let known = shown.is_some();
+                bookmarks.add(Bookmark {
+                    note,
+                    ..shown.unwrap_or(Bookmark {
+                        id,
+                        note: String::new(),
+                        nickname: None,
+                        text: None,
+                        date: None,
+                    })
+                });
+                match known {
+                    true => format!("Message #{id} bookmarked."),
+                    false => format!(
+                        "Message #{id} bookmarked, show it with .bookmarks {id} to see its text."
+                    ),
+                }
+            }
+            BookmarkCommand::Remove(id) => {
+                let count = bookmarks.bookmarks.len();
+                bookmarks.bookmarks.retain(|bookmark| bookmark.id != id);
+                if bookmarks.bookmarks.len() == count {
+                    return Err(anyhow!("Message #{id} is not bookmarked!"));
+                }
+                format!("Bookmark of message #{id} removed.")
+            }
+        };
+        bookmarks.write(Path::new(BOOKMARKS_FILE))?;
+        *current().write().unwrap_or_else(|e| e.into_inner()) = Arc::new(bookmarks);
+        Ok(output)
+    }
+
+    /// Renders the bookmarks as a Markdown list, with the notes quoted under the messages.
+    pub fn to_markdown(&self) -> String {
+        let mut markdown = String::from("# Bookmarks\n\n");
+        for bookmark in &self.bookmarks {
+            markdown.push_str(&format!("- #{}", bookmark.id));
+            if let Some(nickname) = &bookmark.nickname {
+                markdown.push_str(&format!(" **{nickname}**"));
+            }
+            if let Some(date) = &bookmark.date {
+                markdown.push_str(&format!(" ({date})"));
+            }
+            if let Some(text) = &bookmark.text {
+                markdown.push_str(&format!(": {}", text.replace('\n', " ")));
+            }
+            markdown.push('\n');
+            if !bookmark.note.is_empty() {
+                markdown.push_str(&format!("  > {}\n", bookmark.note));
+            }
+        }
+        markdown
+    }
+}
+
+impl fmt::Display for Bookmarks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.bookmarks.is_empty() {
+            return write!(f, "No bookmarks, add one with .bookmark message_id [note].");
+        }
+        write!(f, "Bookmarks:")?;
+        for bookmark in &self.bookmarks {
+            write!(f, "\n  #{}", bookmark.id)?;
+            if let (Some(nickname), Some(text)) = (&bookmark.nickname, &bookmark.text) {
+                write!(
+                    f,
+                    " {nickname}: {}",
+                    crate::connection::preview(&MessageType::text(text))
+                )?;
+            }
+            if !bookmark.note.is_empty() {
+                write!(f, " - {}", bookmark.note)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses the arguments of the `.bookmark` command, e.g. `42 recipe for the cake` or `remove 42`.
+///
+/// # Errors
+///
+/// This function will return an error for a missing or invalid message id.
+pub fn parse_bookmark(arguments: &str) -> Result<BookmarkCommand> {
+    let usage =
+        || anyhow!("Invalid command .bookmark, use message_id [note] or remove message_id!");
+    let arguments = arguments.trim();
+    let (first, rest) = arguments.split_once(' ').unwrap_or((arguments, ""));
+    let command = match first {
+        "remove" => BookmarkCommand::Remove(rest.trim().parse().map_err(|_| usage())?),
+        id => BookmarkCommand::Add {
+            id: id.parse().map_err(|_| usage())?,
+            note: rest.trim().to_string(),
+        },
+    };
+    Ok(command)
+}
+
+/// Parses the arguments of the `.bookmarks` command, no arguments list the bookmarks.
+///
+/// # Errors
+///
+/// This function will return an error for an unknown action, a missing path or an unclosed quote.
+pub fn parse_bookmarks(arguments: &str) -> Result<BookmarkCommand> {
+    let arguments = files::split_arguments(arguments)?;
+    let arguments: Vec<&str> = arguments.iter().map(String::as_str).collect();
+    let command = match arguments.as_slice() {
+        [] | ["list"] => BookmarkCommand::List,
+        ["export", path] => BookmarkCommand::Export(PathBuf::from(path)),
+        [id] if id.parse::<i64>().is_ok() => BookmarkCommand::Context(id.parse()?),
+        _ => {
+            return Err(anyhow!(
+                "Invalid command .bookmarks, use message_id, list or export file.md!"
+            ))
+        }
+    };
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bookmark(id: i64, note: &str) -> Bookmark {
+        Bookmark {
+            id,
+            note: note.into(),
+            nickname: Some("slava".into()),
+            text: Some("cake\nrecipe".into()),
+            date: Some("2024-06-10 08:30".into()),
+        }
+    }
+
+    #[test]
+    fn test_parse_bookmark() {
+        assert_eq!(
+            parse_bookmark("42 recipe for the cake").unwrap(),
+            BookmarkCommand::Add {
+                id: 42,
+                note: "recipe for the cake".into()
+            }
+        );
+        assert_eq!(
+            parse_bookmark("42").unwrap(),
+            BookmarkCommand::Add {
+                id: 42,
+                note: String::new()
+            }
+        );
+        assert_eq!(
+            parse_bookmark("remove 42").unwrap(),
+            BookmarkCommand::Remove(42)
+        );
+        assert!(parse_bookmark("").is_err());
+        assert!(parse_bookmark("remove").is_err());
+        assert!(parse_bookmark("cake").is_err());
+
+        assert_eq!(parse_bookmarks("").unwrap(), BookmarkCommand::List);
+        assert_eq!(parse_bookmarks("42").unwrap(), BookmarkCommand::Context(42));
+        assert_eq!(
+            parse_bookmarks("export \"my bookmarks.md\"").unwrap(),
+            BookmarkCommand::Export("my bookmarks.md".into())
+        );
+        assert!(parse_bookmarks("export").is_err());
+        assert!(parse_bookmarks("cake").is_err());
+    }
+
+    #[test]
+    fn test_bookmarks() {
+        let mut bookmarks = Bookmarks::default();
+        bookmarks.add(bookmark(42, "recipe"));
+        bookmarks.add(Bookmark {
+            id: 7,
+            note: String::new(),
+            nickname: None,
+            text: None,
+            date: None,
+        });
+        bookmarks.add(bookmark(42, ""));
+        assert_eq!(
+            bookmarks.to_markdown(),
+            "# Bookmarks\n\n- #7\n- #42 **slava** (2024-06-10 08:30): cake recipe\n"
+        );
+        assert_eq!(
+            bookmarks.to_string(),
+            "Bookmarks:\n  #7\n  #42 slava: cake recipe"
+        );
+
+        let path = std::env::temp_dir().join(format!("bookmarks-{}.toml", std::process::id()));
+        bookmarks.write(&path).unwrap();
+        let read = Bookmarks::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read, bookmarks);
+    }
+
+    #[test]
+    fn test_context_request() {
+        assert_eq!(
+            context_request(42),
+            MessageType::history_request(Some(48), 11)
+        );
+    }
+}
@@ -0,0 +1,239 @@
+//! Housekeeping of the downloaded attachments.
+//!
+//! The saved images and files grow without a limit in image-heavy rooms. The [`CleanupConfig`] from the client config
+//! deletes the downloads older than `max_age_days` and then the oldest ones until the rest fits into `max_size_mb`.
+//! The cleanup runs at the start and then every day, the `.cleanup` command runs it on demand and reports the freed
+//! space. Extracted directories are cleaned file by file and the directories left empty are removed.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::files::format_size;
+
+/// Default age in days after which a download is deleted.
+pub const MAX_AGE_DAYS: u64 = 30;
+/// Default maximal total size of the downloads in MB.
+pub const MAX_SIZE_MB: u64 = 1024;
+/// Interval of the cleanup while the client runs.
+pub const CLEANUP_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Limits of the downloads, stored in the client config. `0` turns a limit off.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct CleanupConfig {
+    pub max_age_days: u64,
+    pub max_size_mb: u64,
+}
+
+impl Default for CleanupConfig {
+    fn default() -> Self {
+        CleanupConfig {
+            max_age_days: MAX_AGE_DAYS,
+            max_size_mb: MAX_SIZE_MB,
+        }
+    }
+}
+
+impl CleanupConfig {
+    /// Returns true if neither limit is set.
+    pub fn is_off(&self) -> bool {
+        self.max_age_days == 0 && self.max_size_mb == 0
+    }
+}
+
+/// Space freed by a cleanup.
+#[derive(Debug, Default, PartialEq)]
+pub struct Freed {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+impl fmt::Display for Freed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let files = if self.files == 1 { "file" } else { "files" };
+        write!(
+            f,
+            "freed {} ({} {files})",
+            format_size(self.bytes as usize),
+            self.files
+        )
+    }
+}
+
+struct Download {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// Collects the files in the directory and its subdirectories, the symbolic links are never followed.
+fn collect(dir: &Path, downloads: &mut Vec<Download>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            collect(&entry.path(), downloads)?;
+        } else if metadata.is_file() {
+            downloads.push(Download {
+                path: entry.path(),
+                size: metadata.len(),
+                modified: metadata.modified()?,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Removes the empty subdirectories of the directory, the directory itself stays.
+fn remove_empty(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_empty(&entry.path())?;
+            // Fails for a directory which isn't empty.
+            let _ = fs::remove_dir(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the downloads exceeding the limits.
+///
+/// # Arguments
+///
+/// * `dirs` - The download directories, the missing ones are skipped.
+/// * `config` - The limits of the age and the total size.
+/// * `now` - The current time the age is measured from.
+///
+/// # Returns
+///
+/// The number and the size of the deleted files.
+///
+/// # Errors
+///
+/// This function will return an error if a directory can't be read, the files which can't be deleted are skipped.
+pub fn clean(dirs: &[&Path], config: &CleanupConfig, now: SystemTime) -> Result<Freed> {
+    let mut downloads = Vec::new();
+    for dir in dirs.iter().filter(|dir| dir.is_dir()) {
+        collect(dir, &mut downloads)
+            .with_context(|| format!("Reading {} failed!", dir.display()))?;
+    }
+    downloads.sort_by_key(|download| download.modified);
+    let max_age = Duration::from_secs(config.max_age_days * 24 * 60 * 60);
+    let max_size = config.max_size_mb * 1024 * 1024;
+    let mut total: u64 = downloads.iter().map(|download| download.size).sum();
+    let mut freed = Freed::default();
+    // The oldest first, so the first download within both limits ends the cleanup.
+    for download in downloads {
+        let expired = config.max_age_days > 0
+            && now
+                .duration_since(download.modified)
+                .is_ok_and(|age| age > max_age);
+        let over = config.max_size_mb > 0 && total > max_size;
+        if !expired && !over {
+            break;
+        }
+        if fs::remove_file(&download.path).is_ok() {
+            total -= download.size;
+            freed.files += 1;
+            freed.bytes += download.size;
+        }
+    }
+    for dir in dirs.iter().filter(|dir| dir.is_dir()) {
+        remove_empty(dir).with_context(|| format!("Cleaning {} failed!", dir.display()))?;
+    }
+    Ok(freed)
+}
+
+/// Runs [`clean`] on a blocking thread.
+///
+/// # Errors
+///
+/// This function will return an error if the cleanup fails.
+pub async fn run(dirs: Vec<PathBuf>, config: CleanupConfig) -> Result<Freed> {
+    tokio::task::spawn_blocking(move || {
+        let dirs: Vec<&Path> = dirs.iter().map(PathBuf::as_path).collect();
+        clean(&dirs, &config, SystemTime::now())
+    })
+    .await?
+}
+
+/// Cleans the downloads now and then every [`CLEANUP_INTERVAL`], unless both limits are off.
+pub fn spawn(dirs: Vec<PathBuf>, config: CleanupConfig) {
+    if config.is_off() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            match run(dirs.clone(), config).await {
+                Ok(freed) if freed.files > 0 => println!("Cleanup of the downloads {freed}."),
+                Ok(_) => (),
+                Err(err_msg) => eprintln!("Cleanup error: {:#}", err_msg),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn download(path: &Path, size: usize, modified: SystemTime) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, vec![0; size]).unwrap();
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn test_clean() {
+        let root = std::env::temp_dir().join(format!("chat-cleanup-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (images, files) = (root.join("IMAGES"), root.join("FILES"));
+        let now = SystemTime::now();
+        download(&images.join("old.png"), 100, now - 40 * DAY);
+        download(&files.join("photos/a.jpg"), 600 * 1024, now - 3 * DAY);
+        download(&files.join("b.txt"), 600 * 1024, now - 2 * DAY);
+        download(&images.join("new.png"), 10, now);
+
+        let config = CleanupConfig {
+            max_age_days: 30,
+            max_size_mb: 1,
+        };
+        let dirs = [images.as_path(), files.as_path(), &root.join("missing")];
+        let freed = clean(&dirs, &config, now).unwrap();
+        assert_eq!(
+            freed,
+            Freed {
+                files: 2,
+                bytes: 100 + 600 * 1024
+            }
+        );
+        assert_eq!(freed.to_string(), "freed 600.1 KB (2 files)");
+        assert!(!images.join("old.png").exists());
+        assert!(!files.join("photos").exists());
+        assert!(files.join("b.txt").exists());
+        assert!(images.join("new.png").exists());
+
+        let off = CleanupConfig {
+            max_age_days: 0,
+            max_size_mb: 0,
+        };
+        assert!(off.is_off());
+        assert_eq!(
+            clean(&dirs, &off, now + 100 * DAY).unwrap(),
+            Freed::default()
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+//! Clock skew to the server and the local time of the messages.
+//!
+//! The server announces its time in the Welcome message and in periodic pings, the difference from the local clock is
+//! the skew. The delivered messages carry the time in the server's clock, so their timestamps are shifted by the skew
+//! and rendered in the local time zone. The messages are stamped with the local clock, the server replaces the
+//! timestamps of clients with a clock too far off.
+//!
+//! The live messages show their age like `5 min ago` with the [`TimeStyle::Relative`] style, the stored messages of
+//! the history and the search always show the date and the time. The age is measured in the absolute time and the
+//! dates are converted by the rules of the time zone, so a daylight saving time change doesn't shift either of them,
+//! see [`chat::time`].
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use chat::time::{format_age, format_time, DATE_FORMAT};
+use jiff::tz::TimeZone;
+use serde::{Deserialize, Serialize};
+
+/// Skew from which the user is warned about the wrong clock, in milliseconds.
+pub const WARN_SKEW: i64 = 2_000;
+
+/// Rendering of the times of the live messages selected by the user.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeStyle {
+    /// The age of the message, e.g. `just now` or `5 min ago`, the date and the time after a day.
+    #[default]
+    Relative,
+    /// The local time of the message, e.g. `14:03:27`.
+    Absolute,
+}
+
+impl FromStr for TimeStyle {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "relative" => Ok(TimeStyle::Relative),
+            "absolute" => Ok(TimeStyle::Absolute),
+            _ => Err(anyhow!("Invalid time style {s}, use relative or absolute!")),
+        }
+    }
+}
+
+impl fmt::Display for TimeStyle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Relative => write!(f, "relative"),
+            Self::Absolute => write!(f, "absolute"),
+        }
+    }
+}
+
+/// Clock skew and time style shared by the reading and writing loops.
+#[derive(Clone, Default)]
+pub struct Clock {
+    /// Server time minus the local time in milliseconds.
+    skew: Arc<AtomicI64>,
+    relative: Arc<AtomicBool>,
+}
+
+impl Clock {
+    /// Updates the skew from the announced server time.
+    pub fn sync(&self, server_time: u64) {
+        let skew = server_time as i64 - chat::unix_millis() as i64;
+        self.skew.store(skew, Ordering::Relaxed);
+    }
+
+    /// Returns the server time minus the local time in milliseconds.
+    pub fn skew(&self) -> i64 {
+        self.skew.load(Ordering::Relaxed)
+    }
+
+    /// Selects the rendering of the live messages.
+    pub fn set_style(&self, style: TimeStyle) {
+        self.relative
+            .store(style == TimeStyle::Relative, Ordering::Relaxed);
+    }
+
+    /// Renders the time of the live message by the [`TimeStyle`], e.g. `5 min ago` or `14:03:27`.
+    pub fn render(&self, timestamp: u64) -> String {
+        let local = self.local(timestamp);
+        if self.relative.load(Ordering::Relaxed) {
+            format_age(local, chat::unix_millis() as i64, &TimeZone::system())
+        } else {
+            format_time(local, &TimeZone::system(), "%H:%M:%S")
+        }
+    }
+
+    /// Renders the date and the time of a stored message in the local time zone, e.g. `2024-06-10 14:03`.
+    pub fn render_date(&self, timestamp: u64) -> String {
+        format_time(self.local(timestamp), &TimeZone::system(), DATE_FORMAT)
+    }
+
+    /// Describes a noticeable skew, e.g. `your clock is 93 s behind the server`.
+    pub fn describe(&self) -> Option<String> {
+        describe_skew(self.skew())
+    }
+
+    /// Converts the server time to the local clock.
+    fn local(&self, timestamp: u64) -> i64 {
+        timestamp as i64 - self.skew()
+    }
+}
+
+fn describe_skew(skew: i64) -> Option<String> {
+    if skew.abs() < WARN_SKEW {
+        return None;
+    }
+    let direction = if skew > 0 { "behind" } else { "ahead of" };
+    Some(format!(
+        "your clock is {} s {direction} the server",
+        skew.abs() / 1000
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_style() {
+        assert_eq!(
+            "absolute".parse::<TimeStyle>().unwrap(),
+            TimeStyle::Absolute
+        );
+        assert!("ago".parse::<TimeStyle>().is_err());
+
+        let clock = Clock::default();
+        assert!(clock.render(chat::unix_millis()).contains(':'));
+        clock.set_style(TimeStyle::Relative);
+        assert_eq!(clock.render(chat::unix_millis()), "just now");
+    }
+
+    #[test]
+    fn test_skew() {
+        let clock = Clock::default();
+        clock.sync(chat::unix_millis() + 93_000);
+        assert!((92_000..=93_000).contains(&clock.skew()));
+        let local = clock.local(1_000_000);
+        assert!((907_000..=908_000).contains(&local));
+        assert_eq!(
+            describe_skew(93_400).as_deref(),
+            Some("your clock is 93 s behind the server")
+        );
+        assert_eq!(
+            describe_skew(-5_000).as_deref(),
+            Some("your clock is 5 s ahead of the server")
+        );
+        assert_eq!(describe_skew(1_500), None);
+    }
+}
@@ -0,0 +1,130 @@
+//! Client configuration stored in [`CONFIG_FILE`].
+//!
+//! Missing file or missing keys fall back to the defaults, so the file only holds the settings changed by the user.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::alerts::Alert;
+use crate::cleanup::CleanupConfig;
+use crate::clock::TimeStyle;
+use crate::downloads::AutoOpen;
+use crate::idle::IDLE_TIMEOUT;
+use crate::images::ImageConfig;
+use crate::keys::KeysConfig;
+use crate::sound::SoundMode;
+
+/// Path of the configuration file.
+pub const CONFIG_FILE: &str = "client.json";
+/// Argument printing the effective configuration instead of starting the client.
+const PRINT_CONFIG: &str = "--print-config";
+
+/// Client settings.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    /// Notification played when a message is received.
+    pub sound: SoundMode,
+    /// Rendering of the times of the live messages.
+    pub time_style: TimeStyle,
+    /// Rules opening the received attachments automatically.
+    pub auto_open: AutoOpen,
+    /// Notifications besides the sound, e.g. `["flash", "counter"]`.
+    pub alerts: Vec<Alert>,
+    /// Seconds without any data from the server after which the connection is reconnected, `0` never.
+    pub idle_timeout: u64,
+    /// Limits of the age and the total size of the downloads.
+    pub cleanup: CleanupConfig,
+    /// Format and maximal dimension the `.image` command converts the images to.
+    pub images: ImageConfig,
+    /// Whether `.file` and `.image` describe the attachments and wait for a confirmation before sending.
+    pub confirm_attachments: bool,
+    /// Keys of the quick actions, e.g. `"reply": "ctrl+r"`.
+    pub keys: KeysConfig,
+    /// Whether the first start has offered the `.tutorial` already.
+    pub tutorial_offered: bool,
+    /// Whether the texts, code, images and files are encrypted end to end, see [`crate::encryption`].
+    pub encryption: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            sound: Default::default(),
+            time_style: Default::default(),
+            auto_open: Default::default(),
+            alerts: Vec::new(),
+            idle_timeout: IDLE_TIMEOUT,
+            cleanup: Default::default(),
+            images: Default::default(),
+            confirm_attachments: true,
+            keys: Default::default(),
+            tutorial_offered: false,
+            encryption: false,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from [`CONFIG_FILE`].
+    ///
+    /// Returns the default configuration if the file doesn't exist or is invalid.
+    pub fn load() -> Config {
+        let Ok(content) = std::fs::read_to_string(CONFIG_FILE) else {
+            return Config::default();
+        };
+        serde_json::from_str(&content).unwrap_or_else(|err_msg| {
+            eprintln!("Invalid config {CONFIG_FILE}, using defaults: {err_msg}");
+            Config::default()
+        })
+    }
+
+    /// Saves the configuration to [`CONFIG_FILE`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file can't be written.
+    pub fn save(&self) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        std::fs::write(CONFIG_FILE, content)
+            .with_context(|| format!("Saving config {CONFIG_FILE} failed!"))
+    }
+
+    /// Loads the configuration, applies the change and saves it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file can't be written.
+    pub fn update<F: FnOnce(&mut Config)>(change: F) -> Result<()> {
+        let mut config = Config::load();
+        change(&mut config);
+        config.save()
+    }
+}
+
+/// Removes the [`PRINT_CONFIG`] argument.
+///
+/// # Returns
+///
+/// True if the argument was present.
+pub fn take_print_config(arguments: &mut Vec<String>) -> bool {
+    let count = arguments.len();
+    arguments.retain(|argument| argument != PRINT_CONFIG);
+    arguments.len() != count
+}
+
+/// Renders the effective configuration, the config with the defaults and the command line, as JSON.
+///
+/// The client config holds no secrets, so nothing is redacted.
+///
+/// # Errors
+///
+/// This function will return an error if the configuration can't be serialized.
+pub fn render_effective(config: &Config, address: &chat::Address, verbose: bool) -> Result<String> {
+    let effective = serde_json::json!({
+        "address": address.to_string(),
+        "verbose": verbose,
+        "config": config,
+    });
+    Ok(serde_json::to_string_pretty(&effective)?)
+}
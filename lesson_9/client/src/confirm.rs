@@ -0,0 +1,196 @@
+//! Confirmation of the attachments before sending.
+//!
+//! The `.file` and `.image` commands print a line per file with its resolved path, size, detected type and the
+//! dimensions of an image, then wait for Enter to send or Esc (followed by Enter, the terminal reads whole lines) to
+//! cancel. It catches a wrong file or a huge one before it's sent. The confirmation is asked only when both stdin and
+//! stdout are terminals and can be turned off by `"confirm_attachments": false` in the client config.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use image::ImageReader;
+
+use crate::files::format_size;
+
+/// Number of the leading bytes read to detect the type of a file.
+const HEADER_LEN: usize = 512;
+
+/// Attachment described before sending.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attachment {
+    pub path: PathBuf,
+    pub size: u64,
+    pub kind: &'static str,
+    /// Width and height of an image.
+    pub dimensions: Option<(u32, u32)>,
+}
+
+/// Answer of the user.
+#[derive(Debug, PartialEq)]
+pub enum Answer {
+    Send,
+    Cancel,
+}
+
+/// Resolves the path and detects the type of the file from its leading bytes.
+///
+/// # Errors
+///
+/// This function will return an error if the file doesn't exist or can't be read.
+pub fn inspect(path: &Path) -> Result<Attachment> {
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("Resolving {} failed!", path.display()))?;
+    let file = File::open(&path).with_context(|| format!("Opening {} failed!", path.display()))?;
+    let size = file.metadata()?.len();
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    file.take(HEADER_LEN as u64).read_to_end(&mut header)?;
+    let format = image::guess_format(&header).ok();
+    let dimensions = match format {
+        Some(_) => ImageReader::new(BufReader::new(File::open(&path)?))
+            .with_guessed_format()?
+            .into_dimensions()
+            .ok(),
+        None => None,
+    };
+    let kind = match format {
+        Some(format) => format.extensions_str().first().copied().unwrap_or("image"),
+        None => detect_kind(&header),
+    };
+    Ok(Attachment {
+        path,
+        size,
+        kind,
+        dimensions,
+    })
+}
+
+/// Detects the type of a file which is not an image by the signatures of the common formats.
+pub fn detect_kind(header: &[u8]) -> &'static str {
+    const SIGNATURES: [(&[u8], &str); 6] = [
+        (b"%PDF-", "pdf"),
+        (b"PK\x03\x04", "zip"),
+        (b"\x1f\x8b", "gzip"),
+        (b"7z\xbc\xaf\x27\x1c", "7z"),
+        (b"\x7fELF", "executable"),
+        (b"MZ", "executable"),
+    ];
+    if let Some((_, kind)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| header.starts_with(signature))
+    {
+        return kind;
+    }
+    if header.len() > 262 && &header[257..262] == b"ustar" {
+        return "tar";
+    }
+    // The header may end in the middle of a character.
+    let text = match std::str::from_utf8(header) {
+        Ok(text) => text,
+        Err(err_msg) if err_msg.error_len().is_none() => {
+            std::str::from_utf8(&header[..err_msg.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return "binary",
+    };
+    let is_text = text
+        .chars()
+        .all(|c| !c.is_control() || c.is_ascii_whitespace());
+    if is_text {
+        "text"
+    } else {
+        "binary"
+    }
+}
+
+/// Describes the attachment in one line, e.g. `/home/eva/cat.jpg, 2.4 MB, jpg 4032x3024`.
+pub fn describe(attachment: &Attachment) -> String {
+    let size = format_size(attachment.size as usize);
+    let mut line = format!("{}, {size}, {}", attachment.path.display(), attachment.kind);
+    if let Some((width, height)) = attachment.dimensions {
+        line.push_str(&format!(" {width}x{height}"));
+    }
+    line
+}
+
+/// Parses the answer, an empty line sends, a line with Esc or anything else cancels.
+pub fn parse_answer(input: &str) -> Answer {
+    match input.trim() {
+        "" | "y" | "yes" => Answer::Send,
+        _ => Answer::Cancel,
+    }
+}
+
+/// Describes the attachments and asks the user to confirm sending them.
+///
+/// # Returns
+///
+/// True if the user confirmed sending.
+///
+/// # Errors
+///
+/// This function will return an error if a file can't be inspected or the terminal can't be read.
+pub fn confirm(paths: &[PathBuf]) -> Result<bool> {
+    let mut total = 0;
+    for path in paths {
+        let attachment = inspect(path)?;
+        total += attachment.size;
+        println!("  {}", describe(&attachment));
+    }
+    if paths.len() > 1 {
+        println!(
+            "  {} files, {} total",
+            paths.len(),
+            format_size(total as usize)
+        );
+    }
+    print!("Enter to send, Esc to cancel: ");
+    io::stdout().flush()?;
+    let mut input = String::new();
+    if io::stdin().read_line(&mut input)? == 0 {
+        return Ok(false);
+    }
+    Ok(parse_answer(&input) == Answer::Send)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_answer() {
+        assert_eq!(parse_answer("\n"), Answer::Send);
+        assert_eq!(parse_answer("yes\r\n"), Answer::Send);
+        assert_eq!(parse_answer("\u{1b}\n"), Answer::Cancel);
+        assert_eq!(parse_answer("n\n"), Answer::Cancel);
+    }
+
+    #[test]
+    fn test_detect_kind() {
+        assert_eq!(detect_kind(b"%PDF-1.7\n"), "pdf");
+        assert_eq!(detect_kind(b"PK\x03\x04rest"), "zip");
+        assert_eq!(detect_kind("hello\tč\n".as_bytes()), "text");
+        assert_eq!(detect_kind(&"čč".as_bytes()[..3]), "text");
+        assert_eq!(detect_kind(b"\x00\x01\x02"), "binary");
+    }
+
+    #[test]
+    fn test_inspect() {
+        let dir = std::env::temp_dir().join(format!("chat-confirm-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let png = dir.join("dot.png");
+        image::RgbImage::new(3, 2).save(&png).unwrap();
+        let attachment = inspect(&png).unwrap();
+        assert_eq!(attachment.kind, "png");
+        assert_eq!(attachment.dimensions, Some((3, 2)));
+        assert!(attachment.path.is_absolute());
+        assert!(describe(&attachment).ends_with(" B, png 3x2"));
+        let notes = dir.join("notes.txt");
+        std::fs::write(&notes, "hello").unwrap();
+        let attachment = inspect(&notes).unwrap();
+        assert_eq!((attachment.size, attachment.kind), (5, "text"));
+        assert!(inspect(&dir.join("missing")).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
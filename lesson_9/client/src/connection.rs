@@ -0,0 +1,441 @@
+//! Connection to the server surviving server restarts.
+//!
+//! The reading and the writing loop share a [`Link`] with the write half of the connection and its [`State`] in a
+//! watch channel. When either loop notices the broken connection, the link is marked as disconnected and the reading
+//! task reconnects with a growing delay. Messages sent in the meantime, or whose sending failed, are queued in the
+//! [`Outbox`] and sent in order right after the reconnection before any newer message, followed by a Sync message.
+//! They stay queued until the server acknowledges the Sync, so no typed message is lost even if the client quits.
+//! The link also remembers a preview of the last sent message, so an error the server replies with can be shown next
+//! to it, and tracks the [`Deliveries`] of the messages sent with a server acknowledging them.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use chat::codec::MessageCodec;
+use chat::{Address, Message, MessageType, ACK_ANNOTATION};
+use futures_util::SinkExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{watch, Mutex};
+use tokio_util::codec::FramedWrite;
+
+use crate::delivery::{self, Deliveries};
+use crate::outbox::Outbox;
+use crate::quality::Quality;
+
+/// Delay before the first reconnection attempt, doubled after every failed attempt.
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Maximal delay between the reconnection attempts.
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// Maximal length of the preview of the last sent message in characters.
+pub const PREVIEW_LEN: usize = 40;
+
+/// State of the connection shared by the reading and the writing loop.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Connected,
+    Disconnected,
+}
+
+/// Result of [`Link::send`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sent {
+    Delivered,
+    /// The message is queued until the reconnection.
+    Buffered,
+    /// The message waits for the reconnection, but saving it to the outbox failed, so it is lost if the client quits.
+    Unsaved,
+}
+
+struct Writer {
+    stream: Option<FramedWrite<OwnedWriteHalf, MessageCodec>>,
+    /// Messages waiting for the reconnection.
+    pending: VecDeque<Message>,
+    /// Queued messages sent after the reconnection, waiting for the acknowledgement of the Sync.
+    unacked: VecDeque<Message>,
+    /// Id of the last sent Sync message.
+    sync: u32,
+    outbox: Outbox,
+    last_sent: Option<String>,
+    /// The server of the current connection acknowledges the messages.
+    acks: bool,
+    /// Timestamp of the last stamped message, the next one gets a later one.
+    last_stamp: u64,
+}
+
+impl Writer {
+    /// Saves the messages not acknowledged by the server, in the order they are sent.
+    fn save(&self) -> Result<()> {
+        self.outbox.save(self.unacked.iter().chain(&self.pending))
+    }
+}
+
+/// Write half of the connection, replaced on every reconnection.
+#[derive(Clone)]
+pub struct Link {
+    writer: Arc<Mutex<Writer>>,
+    state: Arc<watch::Sender<State>>,
+    deliveries: Deliveries,
+    quality: Quality,
+}
+
+impl Link {
+    /// Creates a disconnected link with the messages queued in the outbox, e.g. by the last run of the client.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the outbox can't be loaded.
+    pub fn new(outbox: Outbox) -> Result<Link> {
+        let pending = outbox.load()?.into();
+        Ok(Link {
+            writer: Arc::new(Mutex::new(Writer {
+                stream: None,
+                pending,
+                unacked: VecDeque::new(),
+                sync: 0,
+                outbox,
+                last_sent: None,
+                acks: false,
+                last_stamp: 0,
+            })),
+            state: Arc::new(watch::Sender::new(State::Disconnected)),
+            deliveries: Deliveries::default(),
+            quality: Quality::default(),
+        })
+    }
+
+    /// Returns the current state of the connection.
+    pub fn state(&self) -> State {
+        *self.state.borrow()
+    }
+
+    /// Stamps the message with the local time, later than any stamped before, and sends it, or queues it until the
+    /// reconnection if the connection is broken. A sent message the server acknowledges waits for its Ack.
+    ///
+    /// The time the message was created at by [`Message::from`] is replaced, the server gets the time of sending.
+    ///
+    /// # Returns
+    ///
+    /// Whether the message was delivered or queued.
+    pub async fn send(&self, mut message: Message) -> Sent {
+        message.timestamp = None;
+        self.resend(message).await
+    }
+
+    /// Sends the message like [`Link::send`] keeping its timestamp, so the Ack of a retried message matches the
+    /// first attempt.
+    ///
+    /// # Returns
+    ///
+    /// Whether the message was delivered or queued.
+    pub async fn resend(&self, mut message: Message) -> Sent {
+        let mut writer = self.writer.lock().await;
+        let stamp = chat::unix_millis().max(writer.last_stamp + 1);
+        writer.last_stamp = writer
+            .last_stamp
+            .max(*message.timestamp.get_or_insert(stamp));
+        writer.last_sent = Some(preview(&message.message));
+        if writer.pending.is_empty() {
+            let acked = writer.acks && delivery::expects_ack(&message.message);
+            if acked
+                && !message
+                    .annotations
+                    .iter()
+                    .any(|(key, _)| key == ACK_ANNOTATION)
+            {
+                message.annotate(ACK_ANNOTATION, "");
+            }
+            if let Some(stream) = writer.stream.as_mut() {
+                if stream.send(&message).await.is_ok() {
+                    if acked {
+                        self.deliveries.sent(&message, Instant::now());
+                    }
+                    return Sent::Delivered;
+                }
+                writer.stream = None;
+                self.state.send_replace(State::Disconnected);
+            }
+        }
+        let saved = writer.outbox.push(&message);
+        writer.pending.push_back(message);
+        match saved {
+            Ok(()) => Sent::Buffered,
+            Err(_) => Sent::Unsaved,
+        }
+    }
+
+    /// Sends the message only if the server is connected, nothing is buffered.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the connection is broken.
+    pub async fn send_now(&self, message: &Message) -> Result<()> {
+        let mut writer = self.writer.lock().await;
+        let stream = writer
+            .stream
+            .as_mut()
+            .ok_or(anyhow!("Not connected to the server!"))?;
+        if let Err(err_msg) = stream.send(message).await {
+            writer.stream = None;
+            self.state.send_replace(State::Disconnected);
+            return Err(err_msg.into());
+        }
+        Ok(())
+    }
+
+    /// Returns the preview of the last message sent by [`Link::send`].
+    pub async fn last_sent(&self) -> Option<String> {
+        self.writer.lock().await.last_sent.clone()
+    }
+
+    /// Returns the number of queued messages not acknowledged by the server yet.
+    pub async fn pending(&self) -> usize {
+        let writer = self.writer.lock().await;
+        writer.pending.len() + writer.unacked.len()
+    }
+
+    /// Marks the connection as broken.
+    pub async fn detach(&self) {
+        self.writer.lock().await.stream = None;
+        self.state.send_replace(State::Disconnected);
+    }
+
+    /// Waits until either loop marks the connection as broken.
+    pub async fn disconnected(&self) {
+        let mut state = self.state.subscribe();
+        let _ = state.wait_for(|state| *state == State::Disconnected).await;
+    }
+
+    /// Uses the new connection, sending the queued messages first followed by a Sync message.
+    ///
+    /// The queued messages are stamped again with the time of sending, and stay queued until [`Link::acknowledge`].
+    ///
+    /// # Returns
+    ///
+    /// The number of the sent queued messages.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the new connection breaks too, the messages stay queued.
+    pub async fn attach(&self, stream: OwnedWriteHalf) -> Result<usize> {
+        let mut stream = FramedWrite::new(stream, MessageCodec::default());
+        let mut writer = self.writer.lock().await;
+        writer.acks = false;
+        self.quality.reset_probe();
+        let mut pending = std::mem::take(&mut writer.pending);
+        writer.unacked.append(&mut pending);
+        let flushed = writer.unacked.len();
+        if flushed > 0 {
+            writer.sync = writer.sync.wrapping_add(1);
+            let now = chat::unix_millis();
+            for message in writer.unacked.iter_mut() {
+                message.timestamp = Some(now);
+                stream.feed(&*message).await?;
+            }
+            let sync = Message::from(
+                &writer.unacked[0].nickname,
+                MessageType::Sync { id: writer.sync },
+            );
+            stream.send(sync).await?;
+        }
+        writer.stream = Some(stream);
+        self.state.send_replace(State::Connected);
+        Ok(flushed)
+    }
+
+    /// Adds the checksums to the frames sent over the current connection, for a server with the `checksum`
+    /// capability. A new connection starts without them until its server announces the capability again.
+    pub async fn enable_checksum(&self) {
+        if let Some(stream) = self.writer.lock().await.stream.as_mut() {
+            stream.encoder_mut().set_checksum(true);
+        }
+    }
+
+    /// Compresses the big messages sent over the current connection, for a server with the `compression` capability.
+    /// A new connection starts without it until its server announces the capability again.
+    pub async fn enable_compression(&self) {
+        if let Some(stream) = self.writer.lock().await.stream.as_mut() {
+            stream.encoder_mut().set_compression(true);
+        }
+    }
+
+    /// Flags the messages sent over the current connection for the acknowledgement, for a server with the `acks`
+    /// capability. A new connection starts without it until its server announces the capability again.
+    pub async fn enable_acks(&self) {
+        self.writer.lock().await.acks = true;
+    }
+
+    /// Returns the messages waiting for the acknowledgement of the server.
+    pub fn deliveries(&self) -> &Deliveries {
+        &self.deliveries
+    }
+
+    /// Returns the measured quality of the connection.
+    pub fn quality(&self) -> &Quality {
+        &self.quality
+    }
+
+    /// Removes the queued messages sent before the acknowledged Sync from the outbox.
+    ///
+    /// # Returns
+    ///
+    /// The number of the acknowledged messages, zero for an outdated Sync.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the outbox can't be saved.
+    pub async fn acknowledge(&self, id: u32) -> Result<usize> {
+        let mut writer = self.writer.lock().await;
+        if id != writer.sync {
+            return Ok(0);
+        }
+        let acknowledged = writer.unacked.len();
+        writer.unacked.clear();
+        writer.save()?;
+        Ok(acknowledged)
+    }
+}
+
+/// Returns the message shortened to [`PREVIEW_LEN`] characters, or its type in parentheses if it has no text.
+pub fn preview(message: &MessageType) -> String {
+    let (msg_type, text) = message.get_type_and_message();
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.is_empty() {
+        return format!("({})", msg_type.to_lowercase());
+    }
+    match text.char_indices().nth(PREVIEW_LEN) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    }
+}
+
+/// Connects to the server, retrying with a growing delay until it succeeds.
+pub async fn reconnect(address: &Address) -> (OwnedReadHalf, OwnedWriteHalf) {
+    let mut delay = RECONNECT_DELAY;
+    loop {
+        tokio::time::sleep(delay).await;
+        if let Ok(stream) = TcpStream::connect(address.to_string()).await {
+            return stream.into_split();
+        }
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat::MessageType;
+    use tokio::net::TcpListener;
+
+    async fn pair(listener: &TcpListener) -> (OwnedWriteHalf, TcpStream) {
+        let address = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(address), listener.accept());
+        let (_, writer) = client.unwrap().into_split();
+        (writer, server.unwrap().0)
+    }
+
+    async fn read_text(stream: &mut TcpStream) -> String {
+        match Message::read(stream).await.unwrap().message {
+            MessageType::Text(text) => text,
+            other => panic!("unexpected message {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queued_until_acknowledged() {
+        let path = std::env::temp_dir().join(format!("chat-link-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let link = Link::new(Outbox::new(&path)).unwrap();
+        assert_eq!(link.state(), State::Disconnected);
+        let (writer, mut server) = pair(&listener).await;
+        assert_eq!(link.attach(writer).await.unwrap(), 0);
+        let text = |text: &str| Message::from("slava", MessageType::text(text));
+        assert_eq!(link.last_sent().await, None);
+        assert_eq!(link.send(text("first")).await, Sent::Delivered);
+        assert_eq!(read_text(&mut server).await, "first");
+        assert_eq!(link.last_sent().await.as_deref(), Some("first"));
+
+        link.detach().await;
+        link.disconnected().await;
+        assert_eq!(link.state(), State::Disconnected);
+        assert_eq!(link.send(text("second")).await, Sent::Buffered);
+        assert_eq!(link.send(text("third")).await, Sent::Buffered);
+        assert!(link.send_now(&text("bench")).await.is_err());
+        assert_eq!(link.pending().await, 2);
+        // The queue survives a restart of the client.
+        let link = Link::new(Outbox::new(&path)).unwrap();
+        assert_eq!(link.pending().await, 2);
+
+        let (writer, mut server) = pair(&listener).await;
+        assert_eq!(link.attach(writer).await.unwrap(), 2);
+        assert_eq!(link.state(), State::Connected);
+        assert_eq!(link.send(text("fourth")).await, Sent::Delivered);
+        for expected in ["second", "third"] {
+            assert_eq!(read_text(&mut server).await, expected);
+        }
+        let sync = Message::read(&mut server).await.unwrap().message;
+        assert_eq!(sync, MessageType::Sync { id: 1 });
+        assert_eq!(read_text(&mut server).await, "fourth");
+        assert_eq!(link.pending().await, 2);
+        assert_eq!(link.acknowledge(0).await.unwrap(), 0);
+        assert_eq!(link.acknowledge(1).await.unwrap(), 2);
+        assert_eq!(link.pending().await, 0);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_acks() {
+        let path = std::env::temp_dir().join(format!("chat-acks-{}.jsonl", std::process::id()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let link = Link::new(Outbox::new(&path)).unwrap();
+        let (writer, mut server) = pair(&listener).await;
+        link.attach(writer).await.unwrap();
+        link.send(Message::from("slava", MessageType::text("before")))
+            .await;
+        let before = Message::read(&mut server).await.unwrap();
+        assert!(before.annotations.is_empty());
+
+        link.enable_acks().await;
+        let mut stamps = Vec::new();
+        for text in ["first", "second"] {
+            link.send(Message::from("slava", MessageType::text(text)))
+                .await;
+            let sent = Message::read(&mut server).await.unwrap();
+            assert_eq!(
+                sent.annotations,
+                [(ACK_ANNOTATION.to_string(), String::new())]
+            );
+            stamps.push(sent.timestamp.unwrap());
+        }
+        // Even the messages sent in the same millisecond get distinct timestamps.
+        assert!(before.timestamp.unwrap() < stamps[0] && stamps[0] < stamps[1]);
+        let delivered = link.deliveries().acknowledge(stamps[1]);
+        assert_eq!(delivered.as_deref(), Some("second"));
+        assert_eq!(
+            link.deliveries().acknowledge(before.timestamp.unwrap()),
+            None
+        );
+
+        // A retry keeps the timestamp of the first attempt, a new message gets the time of sending.
+        let mut retried = Message::from("slava", MessageType::text("retried"));
+        retried.timestamp = Some(stamps[0]);
+        link.resend(retried.clone()).await;
+        let resent = Message::read(&mut server).await.unwrap();
+        assert_eq!(resent.timestamp, Some(stamps[0]));
+        link.send(retried).await;
+        let sent = Message::read(&mut server).await.unwrap();
+        assert!(sent.timestamp.unwrap() > stamps[1]);
+    }
+
+    #[test]
+    fn test_preview() {
+        assert_eq!(preview(&MessageType::text("hello\n  world")), "hello world");
+        assert_eq!(preview(&MessageType::Image(vec![1, 2])), "(image)");
+        let long = "ž".repeat(PREVIEW_LEN + 5);
+        let expected = format!("{}...", "ž".repeat(PREVIEW_LEN));
+        assert_eq!(preview(&MessageType::text(&long)), expected);
+    }
+}
@@ -0,0 +1,262 @@
+//! Local address book stored in [`CONTACTS_FILE`].
+//!
+//! The `.contact` command assigns an alias and a note to a nickname, the alias is printed next to the nickname of the
+//! received messages and in the `.who` list. The contacts never leave the computer, `.contact export` and
+//! `.contact import` move them between computers as a TOML file with a `[contacts.<nickname>]` table per contact.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::files;
+
+/// Path of the address book.
+pub const CONTACTS_FILE: &str = "contacts.toml";
+
+/// Alias and note of a nickname.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Contact {
+    pub alias: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub note: String,
+}
+
+/// Contacts by their nicknames.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Contacts {
+    #[serde(default)]
+    contacts: BTreeMap<String, Contact>,
+}
+
+/// Action of the `.contact` command.
+#[derive(Debug, PartialEq)]
+pub enum ContactCommand {
+    Add { nickname: String, contact: Contact },
+    Remove(String),
+    List,
+    Export(PathBuf),
+    Import(PathBuf),
+}
+
+fn current() -> &'static RwLock<Arc<Contacts>> {
+    static CONTACTS: OnceLock<RwLock<Arc<Contacts>>> = OnceLock::new();
+    CONTACTS.get_or_init(|| RwLock::new(Arc::new(Contacts::load())))
+}
+
+/// Returns the alias of the nickname, if it is a contact.
+pub fn alias(nickname: &str) -> Option<String> {
+    let contacts = current().read().unwrap_or_else(|e| e.into_inner());
+    contacts.contacts.get(nickname).map(|c| c.alias.clone())
+}
+
+/// Returns the nickname followed by its alias in parentheses, if it is a contact.
+pub fn describe(nickname: &str) -> String {
+    match alias(nickname) {
+        Some(alias) => format!("{nickname} ({alias})"),
+        None => nickname.to_string(),
+    }
+}
+
+impl Contacts {
+    /// Loads the contacts from [`CONTACTS_FILE`].
+    ///
+    /// Returns no contacts if the file doesn't exist or is invalid.
+    pub fn load() -> Contacts {
+        match Contacts::read(Path::new(CONTACTS_FILE)) {
+            Ok(contacts) => contacts,
+            Err(_) if !Path::new(CONTACTS_FILE).exists() => Contacts::default(),
+            Err(err_msg) => {
+                eprintln!("{err_msg:#}, starting without contacts.");
+                Contacts::default()
+            }
+        }
+    }
+
+    fn read(path: &Path) -> Result<Contacts> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading contacts {} failed", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("Invalid contacts {}", path.display()))
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        let content = toml::to_string_pretty(self)?;
+        std::fs::write(path, content)
+            .with_context(|| format!("Saving contacts {} failed!", path.display()))
+    }
+
+    /// Runs the `.contact` command, saves the changed contacts and makes them current.
+    ///
+    /// # Returns
+    ///
+    /// The text printed to the user.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a file can't be read or written, or the removed nickname isn't a
+    /// contact.
+    pub fn run(command: ContactCommand) -> Result<String> {
+        let mut contacts = Contacts::clone(&current().read().unwrap_or_else(|e| e.into_inner()));
+        let output = match command {
+            ContactCommand::List => return Ok(contacts.to_string()),
+            ContactCommand::Export(path) => {
+                contacts.write(&path)?;
+                return Ok(format!(
+                    "{} contacts exported to {}.",
+                    contacts.contacts.len(),
+                    path.display()
+                ));
+            }
+            ContactCommand::Add { nickname, contact } => {
+                let output = format!("{nickname} is {}.", contact.alias);
+                contacts.contacts.insert(nickname, contact);
+                output
+            }
+            ContactCommand::Remove(nickname) => {
+                contacts
+                    .contacts
+                    .remove(&nickname)
+                    .ok_or(anyhow!("{nickname} is not a contact!"))?;
+                format!("{nickname} removed from the contacts.")
+            }
+            ContactCommand::Import(path) => {
+                let imported = Contacts::read(&path)?;
+                let count = imported.contacts.len();
+                contacts.merge(imported);
+                format!("{count} contacts imported from {}.", path.display())
+            }
+        };
+        contacts.write(Path::new(CONTACTS_FILE))?;
+        *current().write().unwrap_or_else(|e| e.into_inner()) = Arc::new(contacts);
+        Ok(output)
+    }
+
+    /// Adds the contacts, replacing the ones with the same nickname.
+    fn merge(&mut self, other: Contacts) {
+        self.contacts.extend(other.contacts);
+    }
+}
+
+impl fmt::Display for Contacts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.contacts.is_empty() {
+            return write!(
+                f,
+                "No contacts, add one with .contact add nickname \"alias\" [\"note\"]."
+            );
+        }
+        write!(f, "Contacts:")?;
+        for (nickname, contact) in &self.contacts {
+            write!(f, "\n  {nickname}: {}", contact.alias)?;
+            if !contact.note.is_empty() {
+                write!(f, " - {}", contact.note)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses the arguments of the `.contact` command, e.g. `add slava "Slava from lesson 9" "teacher"`, no arguments
+/// list the contacts.
+///
+/// # Errors
+///
+/// This function will return an error for an unknown action, a missing argument or an unclosed quote.
+pub fn parse_contact(arguments: &str) -> Result<ContactCommand> {
+    let arguments = files::split_arguments(arguments)?;
+    let arguments: Vec<&str> = arguments.iter().map(String::as_str).collect();
+    let command = match arguments.as_slice() {
+        [] | ["list"] => ContactCommand::List,
+        ["add", nickname, alias, note @ ..] if note.len() <= 1 => ContactCommand::Add {
+            nickname: nickname.to_string(),
+            contact: Contact {
+                alias: alias.to_string(),
+                note: note.first().unwrap_or(&"").to_string(),
+            },
+        },
+        ["remove", nickname] => ContactCommand::Remove(nickname.to_string()),
+        ["export", path] => ContactCommand::Export(PathBuf::from(path)),
+        ["import", path] => ContactCommand::Import(PathBuf::from(path)),
+        _ => {
+            return Err(anyhow!(
+                "Invalid command .contact, use add nickname \"alias\" [\"note\"], remove nickname, list, export file \
+                 or import file!"
+            ))
+        }
+    };
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_contact() {
+        assert_eq!(
+            parse_contact("add slava \"Slava from lesson 9\"").unwrap(),
+            ContactCommand::Add {
+                nickname: "slava".into(),
+                contact: Contact {
+                    alias: "Slava from lesson 9".into(),
+                    note: String::new(),
+                },
+            }
+        );
+        let ContactCommand::Add { contact, .. } =
+            parse_contact("add eva Eva \"met at the meetup\"").unwrap()
+        else {
+            panic!("Expected ContactCommand::Add");
+        };
+        assert_eq!(contact.note, "met at the meetup");
+        assert_eq!(parse_contact("").unwrap(), ContactCommand::List);
+        assert_eq!(
+            parse_contact("remove eva").unwrap(),
+            ContactCommand::Remove("eva".into())
+        );
+        assert_eq!(
+            parse_contact("export \"my contacts.toml\"").unwrap(),
+            ContactCommand::Export("my contacts.toml".into())
+        );
+        assert!(parse_contact("add slava").is_err());
+        assert!(parse_contact("add slava a b c").is_err());
+        assert!(parse_contact("delete slava").is_err());
+    }
+
+    #[test]
+    fn test_contacts_toml() {
+        let mut contacts = Contacts::default();
+        contacts.contacts.insert(
+            "slava".into(),
+            Contact {
+                alias: "Slava from lesson 9".into(),
+                note: String::new(),
+            },
+        );
+        let path = std::env::temp_dir().join(format!("contacts-{}.toml", std::process::id()));
+        contacts.write(&path).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            content,
+            "[contacts.slava]\nalias = \"Slava from lesson 9\"\n"
+        );
+
+        let mut imported = Contacts::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(imported, contacts);
+        imported.merge(Contacts {
+            contacts: BTreeMap::from([(
+                "slava".into(),
+                Contact {
+                    alias: "Slava".into(),
+                    note: "teacher".into(),
+                },
+            )]),
+        });
+        assert_eq!(imported.contacts["slava"].alias, "Slava");
+        assert_eq!(imported.to_string(), "Contacts:\n  slava: Slava - teacher");
+    }
+}
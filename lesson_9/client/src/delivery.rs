@@ -0,0 +1,165 @@
+//! Delivery acknowledgements of the sent messages.
+//!
+//! With a server announcing the `acks` capability the [`Link`](crate::connection::Link) flags the typed messages by the
+//! [`chat::ACK_ANNOTATION`] and waits for their [`MessageType::Ack`]. The server acknowledges a message once it is
+//! broadcast and stored, repeating the timestamp of the message, which the link makes unique, so a delivered message
+//! is marked with its stored id. A message without the acknowledgement for [`ACK_TIMEOUT`] is sent again, up to
+//! [`MAX_RETRIES`] times, then reported as not delivered. A retried message may arrive twice, e.g. if the
+//! acknowledgement got lost with a broken connection.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chat::{Message, MessageType};
+
+use crate::connection;
+
+/// Time to wait for the acknowledgement of a message before sending it again.
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+/// Number of the repeated sendings of a message never acknowledged.
+pub const MAX_RETRIES: u32 = 2;
+
+/// Returns true for the typed messages acknowledged by the server, encrypted or not. The requests aren't stored, the
+/// whispers neither, and the parts of a streamed file can't be sent again alone.
+pub fn expects_ack(message: &MessageType) -> bool {
+    matches!(
+        message,
+        MessageType::Text(_)
+            | MessageType::Code { .. }
+            | MessageType::Image(_)
+            | MessageType::File { .. }
+            | MessageType::Encrypted(_)
+    )
+}
+
+struct Awaiting {
+    message: Message,
+    sent: Instant,
+    retries: u32,
+}
+
+/// Messages waiting for the acknowledgement, by their timestamp.
+#[derive(Clone, Default)]
+pub struct Deliveries {
+    awaiting: Arc<Mutex<BTreeMap<u64, Awaiting>>>,
+}
+
+/// Messages whose acknowledgement hasn't arrived in time.
+#[derive(Debug, Default, PartialEq)]
+pub struct Expired {
+    /// The messages to send again, in the order they were sent.
+    pub retry: Vec<Message>,
+    /// Previews of the messages given up after [`MAX_RETRIES`].
+    pub failed: Vec<String>,
+}
+
+impl Deliveries {
+    /// Waits for the acknowledgement of the message sent at the time, again after a retry.
+    pub fn sent(&self, message: &Message, now: Instant) {
+        let Some(timestamp) = message.timestamp else {
+            return;
+        };
+        let mut awaiting = self.awaiting.lock().unwrap_or_else(|e| e.into_inner());
+        awaiting
+            .entry(timestamp)
+            .and_modify(|awaiting| awaiting.sent = now)
+            .or_insert_with(|| Awaiting {
+                message: message.clone(),
+                sent: now,
+                retries: 0,
+            });
+    }
+
+    /// Marks the message with the timestamp as delivered.
+    ///
+    /// # Returns
+    ///
+    /// The preview of the message, `None` if it isn't waiting, e.g. it was acknowledged already.
+    pub fn acknowledge(&self, sent_at: u64) -> Option<String> {
+        let mut awaiting = self.awaiting.lock().unwrap_or_else(|e| e.into_inner());
+        awaiting
+            .remove(&sent_at)
+            .map(|awaiting| connection::preview(&awaiting.message.message))
+    }
+
+    /// Returns the number of the messages waiting for the acknowledgement.
+    pub fn waiting(&self) -> usize {
+        self.awaiting
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .len()
+    }
+
+    /// Returns the messages waiting for [`ACK_TIMEOUT`], the retried ones wait again from now.
+    pub fn expired(&self, now: Instant) -> Expired {
+        let mut awaiting = self.awaiting.lock().unwrap_or_else(|e| e.into_inner());
+        let mut expired = Expired::default();
+        awaiting.retain(|_, awaiting| {
+            if now.saturating_duration_since(awaiting.sent) < ACK_TIMEOUT {
+                return true;
+            }
+            if awaiting.retries == MAX_RETRIES {
+                expired
+                    .failed
+                    .push(connection::preview(&awaiting.message.message));
+                return false;
+            }
+            awaiting.retries += 1;
+            awaiting.sent = now;
+            expired.retry.push(awaiting.message.clone());
+            true
+        });
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat::Sealed;
+
+    fn stamped(text: &str, timestamp: u64) -> Message {
+        let mut message = Message::from("slava", MessageType::text(text));
+        message.timestamp = Some(timestamp);
+        message
+    }
+
+    #[test]
+    fn test_deliveries() {
+        assert!(expects_ack(&MessageType::text("hi")));
+        assert!(!expects_ack(&MessageType::whisper("eva", "psst")));
+        assert!(!expects_ack(&MessageType::join("rust")));
+        // An encrypted text is acknowledged like the plain one.
+        let sealed = Sealed {
+            sender_key: [7; 32],
+            recipients: Vec::new(),
+            nonce: [5; 12],
+            ciphertext: vec![4, 5, 6],
+        };
+        assert!(expects_ack(&MessageType::Encrypted(sealed)));
+
+        let deliveries = Deliveries::default();
+        let start = Instant::now();
+        deliveries.sent(&stamped("first", 1), start);
+        deliveries.sent(&stamped("second", 2), start);
+        assert_eq!(deliveries.acknowledge(1).as_deref(), Some("first"));
+        assert_eq!(deliveries.acknowledge(1), None);
+        assert_eq!(
+            deliveries.expired(start + ACK_TIMEOUT / 2),
+            Expired::default()
+        );
+
+        let mut now = start;
+        for _ in 0..MAX_RETRIES {
+            now += ACK_TIMEOUT;
+            let expired = deliveries.expired(now);
+            assert_eq!(expired.retry, [stamped("second", 2)]);
+            deliveries.sent(&expired.retry[0], now);
+        }
+        let expired = deliveries.expired(now + ACK_TIMEOUT);
+        assert!(expired.retry.is_empty());
+        assert_eq!(expired.failed, ["second"]);
+        assert_eq!(deliveries.acknowledge(2), None);
+    }
+}
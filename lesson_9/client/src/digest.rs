@@ -0,0 +1,81 @@
+//! Email digest of the mentions missed while offline.
+//!
+//! The `.digest` command stores the email address and the daily time on the server, which emails the messages
+//! mentioning `@nickname` since the user was last active. The time is typed in the local time zone and sent as the
+//! minute of the day in UTC, so the digest keeps its UTC time when the local time moves to or from the daylight saving
+//! time.
+
+use anyhow::{anyhow, Context, Result};
+use chat::DigestSetting;
+use jiff::civil::{Date, Time};
+use jiff::tz::TimeZone;
+use jiff::Zoned;
+
+/// Parses the arguments of the `.digest` command, e.g. `email slava@example.com`, `daily 08:30` or `off`.
+///
+/// # Errors
+///
+/// This function will return an error for an unknown action, a missing argument or an invalid time.
+pub fn parse_digest(arguments: &str) -> Result<DigestSetting> {
+    let arguments: Vec<&str> = arguments.split_whitespace().collect();
+    let setting = match arguments.as_slice() {
+        ["email", email] => DigestSetting::Email(email.to_string()),
+        ["daily", time] => {
+            let time: Time = time
+                .parse()
+                .with_context(|| format!("Invalid time {time}, use HH:MM!"))?;
+            let today = Zoned::now().date();
+            DigestSetting::Daily {
+                minute: utc_minute(time, today, &TimeZone::system())?,
+            }
+        }
+        ["off"] => DigestSetting::Off,
+        _ => {
+            return Err(anyhow!(
+                "Invalid command .digest, use email address, daily HH:MM or off!"
+            ))
+        }
+    };
+    Ok(setting)
+}
+
+/// Converts the local time of the day to the minute of the day in UTC.
+fn utc_minute(time: Time, date: Date, time_zone: &TimeZone) -> Result<u16> {
+    let utc = date
+        .to_datetime(time)
+        .to_zoned(time_zone.clone())?
+        .with_time_zone(TimeZone::UTC);
+    Ok(utc.hour() as u16 * 60 + utc.minute() as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_digest() {
+        assert_eq!(
+            parse_digest("email slava@example.com").unwrap(),
+            DigestSetting::Email("slava@example.com".into())
+        );
+        assert!(matches!(
+            parse_digest("daily 08:30").unwrap(),
+            DigestSetting::Daily { .. }
+        ));
+        assert_eq!(parse_digest(" off ").unwrap(), DigestSetting::Off);
+        assert!(parse_digest("daily 25:00").is_err());
+        assert!(parse_digest("daily").is_err());
+        assert!(parse_digest("weekly 08:30").is_err());
+    }
+
+    #[test]
+    fn test_utc_minute() {
+        let date = jiff::civil::date(2024, 6, 10);
+        let prague = TimeZone::fixed(jiff::tz::offset(2));
+        let time = jiff::civil::time(8, 30, 0, 0);
+        assert_eq!(utc_minute(time, date, &TimeZone::UTC).unwrap(), 510);
+        assert_eq!(utc_minute(time, date, &prague).unwrap(), 390);
+        let night = jiff::civil::time(1, 0, 0, 0);
+        assert_eq!(utc_minute(night, date, &prague).unwrap(), 23 * 60);
+    }
+}
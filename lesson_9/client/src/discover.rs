@@ -0,0 +1,151 @@
+//! Choosing the server by `--discover` instead of typing its address.
+//!
+//! The client lists the server running on the same machine, found by the port it wrote to the
+//! [`chat::discovery::port_file`] if it accepts connections, and built with the `mdns` feature the servers advertised
+//! on the LAN within two seconds. The user picks one of them by its number, Enter picks the first one.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use chat::discovery::{self, Found};
+use chat::Address;
+use tokio::net::TcpStream;
+
+/// Argument listing the servers to choose from.
+pub const DISCOVER: &str = "--discover";
+/// Time to wait for the servers advertised on the LAN.
+#[cfg(feature = "mdns")]
+const BROWSE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Time to wait for the local server, a stale port file names a port nobody listens on.
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+/// Name of the server found by the port file.
+const LOCAL: &str = "this machine";
+
+/// Removes the [`DISCOVER`] argument.
+///
+/// # Returns
+///
+/// True if the argument was present.
+pub fn take_discover(arguments: &mut Vec<String>) -> bool {
+    let count = arguments.len();
+    arguments.retain(|argument| argument != DISCOVER);
+    arguments.len() != count
+}
+
+/// Returns the servers running on this machine and on the LAN.
+async fn find() -> Vec<Found> {
+    let mut found = Vec::new();
+    if let Some(port) = discovery::read_port(&discovery::port_file()) {
+        let connecting = TcpStream::connect(("localhost", port));
+        if let Ok(Ok(_)) = tokio::time::timeout(CONNECT_TIMEOUT, connecting).await {
+            found.push(Found {
+                name: LOCAL.to_string(),
+                hostname: "localhost".to_string(),
+                port,
+            });
+        }
+    }
+    #[cfg(feature = "mdns")]
+    match tokio::task::spawn_blocking(|| discovery::browse(BROWSE_TIMEOUT)).await {
+        Ok(Ok(advertised)) => found.extend(advertised),
+        Ok(Err(err_msg)) => eprintln!("Looking for servers on the LAN failed: {}", err_msg),
+        Err(err_msg) => eprintln!("Looking for servers on the LAN failed: {}", err_msg),
+    }
+    found
+}
+
+/// Renders the numbered servers to choose from.
+fn render(found: &[Found]) -> String {
+    let lines: Vec<String> = found
+        .iter()
+        .enumerate()
+        .map(|(index, server)| {
+            format!(
+                "{}. {} at {}",
+                index + 1,
+                server.name,
+                server.address().to_string()
+            )
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// Returns the address of the server chosen by its number, the first one for an empty choice.
+///
+/// # Errors
+///
+/// This function will return an error if the choice isn't a number of a listed server.
+fn choose(found: &[Found], choice: &str) -> Result<Address> {
+    let number: usize = match choice.trim() {
+        "" => 1,
+        choice => choice
+            .parse()
+            .with_context(|| format!("Invalid choice {choice}, type a number!"))?,
+    };
+    number
+        .checked_sub(1)
+        .and_then(|index| found.get(index))
+        .map(Found::address)
+        .ok_or_else(|| anyhow!("There is no server number {number}!"))
+}
+
+/// Lists the found servers and asks the user to choose one.
+///
+/// # Errors
+///
+/// This function will return an error if no server was found, reading the choice fails or the choice is invalid.
+pub async fn pick() -> Result<Address> {
+    #[cfg(not(feature = "mdns"))]
+    println!("Looking for a server on this machine, the client is built without the mdns feature for the LAN.");
+    #[cfg(feature = "mdns")]
+    println!("Looking for servers on this machine and the LAN...");
+    let found = find().await;
+    if found.is_empty() {
+        return Err(anyhow!(
+            "No server found, start one or give its hostname and port!"
+        ));
+    }
+    println!("{}", render(&found));
+    println!("Choose the server [1]:");
+    let mut choice = String::new();
+    std::io::stdin().read_line(&mut choice)?;
+    choose(&found, &choice)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose() {
+        let mut arguments = vec!["client".to_string(), DISCOVER.to_string()];
+        assert!(take_discover(&mut arguments));
+        assert_eq!(arguments, ["client"]);
+
+        let found = [
+            Found {
+                name: LOCAL.to_string(),
+                hostname: "localhost".to_string(),
+                port: 40123,
+            },
+            Found {
+                name: "Slava's chat".to_string(),
+                hostname: "192.168.1.7".to_string(),
+                port: 11111,
+            },
+        ];
+        assert_eq!(
+            render(&found),
+            "1. this machine at localhost:40123\n2. Slava's chat at 192.168.1.7:11111"
+        );
+        assert_eq!(choose(&found, "\n").unwrap().to_string(), "localhost:40123");
+        assert_eq!(
+            choose(&found, "2").unwrap().to_string(),
+            "192.168.1.7:11111"
+        );
+        assert!(choose(&found, "0").is_err());
+        assert!(choose(&found, "3").is_err());
+        assert!(choose(&found, "first").is_err());
+    }
+}
@@ -0,0 +1,260 @@
+//! Recently saved attachments.
+//!
+//! The attachments are saved by the [`DownloadWriter`], so the files of the same name don't overwrite each other.
+//! Saved paths are printed as OSC 8 hyperlinks, which supporting terminals make clickable and others print as plain
+//! text. The `.open` command opens a recent download with the platform opener.
+//!
+//! The [`AutoOpen`] rules open the saved images or print the small text files right away, but only for the senders
+//! listed in the rules. Nothing is opened automatically by default and directory archives never are.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::archive;
+use crate::writer::DownloadWriter;
+
+/// Number of remembered downloads.
+pub const MAX_RECENT: usize = 20;
+/// Default maximal size of the text files printed inline in KB.
+pub const TEXT_MAX_KB: u64 = 4;
+/// Extensions of the images opened by the [`AutoOpen`] rules.
+const IMAGE_EXTENSIONS: [&str; 6] = ["png", "jpg", "jpeg", "gif", "bmp", "webp"];
+
+/// Rules opening the received attachments automatically, stored in the client config.
+///
+/// Every rule applies only to the senders listed in it, so the default empty lists turn all of them off.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct AutoOpen {
+    /// Senders whose images are opened in the default viewer.
+    pub images: Vec<String>,
+    /// Senders whose text files are printed inline.
+    pub text: Vec<String>,
+    /// Maximal size of the text files printed inline in KB.
+    pub text_max_kb: u64,
+}
+
+impl Default for AutoOpen {
+    fn default() -> Self {
+        AutoOpen {
+            images: Vec::new(),
+            text: Vec::new(),
+            text_max_kb: TEXT_MAX_KB,
+        }
+    }
+}
+
+/// Action of the [`AutoOpen`] rules for a saved attachment.
+#[derive(Debug, PartialEq)]
+pub enum AutoAction {
+    /// Open the file with the platform opener.
+    Open,
+    /// Print the text content inline.
+    Print(String),
+}
+
+impl AutoOpen {
+    /// Returns the action for the file saved from the sender, if any rule allows it.
+    ///
+    /// Text is printed only if it is valid UTF-8 without control characters, so a received file can't send escape
+    /// sequences to the terminal.
+    pub fn action(&self, sender: &str, path: &Path) -> Option<AutoAction> {
+        let allows = |senders: &[String]| senders.iter().any(|allowed| allowed == sender);
+        if archive::is_archive(path) {
+            return None;
+        }
+        if allows(&self.images) && is_image(path) {
+            return Some(AutoAction::Open);
+        }
+        if !allows(&self.text) || std::fs::metadata(path).ok()?.len() > self.text_max_kb * 1024 {
+            return None;
+        }
+        let text = String::from_utf8(std::fs::read(path).ok()?).ok()?;
+        let printable = text
+            .chars()
+            .all(|c| !c.is_control() || matches!(c, '\n' | '\r' | '\t'));
+        printable.then_some(AutoAction::Print(text))
+    }
+}
+
+/// Recent downloads shared by the reading and writing loops, the most recent first.
+#[derive(Clone)]
+pub struct Downloads {
+    recent: Arc<Mutex<Vec<PathBuf>>>,
+    rules: Arc<AutoOpen>,
+    writer: DownloadWriter,
+}
+
+impl Downloads {
+    /// Creates the downloads applying the auto-open `rules` and spawns their writer.
+    pub fn new(rules: AutoOpen) -> Downloads {
+        Downloads {
+            recent: Default::default(),
+            rules: Arc::new(rules),
+            writer: DownloadWriter::spawn(),
+        }
+    }
+
+    /// Returns the writer creating the downloaded files.
+    pub fn writer(&self) -> &DownloadWriter {
+        &self.writer
+    }
+
+    /// Remembers the file saved from the sender and returns the action of the auto-open rules.
+    pub fn accept(&self, sender: &str, path: PathBuf) -> Option<AutoAction> {
+        let action = self.rules.action(sender, &path);
+        self.record(path);
+        action
+    }
+
+    /// Remembers the saved file.
+    pub fn record(&self, path: PathBuf) {
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        recent.insert(0, path);
+        recent.truncate(MAX_RECENT);
+    }
+
+    /// Returns the `n`th recent download, `1` is the most recent one.
+    pub fn get(&self, n: usize) -> Option<PathBuf> {
+        let recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        n.checked_sub(1)
+            .and_then(|index| recent.get(index).cloned())
+    }
+
+    /// Returns the recent downloads, the most recent first.
+    pub fn list(&self) -> Vec<PathBuf> {
+        self.recent
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+/// Renders the path as an OSC 8 hyperlink to the file, or as plain text if `styled` is false.
+pub fn hyperlink(path: &Path, styled: bool) -> String {
+    let text = path.display().to_string();
+    if !styled {
+        return text;
+    }
+    match std::fs::canonicalize(path) {
+        Ok(absolute) => format!(
+            "\x1b]8;;file://{}\x1b\\{text}\x1b]8;;\x1b\\",
+            encode_path(&absolute)
+        ),
+        Err(_) => text,
+    }
+}
+
+/// Opens the file with the platform opener (`xdg-open`, `open` or `start`).
+///
+/// # Errors
+///
+/// This function will return an error if the opener can't be started.
+pub fn open(path: &Path) -> Result<()> {
+    if !path.exists() {
+        return Err(anyhow!("File {} doesn't exist!", path.display()));
+    }
+    let mut command = if cfg!(target_os = "windows") {
+        let mut command = Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    } else if cfg!(target_os = "macos") {
+        Command::new("open")
+    } else {
+        Command::new("xdg-open")
+    };
+    command
+        .arg(path)
+        .spawn()
+        .with_context(|| format!("Opening {} failed!", path.display()))?;
+    Ok(())
+}
+
+/// Returns true if the path has an image extension.
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| IMAGE_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+}
+
+/// Percent-encodes the path for the `file://` URL.
+fn encode_path(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut encoded = String::new();
+    if !path.starts_with('/') {
+        encoded.push('/');
+    }
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' | b':' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_recent_downloads() {
+        let downloads = Downloads::new(AutoOpen::default());
+        downloads.record(PathBuf::from("FILES/a.txt"));
+        downloads.record(PathBuf::from("IMAGES/b.png"));
+        assert_eq!(downloads.get(1), Some(PathBuf::from("IMAGES/b.png")));
+        assert_eq!(downloads.get(2), Some(PathBuf::from("FILES/a.txt")));
+        assert_eq!(downloads.get(0), None);
+        assert_eq!(downloads.get(3), None);
+    }
+
+    #[test]
+    fn test_auto_open() {
+        let dir = std::env::temp_dir().join(format!("chat-auto-open-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let notes = dir.join("notes.txt");
+        std::fs::write(&notes, "hello\nworld").unwrap();
+        let escape = dir.join("escape.txt");
+        std::fs::write(&escape, "\x1b]0;title\x07").unwrap();
+        let big = dir.join("big.txt");
+        std::fs::write(&big, "a".repeat(5000)).unwrap();
+        let image = dir.join("cat.PNG");
+
+        assert_eq!(AutoOpen::default().action("eva", &notes), None);
+        assert_eq!(AutoOpen::default().action("eva", &image), None);
+        let rules = AutoOpen {
+            images: vec!["eva".to_string()],
+            text: vec!["eva".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(rules.action("eva", &image), Some(AutoAction::Open));
+        assert_eq!(rules.action("slava", &image), None);
+        assert_eq!(
+            rules.action("eva", &notes),
+            Some(AutoAction::Print("hello\nworld".to_string()))
+        );
+        assert_eq!(rules.action("slava", &notes), None);
+        assert_eq!(rules.action("eva", &escape), None);
+        assert_eq!(rules.action("eva", &big), None);
+        assert_eq!(rules.action("eva", Path::new("FILES/notes.tar.gz")), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_encode_path() {
+        assert_eq!(
+            encode_path(Path::new("/home/user/FILES/my file.txt")),
+            "/home/user/FILES/my%20file.txt"
+        );
+        assert_eq!(
+            encode_path(Path::new("C:\\FILES\\a.txt")),
+            "/C:/FILES/a.txt"
+        );
+    }
+}
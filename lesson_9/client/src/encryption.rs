@@ -0,0 +1,464 @@
+//! End-to-end encryption of the sent texts, code, images and files, see [`chat::crypto`].
+//!
+//! `.pubkeys new` generates the key pair of the user, saved to the key file of the nickname in the current directory,
+//! and turns the encryption on. The public key is announced to every server with the `e2e` capability, which keeps it
+//! and sends it to the other clients. With the encryption on (`.pubkeys on`, saved as `"encryption": true` in the
+//! config) the [`Keyring`] encrypts the messages for every user whose key was announced, the others can't read them.
+//! `.pubkeys` shows the fingerprints of the keys to compare them with the other users, `.pubkeys off` sends the
+//! messages readable by the server again. The received messages encrypted for the key are decrypted whether the
+//! encryption is on or off.
+//!
+//! The key announced first by a nickname is pinned for the session. A changed key is reported and waits until the user
+//! accepts it by `.pubkeys accept <nickname>`, the messages are encrypted for and checked against the pinned key until
+//! then, so a server can't quietly swap it. A key announced for the own nickname other than the own key is refused, so
+//! nobody can add themselves as a recipient of the messages.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use chat::crypto::{self, CryptoError, KeyPair};
+use chat::{Message, MessageType, Sealed};
+
+/// Prefix of the key files, followed by the nickname.
+pub const KEY_PREFIX: &str = "key-";
+/// Annotation of a decrypted message, rendered under it.
+pub const ENCRYPTED_ANNOTATION: &str = "encrypted";
+
+/// Action of the `.pubkeys` command.
+#[derive(Debug, PartialEq)]
+pub enum KeysCommand {
+    Show,
+    New,
+    On,
+    Off,
+    /// Accepts the changed key of the nickname.
+    Accept(String),
+}
+
+/// Change of the known keys by an announced key.
+#[derive(Debug, PartialEq)]
+pub enum Learned {
+    New,
+    Same,
+    /// The nickname announced another key before, e.g. after `.pubkeys new` or from a server swapping it. The previous
+    /// key stays pinned until the change is accepted.
+    Changed,
+    /// The key was announced for the own nickname, but it isn't the own key.
+    Refused,
+}
+
+#[derive(Default)]
+struct Keys {
+    nickname: String,
+    path: PathBuf,
+    own: Option<KeyPair>,
+    enabled: bool,
+    /// Pinned key of every other nickname.
+    known: BTreeMap<String, [u8; 32]>,
+    /// Changed keys waiting for [`Keyring::accept`].
+    changed: BTreeMap<String, [u8; 32]>,
+}
+
+/// Own key pair and the announced keys shared by the reading and writing loops.
+#[derive(Clone, Default)]
+pub struct Keyring {
+    keys: Arc<Mutex<Keys>>,
+}
+
+/// Returns the key file of the nickname in the current directory.
+pub fn key_file(nickname: &str) -> PathBuf {
+    PathBuf::from(format!("{KEY_PREFIX}{nickname}.bin"))
+}
+
+impl Keyring {
+    /// Loads the key pair of the nickname from the file, a missing or invalid file is no key.
+    pub fn load(nickname: &str, path: PathBuf, enabled: bool) -> Keyring {
+        let own = match fs::read(&path) {
+            Ok(secret) => match <[u8; 32]>::try_from(secret) {
+                Ok(secret) => Some(KeyPair::from_secret(secret)),
+                Err(_) => {
+                    eprintln!(
+                        "Invalid key file {}, generate a new key with .pubkeys new.",
+                        path.display()
+                    );
+                    None
+                }
+            },
+            Err(err_msg) if err_msg.kind() == ErrorKind::NotFound => None,
+            Err(err_msg) => {
+                eprintln!(
+                    "Reading the key file {} failed: {}",
+                    path.display(),
+                    err_msg
+                );
+                None
+            }
+        };
+        Keyring {
+            keys: Arc::new(Mutex::new(Keys {
+                nickname: nickname.to_string(),
+                path,
+                enabled: enabled && own.is_some(),
+                own,
+                known: BTreeMap::new(),
+                changed: BTreeMap::new(),
+            })),
+        }
+    }
+
+    fn keys(&self) -> std::sync::MutexGuard<'_, Keys> {
+        self.keys.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Generates and saves a new key pair and turns the encryption on.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the key file can't be written, the previous key pair is kept then.
+    pub fn generate(&self) -> Result<()> {
+        let mut keys = self.keys();
+        let own = KeyPair::generate();
+        save_secret(&keys.path, &own.secret())
+            .with_context(|| format!("Saving the key to {} failed!", keys.path.display()))?;
+        keys.own = Some(own);
+        keys.enabled = true;
+        Ok(())
+    }
+
+    /// Turns the encryption of the sent messages on or off.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the encryption is turned on without a key pair.
+    pub fn set_enabled(&self, enabled: bool) -> Result<()> {
+        let mut keys = self.keys();
+        if enabled && keys.own.is_none() {
+            return Err(anyhow!("No key yet, generate one with .pubkeys new!"));
+        }
+        keys.enabled = enabled;
+        Ok(())
+    }
+
+    /// Returns the message announcing the own public key, `None` without a key pair.
+    pub fn announcement(&self) -> Option<Message> {
+        let keys = self.keys();
+        let key = keys.own.as_ref()?.public();
+        Some(Message::from(
+            &keys.nickname,
+            MessageType::PublicKey { key },
+        ))
+    }
+
+    /// Remembers the key announced by the nickname, pins the first one and keeps a changed one for
+    /// [`Keyring::accept`].
+    pub fn learn(&self, nickname: &str, key: [u8; 32]) -> Learned {
+        let mut keys = self.keys();
+        if nickname == keys.nickname {
+            // The own key is never a recipient from the announcements, only the own key pair is.
+            return match &keys.own {
+                Some(own) if own.public() == key => Learned::Same,
+                _ => Learned::Refused,
+            };
+        }
+        match keys.known.get(nickname) {
+            None => {
+                keys.known.insert(nickname.to_string(), key);
+                Learned::New
+            }
+            Some(pinned) if *pinned == key => {
+                keys.changed.remove(nickname);
+                Learned::Same
+            }
+            Some(_) => {
+                keys.changed.insert(nickname.to_string(), key);
+                Learned::Changed
+            }
+        }
+    }
+
+    /// Pins the changed key of the nickname instead of the previous one.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the nickname hasn't announced a changed key.
+    pub fn accept(&self, nickname: &str) -> Result<[u8; 32]> {
+        let mut keys = self.keys();
+        let key = keys
+            .changed
+            .remove(nickname)
+            .ok_or(anyhow!("{nickname} hasn't announced a changed key!"))?;
+        keys.known.insert(nickname.to_string(), key);
+        Ok(key)
+    }
+
+    /// Returns true if the message is sent encrypted.
+    pub fn encrypts(&self, message: &MessageType) -> bool {
+        self.keys().enabled && crypto::is_sealable(message)
+    }
+
+    /// Encrypts the message for all the pinned keys.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if there is no key pair, nobody else announced a key or a key is invalid.
+    pub fn seal(&self, message: &MessageType) -> Result<Sealed> {
+        let keys = self.keys();
+        let own = keys
+            .own
+            .as_ref()
+            .ok_or(anyhow!("No key yet, generate one with .pubkeys new!"))?;
+        if keys.known.is_empty() {
+            return Err(anyhow!(
+                "Nobody else announced a key yet, nobody could read the message! Send it readable by everybody after \
+                 .pubkeys off."
+            ));
+        }
+        let mut recipients: Vec<[u8; 32]> = keys.known.values().copied().collect();
+        // The own key lets the other clients of the same key pair read it.
+        recipients.push(own.public());
+        recipients.sort_unstable();
+        recipients.dedup();
+        Ok(own.seal(&keys.nickname, message, &recipients)?)
+    }
+
+    /// Returns the users who can't read the encrypted messages, they haven't announced a key.
+    pub fn missing(&self, users: &[String]) -> Vec<String> {
+        let keys = self.keys();
+        users
+            .iter()
+            .filter(|user| **user != keys.nickname && !keys.known.contains_key(*user))
+            .cloned()
+            .collect()
+    }
+
+    /// Decrypts the message of the sender.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the message isn't encrypted for the own key, it was changed, or it is
+    /// encrypted by another key than the pinned key of the sender.
+    pub fn open(&self, sender: &str, sealed: &Sealed) -> Result<MessageType, CryptoError> {
+        let keys = self.keys();
+        let own = keys.own.as_ref().ok_or(CryptoError::NotForMe)?;
+        match keys.known.get(sender) {
+            Some(key) if *key != sealed.sender_key => Err(CryptoError::Tampered),
+            _ => own.open(sender, sealed),
+        }
+    }
+
+    /// Renders the state of the encryption and the fingerprints of the keys for the `.pubkeys` command.
+    pub fn describe(&self) -> String {
+        let keys = self.keys();
+        let state = if keys.enabled { "on" } else { "off" };
+        let mut lines = vec![format!("end-to-end encryption: {state}")];
+        match &keys.own {
+            Some(own) => lines.push(format!(
+                "your key: {} ({})",
+                crypto::fingerprint(&own.public()),
+                keys.path.display()
+            )),
+            None => lines.push("your key: none, generate one with .pubkeys new".to_string()),
+        }
+        for (nickname, key) in &keys.known {
+            lines.push(format!("  {nickname}: {}", crypto::fingerprint(key)));
+            if let Some(changed) = keys.changed.get(nickname) {
+                lines.push(format!(
+                    "    changed to {}, accept it with .pubkeys accept {nickname}",
+                    crypto::fingerprint(changed)
+                ));
+            }
+        }
+        lines.join("\n")
+    }
+}
+
+/// Writes the secret key readable only by the user.
+fn save_secret(path: &Path, secret: &[u8; 32]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(secret)
+}
+
+/// Parses the arguments of the `.pubkeys` command, no arguments show the keys.
+///
+/// # Errors
+///
+/// This function will return an error for an unknown action or `accept` without a nickname.
+pub fn parse_keys(arguments: &str) -> Result<KeysCommand> {
+    let mut words = arguments.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (None, _, _) => Ok(KeysCommand::Show),
+        (Some("new"), None, _) => Ok(KeysCommand::New),
+        (Some("on"), None, _) => Ok(KeysCommand::On),
+        (Some("off"), None, _) => Ok(KeysCommand::Off),
+        (Some("accept"), Some(nickname), None) => Ok(KeysCommand::Accept(nickname.to_string())),
+        _ => Err(anyhow!(
+            "Invalid command .pubkeys, use .pubkeys [new|on|off|accept <nickname>]!"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyring(nickname: &str) -> (Keyring, PathBuf) {
+        let path = std::env::temp_dir().join(format!("chat-test-{nickname}.key"));
+        let _ = fs::remove_file(&path);
+        (Keyring::load(nickname, path.clone(), true), path)
+    }
+
+    #[test]
+    fn test_keyring() {
+        let (slava, slava_path) = keyring("slava");
+        let (eva, eva_path) = keyring("eva");
+        let text = MessageType::text("psst");
+        assert!(!slava.encrypts(&text));
+        assert!(slava.announcement().is_none());
+        assert!(slava.set_enabled(true).is_err());
+
+        slava.generate().unwrap();
+        eva.generate().unwrap();
+        assert!(slava.encrypts(&text));
+        assert!(!slava.encrypts(&MessageType::history_request(None, 20)));
+        assert!(slava.seal(&text).is_err());
+
+        let MessageType::PublicKey { key: eva_key } = eva.announcement().unwrap().message else {
+            panic!("eva's announcement isn't a key");
+        };
+        let MessageType::PublicKey { key: slava_key } = slava.announcement().unwrap().message
+        else {
+            panic!("slava's announcement isn't a key");
+        };
+        assert_eq!(slava.learn("eva", eva_key), Learned::New);
+        assert_eq!(slava.learn("eva", eva_key), Learned::Same);
+        assert_eq!(slava.learn("slava", slava_key), Learned::Same);
+        let users = ["slava", "eva", "bob"].map(String::from);
+        assert_eq!(slava.missing(&users), ["bob"]);
+
+        let sealed = slava.seal(&text).unwrap();
+        assert_eq!(sealed.recipients.len(), 2);
+        assert_eq!(eva.open("slava", &sealed).unwrap(), text);
+        // The sender doesn't match the pinned key.
+        let (mallory, mallory_path) = keyring("mallory");
+        mallory.generate().unwrap();
+        mallory.learn("eva", eva_key);
+        let forged = mallory.seal(&text).unwrap();
+        assert_eq!(eva.learn("slava", slava_key), Learned::New);
+        assert_eq!(eva.open("slava", &forged), Err(CryptoError::Tampered));
+        assert!(eva.accept("slava").is_err());
+        fs::remove_file(mallory_path).unwrap();
+
+        let restored = Keyring::load("eva", eva_path.clone(), false);
+        assert!(!restored.encrypts(&text));
+        assert_eq!(restored.open("slava", &sealed).unwrap(), text);
+        assert!(slava
+            .describe()
+            .starts_with("end-to-end encryption: on\nyour key: "));
+        assert!(slava
+            .describe()
+            .ends_with(&format!("\n  eva: {}", crypto::fingerprint(&eva_key))));
+        fs::remove_file(slava_path).unwrap();
+        fs::remove_file(eva_path).unwrap();
+
+        assert_eq!(parse_keys("").unwrap(), KeysCommand::Show);
+        assert_eq!(parse_keys(" new").unwrap(), KeysCommand::New);
+        assert_eq!(parse_keys("off").unwrap(), KeysCommand::Off);
+        assert_eq!(
+            parse_keys("accept eva").unwrap(),
+            KeysCommand::Accept("eva".to_string())
+        );
+        assert!(parse_keys("accept").is_err());
+        assert!(parse_keys("new eva").is_err());
+        assert!(parse_keys("delete").is_err());
+    }
+
+    #[test]
+    fn test_own_nickname_is_refused() {
+        let (slava, slava_path) = keyring("slava-own");
+        let (eva, eva_path) = keyring("eva-own");
+        slava.generate().unwrap();
+        eva.generate().unwrap();
+        let MessageType::PublicKey { key: eva_key } = eva.announcement().unwrap().message else {
+            panic!("eva's announcement isn't a key");
+        };
+        // Somebody announces a key for the own nickname to read the messages.
+        assert_eq!(slava.learn("slava-own", eva_key), Learned::Refused);
+        assert!(slava.seal(&MessageType::text("psst")).is_err());
+        assert_eq!(slava.learn("eva-own", eva_key), Learned::New);
+        let (mallory, mallory_path) = keyring("mallory-own");
+        mallory.generate().unwrap();
+        let MessageType::PublicKey { key: mallory_key } = mallory.announcement().unwrap().message
+        else {
+            panic!("mallory's announcement isn't a key");
+        };
+        assert_eq!(slava.learn("slava-own", mallory_key), Learned::Refused);
+        let sealed = slava.seal(&MessageType::text("psst")).unwrap();
+        let mut recipients: Vec<[u8; 32]> = sealed.recipients.iter().map(|r| r.key).collect();
+        recipients.sort_unstable();
+        let mut expected = vec![eva_key, slava.keys().own.as_ref().unwrap().public()];
+        expected.sort_unstable();
+        assert_eq!(recipients, expected);
+        assert_eq!(
+            mallory.open("slava-own", &sealed),
+            Err(CryptoError::NotForMe)
+        );
+        for path in [slava_path, eva_path, mallory_path] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_changed_key_stays_pinned() {
+        let (slava, slava_path) = keyring("slava-pin");
+        let (eva, eva_path) = keyring("eva-pin");
+        slava.generate().unwrap();
+        eva.generate().unwrap();
+        let key = |keyring: &Keyring| keyring.keys().own.as_ref().unwrap().public();
+        let first = key(&eva);
+        assert_eq!(slava.learn("eva-pin", first), Learned::New);
+        let text = MessageType::text("psst");
+        eva.learn("slava-pin", key(&slava));
+        let sealed = eva.seal(&text).unwrap();
+
+        // A server swaps the key of eva, the messages stay encrypted for the pinned one.
+        let (mallory, mallory_path) = keyring("mallory-pin");
+        mallory.generate().unwrap();
+        let swapped = key(&mallory);
+        assert_eq!(slava.learn("eva-pin", swapped), Learned::Changed);
+        assert_eq!(slava.learn("eva-pin", swapped), Learned::Changed);
+        let to_eva = slava.seal(&text).unwrap();
+        assert!(to_eva.recipients.iter().any(|r| r.key == first));
+        assert!(to_eva.recipients.iter().all(|r| r.key != swapped));
+        assert_eq!(
+            mallory.open("slava-pin", &to_eva),
+            Err(CryptoError::NotForMe)
+        );
+        assert_eq!(slava.open("eva-pin", &sealed).unwrap(), text);
+        assert!(slava.describe().contains(&format!(
+            "    changed to {}, accept it with .pubkeys accept eva-pin",
+            crypto::fingerprint(&swapped)
+        )));
+        // The pinned key announced again drops the change.
+        assert_eq!(slava.learn("eva-pin", first), Learned::Same);
+        assert!(slava.accept("eva-pin").is_err());
+
+        // Eva really generated a new key, the user accepts it after comparing the fingerprints.
+        eva.generate().unwrap();
+        let second = key(&eva);
+        assert_eq!(slava.learn("eva-pin", second), Learned::Changed);
+        assert_eq!(slava.accept("eva-pin").unwrap(), second);
+        let to_eva = slava.seal(&text).unwrap();
+        assert_eq!(eva.open("slava-pin", &to_eva).unwrap(), text);
+        assert!(!slava.describe().contains("changed to"));
+        for path in [slava_path, eva_path, mallory_path] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Selection of the files shared by the `.file` command.
+//!
+//! Arguments are separated by whitespace, quoted arguments may contain spaces. Arguments with `*`, `?` or `[` are
+//! glob patterns expanded to all the matching files.
+
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+
+/// Splits the arguments by whitespace, keeping the quoted parts together.
+///
+/// # Errors
+///
+/// This function will return an error if a quote is not closed.
+pub fn split_arguments(input: &str) -> Result<Vec<String>> {
+    let mut arguments = Vec::new();
+    let mut current = String::new();
+    let mut quote = None;
+    let mut started = false;
+    for c in input.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => current.push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                started = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if started {
+                    arguments.push(std::mem::take(&mut current));
+                    started = false;
+                }
+            }
+            (None, c) => {
+                current.push(c);
+                started = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        return Err(anyhow!("Missing closing quote in: {input}"));
+    }
+    if started {
+        arguments.push(current);
+    }
+    Ok(arguments)
+}
+
+/// Expands the glob patterns in the arguments.
+///
+/// # Returns
+///
+/// The paths to send and the descriptions of the arguments which can't be used.
+pub fn expand(arguments: &[String]) -> (Vec<PathBuf>, Vec<String>) {
+    let mut paths = Vec::new();
+    let mut errors = Vec::new();
+    for argument in arguments {
+        if !argument.contains(['*', '?', '[']) {
+            paths.push(PathBuf::from(argument));
+            continue;
+        }
+        let matches = match glob::glob(argument) {
+            Ok(matches) => matches,
+            Err(err_msg) => {
+                errors.push(format!("Invalid pattern {argument}: {err_msg}"));
+                continue;
+            }
+        };
+        let files: Vec<PathBuf> = matches
+            .filter_map(|entry| entry.ok())
+            .filter(|path| path.is_file())
+            .collect();
+        if files.is_empty() {
+            errors.push(format!("Pattern {argument} matches no files"));
+        }
+        paths.extend(files);
+    }
+    (paths, errors)
+}
+
+/// Formats the size in bytes for the summary, e.g. `3.1 MB`.
+pub fn format_size(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_arguments() {
+        assert_eq!(
+            split_arguments("a.txt  b.png \"dir with spaces/c.pdf\" 'd e'").unwrap(),
+            ["a.txt", "b.png", "dir with spaces/c.pdf", "d e"]
+        );
+        assert_eq!(split_arguments("\"\"").unwrap(), [""]);
+        assert!(split_arguments("\"open.txt").is_err());
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(2048), "2.0 KB");
+        assert_eq!(format_size(3_250_586), "3.1 MB");
+    }
+}
@@ -0,0 +1,66 @@
+//! Syntax highlighting of the shared code snippets.
+
+use std::sync::OnceLock;
+
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::{as_24_bit_terminal_escaped, LinesWithEndings};
+
+use crate::assets;
+
+/// Highlighting theme used unless the `highlight` key of the theme file names another one.
+pub const DEFAULT_THEME: &str = "base16-ocean.dark";
+const RESET: &str = "\x1b[0m";
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn themes() -> &'static ThemeSet {
+    static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+    THEMES.get_or_init(ThemeSet::load_defaults)
+}
+
+fn theme() -> &'static Theme {
+    let themes = &themes().themes;
+    themes
+        .get(&assets::theme().highlight)
+        .unwrap_or(&themes[DEFAULT_THEME])
+}
+
+/// Returns true if the highlighting theme is built in.
+pub fn has_theme(name: &str) -> bool {
+    themes().themes.contains_key(name)
+}
+
+/// Renders the code snippet.
+///
+/// Styled output is highlighted with terminal escape sequences, unknown languages are rendered as plain text.
+/// Plain output is fenced with the language name, like a Markdown code block.
+///
+/// # Arguments
+///
+/// * `lang` - Language name or file extension, e.g. `rust` or `rs`.
+/// * `source` - The source code.
+/// * `styled` - Apply terminal styling if true.
+pub fn render_code(lang: &str, source: &str, styled: bool) -> String {
+    if !styled {
+        return format!("```{lang}\n{}\n```", source.trim_end());
+    }
+    let syntaxes = syntaxes();
+    let syntax = syntaxes
+        .find_syntax_by_token(lang)
+        .unwrap_or_else(|| syntaxes.find_syntax_plain_text());
+    let mut highlighter = HighlightLines::new(syntax, theme());
+    let mut output = String::new();
+    for line in LinesWithEndings::from(source.trim_end()) {
+        match highlighter.highlight_line(line, syntaxes) {
+            Ok(ranges) => output.push_str(&as_24_bit_terminal_escaped(&ranges, false)),
+            Err(_) => output.push_str(line),
+        }
+    }
+    output.push_str(RESET);
+    output
+}
@@ -0,0 +1,30 @@
+//! Detection of a stalled connection to the server.
+//!
+//! A connection broken on a flaky network may look open for a long time, the socket reports no error and the client
+//! waits for messages that never come. The server sends a Ping every 30 seconds, so the client notices a stall when no
+//! byte arrived within the idle window ([`IDLE_TIMEOUT`] by default, `idle_timeout` in `client.json`, `0` disables
+//! it) and reconnects. Bytes of a big frame still arriving count as activity, so a slow download isn't a stall. The
+//! watching itself is [`chat::keepalive::Idle`].
+
+use std::time::Duration;
+
+pub use chat::keepalive::Idle;
+
+/// Default idle window in seconds, three missed pings of the server.
+pub const IDLE_TIMEOUT: u64 = chat::keepalive::DEAD_AFTER.as_secs();
+
+/// Converts the configured idle window in seconds, `0` disables the detection.
+pub fn window(seconds: u64) -> Option<Duration> {
+    (seconds > 0).then(|| Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window() {
+        assert_eq!(window(IDLE_TIMEOUT), Some(chat::keepalive::DEAD_AFTER));
+        assert_eq!(window(0), None);
+    }
+}
@@ -0,0 +1,149 @@
+//! Client side image conversion.
+//!
+//! Images shared with `.image` are converted to the configured [`TargetFormat`] and downscaled to fit into the
+//! configured maximal dimension before sending, so recipients always get consistently sized, valid images. Both are
+//! set by the `images` section of the client config, [`DEFAULT_FORMAT`] and [`IMAGE_MAX_DIMENSION`] by default.
+
+use std::io::Cursor;
+
+use anyhow::{Context, Result};
+use image::{DynamicImage, ImageFormat, ImageReader};
+use serde::{Deserialize, Serialize};
+
+/// Default format of the images sent to the chat.
+pub const DEFAULT_FORMAT: TargetFormat = TargetFormat::Png;
+/// Default maximal width and height of the images sent to the chat.
+pub const IMAGE_MAX_DIMENSION: u32 = 1920;
+
+/// Format the images are converted to before sending.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TargetFormat {
+    Png,
+    Jpeg,
+    Webp,
+    Bmp,
+}
+
+impl TargetFormat {
+    fn image_format(self) -> ImageFormat {
+        match self {
+            TargetFormat::Png => ImageFormat::Png,
+            TargetFormat::Jpeg => ImageFormat::Jpeg,
+            TargetFormat::Webp => ImageFormat::WebP,
+            TargetFormat::Bmp => ImageFormat::Bmp,
+        }
+    }
+}
+
+/// Conversion of the sent images, stored in the client config.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct ImageConfig {
+    /// Format of the sent images.
+    pub format: TargetFormat,
+    /// Maximal width and height of the sent images in pixels.
+    pub max_dimension: u32,
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        ImageConfig {
+            format: DEFAULT_FORMAT,
+            max_dimension: IMAGE_MAX_DIMENSION,
+        }
+    }
+}
+
+/// Converts the image to the configured format and downscales it to fit into the configured maximal dimension.
+///
+/// Images which already have the target format and fit into the dimensions are returned unchanged.
+///
+/// # Arguments
+///
+/// * `content` - Raw content of the image file (PNG, JPEG, BMP or WebP).
+/// * `config` - Target format and maximal dimension.
+///
+/// # Errors
+///
+/// This function will return an error if the content is not a supported image or the encoding fails.
+pub fn convert_image(content: Vec<u8>, config: &ImageConfig) -> Result<Vec<u8>> {
+    let target = config.format.image_format();
+    let max = config.max_dimension.max(1);
+    let reader = ImageReader::new(Cursor::new(&content))
+        .with_guessed_format()
+        .context("Reading image failed!")?;
+    let format = reader.format().context("Unknown image format!")?;
+    let (width, height) = reader
+        .into_dimensions()
+        .context("Reading image dimensions failed!")?;
+    let fits = width <= max && height <= max;
+    if format == target && fits {
+        return Ok(content);
+    }
+
+    let mut image =
+        image::load_from_memory_with_format(&content, format).context("Decoding image failed!")?;
+    if !fits {
+        image = image.thumbnail(max, max);
+    }
+    // JPEG has no alpha channel.
+    if target == ImageFormat::Jpeg {
+        image = DynamicImage::ImageRgb8(image.to_rgb8());
+    }
+    let mut converted = Cursor::new(Vec::new());
+    image
+        .write_to(&mut converted, target)
+        .context("Encoding image failed!")?;
+    Ok(converted.into_inner())
+}
+
+/// Returns the file extension matching the format of the received image.
+///
+/// Falls back to the extension of the [`DEFAULT_FORMAT`] for unknown content.
+pub fn image_extension(content: &[u8]) -> &'static str {
+    image::guess_format(content)
+        .unwrap_or(DEFAULT_FORMAT.image_format())
+        .extensions_str()
+        .first()
+        .copied()
+        .unwrap_or("png")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, RgbaImage};
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut content = Cursor::new(Vec::new());
+        DynamicImage::ImageRgba8(RgbaImage::new(width, height))
+            .write_to(&mut content, ImageFormat::Png)
+            .unwrap();
+        content.into_inner()
+    }
+
+    #[test]
+    fn test_convert_image() {
+        let small = png(40, 20);
+        assert_eq!(
+            convert_image(small.clone(), &ImageConfig::default()).unwrap(),
+            small
+        );
+
+        let config = ImageConfig {
+            format: TargetFormat::Jpeg,
+            max_dimension: 100,
+        };
+        let converted = convert_image(png(400, 200), &config).unwrap();
+        assert_eq!(image::guess_format(&converted).unwrap(), ImageFormat::Jpeg);
+        let image = image::load_from_memory(&converted).unwrap();
+        assert_eq!(image.dimensions(), (100, 50));
+        assert_eq!(image_extension(&converted), "jpg");
+
+        let config: ImageConfig = serde_json::from_str(r#"{"format": "webp"}"#).unwrap();
+        assert_eq!(config.max_dimension, IMAGE_MAX_DIMENSION);
+        let converted = convert_image(small, &config).unwrap();
+        assert_eq!(image::guess_format(&converted).unwrap(), ImageFormat::WebP);
+    }
+}
@@ -0,0 +1,391 @@
+//! Keyboard shortcuts of the quick actions.
+//!
+//! The terminal reads whole lines, so a shortcut is its key pressed alone on the line and followed by Enter, like Esc
+//! of the attachment confirmation. The client recognizes the escape sequence or control character the key types:
+//!
+//! - `ctrl+r` replies to the last received message, the reply is prefixed by the `@nickname` of its sender,
+//! - `ctrl+u` uploads a file chosen by the [`picker`](crate::picker),
+//! - `pageup` and `pagedown` scroll back through the stored messages a history page at a time.
+//!
+//! The keys are changed by the `keys` section of the client config, an empty key turns its action off. A key which
+//! can't be parsed, is needed by the terminal (e.g. `ctrl+c` or `ctrl+m`, the Enter) or is bound twice is reported at
+//! the start, the action keeps its default key or stays unbound. `.keys` prints the bindings.
+//!
+//! Unix terminals in line mode erase the line on Ctrl+U and reprint it on Ctrl+R before the client sees them, free
+//! them by `stty kill undef rprnt undef` or bind other keys, e.g. `alt+u`.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use chat::{Message, MessageType};
+
+use crate::connection;
+
+/// Control keys taken by the terminal: interrupt, end of input, suspend, flow control, Tab, Backspace and Enter.
+const RESERVED: [char; 9] = ['c', 'd', 'z', 's', 'q', 'i', 'h', 'j', 'm'];
+
+/// Action started by a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Reply,
+    Upload,
+    ScrollUp,
+    ScrollDown,
+}
+
+impl Action {
+    /// Describes what the action does, for the `.keys` help.
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Self::Reply => "reply to the last message",
+            Self::Upload => "upload a file chosen in the picker",
+            Self::ScrollUp => "show the older page of the history",
+            Self::ScrollDown => "show the newer page of the history",
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Reply => write!(f, "reply"),
+            Self::Upload => write!(f, "upload"),
+            Self::ScrollUp => write!(f, "scroll_up"),
+            Self::ScrollDown => write!(f, "scroll_down"),
+        }
+    }
+}
+
+/// Key of a shortcut, e.g. `ctrl+r`, `alt+u`, `pageup` or `f5`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Ctrl(char),
+    Alt(char),
+    PageUp,
+    PageDown,
+    Function(u8),
+}
+
+impl Key {
+    /// Returns the characters the terminal types for the key.
+    pub fn sequence(&self) -> String {
+        match self {
+            Self::Ctrl(c) => char::from(*c as u8 & 0x1f).to_string(),
+            Self::Alt(c) => format!("\x1b{c}"),
+            Self::PageUp => "\x1b[5~".to_string(),
+            Self::PageDown => "\x1b[6~".to_string(),
+            Self::Function(n @ 1..=4) => format!("\x1bO{}", char::from(b'P' + n - 1)),
+            Self::Function(n) => {
+                // The codes skip 16 and 22.
+                let code = match n {
+                    5 => 15,
+                    6..=10 => n + 11,
+                    _ => n + 12,
+                };
+                format!("\x1b[{code}~")
+            }
+        }
+    }
+}
+
+impl FromStr for Key {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim().to_lowercase();
+        let key = match s.as_str() {
+            "pageup" | "pgup" => Key::PageUp,
+            "pagedown" | "pgdn" => Key::PageDown,
+            _ => match (s.split_once('+'), s.strip_prefix('f')) {
+                (Some(("ctrl", c)), _) => Key::Ctrl(single(c, char::is_ascii_lowercase)?),
+                (Some(("alt", c)), _) => Key::Alt(single(c, char::is_ascii_alphanumeric)?),
+                (None, Some(n)) => match n.parse() {
+                    Ok(n @ 1..=12) => Key::Function(n),
+                    _ => return Err(anyhow!("Unknown key {s}, use f1 to f12!")),
+                },
+                _ => {
+                    return Err(anyhow!(
+                        "Unknown key {s}, use ctrl+letter, alt+letter, alt+digit, pageup, pagedown or f1 to f12!"
+                    ))
+                }
+            },
+        };
+        match key {
+            Key::Ctrl(c) if RESERVED.contains(&c) => Err(anyhow!("{key} is used by the terminal!")),
+            key => Ok(key),
+        }
+    }
+}
+
+/// Returns the only character of the text if it passes the check.
+fn single(text: &str, check: fn(&char) -> bool) -> Result<char> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if check(&c) => Ok(c),
+        _ => Err(anyhow!("Invalid key {text} after the modifier!")),
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ctrl(c) => write!(f, "ctrl+{c}"),
+            Self::Alt(c) => write!(f, "alt+{c}"),
+            Self::PageUp => write!(f, "pageup"),
+            Self::PageDown => write!(f, "pagedown"),
+            Self::Function(n) => write!(f, "f{n}"),
+        }
+    }
+}
+
+/// Keys of the actions, stored in the client config. An empty key turns the action off.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct KeysConfig {
+    pub reply: String,
+    pub upload: String,
+    pub scroll_up: String,
+    pub scroll_down: String,
+}
+
+impl Default for KeysConfig {
+    fn default() -> Self {
+        KeysConfig {
+            reply: "ctrl+r".to_string(),
+            upload: "ctrl+u".to_string(),
+            scroll_up: "pageup".to_string(),
+            scroll_down: "pagedown".to_string(),
+        }
+    }
+}
+
+/// Validated bindings of the keys with the problems found in the config.
+#[derive(Debug, Default)]
+pub struct Keymap {
+    bindings: Vec<(Key, Action)>,
+    /// Invalid or conflicting keys, replaced by the defaults or left unbound.
+    pub problems: Vec<String>,
+}
+
+impl Keymap {
+    /// Binds the keys of the config.
+    pub fn new(config: &KeysConfig) -> Keymap {
+        let defaults = KeysConfig::default();
+        let mut keymap = Keymap::default();
+        for (action, key, default) in [
+            (Action::Reply, &config.reply, &defaults.reply),
+            (Action::Upload, &config.upload, &defaults.upload),
+            (Action::ScrollUp, &config.scroll_up, &defaults.scroll_up),
+            (
+                Action::ScrollDown,
+                &config.scroll_down,
+                &defaults.scroll_down,
+            ),
+        ] {
+            if key.trim().is_empty() {
+                continue;
+            }
+            let key = match Key::from_str(key) {
+                Ok(key) => key,
+                Err(err_msg) => {
+                    keymap.problems.push(format!(
+                        "Invalid key of {action}, using {default}: {err_msg}"
+                    ));
+                    default.parse().expect("valid default key")
+                }
+            };
+            if let Some((_, bound)) = keymap.bindings.iter().find(|(other, _)| *other == key) {
+                keymap.problems.push(format!(
+                    "{key} is bound to {bound} already, {action} has no key."
+                ));
+                continue;
+            }
+            keymap.bindings.push((key, action));
+        }
+        keymap
+    }
+
+    /// Returns the action of the line typed by its key alone.
+    pub fn action(&self, input: &str) -> Option<Action> {
+        self.bindings
+            .iter()
+            .find(|(key, _)| key.sequence() == input)
+            .map(|(_, action)| *action)
+    }
+
+    /// Renders the bindings for the `.keys` command.
+    pub fn help(&self) -> String {
+        if self.bindings.is_empty() {
+            return "No keys are bound, set them in the keys section of client.json.".to_string();
+        }
+        let mut help = "keys (press the key, then Enter):".to_string();
+        for (key, action) in &self.bindings {
+            help.push_str(&format!(
+                "\n  {:<10} {}",
+                key.to_string(),
+                action.describe()
+            ));
+        }
+        help
+    }
+}
+
+/// Received messages the shortcuts act on, shared by the reading and writing loops.
+#[derive(Clone, Default)]
+pub struct Recent {
+    state: Arc<Mutex<RecentState>>,
+}
+
+#[derive(Default)]
+struct RecentState {
+    /// Sender and preview of the last received message.
+    last: Option<(String, String)>,
+    /// Id of the oldest message of the last history page, none if the page was empty.
+    oldest: Option<i64>,
+    /// The `before` of the history pages scrolled through by the keys, the shown one last.
+    pages: Vec<Option<i64>>,
+}
+
+impl Recent {
+    /// Remembers the message of another user to reply to, or the oldest message of a history page.
+    pub fn observe(&self, message: &Message) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        match &message.message {
+            MessageType::History(entries) => state.oldest = entries.first().map(|entry| entry.id),
+            _ if message.system => (),
+            MessageType::Text(_)
+            | MessageType::Code { .. }
+            | MessageType::Image(_)
+            | MessageType::File { .. }
+            | MessageType::Attachment { .. }
+            | MessageType::FileStart { .. }
+            | MessageType::Poll { .. } => {
+                let preview = connection::preview(&message.message);
+                state.last = Some((message.nickname.clone(), preview));
+            }
+            _ => (),
+        }
+    }
+
+    /// Returns the sender and the preview of the last received message.
+    pub fn last(&self) -> Option<(String, String)> {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .last
+            .clone()
+    }
+
+    /// Scrolls to the older history page.
+    ///
+    /// # Returns
+    ///
+    /// The `before` of the page to request, the latest page first, or `None` if there are no older messages.
+    pub fn page_up(&self) -> Option<Option<i64>> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let before = match state.pages.last() {
+            None => None,
+            Some(_) => Some(state.oldest?),
+        };
+        state.pages.push(before);
+        Some(before)
+    }
+
+    /// Scrolls to the newer history page.
+    ///
+    /// # Returns
+    ///
+    /// The `before` of the page to request, or `None` if the latest page is shown or nothing was scrolled.
+    pub fn page_down(&self) -> Option<Option<i64>> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.pages.pop();
+        state.pages.last().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat::HistoryEntry;
+
+    #[test]
+    fn test_key() {
+        let sequence = |key: &str| Key::from_str(key).unwrap().sequence();
+        assert_eq!(sequence("ctrl+r"), "\u{12}");
+        assert_eq!(sequence("Alt+1"), "\x1b1");
+        assert_eq!(sequence("pgup"), "\x1b[5~");
+        assert_eq!(sequence("pagedown"), "\x1b[6~");
+        assert_eq!(sequence("f2"), "\x1bOQ");
+        assert_eq!(sequence("f5"), "\x1b[15~");
+        assert_eq!(sequence("f12"), "\x1b[24~");
+        assert_eq!(Key::from_str("alt+u").unwrap().to_string(), "alt+u");
+        for invalid in [
+            "ctrl+c", "ctrl+m", "ctrl+1", "alt+uu", "f13", "shift+a", "home",
+        ] {
+            assert!(Key::from_str(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_keymap() {
+        let keymap = Keymap::new(&KeysConfig::default());
+        assert!(keymap.problems.is_empty());
+        assert_eq!(keymap.action("\u{15}"), Some(Action::Upload));
+        assert_eq!(keymap.action("\x1b[5~"), Some(Action::ScrollUp));
+        assert_eq!(keymap.action("hello"), None);
+
+        let keymap = Keymap::new(&KeysConfig {
+            reply: "ctrl+c".to_string(),
+            upload: "ctrl+r".to_string(),
+            scroll_up: String::new(),
+            scroll_down: "alt+j".to_string(),
+        });
+        assert_eq!(keymap.problems.len(), 2);
+        assert_eq!(keymap.action("\u{12}"), Some(Action::Reply));
+        assert_eq!(keymap.action("\u{15}"), None);
+        assert_eq!(keymap.action("\x1bj"), Some(Action::ScrollDown));
+        assert_eq!(
+            keymap.help(),
+            "keys (press the key, then Enter):\n  \
+             ctrl+r     reply to the last message\n  \
+             alt+j      show the newer page of the history"
+        );
+    }
+
+    #[test]
+    fn test_recent() {
+        let recent = Recent::default();
+        recent.observe(&Message::from("eva", MessageType::text("hello  there")));
+        recent.observe(&Message::system(MessageType::text("eva joined")));
+        assert_eq!(
+            recent.last(),
+            Some(("eva".to_string(), "hello there".to_string()))
+        );
+
+        let history = |ids: &[i64]| {
+            let entries = ids
+                .iter()
+                .map(|id| HistoryEntry {
+                    id: *id,
+                    nickname: "eva".to_string(),
+                    msg_type: "Text".to_string(),
+                    message: "hi".to_string(),
+                    timestamp: None,
+                })
+                .collect();
+            Message::system(MessageType::History(entries))
+        };
+        assert_eq!(recent.page_down(), None);
+        assert_eq!(recent.page_up(), Some(None));
+        recent.observe(&history(&[41, 42]));
+        assert_eq!(recent.page_up(), Some(Some(41)));
+        recent.observe(&history(&[]));
+        assert_eq!(recent.page_up(), None);
+        assert_eq!(recent.page_down(), Some(None));
+        assert_eq!(recent.page_down(), None);
+    }
+}
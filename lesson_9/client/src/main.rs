@@ -6,35 +6,142 @@
 //!
 //! - **hostname** default: localhost
 //! - **port** default: 11111
+//! - **--verbose** shows the raw causes of the errors
+//! - **--print-config** prints the effective configuration instead of starting the client
+//! - **--discover** lists the servers on this machine and the LAN to choose from instead of the address, see
+//!   [`discover`]
 //!
 //! # Commands:
 //!
-//! - Write your message
-//! - Share file: .file path_to_file.txt
+//! - Write your message, format it with *bold*, _italic_ and `code`
+//! - Share files: .file a.txt "dir with spaces/b.pdf" logs/*.log, or just .file to pick one
 //! - Share image: .image path_to_image.png
+//! - Attachments are described and sent after Enter, Esc cancels them, see [`confirm`]
+//! - Share directory: .dir path_to_directory, extract a received one with .extract n
+//! - Share code: .code rust, followed by the code lines and .end
+//! - History: .history [message_id]
+//! - Search: .search text, ignoring case and accents
+//! - Bookmarks: .bookmark message_id [note], .bookmark remove message_id, .bookmarks [message_id|export file.md]
+//! - Polls: .poll "question" option1 option2 ..., .vote poll_id option, .close poll_id
+//! - Report a message to the moderators: .report message_id reason
+//! - Rooms: .join room, .room [room] switches or lists them, .leave [room], see [`rooms`]
+//! - Whisper to one user, never stored by the server: .whisper nickname text
+//! - Active users: .who
+//! - Connection quality, round trip and recent problems: .connection, see [`quality`]
+//! - Contacts: .contact add nickname "alias" ["note"], .contact remove nickname, .contact export|import file.toml
+//! - Email digest of missed mentions: .digest email address, .digest daily HH:MM, .digest off
+//! - Times of the live messages: .time relative|absolute
+//! - Notification: .sound on|off|bell
+//! - Silent alerts: .alerts bell flash counter, or .alerts off
+//! - Theme and sound pack: .reload-assets, after changing the files in assets/
+//! - Transfer benchmark: .bench size_mb [--loop N]
+//! - Open download: .open [n]
+//! - Keyboard shortcuts: Ctrl+R replies, Ctrl+U uploads, PageUp and PageDown scroll the history, .keys lists
+//!   them, see [`keys`]
+//! - End-to-end encryption: .pubkeys new generates a key and turns it on, .pubkeys on|off, .pubkeys shows the
+//!   fingerprints, see [`encryption`]
+//! - Delete old downloads: .cleanup
+//! - Learn the basics offline: .tutorial, see [`tutorial`]
 //! - Leave: .quit
 
 extern crate chat;
 
-use chat::{Message, MessageType};
-use std::path::Path;
-use std::thread;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+mod alerts;
+mod archive;
+mod assets;
+mod attachments;
+mod bench;
+mod bookmarks;
+mod cleanup;
+mod clock;
+mod config;
+mod confirm;
+mod connection;
+mod contacts;
+mod delivery;
+mod digest;
+mod discover;
+mod downloads;
+mod encryption;
+mod files;
+mod highlight;
+mod idle;
+mod images;
+mod keys;
+mod markdown;
+mod outbox;
+mod picker;
+mod polls;
+mod quality;
+mod rooms;
+mod roster;
+mod server_info;
+mod sound;
+mod tutorial;
+mod writer;
+
+use alerts::{Alert, Alerts};
+use assets::{Assets, SoundEvent, ASSETS_DIR};
+use attachments::Fetcher;
+use bookmarks::{BookmarkCommand, Bookmarks};
+use clock::{Clock, TimeStyle};
+use chat::codec::{self, MessageCodec};
+use chat::report::{self, Report};
+use chat::transfer::Transfers;
+use chat::{ErrorCode, HistoryEntry, Message, MessageError, MessageType};
+use config::Config;
+use connection::{Link, Sent, State};
+use contacts::{ContactCommand, Contacts};
+use downloads::{AutoAction, Downloads};
+use encryption::{Keyring, KeysCommand, Learned};
+use keys::{Action, Keymap, Recent};
+use outbox::Outbox;
+use quality::{Event, Observed};
+use roster::Applied;
+use server_info::ServerInfo;
+use sound::{Sound, SoundMode};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Context, Result};
-use rodio::{source::Source, Decoder, OutputStream};
+use futures_util::StreamExt;
 use slugify::slugify;
 use tokio::fs::{self, File};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_util::codec::FramedRead;
 
 const IMAGE_FOLDER: &str = "IMAGES";
 const FILE_FOLDER: &str = "FILES";
-const SOUND_FILE: &str = "meow.wav";
+const DOWNLOAD_FOLDERS: [&str; 2] = [IMAGE_FOLDER, FILE_FOLDER];
+
+/// Files streamed in chunks being received, written straight to the [`FILE_FOLDER`].
+type Streams = Transfers<std::fs::File>;
+const HISTORY_PAGE: u32 = 20;
 
 enum Command {
     Message(Message),
+    Files(Vec<PathBuf>),
+    Sound(SoundMode),
+    Time(TimeStyle),
+    Alerts(Vec<Alert>),
+    ReloadAssets,
+    Who,
+    Connection,
+    Contact(ContactCommand),
+    Bookmark(BookmarkCommand),
+    Join(String),
+    Leave(Option<String>),
+    Room(Option<String>),
+    Bench { size_mb: usize, count: u32 },
+    Open(Option<usize>),
+    Extract(usize),
+    Cleanup,
+    Shortcuts,
+    Keys(KeysCommand),
+    Tutorial,
     Quit,
 }
 
@@ -42,9 +149,40 @@ fn print_help(nickname: &str) {
     println!("");
     println!("{nickname} welcome to chat!");
     println!("");
-    println!("write your message or use command:");
-    println!(".file path_to_file.txt");
+    println!("write your message (*bold*, _italic_, `code`) or use command:");
+    println!(".file [path_to_file.txt, more files or patterns like logs/*.log]");
     println!(".image path_to_image.png");
+    println!(".dir path_to_directory");
+    println!(".code language (finish the code with .end)");
+    println!(".history [message_id]");
+    println!(".search text");
+    println!(".bookmark message_id [note]|remove message_id");
+    println!(".bookmarks [message_id|export file.md]");
+    println!(".poll \"question\" option1 option2 ...");
+    println!(".vote poll_id option");
+    println!(".close poll_id");
+    println!(".report message_id reason");
+    println!(".join room");
+    println!(".room [room]");
+    println!(".leave [room]");
+    println!(".whisper nickname text");
+    println!(".who");
+    println!(".connection");
+    println!(
+        ".contact [add nickname \"alias\" [\"note\"]|remove nickname|list|export file|import file]"
+    );
+    println!(".digest email address|daily HH:MM|off");
+    println!(".time relative|absolute");
+    println!(".sound on|off|bell");
+    println!(".alerts bell flash counter|off");
+    println!(".reload-assets");
+    println!(".bench size_mb [--loop N]");
+    println!(".open [n]");
+    println!(".extract n");
+    println!(".cleanup");
+    println!(".keys");
+    println!(".pubkeys [new|on|off|accept <nickname>]");
+    println!(".tutorial");
     println!(".quit");
     println!("");
 }
@@ -53,34 +191,164 @@ fn print_help(nickname: &str) {
 ///
 /// This function parses the arguments to get the address of the server,
 /// connects to the server, and splits the stream into reading and writing parts.
-/// It then gets the user's nickname, prints the help message, and spawns the
-/// reading loop in a separate task. The writing loop runs in the main task.
+/// It then gets the user's nickname, sends the messages it queued offline, prints the help message, and spawns the
+/// reading loop in a separate task, which reconnects when the connection breaks or stalls.
+/// If the server is not reachable, the client starts offline and the reading task connects in the background.
+/// The writing loop runs in the main task.
 ///
 /// # Errors
 ///
-/// This function will return an error if there is a problem connecting to the server,
+/// This function will return an error if the queued messages can't be loaded,
 /// getting the nickname, or if there is an error in the reading or writing loops.
 async fn run_client() -> Result<()> {
-    let address = chat::Address::parse_arguments();
-    let stream = TcpStream::connect(address.to_string()).await?;
-    let (reading_stream, writing_stream) = stream.into_split();
+    let mut arguments: Vec<String> = std::env::args().collect();
+    let verbose = report::take_verbose(&mut arguments);
+    let print_config = config::take_print_config(&mut arguments);
+    let discover = discover::take_discover(&mut arguments);
+    let mut address = chat::Address::from_arguments(&arguments);
+    if print_config {
+        println!(
+            "{}",
+            config::render_effective(&Config::load(), &address, verbose)?
+        );
+        return Ok(());
+    }
+    if discover {
+        address = discover::pick().await?;
+    }
+    let stream = match TcpStream::connect(address.to_string()).await {
+        Ok(stream) => Some(stream.into_split()),
+        Err(err_msg) => {
+            eprintln!("Connecting to {} failed: {}", address.to_string(), err_msg);
+            println!("Working offline, the messages are queued until the server is reachable.");
+            None
+        }
+    };
     let nickname = get_nickname()?;
+    let outbox = Outbox::for_nickname(&nickname);
+    let outbox_path = outbox.path().display().to_string();
+    let link = Link::new(outbox)
+        .with_context(|| format!("Loading queued messages from {outbox_path} failed!"))?;
+    let queued = link.pending().await;
+    if queued > 0 {
+        println!("{queued} messages queued (offline) in the last session.");
+    }
+    let reading_stream = match stream {
+        Some((reading_stream, writing_stream)) => {
+            print_attached(link.attach(writing_stream).await);
+            Some(reading_stream)
+        }
+        None => None,
+    };
+    let reading_link = link.clone();
+    tokio::spawn(retry_unacknowledged(link.clone()));
     print_help(&nickname);
+    let config = Config::load();
+    if !config.tutorial_offered {
+        println!("New here? Type .tutorial to try the basic commands offline.");
+        if let Err(err_msg) = Config::update(|config| config.tutorial_offered = true) {
+            eprintln!("Saving the config failed: {}", err_msg);
+        }
+    }
+    let keymap = Keymap::new(&config.keys);
+    for problem in &keymap.problems {
+        eprintln!("{problem}");
+    }
+    let assets = Assets::load(Path::new(ASSETS_DIR));
+    for problem in &assets.problems {
+        eprintln!("{problem}");
+    }
+    let sound = Sound::new(config.sound, assets.apply(), Alerts::new(config.alerts));
+    let reading_sound = sound.clone();
+    let (bench_acks, mut acks) = mpsc::unbounded_channel();
+    let downloads = Downloads::new(config.auto_open);
+    let reading_downloads = downloads.clone();
+    let keyring = Keyring::load(
+        &nickname,
+        encryption::key_file(&nickname),
+        config.encryption,
+    );
+    let server = ServerInfo::new(&nickname).with_keyring(keyring);
+    server.clock().set_style(config.time_style);
+    cleanup::spawn(download_folders(), config.cleanup);
+    let reading_server = server.clone();
+    let fetcher = Fetcher::new(address.hostname());
+    let window = idle::window(config.idle_timeout);
+    tokio::spawn(monitor_quality(link.clone(), nickname.clone()));
     tokio::spawn(async move {
-        reading_loop(reading_stream)
-            .await
-            .unwrap_or_else(|err_msg| eprintln!("Reading error: {:?}", err_msg))
+        let mut stream = reading_stream;
+        let idle = reading_link.quality().idle().clone();
+        loop {
+            if let Some(stream) = stream.take() {
+                let reading = reading_loop(
+                    idle.watch(stream),
+                    reading_link.clone(),
+                    reading_sound.clone(),
+                    bench_acks.clone(),
+                    reading_downloads.clone(),
+                    reading_server.clone(),
+                    fetcher.clone(),
+                );
+                let event = tokio::select! {
+                    result = reading => {
+                        if let Err(err_msg) = result {
+                            eprintln!("Reading error: {}", Report::new(err_msg.as_ref()));
+                        }
+                        Event::Disconnect
+                    }
+                    _ = reading_link.disconnected() => {
+                        eprintln!("Sending error, the connection is broken.");
+                        Event::Disconnect
+                    }
+                    stall = idle.stalled(window) => {
+                        eprintln!("No data from the server for {} s, the connection is stalled.", stall.as_secs());
+                        Event::Stall
+                    }
+                };
+                reading_link.quality().record(event, Instant::now());
+                reading_link.detach().await;
+                reading_server.roster().clear();
+                println!(
+                    "Connection lost, reconnecting to {}...",
+                    address.to_string()
+                );
+            }
+            let (reader, writer) = connection::reconnect(&address).await;
+            println!("Connected to {}.", address.to_string());
+            print_attached(reading_link.attach(writer).await);
+            stream = Some(reader);
+        }
     });
-    writing_loop(writing_stream, &nickname).await?;
+    writing_loop(
+        &link, &nickname, &sound, &mut acks, &downloads, &server, &keymap,
+    )
+    .await?;
     Ok(())
 }
 
+/// Reports the queued messages sent after connecting.
+fn print_attached(flushed: Result<usize>) {
+    match flushed {
+        Ok(0) => (),
+        Ok(flushed) => {
+            println!("Sent {flushed} queued messages, waiting for the server to confirm them.")
+        }
+        Err(err_msg) => eprintln!("Sending queued messages failed: {}", err_msg),
+    }
+}
+
+/// Asks for the nickname until the user chooses one not reserved for the server.
 fn get_nickname() -> Result<String> {
-    let mut input = String::new();
     println!("Choose your nickname:");
-    std::io::stdin().read_line(&mut input)?;
-    let nickname = slugify!(input.trim());
-    Ok(nickname)
+    loop {
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input)?;
+        let nickname = slugify!(input.trim());
+        if !chat::is_reserved(&nickname) {
+            return Ok(nickname);
+        }
+        println!("{nickname} is reserved for the server, choose another nickname:");
+    }
 }
 
 /// Reads messages from the server in a loop.
@@ -89,20 +357,281 @@ fn get_nickname() -> Result<String> {
 ///
 /// # Arguments
 ///
-/// * `stream` - The read half of the TCP stream.
+/// * `stream` - The read half of the TCP stream, watched for stalls.
+/// * `link` - The connection to the server, remembering the last sent message the server errors refer to.
+/// * `sound` - The notification player.
+/// * `bench_acks` - Receives the ids of the acknowledged `.bench` payloads instead of printing them.
+/// * `downloads` - Records the saved attachments.
+/// * `server` - Remembers the features, limits and time announced by the server.
+/// * `fetcher` - Downloads the big files delivered over HTTP.
 ///
 /// # Errors
 ///
 /// This function will return an error if there is a problem reading from the stream.
-async fn reading_loop(mut stream: OwnedReadHalf) -> Result<()> {
+async fn reading_loop(
+    stream: impl AsyncRead + Unpin,
+    link: Link,
+    sound: Sound,
+    bench_acks: mpsc::UnboundedSender<u32>,
+    downloads: Downloads,
+    server: ServerInfo,
+    fetcher: Fetcher,
+) -> Result<()> {
+    let mut frames = FramedRead::new(stream, MessageCodec::default());
+    let mut streams = Streams::default();
     loop {
-        let message = chat::Message::read(&mut stream).await?;
-        if let Err(err_msg) = handle_message(message).await {
+        let message = match codec::flatten(frames.next().await) {
+            Err(MessageError::ChecksumMismatch { .. }) => {
+                eprintln!("A message from the server was corrupted on the way and dropped.");
+                continue;
+            }
+            read => read?,
+        };
+        // Only the server controls the connection, the same messages sent by a client are just printed.
+        if message.system && control(&message.message, &link, &sound, &bench_acks, &server).await {
+            continue;
+        }
+        // The chunks of a streamed file are written silently, only its start and end are printed.
+        if let MessageType::FileChunk { id, offset, data } = &message.message {
+            if let Err(err_msg) = streams.chunk(&message.nickname, *id, *offset, data) {
+                eprintln!(
+                    "Receiving a file from {} failed: {}",
+                    message.nickname, err_msg
+                );
+            }
+            continue;
+        }
+        // The keys are remembered silently, the encrypted messages are printed decrypted.
+        if let MessageType::PublicKey { key } = &message.message {
+            let fingerprint = chat::crypto::fingerprint(key);
+            match server.keyring().learn(&message.nickname, *key) {
+                Learned::Changed => println!(
+                    "{0} has a new key {fingerprint}, the previous one is used until you compare it with them and \
+                     accept it by .pubkeys accept {0}.",
+                    message.nickname
+                ),
+                Learned::Refused => eprintln!(
+                    "Ignoring the key {fingerprint} announced for your nickname, it isn't yours."
+                ),
+                Learned::New | Learned::Same => (),
+            }
+            continue;
+        }
+        let Some(message) = decrypt(message, server.keyring()) else {
+            continue;
+        };
+        server.recent().observe(&message);
+        let event = match message.message {
+            MessageType::Image(_)
+            | MessageType::File { .. }
+            | MessageType::Attachment { .. }
+            | MessageType::FileEnd { .. } => SoundEvent::File,
+            _ => SoundEvent::Message,
+        };
+        if let Err(err_msg) =
+            handle_message(message, &downloads, &server, &fetcher, &mut streams).await
+        {
             eprintln!("Message handling error: {:?}", err_msg);
         };
-        thread::spawn(move || {
-            meow().unwrap_or_else(|err_msg| eprintln!("Sound error {:?}", err_msg))
-        });
+        sound.notify(event);
+    }
+}
+
+/// Replaces the encrypted message by its content annotated by the fingerprint of the sender's key, see [`encryption`].
+///
+/// # Returns
+///
+/// The message to print, the encrypted one if it isn't for the user's key, or `None` if it can't be decrypted.
+fn decrypt(mut message: Message, keyring: &Keyring) -> Option<Message> {
+    let MessageType::Encrypted(sealed) = &message.message else {
+        return Some(message);
+    };
+    match keyring.open(&message.nickname, sealed) {
+        Ok(content) => {
+            let fingerprint = chat::crypto::fingerprint(&sealed.sender_key);
+            message.message = content;
+            message
+                .annotations
+                .push((encryption::ENCRYPTED_ANNOTATION.to_string(), fingerprint));
+            Some(message)
+        }
+        Err(chat::crypto::CryptoError::NotForMe) => Some(message),
+        Err(err_msg) => {
+            eprintln!(
+                "An encrypted message from {} can't be read: {}",
+                message.nickname, err_msg
+            );
+            None
+        }
+    }
+}
+
+/// Handles the message of the server controlling the connection, e.g. a ping or an acknowledgement.
+///
+/// # Returns
+///
+/// True if the message was consumed, false if it should be printed.
+async fn control(
+    message: &MessageType,
+    link: &Link,
+    sound: &Sound,
+    bench_acks: &mpsc::UnboundedSender<u32>,
+    server: &ServerInfo,
+) -> bool {
+    match message {
+        MessageType::Ping { server_time } => {
+            server.clock().sync(*server_time);
+            if let Some(pong) = server.pong(message) {
+                // A failure is noticed by the reconnection.
+                let _ = link.send_now(&pong).await;
+            }
+        }
+        MessageType::Welcome {
+            server_time,
+            capabilities,
+            ..
+        } => {
+            server.clock().sync(*server_time);
+            if capabilities
+                .iter()
+                .any(|capability| capability == "checksum")
+            {
+                link.enable_checksum().await;
+            }
+            if capabilities
+                .iter()
+                .any(|capability| capability == "compression")
+            {
+                link.enable_compression().await;
+            }
+            if capabilities.iter().any(|capability| capability == "acks") {
+                link.enable_acks().await;
+            }
+            if capabilities.iter().any(|capability| capability == "roster") {
+                request_roster(link, server).await;
+            }
+            if capabilities.iter().any(|capability| capability == "rooms") {
+                restore_rooms(link, server).await;
+            }
+            if capabilities.iter().any(|capability| capability == "e2e") {
+                announce_key(link, server).await;
+            }
+            return false;
+        }
+        MessageType::RosterSnapshot { version, users } => server.roster().replace(*version, users),
+        MessageType::RosterDelta { version, change } => {
+            if server.roster().apply(*version, change) == Applied::Gap {
+                request_roster(link, server).await;
+            }
+        }
+        MessageType::BenchAck { id, .. } => {
+            let _ = bench_acks.send(*id);
+        }
+        MessageType::Ack {
+            message_id,
+            sent_at,
+        } => {
+            if let Some(preview) = link.deliveries().acknowledge(*sent_at) {
+                println!("{}", render_delivered(&preview, *message_id));
+            }
+        }
+        MessageType::SyncAck { id } if quality::is_probe(*id) => {
+            link.quality().answered(*id, Instant::now());
+        }
+        MessageType::SyncAck { id } => match link.acknowledge(*id).await {
+            Ok(0) => (),
+            Ok(delivered) => println!("{delivered} queued messages delivered."),
+            Err(err_msg) => eprintln!("Updating the queue failed: {}", err_msg),
+        },
+        MessageType::ServerError { code } => {
+            println!("{}", render_error(code, link.last_sent().await.as_deref()));
+            sound.notify(SoundEvent::Error);
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// Asks the server for a fresh roster, a failure is noticed by the reconnection.
+async fn request_roster(link: &Link, server: &ServerInfo) {
+    if let Err(err_msg) = link.send_now(&server.roster().request()).await {
+        eprintln!("Requesting the users failed: {}", err_msg);
+    }
+}
+
+/// Joins the rooms of the previous connection again, a failure is noticed by the reconnection.
+async fn restore_rooms(link: &Link, server: &ServerInfo) {
+    for request in server.rooms().restore() {
+        if let Err(err_msg) = link.send_now(&request).await {
+            eprintln!("Joining the rooms failed: {}", err_msg);
+            return;
+        }
+    }
+}
+
+/// Announces the public key of the user, a failure is noticed by the reconnection.
+async fn announce_key(link: &Link, server: &ServerInfo) {
+    if let Some(announcement) = server.keyring().announcement() {
+        if let Err(err_msg) = link.send_now(&announcement).await {
+            eprintln!("Announcing the key failed: {}", err_msg);
+        }
+    }
+}
+
+/// Sends the messages the server hasn't acknowledged in time again while connected, reporting the ones given up.
+async fn retry_unacknowledged(link: Link) {
+    let mut interval = tokio::time::interval(delivery::ACK_TIMEOUT / 3);
+    loop {
+        interval.tick().await;
+        if link.state() == State::Disconnected {
+            continue;
+        }
+        let expired = link.deliveries().expired(Instant::now());
+        for message in expired.retry {
+            link.quality().record(Event::Retry, Instant::now());
+            let preview = connection::preview(&message.message);
+            if let Some(queued) = render_queued(&preview, link.resend(message).await) {
+                println!("{queued}");
+            }
+        }
+        for preview in expired.failed {
+            eprintln!(
+                "  {} \"{preview}\": not delivered, the server didn't confirm it",
+                assets::theme().error
+            );
+        }
+    }
+}
+
+/// Returns the state of the connection scored by its [`quality::Quality`].
+async fn observe(link: &Link) -> Observed {
+    Observed {
+        connected: link.state() == State::Connected,
+        queued: link.pending().await,
+        unacked: link.deliveries().waiting(),
+    }
+}
+
+/// Probes the round trip time while connected, printing the quality indicator whenever its score changes.
+async fn monitor_quality(link: Link, nickname: String) {
+    let mut interval = tokio::time::interval(quality::PROBE_INTERVAL);
+    let mut shown = quality::DOTS;
+    loop {
+        interval.tick().await;
+        if link.state() == State::Disconnected {
+            // The lost connection is reported by the reading task.
+            shown = 0;
+            continue;
+        }
+        // Scored before the next probe, so a probe unanswered since the last tick counts as slow.
+        let score = link.quality().score(observe(&link).await, Instant::now());
+        if score != shown {
+            println!("connection {}", quality::indicator(score));
+            shown = score;
+        }
+        let probe = Message::from(&nickname, link.quality().probe(Instant::now()));
+        // A failure is noticed by the reconnection.
+        let _ = link.send_now(&probe).await;
     }
 }
 
@@ -113,32 +642,209 @@ async fn reading_loop(mut stream: OwnedReadHalf) -> Result<()> {
 ///
 /// # Arguments
 ///
-/// * `stream` - The write half of the TCP stream.
+/// * `link` - The connection to the server, queueing the messages while it is broken.
 /// * `nickname` - The user's nickname.
+/// * `sound` - The notification player, configured by the `.sound` and `.alerts` commands.
+/// * `acks` - Ids of the acknowledged `.bench` payloads.
+/// * `downloads` - Recent downloads opened by the `.open` command.
+/// * `server` - Remembers the features and limits of the server and the messages the shortcuts act on.
+/// * `keymap` - Keys of the shortcuts, printed by the `.keys` command.
 ///
 /// # Errors
 ///
 /// This function will return an error if there is a problem writing to the stream.
-async fn writing_loop(mut stream: OwnedWriteHalf, nickname: &str) -> Result<()> {
+async fn writing_loop(
+    link: &Link,
+    nickname: &str,
+    sound: &Sound,
+    acks: &mut mpsc::UnboundedReceiver<u32>,
+    downloads: &Downloads,
+    server: &ServerInfo,
+    keymap: &Keymap,
+) -> Result<()> {
     loop {
-        match get_input(nickname).await {
+        let input = get_input(nickname, keymap, server.recent()).await;
+        sound.alerts().mark_read();
+        match input {
             Ok(result) => match result {
                 Command::Quit => break,
-                Command::Message(message) => message.send(&mut stream).await?,
+                Command::Message(message)
+                    if message.message.is_no_persist() && link.state() == State::Disconnected =>
+                {
+                    eprintln!("Not connected, a whisper isn't queued offline.")
+                }
+                Command::Message(mut message) => {
+                    let preview = connection::preview(&message.message);
+                    match prepare(server, message.message) {
+                        Ok(prepared) => {
+                            message.message = prepared;
+                            message.room = server.rooms().current();
+                            if let Some(queued) = render_queued(&preview, link.send(message).await) {
+                                println!("{queued}");
+                            }
+                        }
+                        Err(err_msg) => eprintln!("{err_msg}"),
+                    }
+                }
+                Command::Join(_) | Command::Leave(_) if !server.supports("rooms") => {
+                    eprintln!("The server doesn't support rooms!")
+                }
+                Command::Join(room) => match server.rooms().join(&room) {
+                    Ok(request) => {
+                        send_request(link, request).await;
+                        println!("Writing to #{room}.");
+                    }
+                    Err(err_msg) => eprintln!("{err_msg}"),
+                },
+                Command::Leave(room) => match server.rooms().leave(room.as_deref()) {
+                    Ok(request) => {
+                        send_request(link, request).await;
+                        println!("Writing to #{}.", server.rooms().current());
+                    }
+                    Err(err_msg) => eprintln!("{err_msg}"),
+                },
+                Command::Room(Some(room)) => match server.rooms().switch(&room) {
+                    Ok(()) => println!("Writing to #{room}."),
+                    Err(err_msg) => eprintln!("{err_msg}"),
+                },
+                Command::Room(None) => println!("{}", server.rooms().describe()),
+                Command::Files(paths) => send_files(link, nickname, &paths, server).await,
+                Command::Sound(mode) => {
+                    sound.set_mode(mode);
+                    Config::update(|config| config.sound = mode)?;
+                    println!("Sound: {mode}");
+                }
+                Command::Time(style) => {
+                    server.clock().set_style(style);
+                    Config::update(|config| config.time_style = style)?;
+                    println!("Time: {style}");
+                }
+                Command::Alerts(alerts) => {
+                    println!("Alerts: {}", alerts::describe(&alerts));
+                    sound.alerts().set(alerts.clone());
+                    Config::update(|config| config.alerts = alerts)?;
+                }
+                Command::ReloadAssets => reload_assets(sound),
+                Command::Cleanup => {
+                    let config = Config::load().cleanup;
+                    if config.is_off() {
+                        println!("Cleanup is off, set cleanup.max_age_days or cleanup.max_size_mb in client.json.");
+                    } else {
+                        match cleanup::run(download_folders(), config).await {
+                            Ok(freed) => println!("Cleanup {freed}."),
+                            Err(err_msg) => eprintln!("Cleanup error: {:#}", err_msg),
+                        }
+                    }
+                }
+                Command::Who => match server.roster().users() {
+                    Some(users) => {
+                        let users: Vec<String> = users.iter().map(|user| contacts::describe(user)).collect();
+                        println!("{} online: {}", users.len(), users.join(", "))
+                    }
+                    None => println!("The users are not known yet, the server doesn't send them or is connecting."),
+                },
+                Command::Connection => {
+                    let observed = observe(link).await;
+                    println!("{}", link.quality().describe(observed, Instant::now()))
+                }
+                Command::Bookmark(command) => match Bookmarks::run(command) {
+                    Ok(output) => println!("{output}"),
+                    Err(err_msg) => eprintln!("Bookmarks error: {:#}", err_msg),
+                },
+                Command::Contact(command) => match Contacts::run(command) {
+                    Ok(output) => println!("{output}"),
+                    Err(err_msg) => eprintln!("Contacts error: {:#}", err_msg),
+                },
+                Command::Bench { .. } if link.state() == State::Disconnected => {
+                    eprintln!("Not connected, run the bench after reconnecting.")
+                }
+                Command::Bench { .. } if !server.supports("bench") => {
+                    eprintln!("The server doesn't support bench!")
+                }
+                Command::Bench { size_mb, count } => {
+                    match bench::run(link, nickname, size_mb, count, acks).await {
+                        Ok(summary) => println!("{summary}"),
+                        Err(err_msg) => eprintln!("Bench error: {}", err_msg),
+                    }
+                }
+                Command::Open(Some(n)) => match downloads.get(n) {
+                    Some(path) => downloads::open(&path)
+                        .unwrap_or_else(|err_msg| eprintln!("Open error: {}", err_msg)),
+                    None => eprintln!("No download {n}, use .open to list them."),
+                },
+                Command::Open(None) => print_downloads(downloads),
+                Command::Shortcuts => println!("{}", keymap.help()),
+                Command::Keys(command) => {
+                    if let Err(err_msg) = run_keys(command, link, server).await {
+                        eprintln!("Keys error: {:#}", err_msg);
+                    }
+                }
+                Command::Tutorial => {
+                    let (mut input, mut output) = (std::io::stdin().lock(), std::io::stdout());
+                    if let Err(err_msg) = tutorial::run(nickname, &mut input, &mut output).await {
+                        eprintln!("Tutorial error: {}", err_msg);
+                    }
+                }
+                Command::Extract(n) => extract(downloads, n).await,
             },
             Err(err_msg) => eprintln!("Input error: {}", err_msg),
         }
     }
+    let pending = link.pending().await;
+    if pending > 0 {
+        eprintln!("{pending} messages stay queued (offline), they are sent on the next start as {nickname}.");
+    }
     Ok(())
 }
 
-async fn get_input(nickname: &str) -> Result<Command> {
+async fn get_input(nickname: &str, keymap: &Keymap, recent: &Recent) -> Result<Command> {
     let mut input = String::new();
     std::io::stdin().read_line(&mut input)?;
     let input = input.trim().to_string();
+    if let Some(action) = keymap.action(&input) {
+        return shortcut(action, nickname, recent);
+    }
     parse_input(input, nickname).await
 }
 
+/// Returns the command of the keyboard shortcut, see [`keys`].
+///
+/// # Errors
+///
+/// This function will return an error if there is nothing to reply to or scroll to, or the user cancelled the action.
+fn shortcut(action: Action, nickname: &str, recent: &Recent) -> Result<Command> {
+    use std::io::Write;
+
+    let message = match action {
+        Action::Reply => {
+            let (sender, preview) = recent
+                .last()
+                .ok_or(anyhow!("No message to reply to yet!"))?;
+            print!("reply to {sender} (\"{preview}\"), empty to cancel: ");
+            std::io::stdout().flush()?;
+            let mut input = String::new();
+            std::io::stdin().read_line(&mut input)?;
+            match input.trim() {
+                "" => return Err(anyhow!("Reply cancelled!")),
+                text => MessageType::text(format!("@{sender} {text}")),
+            }
+        }
+        Action::Upload if picker::is_interactive() => return pick_file(),
+        Action::Upload => return Err(anyhow!("The file picker needs a terminal, use .file path!")),
+        Action::ScrollUp => {
+            let before = recent.page_up().ok_or(anyhow!("No older messages!"))?;
+            MessageType::history_request(before, HISTORY_PAGE)
+        }
+        Action::ScrollDown => {
+            let before = recent
+                .page_down()
+                .ok_or(anyhow!("The latest messages are shown already!"))?;
+            MessageType::history_request(before, HISTORY_PAGE)
+        }
+    };
+    Ok(Command::Message(Message::from(nickname, message)))
+}
+
 /// Parses the given input string and returns a `Command` based on the input content.
 ///
 /// This function processes the input string to determine the type of command being issued.
@@ -158,31 +864,222 @@ async fn get_input(nickname: &str) -> Result<Command> {
 ///
 /// The function recognizes the following commands:
 ///
-/// * `.file <path>` - Sends a file located at the specified path.
-/// * `.image <path>` - Sends an image located at the specified path.
+/// * `.file <paths>` - Sends the files, see [`files::split_arguments`] and [`files::expand`]. Without paths on a
+///   terminal it opens [`picker::pick_file`].
+/// * `.code <lang>` - Sends the code typed on the following lines, terminated by `.end`.
+/// * `.dir <path>` - Sends the directory packed by [`archive::pack`].
+/// * `.image <path>` - Sends an image located at the specified path, converted by [`images::convert_image`].
+/// * `.history [id]` - Requests the latest stored messages or the messages older than the id.
+/// * `.search <text>` - Requests the latest stored messages containing the text, ignoring case and accents.
+/// * `.bookmark <id> [note]` - Bookmarks the stored message, see [`bookmarks::parse_bookmark`].
+/// * `.bookmarks [id|export <file>]` - Lists the bookmarks, requests the messages around one or exports them.
+/// * `.poll <question> <options>` - Creates a poll, see [`polls::parse_poll`].
+/// * `.vote <poll-id> <option>` - Votes for the option given by its text or number.
+/// * `.close <poll-id>` - Closes the own poll and announces its final results.
+/// * `.report <id> <reason>` - Reports the stored message to the moderators of the server.
+/// * `.join <room>` - Joins the room and writes to it, see [`rooms::Rooms`].
+/// * `.room [room]` - Writes to the joined room, lists the joined rooms without a room.
+/// * `.leave [room]` - Leaves the room, the current one without a room.
+/// * `.whisper <nickname> <text>` - Sends the text only to the user, the server doesn't store it.
+/// * `.who` - Lists the active users, see [`roster::Roster`].
+/// * `.contact [action]` - Manages the local contacts, see [`contacts::parse_contact`].
+/// * `.digest <action>` - Sets the email digest of the missed mentions, see [`digest::parse_digest`].
+/// * `.time <relative|absolute>` - Shows the age or the local time of the live messages, see [`clock::TimeStyle`].
+/// * `.sound <on|off|bell>` - Sets the notification mode.
+/// * `.alerts <bell|flash|counter>...` - Selects the alerts besides the sound, `.alerts off` disables them.
+/// * `.bench <size-mb> [--loop N]` - Measures the transfer speed with a synthetic payload, see [`bench::run`].
+/// * `.open [n]` - Opens the nth recent download, lists the recent downloads without `n`.
+/// * `.extract <n>` - Extracts the nth recent download, if it is a directory archive.
+/// * `.cleanup` - Deletes the old downloads over the limits of the config, see [`cleanup::clean`].
+/// * `.keys` - Lists the keyboard shortcuts, see [`keys::Keymap`].
+/// * `.pubkeys [new|on|off|accept <nickname>]` - Manages the end-to-end encryption, see [`encryption::parse_keys`].
+/// * `.tutorial` - Walks through the basic commands offline, see [`tutorial::run`].
 /// * `.quit` - Issues a quit command.
 /// * Any other input is treated as a text message.
 ///
 /// # Errors
 ///
 /// This function returns an error if the `.file` or `.image` commands are used without a valid path,
-/// or if there is an issue retrieving the file contents.
+/// or if there is an issue retrieving the image contents.
 async fn parse_input(input: String, nickname: &str) -> Result<Command> {
     let nickname = nickname.to_string();
-    let command = if input.starts_with(".file") {
-        let (_, path) = input
+    let command = if input == ".file" && picker::is_interactive() {
+        pick_file()?
+    } else if input.starts_with(".file") {
+        let (_, arguments) = input
             .split_once(" ")
             .ok_or(anyhow!("Invalid command .file!"))?;
-        let (name, content) = get_file(path).await?;
+        let (paths, errors) = files::expand(&files::split_arguments(arguments)?);
+        for error in errors {
+            eprintln!("{error}");
+        }
+        if paths.is_empty() {
+            return Err(anyhow!("No files to send!"));
+        }
+        confirm_attachments(&paths)?;
+        Command::Files(paths)
+    } else if input.starts_with(".dir") {
+        let (_, path) = input
+            .split_once(" ")
+            .ok_or(anyhow!("Invalid command .dir!"))?;
+        let path = PathBuf::from(path.trim());
+        let (name, content) = tokio::task::spawn_blocking(move || archive::pack(&path)).await??;
+        println!("sending {name} ({})", files::format_size(content.len()));
         let message = MessageType::file(name, &content);
         Command::Message(Message::from(nickname, message))
+    } else if input.starts_with(".extract") {
+        let (_, n) = input
+            .split_once(" ")
+            .ok_or(anyhow!("Invalid command .extract!"))?;
+        Command::Extract(n.trim().parse().context("Invalid download number!")?)
     } else if input.starts_with(".image") {
         let (_, path) = input
             .split_once(" ")
             .ok_or(anyhow!("Invalid command .image!"))?;
+        confirm_attachments(&[PathBuf::from(path)])?;
         let (_, content) = get_file(path).await?;
+        let config = Config::load().images;
+        let content =
+            tokio::task::spawn_blocking(move || images::convert_image(content, &config)).await??;
         let message = MessageType::image(&content);
         Command::Message(Message::from(nickname, message))
+    } else if input.starts_with(".code") {
+        let (_, lang) = input
+            .split_once(" ")
+            .ok_or(anyhow!("Invalid command .code!"))?;
+        let source = read_code()?;
+        let message = MessageType::code(lang.trim(), source);
+        Command::Message(Message::from(nickname, message))
+    } else if input.starts_with(".history") {
+        let before = match input.split_once(" ") {
+            Some((_, id)) => Some(id.trim().parse().context("Invalid message id!")?),
+            None => None,
+        };
+        let message = MessageType::history_request(before, HISTORY_PAGE);
+        Command::Message(Message::from(nickname, message))
+    } else if input.starts_with(".bookmarks") {
+        let arguments = input.split_once(" ").map_or("", |(_, arguments)| arguments);
+        match bookmarks::parse_bookmarks(arguments)? {
+            BookmarkCommand::Context(id) => {
+                Command::Message(Message::from(nickname, bookmarks::context_request(id)))
+            }
+            command => Command::Bookmark(command),
+        }
+    } else if input.starts_with(".bookmark") {
+        let arguments = input.split_once(" ").map_or("", |(_, arguments)| arguments);
+        Command::Bookmark(bookmarks::parse_bookmark(arguments)?)
+    } else if input.starts_with(".search") {
+        let (_, query) = input
+            .split_once(" ")
+            .ok_or(anyhow!("Invalid command .search!"))?;
+        let message = MessageType::search_request(query.trim(), HISTORY_PAGE);
+        Command::Message(Message::from(nickname, message))
+    } else if input.starts_with(".poll") {
+        let (_, arguments) = input
+            .split_once(" ")
+            .ok_or(anyhow!("Invalid command .poll!"))?;
+        Command::Message(Message::from(nickname, polls::parse_poll(arguments)?))
+    } else if input.starts_with(".vote") {
+        let (_, arguments) = input
+            .split_once(" ")
+            .ok_or(anyhow!("Invalid command .vote!"))?;
+        Command::Message(Message::from(nickname, polls::parse_vote(arguments)?))
+    } else if input.starts_with(".close") {
+        let (_, poll) = input
+            .split_once(" ")
+            .ok_or(anyhow!("Invalid command .close!"))?;
+        let poll = poll.trim().parse().context("Invalid poll id!")?;
+        Command::Message(Message::from(nickname, MessageType::ClosePoll { poll }))
+    } else if input.starts_with(".report") {
+        let (id, reason) = input
+            .split_once(" ")
+            .and_then(|(_, arguments)| arguments.trim().split_once(" "))
+            .ok_or(anyhow!(
+                "Invalid command .report, use .report message_id reason!"
+            ))?;
+        let message = id.parse().context("Invalid message id!")?;
+        let reason = reason.trim().to_string();
+        if reason.chars().count() > chat::MAX_REPORT_REASON {
+            return Err(anyhow!(
+                "The reason must have at most {} characters!",
+                chat::MAX_REPORT_REASON
+            ));
+        }
+        Command::Message(Message::from(
+            nickname,
+            MessageType::Report { message, reason },
+        ))
+    } else if input.starts_with(".join") {
+        let (_, room) = input
+            .split_once(" ")
+            .ok_or(anyhow!("Invalid command .join, use .join room!"))?;
+        Command::Join(room.trim().trim_start_matches('#').to_string())
+    } else if input.starts_with(".leave") {
+        let room = input.split_once(" ").map(|(_, room)| room);
+        Command::Leave(room.map(|room| room.trim().trim_start_matches('#').to_string()))
+    } else if input.starts_with(".room") {
+        let room = input.split_once(" ").map(|(_, room)| room);
+        Command::Room(room.map(|room| room.trim().trim_start_matches('#').to_string()))
+    } else if input.starts_with(".whisper") {
+        let (to, text) = input
+            .split_once(" ")
+            .and_then(|(_, arguments)| arguments.trim().split_once(" "))
+            .ok_or(anyhow!(
+                "Invalid command .whisper, use .whisper nickname text!"
+            ))?;
+        let message = MessageType::whisper(to, text.trim());
+        Command::Message(Message::from(nickname, message))
+    } else if input.starts_with(".time") {
+        let (_, style) = input
+            .split_once(" ")
+            .ok_or(anyhow!("Invalid command .time!"))?;
+        Command::Time(TimeStyle::from_str(style.trim())?)
+    } else if input.starts_with(".sound") {
+        let (_, mode) = input
+            .split_once(" ")
+            .ok_or(anyhow!("Invalid command .sound!"))?;
+        Command::Sound(SoundMode::from_str(mode.trim())?)
+    } else if input.starts_with(".alerts") {
+        let (_, arguments) = input
+            .split_once(" ")
+            .ok_or(anyhow!("Invalid command .alerts!"))?;
+        Command::Alerts(alerts::parse_alerts(arguments)?)
+    } else if input == ".reload-assets" {
+        Command::ReloadAssets
+    } else if input == ".cleanup" {
+        Command::Cleanup
+    } else if input == ".keys" {
+        Command::Shortcuts
+    } else if input.starts_with(".pubkeys") {
+        let arguments = input.split_once(" ").map_or("", |(_, arguments)| arguments);
+        Command::Keys(encryption::parse_keys(arguments)?)
+    } else if input == ".tutorial" {
+        Command::Tutorial
+    } else if input == ".who" {
+        Command::Who
+    } else if input == ".connection" {
+        Command::Connection
+    } else if input.starts_with(".contact") {
+        let arguments = input.split_once(" ").map_or("", |(_, arguments)| arguments);
+        Command::Contact(contacts::parse_contact(arguments)?)
+    } else if input.starts_with(".digest") {
+        let (_, arguments) = input
+            .split_once(" ")
+            .ok_or(anyhow!("Invalid command .digest!"))?;
+        let message = MessageType::Digest(digest::parse_digest(arguments)?);
+        Command::Message(Message::from(nickname, message))
+    } else if input.starts_with(".bench") {
+        let (_, arguments) = input
+            .split_once(" ")
+            .ok_or(anyhow!("Invalid command .bench!"))?;
+        let (size_mb, count) = bench::parse_arguments(arguments)?;
+        Command::Bench { size_mb, count }
+    } else if input.starts_with(".open") {
+        let n = match input.split_once(" ") {
+            Some((_, n)) => Some(n.trim().parse().context("Invalid download number!")?),
+            None => None,
+        };
+        Command::Open(n)
     } else if input == ".quit" {
         Command::Quit
     } else {
@@ -192,6 +1089,158 @@ async fn parse_input(input: String, nickname: &str) -> Result<Command> {
     Ok(command)
 }
 
+/// Sends the request of the user, or queues it while the server is not connected.
+async fn send_request(link: &Link, request: Message) {
+    let preview = connection::preview(&request.message);
+    if let Some(queued) = render_queued(&preview, link.send(request).await) {
+        println!("{queued}");
+    }
+}
+
+/// Lets the user pick a file to send by [`picker::pick_file`].
+///
+/// # Errors
+///
+/// This function will return an error if no file was selected or the user cancelled sending.
+fn pick_file() -> Result<Command> {
+    let path = picker::pick_file()?.ok_or(anyhow!("No file selected!"))?;
+    confirm_attachments(std::slice::from_ref(&path))?;
+    Ok(Command::Files(vec![path]))
+}
+
+/// Asks the user to confirm sending the attachments, unless the confirmation is turned off or the input is piped.
+///
+/// # Errors
+///
+/// This function will return an error if a file can't be read or the user cancelled sending.
+fn confirm_attachments(paths: &[PathBuf]) -> Result<()> {
+    if !Config::load().confirm_attachments || !picker::is_interactive() {
+        return Ok(());
+    }
+    match confirm::confirm(paths)? {
+        true => Ok(()),
+        false => Err(anyhow!("Sending cancelled!")),
+    }
+}
+
+/// Reads the lines of code from the standard input until the `.end` line.
+fn read_code() -> Result<String> {
+    let mut source = String::new();
+    loop {
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line)? == 0 || line.trim_end() == ".end" {
+            break;
+        }
+        source.push_str(&line);
+    }
+    Ok(source)
+}
+
+/// Sends every file as its own File message.
+///
+/// Files which can't be read are reported and skipped, the summary with the number and the total size of the sent
+/// files is printed at the end. Files sent while the server is not connected are queued until the reconnection.
+async fn send_files(link: &Link, nickname: &str, paths: &[PathBuf], server: &ServerInfo) {
+    let (mut sent, mut total, mut failed, mut buffered) = (0, 0, 0, 0);
+    for path in paths {
+        let (name, content) = match get_file(&path.to_string_lossy()).await {
+            Ok(file) => file,
+            Err(err_msg) => {
+                eprintln!("Skipping {}: {}", path.display(), err_msg);
+                failed += 1;
+                continue;
+            }
+        };
+        let message = MessageType::file(name, &content);
+        let preview = connection::preview(&message);
+        let message = match prepare(server, message) {
+            Ok(message) => Message::in_room(server.rooms().current(), nickname, message),
+            Err(err_msg) => {
+                eprintln!("Skipping {}: {}", path.display(), err_msg);
+                failed += 1;
+                continue;
+            }
+        };
+        if let Some(queued) = render_queued(&preview, link.send(message).await) {
+            println!("{queued}");
+            buffered += 1;
+        }
+        sent += 1;
+        total += content.len();
+    }
+    let files = if sent == 1 { "file" } else { "files" };
+    let size = files::format_size(total);
+    match failed {
+        0 => println!("sent {sent} {files} ({size} total)"),
+        _ => println!("sent {sent} {files} ({size} total), {failed} failed"),
+    }
+    if buffered > 0 {
+        println!("{buffered} of them are queued (offline) until reconnecting.");
+    }
+}
+
+/// Encrypts the message if the encryption is on and checks it against the features and limits of the server.
+///
+/// The users without a key are reported, they can't read the encrypted message.
+///
+/// # Errors
+///
+/// This function will return an error if the message can't be encrypted or the server would reject it.
+fn prepare(server: &ServerInfo, message: MessageType) -> Result<MessageType> {
+    let message = if server.keyring().encrypts(&message) {
+        let sealed = server.keyring().seal(&message)?;
+        if let Some(users) = server.roster().users() {
+            let missing = server.keyring().missing(&users);
+            if !missing.is_empty() {
+                println!("not readable by {} (no key)", missing.join(", "));
+            }
+        }
+        MessageType::Encrypted(sealed)
+    } else {
+        message
+    };
+    server.check(&message)?;
+    Ok(message)
+}
+
+/// Runs the `.pubkeys` command, a new key is announced right away while connected, see [`encryption`].
+///
+/// # Errors
+///
+/// This function will return an error if the key or the config can't be saved, or the encryption is turned on
+/// without a key.
+async fn run_keys(command: KeysCommand, link: &Link, server: &ServerInfo) -> Result<()> {
+    let keyring = server.keyring();
+    match command {
+        KeysCommand::Show => println!("{}", keyring.describe()),
+        KeysCommand::New => {
+            keyring.generate()?;
+            Config::update(|config| config.encryption = true)?;
+            if link.state() == State::Connected && server.supports("e2e") {
+                announce_key(link, server).await;
+            }
+            println!("{}", keyring.describe());
+        }
+        KeysCommand::On | KeysCommand::Off => {
+            let enabled = command == KeysCommand::On;
+            keyring.set_enabled(enabled)?;
+            Config::update(|config| config.encryption = enabled)?;
+            println!(
+                "End-to-end encryption: {}",
+                if enabled { "on" } else { "off" }
+            );
+        }
+        KeysCommand::Accept(nickname) => {
+            let key = keyring.accept(&nickname)?;
+            println!(
+                "The messages are encrypted for the key {} of {nickname} now.",
+                chat::crypto::fingerprint(&key)
+            );
+        }
+    }
+    Ok(())
+}
+
 async fn get_file(path: &str) -> Result<(String, Vec<u8>)> {
     let mut file = File::open(path).await?;
     let mut buff = Vec::new();
@@ -207,13 +1256,29 @@ async fn get_file(path: &str) -> Result<(String, Vec<u8>)> {
 /// Handles an incoming message by printing or saving its content.
 ///
 /// This function takes a `Message` struct as input and processes it based on its type:
-/// - For text messages, it prints the text content to the console.
+/// - For text messages, it prints the text content rendered by [`markdown::render`] to the console.
 /// - For image messages, it saves the image content to a file.
 /// - For file messages, it saves the file content to a file.
+/// - For attachment messages, it downloads the file over HTTP in the background.
+/// - For streamed files, it creates the file at the start and checks it at the end, the chunks between are written
+///   by [`reading_loop`].
+/// - For code messages, it prints the code highlighted by [`highlight::render_code`] to the console.
+/// - For polls, it prints the numbered options, and the poll results as a bar chart by [`polls::render_results`].
+/// - For history pages and search results, it prints the stored messages with their ids.
+/// - For server errors, it prints the error description to the console.
+/// - For a full server, it prints the position in the waiting queue and the admission.
+/// - For the Welcome message, it prints the banner and remembers the server features and limits.
+/// - Annotations attached by the server are printed under the message.
+/// - Messages authored by the server are marked by the system symbol of the theme instead of the sender.
+/// - Messages of another room than the current one are prefixed by their `#room`, see [`rooms`].
 ///
 /// # Arguments
 ///
 /// * `message` - A `Message` struct containing the sender's nickname and the message content.
+/// * `downloads` - Records the saved image or file.
+/// * `server` - Remembers the features and limits from the Welcome message, renders the times in the local clock.
+/// * `fetcher` - Downloads the attachments.
+/// * `streams` - The streamed files being received.
 ///
 /// # Returns
 ///
@@ -224,65 +1289,343 @@ async fn get_file(path: &str) -> Result<(String, Vec<u8>)> {
 /// # Errors
 ///
 /// This function will return an error if saving the image or file fails.
-async fn handle_message(message: Message) -> Result<()> {
+async fn handle_message(
+    message: Message,
+    downloads: &Downloads,
+    server: &ServerInfo,
+    fetcher: &Fetcher,
+    streams: &mut Streams,
+) -> Result<()> {
+    let clock = server.clock();
     let nickname = message.nickname;
-    print!("{nickname} --> ");
+    let annotations = message.annotations;
+    let styled_nickname =
+        markdown::render_nickname(&nickname, &annotations, markdown::use_styling());
+    if let Some(timestamp) = message.timestamp {
+        print!("[{}] ", clock.render(timestamp));
+    }
+    let whisper = matches!(message.message, MessageType::Whisper { .. });
+    if !message.system && !whisper && message.room != server.rooms().current() {
+        print!("#{} ", message.room);
+    }
+    if message.system {
+        print!("{} ", assets::theme().system);
+    } else {
+        match contacts::alias(&nickname) {
+            Some(alias) => print!("{styled_nickname} ({alias}){}", assets::theme().arrow),
+            None => print!("{styled_nickname}{}", assets::theme().arrow),
+        }
+    }
     match message.message {
-        MessageType::Text(text) => println!("{text}"),
-        MessageType::Image(content) => save_image(content).await.context("Saving image failed!")?,
-        MessageType::File { name, content } => save_file(name, content)
-            .await
-            .context("Saving file failed!")?,
+        MessageType::Text(text) => println!("{}", markdown::render(&text, markdown::use_styling())),
+        MessageType::Image(content) => {
+            let path = save_image(downloads, content)
+                .await
+                .context("Saving image failed!")?;
+            println!("Saving image to: {}.", link(&path));
+            auto_open(downloads, &nickname, path);
+        }
+        MessageType::File { name, content } => {
+            let path = downloads
+                .writer()
+                .save(FILE_FOLDER, &name, content)
+                .await
+                .context("Saving file failed!")?;
+            if let Some(conflict) = writer::render_conflict(&name, &path) {
+                println!("{conflict}");
+            }
+            println!("Saving file to: {}.", link(&path));
+            if archive::is_archive(&path) {
+                println!("Directory archive, extract it with: .extract 1");
+            }
+            auto_open(downloads, &nickname, path);
+        }
+        MessageType::Attachment { name, size, url } => {
+            let size_text = files::format_size(size as usize);
+            println!("sharing {name} ({size_text}), downloading...");
+            let fetcher = fetcher.clone();
+            let downloads = downloads.clone();
+            let sender = nickname.clone();
+            tokio::spawn(async move {
+                match download_file(&fetcher, &downloads, &name, &url, size).await {
+                    Ok(path) => {
+                        println!("Saving file to: {}.", link(&path));
+                        auto_open(&downloads, &sender, path);
+                    }
+                    Err(err_msg) => {
+                        eprintln!(
+                            "Downloading {name} failed: {}",
+                            Report::new(err_msg.as_ref())
+                        )
+                    }
+                }
+            });
+        }
+        MessageType::FileStart { id, name, size } => {
+            let (path, file) = downloads
+                .writer()
+                .reserve(FILE_FOLDER, &name)
+                .await
+                .context("Receiving file failed!")?;
+            let size_text = files::format_size(size as usize);
+            println!("sharing {name} ({size_text}), receiving...");
+            if let Some(conflict) = writer::render_conflict(&name, &path) {
+                println!("{conflict}");
+            }
+            // The chunks are written to the file under the name it was saved as.
+            let saved = path.file_name().unwrap_or_default().to_string_lossy();
+            streams.start(&nickname, id, &saved, size, file.into_std().await);
+        }
+        MessageType::FileChunk { offset, .. } => println!("(file chunk at {offset})"),
+        MessageType::FileEnd { id, checksum } => {
+            let incoming = streams
+                .finish(&nickname, id, checksum)
+                .context("Receiving file failed!")?;
+            let path = Path::new(FILE_FOLDER).join(incoming.name);
+            println!("Saving file to: {}.", link(&path));
+            auto_open(downloads, &nickname, path);
+        }
+        MessageType::Code { lang, source } => {
+            let code = highlight::render_code(&lang, &source, markdown::use_styling());
+            println!("\n{code}")
+        }
+        MessageType::ServerError { code } => println!("Error: {code}"),
+        MessageType::HistoryRequest { .. } => println!("(history request)"),
+        MessageType::History(entries) => print_history(&entries, clock),
+        MessageType::SearchRequest { query, .. } => println!("(search request: {query})"),
+        MessageType::SearchResults(entries) => print_search_results(&entries, clock),
+        MessageType::Bench { payload, .. } => println!("(bench payload, {} bytes)", payload.len()),
+        MessageType::BenchAck { size, .. } => println!("(bench ack, {size} bytes)"),
+        MessageType::Sync { id } | MessageType::SyncAck { id } => println!("(sync {id})"),
+        MessageType::Ack { message_id, .. } => println!("(delivered #{message_id})"),
+        MessageType::ServerFull { position } => {
+            println!("server is full, you are number {position} in the queue")
+        }
+        MessageType::Admitted => println!("you are in, welcome to chat!"),
+        MessageType::Poll {
+            id,
+            question,
+            options,
+        } => println!("{}", polls::render_poll(id, &question, &options)),
+        MessageType::Vote { poll, option } => println!("(vote for {option} in poll #{poll})"),
+        MessageType::ClosePoll { poll } => println!("(closing poll #{poll})"),
+        MessageType::Report { message, .. } => println!("(report of message #{message})"),
+        MessageType::Join { room } => println!("(joining #{room})"),
+        MessageType::Leave { room } => println!("(leaving #{room})"),
+        MessageType::System(text) => println!("{text}"),
+        MessageType::Whisper { text, .. } => println!("(whisper) {text}"),
+        MessageType::Ping { .. } => println!("(ping)"),
+        MessageType::Pong { .. } => println!("(pong)"),
+        MessageType::RosterRequest => println!("(users request)"),
+        MessageType::RosterSnapshot { users, .. } => println!("(users: {})", users.join(", ")),
+        MessageType::RosterDelta { version, .. } => println!("(users change {version})"),
+        MessageType::Digest(_) => println!("(digest setting)"),
+        MessageType::PublicKey { .. } => println!("(public key)"),
+        MessageType::Encrypted(_) => println!("(encrypted message, not for your key)"),
+        MessageType::PollResults {
+            id,
+            question,
+            votes,
+            closed,
+        } => println!("\n{}", polls::render_results(id, &question, &votes, closed)),
+        MessageType::Welcome {
+            server_name,
+            version,
+            motd,
+            capabilities,
+            limits,
+            ..
+        } => {
+            let banner = server_info::banner(
+                &server_name,
+                &version,
+                motd.as_deref(),
+                &capabilities,
+                &limits,
+            );
+            println!("\n{banner}");
+            if let Some(skew) = clock.describe() {
+                println!("{skew}, the message times are shown in your local time");
+            }
+            server.configure(capabilities, limits);
+        }
     }
+    print_annotations(&annotations);
     Ok(())
 }
 
-fn meow() -> Result<()> {
-    let (_stream, stream_handle) = OutputStream::try_default()?;
-    let file = std::fs::File::open(SOUND_FILE)?;
-    let source = Decoder::new(std::io::BufReader::new(file))?;
-    stream_handle.play_raw(source.convert_samples())?;
-    std::thread::sleep(std::time::Duration::from_secs(2));
-    Ok(())
+/// Prints the annotations of the server, except the nickname color rendered with the nickname.
+fn print_annotations(annotations: &[(String, String)]) {
+    let annotations: Vec<String> = annotations
+        .iter()
+        .filter(|(key, _)| key != chat::COLOR_ANNOTATION)
+        .map(|(key, value)| match key.as_str() {
+            chat::TIME_ADJUSTED_ANNOTATION => {
+                format!("sender's clock off by {value}, time adjusted")
+            }
+            encryption::ENCRYPTED_ANNOTATION => format!("end-to-end encrypted, key {value}"),
+            _ => format!("{key}: {value}"),
+        })
+        .collect();
+    if annotations.is_empty() {
+        return;
+    }
+    println!("    ({})", annotations.join(", "));
+}
+
+/// Renders the server error indented below the typed message it refers to, quoting the message.
+fn render_error(code: &ErrorCode, last_sent: Option<&str>) -> String {
+    let marker = &assets::theme().error;
+    match last_sent {
+        Some(preview) => format!("  {marker} \"{preview}\": {code}"),
+        None => format!("  {marker} {code}"),
+    }
+}
+
+/// Renders the marker of a message queued while offline, nothing for a delivered one.
+fn render_queued(preview: &str, sent: Sent) -> Option<String> {
+    match sent {
+        Sent::Delivered => None,
+        Sent::Buffered => Some(format!("  \"{preview}\": queued (offline)")),
+        Sent::Unsaved => Some(format!(
+            "  \"{preview}\": queued (offline), saving the queue failed, lost on quit"
+        )),
+    }
+}
+
+/// Renders the mark of a message acknowledged by the server with its stored id, e.g. for `.report`.
+fn render_delivered(preview: &str, message_id: i64) -> String {
+    format!("  \"{preview}\": delivered as #{message_id}")
+}
+
+/// Loads the theme and the sound pack again, invalid files are reported and replaced by the defaults.
+fn reload_assets(sound: &Sound) {
+    let assets = Assets::load(Path::new(ASSETS_DIR));
+    println!("{assets}");
+    for problem in &assets.problems {
+        eprintln!("{problem}");
+    }
+    sound.set_pack(assets.apply());
+}
+
+fn print_history(entries: &[HistoryEntry], clock: &Clock) {
+    println!("history:");
+    print_entries(entries, clock);
+    match entries.first() {
+        Some(entry) => println!("older messages: .history {}", entry.id),
+        None => println!("no more messages"),
+    }
+}
+
+fn print_search_results(entries: &[HistoryEntry], clock: &Clock) {
+    println!("found {} messages:", entries.len());
+    print_entries(entries, clock);
+}
+
+fn print_entries(entries: &[HistoryEntry], clock: &Clock) {
+    bookmarks::remember(entries, clock);
+    let styled = markdown::use_styling();
+    let arrow = &assets::theme().arrow;
+    for entry in entries {
+        let message = match entry.msg_type.as_str() {
+            "Text" => markdown::render(&entry.message, styled),
+            msg_type => format!("[{msg_type}] {}", entry.message),
+        };
+        let time = entry
+            .timestamp
+            .map(|timestamp| format!("[{}] ", clock.render_date(timestamp)))
+            .unwrap_or_default();
+        println!("#{} {time}{}{arrow}{}", entry.id, entry.nickname, message);
+        match bookmarks::note(entry.id) {
+            Some(note) if !note.is_empty() => println!("  bookmarked: {note}"),
+            Some(_) => println!("  bookmarked"),
+            None => (),
+        }
+    }
 }
 
 fn get_timestamp() -> Result<u64> {
     Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
 }
 
-async fn save_image(content: Vec<u8>) -> Result<()> {
-    create_directory(FILE_FOLDER).await?;
-    let timestamp = get_timestamp()?;
-    let name = format!("{timestamp:?}.png");
-    let path = Path::new(IMAGE_FOLDER).join(&name);
-    let mut file = File::create(path).await?;
-    file.write_all(&content).await?;
-    println!("Saving image to: {}/{}.", IMAGE_FOLDER, &name);
-    Ok(())
+fn print_downloads(downloads: &Downloads) {
+    let recent = downloads.list();
+    if recent.is_empty() {
+        println!("no downloads yet");
+    }
+    for (index, path) in recent.iter().enumerate() {
+        println!("{}: {}", index + 1, link(path));
+    }
 }
 
-async fn save_file(name: String, content: Vec<u8>) -> Result<()> {
-    create_directory(FILE_FOLDER).await?;
-    let path = Path::new(FILE_FOLDER).join(&name);
-    let mut file = File::create(path).await?;
-    file.write_all(&content).await?;
-    println!("Saving file to: {}/{}.", FILE_FOLDER, &name);
-    Ok(())
+/// Records the saved file and opens or prints it if the auto-open rules allow it for the sender.
+fn auto_open(downloads: &Downloads, sender: &str, path: PathBuf) {
+    match downloads.accept(sender, path.clone()) {
+        Some(AutoAction::Open) => {
+            if let Err(err_msg) = downloads::open(&path) {
+                eprintln!("Open error: {}", err_msg);
+            }
+        }
+        Some(AutoAction::Print(text)) => println!("{}:\n{text}", path.display()),
+        None => (),
+    }
 }
 
-async fn create_directory(path: &str) -> Result<()> {
-    if !Path::new(path).exists() {
-        fs::create_dir_all(path)
-            .await
-            .with_context(|| format!("Creating dir {path} failed!"))?;
+/// Extracts the nth recent download after the `.extract` confirmation.
+async fn extract(downloads: &Downloads, n: usize) {
+    let Some(path) = downloads.get(n) else {
+        eprintln!("No download {n}, use .open to list them.");
+        return;
+    };
+    match tokio::task::spawn_blocking(move || archive::unpack(&path)).await {
+        Ok(Ok(destination)) => println!("Extracted to: {}.", link(&destination)),
+        Ok(Err(err_msg)) => eprintln!("Extract error: {}", err_msg),
+        Err(err_msg) => eprintln!("Extract error: {}", err_msg),
     }
-    Ok(())
+}
+
+fn link(path: &Path) -> String {
+    downloads::hyperlink(path, markdown::use_styling())
+}
+
+async fn save_image(downloads: &Downloads, content: Vec<u8>) -> Result<PathBuf> {
+    let timestamp = get_timestamp()?;
+    let extension = images::image_extension(&content);
+    let name = format!("{timestamp:?}.{extension}");
+    downloads.writer().save(IMAGE_FOLDER, &name, content).await
+}
+
+/// Downloads the attachment to a new file reserved by the [`writer::DownloadWriter`], reporting a taken name.
+async fn download_file(
+    fetcher: &Fetcher,
+    downloads: &Downloads,
+    name: &str,
+    url: &str,
+    size: u64,
+) -> Result<PathBuf> {
+    let (path, _) = downloads.writer().reserve(FILE_FOLDER, name).await?;
+    if let Some(conflict) = writer::render_conflict(name, &path) {
+        println!("{conflict}");
+    }
+    if let Err(err_msg) = fetcher.download(url, &path, size).await {
+        // The empty reserved file would look like a download.
+        let _ = fs::remove_file(&path).await;
+        return Err(err_msg);
+    }
+    Ok(path)
+}
+
+fn download_folders() -> Vec<PathBuf> {
+    DOWNLOAD_FOLDERS.iter().map(PathBuf::from).collect()
 }
 
 #[tokio::main]
 async fn main() {
     match run_client().await {
         Ok(_) => (),
-        Err(err_msg) => eprintln!("Client error: {}", err_msg),
+        Err(err_msg) => {
+            eprintln!("Client error: {}", Report::new(err_msg.as_ref()));
+            std::process::exit(1);
+        }
     }
 }
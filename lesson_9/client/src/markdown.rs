@@ -0,0 +1,156 @@
+//! Rendering of the Markdown subset used in text messages.
+//!
+//! Supported markers are `*bold*`, `_italic_` and `` `inline code` ``. Markers are recognized only around words,
+//! so `2*3*4` or `snake_case_name` stay untouched. The raw text is sent over the wire, the styling is applied by
+//! the receiving client.
+
+use std::io::IsTerminal;
+
+use crate::assets;
+
+const BOLD: (&str, &str) = ("\x1b[1m", "\x1b[22m");
+const ITALIC: (&str, &str) = ("\x1b[3m", "\x1b[23m");
+/// Resets the foreground color set by the theme.
+const DEFAULT_COLOR: &str = "\x1b[39m";
+
+/// Returns true if the output should be styled with terminal escape sequences.
+///
+/// Styling is disabled when the standard output is not a terminal or the `NO_COLOR` variable is set.
+pub fn use_styling() -> bool {
+    std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+}
+
+/// Renders the Markdown subset of the text.
+///
+/// # Arguments
+///
+/// * `text` - Raw text of the message.
+/// * `styled` - Apply terminal styling if true, only strip the markers otherwise.
+pub fn render(text: &str, styled: bool) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut output = String::with_capacity(text.len());
+    render_chars(&chars, styled, &mut output);
+    output
+}
+
+/// Renders the nickname in the color assigned by the server, looked up in the nickname colors of the theme.
+///
+/// # Arguments
+///
+/// * `nickname` - Nickname of the sender.
+/// * `annotations` - Annotations of the message, the nickname is plain without the color annotation.
+/// * `styled` - Apply terminal styling if true, return the plain nickname otherwise.
+pub fn render_nickname(nickname: &str, annotations: &[(String, String)], styled: bool) -> String {
+    let theme = assets::theme();
+    let color = annotations
+        .iter()
+        .find(|(key, _)| key == chat::COLOR_ANNOTATION)
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .and_then(|index| theme.nicknames.get(index));
+    match color {
+        Some(color) if styled => format!("\x1b[{color}m{nickname}{DEFAULT_COLOR}"),
+        _ => nickname.to_string(),
+    }
+}
+
+fn render_chars(chars: &[char], styled: bool, output: &mut String) {
+    let code = format!("\x1b[{}m", assets::theme().code);
+    let mut i = 0;
+    while i < chars.len() {
+        let marker = chars[i];
+        let style = match marker {
+            '*' => Some(BOLD),
+            '_' => Some(ITALIC),
+            '`' => Some((code.as_str(), DEFAULT_COLOR)),
+            _ => None,
+        };
+        let end = style.and_then(|_| closing_marker(chars, i));
+        match (style, end) {
+            (Some((start_code, end_code)), Some(end)) => {
+                let inner = &chars[i + 1..end];
+                if styled {
+                    output.push_str(start_code);
+                }
+                if marker == '`' {
+                    output.extend(inner);
+                } else {
+                    render_chars(inner, styled, output);
+                }
+                if styled {
+                    output.push_str(end_code);
+                }
+                i = end + 1;
+            }
+            _ => {
+                output.push(marker);
+                i += 1;
+            }
+        }
+    }
+}
+
+fn is_boundary(c: Option<&char>) -> bool {
+    c.is_none_or(|c| !c.is_alphanumeric())
+}
+
+/// Finds the closing marker for the opening marker at `start`.
+fn closing_marker(chars: &[char], start: usize) -> Option<usize> {
+    let marker = chars[start];
+    let before = start.checked_sub(1).and_then(|i| chars.get(i));
+    let first = chars.get(start + 1)?;
+    if !is_boundary(before) || first.is_whitespace() || *first == marker {
+        return None;
+    }
+    (start + 2..chars.len()).find(|&end| {
+        chars[end] == marker && !chars[end - 1].is_whitespace() && is_boundary(chars.get(end + 1))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_nickname() {
+        let annotations = [
+            ("lang".to_string(), "eng".to_string()),
+            ("color".to_string(), "3".to_string()),
+        ];
+        assert_eq!(
+            render_nickname("slava", &annotations, true),
+            "\x1b[34mslava\x1b[39m"
+        );
+        assert_eq!(render_nickname("slava", &annotations, false), "slava");
+        assert_eq!(render_nickname("slava", &[], true), "slava");
+        let invalid = [("color".to_string(), "99".to_string())];
+        assert_eq!(render_nickname("slava", &invalid, true), "slava");
+    }
+
+    #[test]
+    fn test_render_plain() {
+        assert_eq!(render("*bold* and _italic_", false), "bold and italic");
+        assert_eq!(render("run `cargo test`", false), "run cargo test");
+        assert_eq!(render("*_both_*", false), "both");
+    }
+
+    #[test]
+    fn test_render_styled() {
+        assert_eq!(render("*bold*", true), "\x1b[1mbold\x1b[22m");
+        assert_eq!(render("_it_!", true), "\x1b[3mit\x1b[23m!");
+        assert_eq!(render("`_x_`", true), "\x1b[36m_x_\x1b[39m");
+    }
+
+    #[test]
+    fn test_render_untouched() {
+        for text in [
+            "2*3*4",
+            "snake_case_name",
+            "* not bold *",
+            "**",
+            "a * b",
+            "`open",
+        ] {
+            assert_eq!(render(text, true), text);
+        }
+    }
+}
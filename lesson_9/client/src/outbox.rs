@@ -0,0 +1,134 @@
+//! Durable queue of the messages composed while the server is not connected.
+//!
+//! The queued messages are kept in `outbox-<nickname>.jsonl`, one JSON message per line, so they survive a restart of
+//! the client and are sent by the next client started with the same nickname. After the reconnection they are sent in order followed by a Sync message, and removed from the file only
+//! when the server acknowledges it. A connection breaking before the acknowledgement sends them again, so a queued
+//! message may arrive twice but is never lost.
+
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chat::Message;
+
+/// Prefix of the queue files, followed by the nickname.
+pub const OUTBOX_PREFIX: &str = "outbox-";
+
+/// Queue file of the messages waiting for the server.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    path: PathBuf,
+}
+
+impl Outbox {
+    pub fn new<P: AsRef<Path>>(path: P) -> Outbox {
+        Outbox {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Returns the queue of the nickname in the current directory, so clients sharing it don't send each other's
+    /// messages.
+    pub fn for_nickname(nickname: &str) -> Outbox {
+        Outbox::new(format!("{OUTBOX_PREFIX}{nickname}.jsonl"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Loads the queued messages, a missing file is an empty queue.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file can't be read or a line isn't a message, the file is left
+    /// untouched so no message is lost.
+    pub fn load(&self) -> Result<Vec<Message>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(err_msg) if err_msg.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err_msg) => return Err(err_msg.into()),
+        };
+        content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .enumerate()
+            .map(|(index, line)| {
+                serde_json::from_str(line).with_context(|| {
+                    format!("Invalid message {} in {}", index + 1, self.path.display())
+                })
+            })
+            .collect()
+    }
+
+    /// Appends the message to the queue.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file can't be written.
+    pub fn push(&self, message: &Message) -> Result<()> {
+        let mut line = serde_json::to_string(message)?;
+        line.push('\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .with_context(|| format!("Queueing message to {} failed!", self.path.display()))
+    }
+
+    /// Replaces the queue by the messages, removing the file if there are none.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file can't be written.
+    pub fn save<'a, I: IntoIterator<Item = &'a Message>>(&self, messages: I) -> Result<()> {
+        let mut content = String::new();
+        for message in messages {
+            content.push_str(&serde_json::to_string(message)?);
+            content.push('\n');
+        }
+        if content.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(err_msg) if err_msg.kind() != ErrorKind::NotFound => Err(err_msg.into()),
+                _ => Ok(()),
+            };
+        }
+        // Written aside and renamed, so a crash never leaves a truncated queue.
+        let temporary = self.path.with_extension("jsonl.tmp");
+        fs::write(&temporary, content)
+            .and_then(|()| fs::rename(&temporary, &self.path))
+            .with_context(|| format!("Saving queue {} failed!", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat::MessageType;
+
+    #[test]
+    fn test_outbox() {
+        let path = std::env::temp_dir().join(format!("chat-outbox-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let outbox = Outbox::new(&path);
+        assert!(outbox.load().unwrap().is_empty());
+
+        let first = Message::from("slava", MessageType::text("first\nline"));
+        let second = Message::from("slava", MessageType::Image(vec![1, 2, 3]));
+        outbox.push(&first).unwrap();
+        outbox.push(&second).unwrap();
+        // A restarted client finds the queue.
+        assert_eq!(Outbox::new(&path).load().unwrap(), [first, second.clone()]);
+
+        outbox.save([&second]).unwrap();
+        assert_eq!(outbox.load().unwrap(), [second]);
+        outbox.save([]).unwrap();
+        assert!(!path.exists());
+
+        fs::write(&path, "not json\n").unwrap();
+        assert!(outbox.load().is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+//! Minimal file picker for the `.file` command typed without a path.
+//!
+//! The picker lists the current directory with numbered entries, a number opens a directory or selects a file, `..`
+//! goes to the parent directory and an empty line cancels it. It is used only when both stdin and stdout are
+//! terminals, piped input keeps the plain error.
+
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// Returns true if the user can answer the picker.
+pub fn is_interactive() -> bool {
+    io::stdin().is_terminal() && io::stdout().is_terminal()
+}
+
+/// Entry of the listed directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+}
+
+/// Answer of the user.
+#[derive(Debug, PartialEq)]
+pub enum Choice {
+    Open(usize),
+    Parent,
+    Cancel,
+    Invalid,
+}
+
+/// Lists the directory, directories first, both sorted by name. Hidden entries are skipped.
+///
+/// # Errors
+///
+/// This function will return an error if the directory can't be read.
+pub fn list_entries(dir: &Path) -> Result<Vec<Entry>> {
+    let mut entries: Vec<Entry> = fs::read_dir(dir)
+        .with_context(|| format!("Reading {} failed!", dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| Entry {
+            name: entry.file_name().to_string_lossy().to_string(),
+            is_dir: entry.path().is_dir(),
+        })
+        .filter(|entry| !entry.name.starts_with('.'))
+        .collect();
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+/// Parses the answer for the list of `count` entries numbered from 1.
+pub fn parse_choice(input: &str, count: usize) -> Choice {
+    match input.trim() {
+        "" => Choice::Cancel,
+        ".." => Choice::Parent,
+        number => match number.parse::<usize>() {
+            Ok(n) if (1..=count).contains(&n) => Choice::Open(n - 1),
+            _ => Choice::Invalid,
+        },
+    }
+}
+
+/// Lets the user pick a file starting in the current directory.
+///
+/// # Returns
+///
+/// The selected file or `None` if the user cancelled the picker.
+///
+/// # Errors
+///
+/// This function will return an error if the terminal can't be read.
+pub fn pick_file() -> Result<Option<PathBuf>> {
+    let mut dir = std::env::current_dir()?;
+    loop {
+        let entries = match list_entries(&dir) {
+            Ok(entries) => entries,
+            Err(err_msg) => {
+                eprintln!("{err_msg}");
+                Vec::new()
+            }
+        };
+        println!("{}:", dir.display());
+        for (i, entry) in entries.iter().enumerate() {
+            let suffix = if entry.is_dir { "/" } else { "" };
+            println!("{:>3}. {}{}", i + 1, entry.name, suffix);
+        }
+        print!("number, .. for parent, empty to cancel: ");
+        io::stdout().flush()?;
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input)? == 0 {
+            return Ok(None);
+        }
+        match parse_choice(&input, entries.len()) {
+            Choice::Open(index) if entries[index].is_dir => dir.push(&entries[index].name),
+            Choice::Open(index) => return Ok(Some(dir.join(&entries[index].name))),
+            Choice::Parent => {
+                dir.pop();
+            }
+            Choice::Cancel => return Ok(None),
+            Choice::Invalid => eprintln!("Invalid choice: {}", input.trim()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_choice() {
+        assert_eq!(parse_choice("2\n", 3), Choice::Open(1));
+        assert_eq!(parse_choice("..", 3), Choice::Parent);
+        assert_eq!(parse_choice("\n", 3), Choice::Cancel);
+        assert_eq!(parse_choice("0", 3), Choice::Invalid);
+        assert_eq!(parse_choice("4", 3), Choice::Invalid);
+        assert_eq!(parse_choice("x", 3), Choice::Invalid);
+    }
+
+    #[test]
+    fn test_list_entries() {
+        let dir = std::env::temp_dir().join(format!("chat-picker-{}", std::process::id()));
+        fs::create_dir_all(dir.join("zdir")).unwrap();
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        fs::write(dir.join(".hidden"), "h").unwrap();
+        let names: Vec<(String, bool)> = list_entries(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.name, entry.is_dir))
+            .collect();
+        assert_eq!(
+            names,
+            [("zdir".to_string(), true), ("a.txt".to_string(), false)]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
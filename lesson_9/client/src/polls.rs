@@ -0,0 +1,132 @@
+//! Polls created by the `.poll` command and their results.
+//!
+//! `.poll "Lunch?" pizza sushi salad` creates a poll, the arguments are split like the `.file` paths, so quoted
+//! questions and options may contain spaces. The results are rendered as a text bar chart.
+
+use anyhow::{anyhow, Context, Result};
+use chat::{MessageType, MAX_POLL_OPTIONS};
+
+use crate::files;
+
+/// Width of the longest bar of the results chart.
+pub const BAR_WIDTH: usize = 20;
+
+/// Parses the arguments of the `.poll` command, the question followed by the options.
+///
+/// # Errors
+///
+/// This function will return an error if a quote is not closed or there are not 2 to [`MAX_POLL_OPTIONS`] options.
+pub fn parse_poll(arguments: &str) -> Result<MessageType> {
+    let arguments = files::split_arguments(arguments)?;
+    let Some((question, options)) = arguments.split_first() else {
+        return Err(anyhow!("Missing poll question!"));
+    };
+    if !(2..=MAX_POLL_OPTIONS).contains(&options.len()) {
+        return Err(anyhow!(
+            "A poll needs 2 to {MAX_POLL_OPTIONS} options, e.g. .poll \"Lunch?\" pizza sushi"
+        ));
+    }
+    Ok(MessageType::poll(question.clone(), options))
+}
+
+/// Parses the arguments of the `.vote` command, the poll id followed by the option text or number.
+///
+/// # Errors
+///
+/// This function will return an error if the poll id or the option is missing.
+pub fn parse_vote(arguments: &str) -> Result<MessageType> {
+    let (poll, option) = arguments
+        .trim()
+        .split_once(' ')
+        .ok_or(anyhow!("Use .vote <poll-id> <option>!"))?;
+    let poll = poll.parse().context("Invalid poll id!")?;
+    Ok(MessageType::vote(poll, option.trim()))
+}
+
+/// Renders the announced poll with the numbered options.
+pub fn render_poll(id: i64, question: &str, options: &[String]) -> String {
+    let mut lines = vec![format!("poll #{id}: {question}")];
+    for (number, option) in options.iter().enumerate() {
+        lines.push(format!("  {}. {option}", number + 1));
+    }
+    lines.push(format!("vote with: .vote {id} <option>"));
+    lines.join("\n")
+}
+
+/// Renders the results as a bar chart, with `█` bars on a terminal and `#` bars otherwise.
+pub fn render_results(id: i64, question: &str, votes: &[(String, u32)], closed: bool) -> String {
+    render_chart(id, question, votes, closed, crate::markdown::use_styling())
+}
+
+fn render_chart(
+    id: i64,
+    question: &str,
+    votes: &[(String, u32)],
+    closed: bool,
+    styled: bool,
+) -> String {
+    let state = if closed { "final results" } else { "results" };
+    let mut lines = vec![format!("poll #{id} {state}: {question}")];
+    let total: u32 = votes.iter().map(|(_, count)| count).sum();
+    let most = votes.iter().map(|(_, count)| *count).max().unwrap_or(0);
+    let width = votes
+        .iter()
+        .map(|(option, _)| option.chars().count())
+        .max()
+        .unwrap_or(0);
+    let bar = if styled { "█" } else { "#" };
+    for (option, count) in votes {
+        let length = match most {
+            0 => 0,
+            most => (*count as usize * BAR_WIDTH).div_ceil(most as usize),
+        };
+        let percent = match total {
+            0 => 0,
+            total => (*count * 100 + total / 2) / total,
+        };
+        lines.push(format!(
+            "  {option:<width$} {:<BAR_WIDTH$} {count} ({percent}%)",
+            bar.repeat(length)
+        ));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_poll() {
+        let poll = parse_poll("\"Lunch today?\" pizza sushi 'green salad'").unwrap();
+        assert_eq!(
+            poll,
+            MessageType::poll("Lunch today?", &["pizza", "sushi", "green salad"])
+        );
+        assert!(parse_poll("\"Lunch?\" pizza").is_err());
+        assert!(parse_poll("\"Lunch? pizza sushi").is_err());
+        assert_eq!(
+            parse_vote("3 green salad").unwrap(),
+            MessageType::vote(3, "green salad")
+        );
+        assert!(parse_vote("pizza").is_err());
+        assert!(parse_vote("x pizza").is_err());
+    }
+
+    #[test]
+    fn test_render_chart() {
+        let votes = [("pizza".to_string(), 3), ("sushi".to_string(), 1)];
+        let chart = render_chart(3, "Lunch?", &votes, true, false);
+        assert_eq!(
+            chart,
+            [
+                "poll #3 final results: Lunch?",
+                "  pizza #################### 3 (75%)",
+                "  sushi #######              1 (25%)",
+            ]
+            .join("\n")
+        );
+        let empty = [("pizza".to_string(), 0), ("sushi".to_string(), 0)];
+        assert!(render_chart(3, "Lunch?", &empty, false, false).contains("0 (0%)"));
+    }
+}
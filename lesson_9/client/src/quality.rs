@@ -0,0 +1,284 @@
+//! Quality of the connection to the server.
+//!
+//! A quiet chat and a dying connection look the same, no message arrives. The client measures the round trip time by
+//! a Sync probe every [`PROBE_INTERVAL`], which the server answers at once, and remembers the reconnections, stalls and
+//! retried messages of the last [`EVENT_WINDOW`]. Together with the queued and unacknowledged messages they score the
+//! connection from 0 to [`DOTS`], printed as `●●●○○` whenever the score changes. `.connection` prints the details.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chat::MessageType;
+
+use crate::idle::Idle;
+
+/// Time between two probes of the round trip time.
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(15);
+/// Age of the events still lowering the score.
+pub const EVENT_WINDOW: Duration = Duration::from_secs(300);
+/// Number of the dots of the indicator, the score of a good connection.
+pub const DOTS: usize = 5;
+/// Bit of the Sync ids of the probes, the Syncs of the queued messages count from zero and never reach it.
+const PROBE_BIT: u32 = 1 << 31;
+/// Round trip times lowering the score by one more dot each.
+const SLOW_RTT: [Duration; 3] = [
+    Duration::from_millis(150),
+    Duration::from_millis(500),
+    Duration::from_secs(2),
+];
+/// Number of the queued and unacknowledged messages lowering the score.
+const DEEP_QUEUE: usize = 10;
+
+/// Event of the connection lowering its score for the [`EVENT_WINDOW`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// The connection broke and the client reconnected.
+    Disconnect,
+    /// No data arrived for the idle window, see [`crate::idle`].
+    Stall,
+    /// A message wasn't acknowledged in time and was sent again.
+    Retry,
+}
+
+/// State of the connection observed outside of [`Quality`].
+#[derive(Debug, Clone, Copy)]
+pub struct Observed {
+    pub connected: bool,
+    /// Messages queued until the server confirms them.
+    pub queued: usize,
+    /// Sent messages waiting for their acknowledgement.
+    pub unacked: usize,
+}
+
+#[derive(Default)]
+struct Measured {
+    /// Smoothed round trip time, `None` before the first answered probe.
+    rtt: Option<Duration>,
+    /// Last answered probe.
+    last_rtt: Option<Duration>,
+    /// Id and time of the probe waiting for the answer.
+    probe: Option<(u32, Instant)>,
+    next_probe: u32,
+    events: VecDeque<(Instant, Event)>,
+}
+
+/// Measurements shared by the reading loop, the probing task and the `.connection` command.
+#[derive(Clone, Default)]
+pub struct Quality {
+    measured: Arc<Mutex<Measured>>,
+    idle: Idle,
+}
+
+/// Returns true for the Sync ids of the probes.
+pub fn is_probe(id: u32) -> bool {
+    id & PROBE_BIT != 0
+}
+
+impl Quality {
+    /// Returns the time of the last data from the server, watched by the reading loop.
+    pub fn idle(&self) -> &Idle {
+        &self.idle
+    }
+
+    /// Returns a new probe sent at the time, replacing the unanswered one.
+    pub fn probe(&self, now: Instant) -> MessageType {
+        let mut measured = self.measured.lock().unwrap_or_else(|e| e.into_inner());
+        let id = PROBE_BIT | measured.next_probe;
+        measured.next_probe = (measured.next_probe + 1) & !PROBE_BIT;
+        measured.probe = Some((id, now));
+        MessageType::Sync { id }
+    }
+
+    /// Records the answer of the probe arriving at the time.
+    ///
+    /// # Returns
+    ///
+    /// The round trip time, `None` for an outdated probe.
+    pub fn answered(&self, id: u32, now: Instant) -> Option<Duration> {
+        let mut measured = self.measured.lock().unwrap_or_else(|e| e.into_inner());
+        let (_, sent) = measured.probe.filter(|(probe, _)| *probe == id)?;
+        measured.probe = None;
+        let rtt = now.saturating_duration_since(sent);
+        // Smoothed like the TCP, one sample moves the estimate by an eighth.
+        measured.rtt = Some(
+            measured
+                .rtt
+                .map_or(rtt, |smoothed| (smoothed * 7 + rtt) / 8),
+        );
+        measured.last_rtt = Some(rtt);
+        Some(rtt)
+    }
+
+    /// Records the event happened at the time.
+    pub fn record(&self, event: Event, now: Instant) {
+        let mut measured = self.measured.lock().unwrap_or_else(|e| e.into_inner());
+        measured.events.push_back((now, event));
+    }
+
+    /// Forgets the probe of a broken connection, its answer never comes.
+    pub fn reset_probe(&self) {
+        self.measured
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .probe = None;
+    }
+
+    /// Returns the round trip time, a probe waiting longer than the smoothed one counts by its waiting.
+    fn rtt(measured: &Measured, now: Instant) -> Option<Duration> {
+        let waiting = measured
+            .probe
+            .map(|(_, sent)| now.saturating_duration_since(sent));
+        match (measured.rtt, waiting) {
+            (Some(rtt), Some(waiting)) => Some(rtt.max(waiting)),
+            (Some(rtt), None) => Some(rtt),
+            (None, waiting) => waiting.filter(|waiting| *waiting >= SLOW_RTT[0]),
+        }
+    }
+
+    /// Returns the number of the events of the kind within the [`EVENT_WINDOW`], forgetting the older ones.
+    fn recent(measured: &mut Measured, event: Event, now: Instant) -> usize {
+        while let Some((at, _)) = measured.events.front() {
+            if now.saturating_duration_since(*at) < EVENT_WINDOW {
+                break;
+            }
+            measured.events.pop_front();
+        }
+        measured
+            .events
+            .iter()
+            .filter(|(_, recorded)| *recorded == event)
+            .count()
+    }
+
+    /// Scores the connection from 0, disconnected, to [`DOTS`].
+    pub fn score(&self, observed: Observed, now: Instant) -> usize {
+        if !observed.connected {
+            return 0;
+        }
+        let mut measured = self.measured.lock().unwrap_or_else(|e| e.into_inner());
+        let slow = Quality::rtt(&measured, now)
+            .map_or(0, |rtt| SLOW_RTT.iter().filter(|slow| rtt > **slow).count());
+        let broken = Quality::recent(&mut measured, Event::Disconnect, now)
+            + Quality::recent(&mut measured, Event::Stall, now);
+        let retried = Quality::recent(&mut measured, Event::Retry, now);
+        let penalty = slow
+            + broken.min(2)
+            + usize::from(retried > 0)
+            + usize::from(observed.queued + observed.unacked > DEEP_QUEUE);
+        // A connected server is never scored as none.
+        DOTS.saturating_sub(penalty).max(1)
+    }
+
+    /// Renders the details for the `.connection` command.
+    pub fn describe(&self, observed: Observed, now: Instant) -> String {
+        let score = self.score(observed, now);
+        let mut measured = self.measured.lock().unwrap_or_else(|e| e.into_inner());
+        let mut details = format!("connection {}", indicator(score));
+        if !observed.connected {
+            details.push_str(" offline, reconnecting");
+        }
+        let rtt = |rtt: Option<Duration>| {
+            rtt.map_or("-".to_string(), |rtt| format!("{} ms", rtt.as_millis()))
+        };
+        let _ = write!(
+            details,
+            "\n  round trip: {} (last {})",
+            rtt(Quality::rtt(&measured, now)),
+            rtt(measured.last_rtt)
+        );
+        if observed.connected {
+            let _ = write!(
+                details,
+                "\n  last data: {} s ago",
+                self.idle.elapsed().as_secs()
+            );
+        }
+        let _ = write!(
+            details,
+            "\n  last {} min: {} reconnections, {} stalls, {} retried messages",
+            EVENT_WINDOW.as_secs() / 60,
+            Quality::recent(&mut measured, Event::Disconnect, now),
+            Quality::recent(&mut measured, Event::Stall, now),
+            Quality::recent(&mut measured, Event::Retry, now)
+        );
+        let _ = write!(
+            details,
+            "\n  queued: {}, waiting for confirmation: {}",
+            observed.queued, observed.unacked
+        );
+        details
+    }
+}
+
+/// Renders the score as the filled and empty dots, e.g. `●●●○○`.
+pub fn indicator(score: usize) -> String {
+    let score = score.min(DOTS);
+    "●".repeat(score) + &"○".repeat(DOTS - score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observed(connected: bool, queued: usize) -> Observed {
+        Observed {
+            connected,
+            queued,
+            unacked: 0,
+        }
+    }
+
+    #[test]
+    fn test_score() {
+        let quality = Quality::default();
+        let start = Instant::now();
+        assert_eq!(quality.score(observed(true, 0), start), DOTS);
+        assert_eq!(quality.score(observed(false, 0), start), 0);
+        assert_eq!(quality.score(observed(true, DEEP_QUEUE + 1), start), 4);
+
+        let MessageType::Sync { id } = quality.probe(start) else {
+            unreachable!()
+        };
+        assert!(is_probe(id));
+        assert!(!is_probe(1));
+        // A probe unanswered for long counts as slow before its answer.
+        assert_eq!(quality.score(observed(true, 0), start + SLOW_RTT[1] * 2), 3);
+        assert_eq!(quality.answered(id + 1, start), None);
+        let rtt = quality.answered(id, start + Duration::from_millis(600));
+        assert_eq!(rtt, Some(Duration::from_millis(600)));
+        assert_eq!(quality.answered(id, start), None);
+        assert_eq!(quality.score(observed(true, 0), start), 3);
+
+        let quality = Quality::default();
+        quality.record(Event::Disconnect, start);
+        quality.record(Event::Stall, start);
+        quality.record(Event::Disconnect, start);
+        quality.record(Event::Retry, start);
+        assert_eq!(quality.score(observed(true, 0), start), 2);
+        assert_eq!(quality.score(observed(true, 0), start + EVENT_WINDOW), DOTS);
+    }
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(indicator(3), "●●●○○");
+        assert_eq!(indicator(0), "○○○○○");
+
+        let quality = Quality::default();
+        let start = Instant::now();
+        let MessageType::Sync { id } = quality.probe(start) else {
+            unreachable!()
+        };
+        quality.answered(id, start + Duration::from_millis(40));
+        quality.record(Event::Retry, start);
+        assert_eq!(
+            quality.describe(observed(true, 2), start),
+            "connection ●●●●○\n  round trip: 40 ms (last 40 ms)\n  last data: 0 s ago\n  last 5 min: 0 \
+             reconnections, 0 stalls, 1 retried messages\n  queued: 2, waiting for confirmation: 0"
+        );
+        assert!(quality
+            .describe(observed(false, 0), start)
+            .starts_with("connection ○○○○○ offline, reconnecting\n"));
+    }
+}
@@ -0,0 +1,186 @@
+//! Rooms joined by the user.
+//!
+//! The client starts in the [`chat::DEFAULT_ROOM`]. `.join room` joins another room and writes to it, `.room name`
+//! switches between the joined rooms and `.leave` leaves the current one, the last room can't be left. The typed
+//! messages go to the current room, the received messages of the other rooms are prefixed by their `#room`. The server
+//! forgets the rooms of a broken connection, so they are joined again after the Welcome of the reconnection.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use chat::{Message, MessageType, DEFAULT_ROOM};
+
+struct Joined {
+    /// The joined rooms in the order of joining.
+    rooms: Vec<String>,
+    /// The room the typed messages go to.
+    current: String,
+}
+
+impl Default for Joined {
+    fn default() -> Self {
+        Joined {
+            rooms: vec![DEFAULT_ROOM.to_string()],
+            current: DEFAULT_ROOM.to_string(),
+        }
+    }
+}
+
+/// Rooms shared by the reading and writing loops.
+#[derive(Clone, Default)]
+pub struct Rooms {
+    nickname: Arc<str>,
+    joined: Arc<Mutex<Joined>>,
+}
+
+impl Rooms {
+    /// Creates the rooms of the user with the nickname, only the default one is joined.
+    pub fn new(nickname: &str) -> Rooms {
+        Rooms {
+            nickname: nickname.into(),
+            joined: Default::default(),
+        }
+    }
+
+    /// Returns the room the typed messages go to.
+    pub fn current(&self) -> String {
+        self.joined
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .current
+            .clone()
+    }
+
+    /// Joins the room and makes it the current one.
+    ///
+    /// # Returns
+    ///
+    /// The Join request for the server.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the room name is invalid, see [`chat::is_valid_room`].
+    pub fn join(&self, room: &str) -> Result<Message> {
+        if !chat::is_valid_room(room) {
+            return Err(anyhow!(
+                "Invalid room {room}, use 1 to {} letters, digits, - or _!",
+                chat::MAX_ROOM_NAME
+            ));
+        }
+        let mut joined = self.joined.lock().unwrap_or_else(|e| e.into_inner());
+        if !joined.rooms.iter().any(|joined| joined == room) {
+            joined.rooms.push(room.to_string());
+        }
+        joined.current = room.to_string();
+        Ok(Message::from(&*self.nickname, MessageType::join(room)))
+    }
+
+    /// Leaves the room, the current one without a name. Leaving the current room switches to the last joined one.
+    ///
+    /// # Returns
+    ///
+    /// The Leave request for the server.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the room isn't joined or it is the last joined room.
+    pub fn leave(&self, room: Option<&str>) -> Result<Message> {
+        let mut joined = self.joined.lock().unwrap_or_else(|e| e.into_inner());
+        let room = room.map_or_else(|| joined.current.clone(), str::to_string);
+        let Some(index) = joined.rooms.iter().position(|joined| *joined == room) else {
+            return Err(anyhow!("You aren't in #{room}!"));
+        };
+        if joined.rooms.len() == 1 {
+            return Err(anyhow!(
+                "#{room} is your last room, join another one first!"
+            ));
+        }
+        joined.rooms.remove(index);
+        if joined.current == room {
+            joined.current = joined.rooms[joined.rooms.len() - 1].clone();
+        }
+        Ok(Message::from(&*self.nickname, MessageType::leave(room)))
+    }
+
+    /// Makes the joined room the current one.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the room isn't joined.
+    pub fn switch(&self, room: &str) -> Result<()> {
+        let mut joined = self.joined.lock().unwrap_or_else(|e| e.into_inner());
+        if !joined.rooms.iter().any(|joined| joined == room) {
+            return Err(anyhow!("You aren't in #{room}, use .join {room}!"));
+        }
+        joined.current = room.to_string();
+        Ok(())
+    }
+
+    /// Returns the requests restoring the rooms on a new connection, which starts in the default room only.
+    pub fn restore(&self) -> Vec<Message> {
+        let joined = self.joined.lock().unwrap_or_else(|e| e.into_inner());
+        let mut requests: Vec<Message> = joined
+            .rooms
+            .iter()
+            .filter(|room| *room != DEFAULT_ROOM)
+            .map(|room| Message::from(&*self.nickname, MessageType::join(room)))
+            .collect();
+        if !joined.rooms.iter().any(|room| room == DEFAULT_ROOM) {
+            requests.push(Message::from(
+                &*self.nickname,
+                MessageType::leave(DEFAULT_ROOM),
+            ));
+        }
+        requests
+    }
+
+    /// Renders the joined rooms for the `.room` command, the current one marked by `*`.
+    pub fn describe(&self) -> String {
+        let joined = self.joined.lock().unwrap_or_else(|e| e.into_inner());
+        let rooms: Vec<String> = joined
+            .rooms
+            .iter()
+            .map(|room| match *room == joined.current {
+                true => format!("*#{room}"),
+                false => format!("#{room}"),
+            })
+            .collect();
+        format!("rooms: {}", rooms.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rooms() {
+        let rooms = Rooms::new("slava");
+        assert_eq!(rooms.current(), DEFAULT_ROOM);
+        assert!(rooms.leave(None).is_err());
+        assert!(rooms.join("no spaces").is_err());
+
+        let join = rooms.join("rust").unwrap();
+        assert_eq!(join.message, MessageType::join("rust"));
+        rooms.join("go").unwrap();
+        assert_eq!(rooms.describe(), "rooms: #general #rust *#go");
+        rooms.switch("rust").unwrap();
+        assert!(rooms.switch("python").is_err());
+        assert_eq!(rooms.current(), "rust");
+
+        let leave = rooms.leave(Some(DEFAULT_ROOM)).unwrap();
+        assert_eq!(leave.message, MessageType::leave(DEFAULT_ROOM));
+        rooms.leave(None).unwrap();
+        assert_eq!(rooms.current(), "go");
+        assert!(rooms.leave(Some("rust")).is_err());
+        let restored: Vec<MessageType> = rooms
+            .restore()
+            .into_iter()
+            .map(|request| request.message)
+            .collect();
+        assert_eq!(
+            restored,
+            [MessageType::join("go"), MessageType::leave(DEFAULT_ROOM)]
+        );
+    }
+}
@@ -0,0 +1,128 @@
+//! Users active on the server, kept in sync by the roster snapshot and deltas.
+//!
+//! The server sends a snapshot when the client joins and a delta for every join, leave or rename, each one with the
+//! next version. A delta skipping a version means a lost one, so the client asks for a fresh snapshot instead of
+//! guessing. The `.who` command lists the users without asking the server.
+
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+use chat::{Message, MessageType, RosterChange};
+
+/// Result of applying a roster delta.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Applied {
+    Updated,
+    /// The delta is already included in the snapshot, or no snapshot arrived yet.
+    Ignored,
+    /// A delta is missing, a fresh snapshot is needed.
+    Gap,
+}
+
+#[derive(Default)]
+struct Users {
+    /// Version of the last applied delta, unknown before the first snapshot.
+    version: Option<u64>,
+    nicknames: BTreeSet<String>,
+}
+
+/// Roster shared by the reading and writing loops.
+#[derive(Clone, Default)]
+pub struct Roster {
+    nickname: Arc<str>,
+    users: Arc<Mutex<Users>>,
+}
+
+impl Roster {
+    /// Creates an empty roster of the user with the nickname.
+    pub fn new(nickname: &str) -> Roster {
+        Roster {
+            nickname: nickname.into(),
+            users: Default::default(),
+        }
+    }
+
+    /// Returns the request of a fresh snapshot.
+    pub fn request(&self) -> Message {
+        Message::from(&*self.nickname, MessageType::RosterRequest)
+    }
+
+    /// Replaces the roster by the snapshot.
+    pub fn replace(&self, version: u64, nicknames: &[String]) {
+        let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        users.version = Some(version);
+        users.nicknames = nicknames.iter().cloned().collect();
+    }
+
+    /// Applies the delta if it is the next one.
+    pub fn apply(&self, version: u64, change: &RosterChange) -> Applied {
+        let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(current) = users.version else {
+            return Applied::Ignored;
+        };
+        if version <= current {
+            return Applied::Ignored;
+        }
+        if version > current + 1 {
+            users.version = None;
+            return Applied::Gap;
+        }
+        match change {
+            RosterChange::Joined(nickname) => {
+                users.nicknames.insert(nickname.clone());
+            }
+            RosterChange::Left(nickname) => {
+                users.nicknames.remove(nickname);
+            }
+            RosterChange::Renamed { from, to } => {
+                users.nicknames.remove(from);
+                users.nicknames.insert(to.clone());
+            }
+        }
+        users.version = Some(version);
+        Applied::Updated
+    }
+
+    /// Forgets the users after the connection broke, the next connection sends a new snapshot.
+    pub fn clear(&self) {
+        *self.users.lock().unwrap_or_else(|e| e.into_inner()) = Users::default();
+    }
+
+    /// Returns the active users, none before the first snapshot.
+    pub fn users(&self) -> Option<Vec<String>> {
+        let users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        users.version?;
+        Some(users.nicknames.iter().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roster() {
+        let roster = Roster::new("slava");
+        let joined = RosterChange::Joined("eva".into());
+        assert_eq!(roster.apply(1, &joined), Applied::Ignored);
+        assert_eq!(roster.users(), None);
+
+        roster.replace(4, &["slava".into()]);
+        assert_eq!(roster.apply(4, &joined), Applied::Ignored);
+        assert_eq!(roster.apply(5, &joined), Applied::Updated);
+        let renamed = RosterChange::Renamed {
+            from: "slava".into(),
+            to: "ana".into(),
+        };
+        assert_eq!(roster.apply(6, &renamed), Applied::Updated);
+        assert_eq!(roster.users().unwrap(), ["ana", "eva"]);
+
+        assert_eq!(
+            roster.apply(8, &RosterChange::Left("eva".into())),
+            Applied::Gap
+        );
+        assert_eq!(roster.users(), None);
+        assert_eq!(roster.request().message, MessageType::RosterRequest);
+        assert_eq!(roster.request().nickname, "slava");
+    }
+}
@@ -0,0 +1,215 @@
+//! Features and limits announced by the server in the Welcome message.
+//!
+//! The Welcome message is printed as a banner and remembered, so the client refuses messages the server would
+//! reject, e.g. too big attachments or commands of disabled features. Before the Welcome arrives (or with an older
+//! server which doesn't send it) everything is allowed. The server time from the Welcome and the pings keeps the
+//! [`Clock`] in sync and the roster messages keep the [`Roster`] of the active users. The [`Recent`] messages are kept
+//! for the keyboard shortcuts and the joined [`Rooms`] for the reconnections. The pings of a server with the
+//! `keepalive` capability are answered, so it knows the quiet client is still connected. The [`Keyring`] encrypts the
+//! sent messages end to end.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use chat::{keepalive, Message, MessageType, ServerLimits};
+
+use crate::clock::Clock;
+use crate::encryption::Keyring;
+use crate::files;
+use crate::keys::Recent;
+use crate::rooms::Rooms;
+use crate::roster::Roster;
+
+struct Announced {
+    capabilities: Vec<String>,
+    limits: ServerLimits,
+}
+
+/// Announced features, limits and time shared by the reading and writing loops.
+#[derive(Clone, Default)]
+pub struct ServerInfo {
+    nickname: Arc<str>,
+    announced: Arc<Mutex<Option<Announced>>>,
+    clock: Clock,
+    roster: Roster,
+    recent: Recent,
+    rooms: Rooms,
+    keyring: Keyring,
+}
+
+impl ServerInfo {
+    /// Creates the info of the server the user with the nickname connects to.
+    pub fn new(nickname: &str) -> ServerInfo {
+        ServerInfo {
+            nickname: nickname.into(),
+            roster: Roster::new(nickname),
+            rooms: Rooms::new(nickname),
+            ..Default::default()
+        }
+    }
+
+    /// Uses the key pair and the announced keys of the user.
+    pub fn with_keyring(self, keyring: Keyring) -> ServerInfo {
+        ServerInfo { keyring, ..self }
+    }
+
+    /// Returns the users active on the server.
+    pub fn roster(&self) -> &Roster {
+        &self.roster
+    }
+
+    /// Returns the received messages the keyboard shortcuts act on.
+    pub fn recent(&self) -> &Recent {
+        &self.recent
+    }
+
+    /// Returns the rooms joined by the user.
+    pub fn rooms(&self) -> &Rooms {
+        &self.rooms
+    }
+
+    /// Returns the keys encrypting the messages end to end.
+    pub fn keyring(&self) -> &Keyring {
+        &self.keyring
+    }
+
+    /// Returns the clock skew to the server.
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// Remembers the features and limits from the Welcome message.
+    pub fn configure(&self, capabilities: Vec<String>, limits: ServerLimits) {
+        *self.announced.lock().unwrap_or_else(|e| e.into_inner()) = Some(Announced {
+            capabilities,
+            limits,
+        });
+    }
+
+    /// Returns true if the server has the feature or didn't announce its features.
+    pub fn supports(&self, capability: &str) -> bool {
+        match &*self.announced.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(announced) => announced
+                .capabilities
+                .iter()
+                .any(|known| known == capability),
+            None => true,
+        }
+    }
+
+    /// Returns the Pong answering the Ping of a server with the `keepalive` capability, `None` for an older server or
+    /// another message.
+    pub fn pong(&self, ping: &MessageType) -> Option<Message> {
+        let announced = self.announced.lock().unwrap_or_else(|e| e.into_inner());
+        let capabilities = &announced.as_ref()?.capabilities;
+        if !capabilities.iter().any(|known| known == "keepalive") {
+            return None;
+        }
+        keepalive::pong(&*self.nickname, ping)
+    }
+
+    /// Checks the message against the announced features and limits.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error describing why the server would reject the message.
+    pub fn check(&self, message: &MessageType) -> Result<()> {
+        let capability = match message {
+            MessageType::HistoryRequest { .. } => Some("history"),
+            MessageType::SearchRequest { .. } => Some("search"),
+            MessageType::Bench { .. } => Some("bench"),
+            MessageType::Digest(_) => Some("digest"),
+            MessageType::Report { .. } => Some("reports"),
+            MessageType::Join { .. } | MessageType::Leave { .. } => Some("rooms"),
+            MessageType::Whisper { .. } => Some("whisper"),
+            MessageType::Poll { .. } | MessageType::Vote { .. } | MessageType::ClosePoll { .. } => {
+                Some("polls")
+            }
+            MessageType::PublicKey { .. } | MessageType::Encrypted(_) => Some("e2e"),
+            _ => None,
+        };
+        if let Some(capability) = capability.filter(|capability| !self.supports(capability)) {
+            return Err(anyhow!("The server doesn't support {capability}!"));
+        }
+        let announced = self.announced.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(Announced { limits, .. }) = &*announced {
+            let size = message.attachment_size() as u64;
+            if size > limits.max_attachment {
+                return Err(anyhow!(
+                    "Attachment of {} is too big, the server accepts at most {}!",
+                    files::format_size(size as usize),
+                    files::format_size(limits.max_attachment as usize)
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Renders the Welcome message as a banner.
+pub fn banner(
+    server_name: &str,
+    version: &str,
+    motd: Option<&str>,
+    capabilities: &[String],
+    limits: &ServerLimits,
+) -> String {
+    let mut lines = vec![format!("connected to {server_name} (version {version})")];
+    if let Some(motd) = motd {
+        lines.push(motd.to_string());
+    }
+    lines.push(format!("features: {}", capabilities.join(", ")));
+    lines.push(format!(
+        "limits: attachments up to {}, history pages up to {} messages",
+        files::format_size(limits.max_attachment as usize),
+        limits.max_history
+    ));
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_announced() {
+        let info = ServerInfo::default();
+        let file = MessageType::file("a.bin", &[0; 2048]);
+        let search = MessageType::search_request("hello", 20);
+        assert!(info.check(&file).is_ok());
+        assert!(info.check(&search).is_ok());
+
+        let limits = ServerLimits {
+            max_attachment: 1024,
+            max_history: 100,
+        };
+        info.configure(vec!["history".to_string()], limits);
+        assert!(info.check(&file).is_err());
+        assert!(info.check(&search).is_err());
+        assert!(info.check(&MessageType::history_request(None, 20)).is_ok());
+        assert!(info.check(&MessageType::text("hi")).is_ok());
+        let digest = MessageType::Digest(chat::DigestSetting::Off);
+        assert!(info.check(&digest).is_err());
+        let key = MessageType::PublicKey { key: [1; 32] };
+        assert!(info.check(&key).is_err());
+    }
+
+    #[test]
+    fn test_pong() {
+        let info = ServerInfo::new("slava");
+        let ping = MessageType::Ping { server_time: 42 };
+        assert_eq!(info.pong(&ping), None);
+        let limits = ServerLimits {
+            max_attachment: 1024,
+            max_history: 100,
+        };
+        info.configure(vec!["history".to_string()], limits);
+        assert_eq!(info.pong(&ping), None);
+
+        info.configure(vec!["keepalive".to_string()], limits);
+        let pong = info.pong(&ping).unwrap();
+        assert_eq!(pong.nickname, "slava");
+        assert_eq!(pong.message, MessageType::Pong { server_time: 42 });
+        assert_eq!(info.pong(&MessageType::text("hi")), None);
+    }
+}
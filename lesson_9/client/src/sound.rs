@@ -0,0 +1,193 @@
+//! Notification sound played when a message is received.
+//!
+//! Machines without an audio device (headless servers, containers) fall back to the terminal bell after a one-time
+//! warning instead of failing on every message. The client built without the `sound` feature doesn't link the audio
+//! libraries and always uses the terminal bell. Every [`SoundEvent`] plays its sound from the [`SoundPack`] and fires
+//! the selected [`Alerts`].
+
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{anyhow, Result};
+use chat::report::{Failure, Report};
+
+use crate::alerts::Alerts;
+use crate::assets::{SoundEvent, SoundPack};
+#[cfg(feature = "sound")]
+use rodio::{source::Source, Decoder, OutputStream, PlayError, StreamError};
+use serde::{Deserialize, Serialize};
+
+/// Default sound of every event.
+pub const SOUND_FILE: &str = "meow.wav";
+const BELL: &str = "\x07";
+
+/// Notification mode selected by the user.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SoundMode {
+    /// Play the sound of the event.
+    #[default]
+    On,
+    /// No notification.
+    Off,
+    /// Ring the terminal bell.
+    Bell,
+}
+
+impl FromStr for SoundMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "on" => Ok(SoundMode::On),
+            "off" => Ok(SoundMode::Off),
+            "bell" => Ok(SoundMode::Bell),
+            _ => Err(anyhow!("Invalid sound mode {s}, use on, off or bell!")),
+        }
+    }
+}
+
+impl fmt::Display for SoundMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::On => write!(f, "on"),
+            Self::Off => write!(f, "off"),
+            Self::Bell => write!(f, "bell"),
+        }
+    }
+}
+
+/// Notification player shared by the reading and writing loops.
+#[derive(Clone)]
+pub struct Sound {
+    mode: Arc<Mutex<SoundMode>>,
+    pack: Arc<Mutex<SoundPack>>,
+    available: Arc<AtomicBool>,
+    alerts: Alerts,
+}
+
+impl Sound {
+    /// Creates the player and checks whether the message sound can be played.
+    pub fn new(mode: SoundMode, pack: SoundPack, alerts: Alerts) -> Sound {
+        let sound = Sound {
+            mode: Arc::new(Mutex::new(mode)),
+            pack: Arc::new(Mutex::new(pack)),
+            available: Arc::new(AtomicBool::new(true)),
+            alerts,
+        };
+        if mode == SoundMode::On {
+            if let Err(err_msg) = check_device(sound.pack().sound(SoundEvent::Message)) {
+                sound.disable(err_msg);
+            }
+        }
+        sound
+    }
+
+    /// Sets the notification mode.
+    pub fn set_mode(&self, mode: SoundMode) {
+        *self.mode.lock().unwrap_or_else(|e| e.into_inner()) = mode;
+        if mode == SoundMode::On && !self.available.load(Ordering::SeqCst) {
+            println!("Sound device is not available, using the terminal bell.");
+        }
+    }
+
+    /// Replaces the sound pack, e.g. after reloading the assets.
+    pub fn set_pack(&self, pack: SoundPack) {
+        *self.pack.lock().unwrap_or_else(|e| e.into_inner()) = pack;
+    }
+
+    /// Returns the alerts fired with the sound.
+    pub fn alerts(&self) -> &Alerts {
+        &self.alerts
+    }
+
+    fn pack(&self) -> SoundPack {
+        self.pack.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Plays the notification of the event according to the mode and fires the alerts.
+    pub fn notify(&self, event: SoundEvent) {
+        let mode = *self.mode.lock().unwrap_or_else(|e| e.into_inner());
+        let rang = match mode {
+            SoundMode::On if self.available.load(Ordering::SeqCst) => {
+                let sound = self.clone();
+                let path = self.pack().sound(event).to_path_buf();
+                thread::spawn(move || {
+                    if let Err(err_msg) = play(&path) {
+                        sound.disable(err_msg);
+                    }
+                });
+                false
+            }
+            SoundMode::On | SoundMode::Bell => {
+                bell();
+                true
+            }
+            SoundMode::Off => false,
+        };
+        self.alerts.notify(event, rang);
+    }
+
+    fn disable(&self, err_msg: anyhow::Error) {
+        if self.available.swap(false, Ordering::SeqCst) {
+            let report = if is_device_error(&err_msg) {
+                Report::with_failure(err_msg.as_ref(), Failure::NoSoundDevice)
+            } else {
+                Report::new(err_msg.as_ref())
+            };
+            eprintln!("Sound is not available, using the terminal bell: {report}");
+            eprintln!("Use .sound off to disable the notification.");
+        }
+    }
+}
+
+#[cfg(feature = "sound")]
+fn check_device(path: &Path) -> Result<()> {
+    if !path.exists() {
+        return Err(anyhow!("missing {}", path.display()));
+    }
+    OutputStream::try_default()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "sound"))]
+fn check_device(_path: &Path) -> Result<()> {
+    Err(anyhow!("the client was built without the sound feature"))
+}
+
+/// Returns true if the error comes from the audio output, not from the sound file.
+#[cfg(feature = "sound")]
+fn is_device_error(err_msg: &anyhow::Error) -> bool {
+    err_msg.is::<StreamError>() || err_msg.is::<PlayError>()
+}
+
+#[cfg(not(feature = "sound"))]
+fn is_device_error(_err_msg: &anyhow::Error) -> bool {
+    false
+}
+
+/// Rings the terminal bell.
+pub fn bell() {
+    print!("{BELL}");
+    let _ = std::io::stdout().flush();
+}
+
+#[cfg(feature = "sound")]
+fn play(path: &Path) -> Result<()> {
+    let (_stream, stream_handle) = OutputStream::try_default()?;
+    let file = std::fs::File::open(path)?;
+    let source = Decoder::new(std::io::BufReader::new(file))?;
+    stream_handle.play_raw(source.convert_samples())?;
+    std::thread::sleep(std::time::Duration::from_secs(2));
+    Ok(())
+}
+
+#[cfg(not(feature = "sound"))]
+fn play(path: &Path) -> Result<()> {
+    check_device(path)
+}
@@ -0,0 +1,287 @@
+//! Interactive tutorial of the basic commands, started by `.tutorial`.
+//!
+//! The tutorial needs no server: [`chat::testing`] connects the user in memory with the echo partner [`PARTNER`], who
+//! answers every step the way the chat would. The steps teach sending a text, a file, a whisper, joining a room and
+//! searching the history, a wrong command is explained and the step repeated, `.skip` ends the tutorial. Nothing is
+//! sent to the real server, its live messages keep arriving meanwhile. The first start of the client offers the
+//! tutorial once, remembered by `tutorial_offered` in the client config.
+
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chat::testing::{spawn_inproc_server, InProcClient};
+use chat::{HistoryEntry, Message, MessageType};
+
+use crate::files;
+
+/// Nickname of the partner answering the steps of the tutorial.
+pub const PARTNER: &str = "echo";
+/// Input ending the tutorial before its last step.
+pub const SKIP: &str = ".skip";
+/// Time to wait for the answer of the partner.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Content of the sample file offered by the file step.
+const SAMPLE: &str = "Hello from the chat tutorial!\n";
+
+/// Step of the tutorial, in the order of [`STEPS`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Step {
+    Text,
+    File,
+    Whisper,
+    Join,
+    Search,
+}
+
+const STEPS: [Step; 5] = [
+    Step::Text,
+    Step::File,
+    Step::Whisper,
+    Step::Join,
+    Step::Search,
+];
+
+impl Step {
+    /// Returns what the user should do in the step.
+    fn instruction(self, sample: &Path) -> String {
+        match self {
+            Step::Text => "Type a message and press Enter, e.g. hello everyone".to_string(),
+            Step::File => format!(
+                "Share a file with .file and its path, e.g. .file {}",
+                sample.display()
+            ),
+            Step::Whisper => {
+                format!("Whisper to one user only with .whisper, e.g. .whisper {PARTNER} psst")
+            }
+            Step::Join => "Join a room with .join, e.g. .join rust".to_string(),
+            Step::Search => {
+                "Search the stored messages with .search, e.g. .search hello".to_string()
+            }
+        }
+    }
+
+    /// Parses the input of the step.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error explaining the expected command if the input is another one.
+    fn parse(self, input: &str) -> Result<MessageType> {
+        let (command, arguments) = input.split_once(' ').unwrap_or((input, ""));
+        let arguments = arguments.trim();
+        match self {
+            Step::Text if !input.starts_with('.') => Ok(MessageType::text(input)),
+            Step::Text => Err(anyhow!("Commands start with a dot, a message doesn't.")),
+            Step::File if command == ".file" && !arguments.is_empty() => {
+                let path = PathBuf::from(arguments);
+                let content = std::fs::read(&path)
+                    .map_err(|err_msg| anyhow!("Can't read {}: {}", path.display(), err_msg))?;
+                let name = path
+                    .file_name()
+                    .map_or(arguments.into(), |name| name.to_string_lossy());
+                Ok(MessageType::file(name, &content))
+            }
+            Step::Whisper if command == ".whisper" => match arguments.split_once(' ') {
+                Some((PARTNER, text)) => Ok(MessageType::whisper(PARTNER, text.trim())),
+                _ => Err(anyhow!(
+                    "Name {PARTNER} and the text, .whisper {PARTNER} psst"
+                )),
+            },
+            Step::Join if command == ".join" => {
+                let room = arguments.trim_start_matches('#');
+                if !chat::is_valid_room(room) {
+                    return Err(anyhow!(
+                        "A room has 1 to {} letters, digits, - or _.",
+                        chat::MAX_ROOM_NAME
+                    ));
+                }
+                Ok(MessageType::join(room))
+            }
+            Step::Search if command == ".search" && !arguments.is_empty() => {
+                Ok(MessageType::search_request(arguments, 20))
+            }
+            step => Err(anyhow!(
+                "Not quite, {} Or {SKIP} to end the tutorial.",
+                step.instruction(Path::new("path_to_file.txt"))
+            )),
+        }
+    }
+}
+
+/// Runs the tutorial, reading the commands from the input and writing the steps and the answers to the output.
+///
+/// # Returns
+///
+/// True if all the steps were done, false if the tutorial was skipped or the input ended.
+///
+/// # Errors
+///
+/// This function will return an error if reading the input or writing the output fails, or the partner doesn't
+/// answer.
+pub async fn run<R: BufRead, W: Write>(
+    nickname: &str,
+    input: &mut R,
+    output: &mut W,
+) -> Result<bool> {
+    let sample = std::env::temp_dir().join("chat-tutorial.txt");
+    std::fs::write(&sample, SAMPLE)?;
+    let server = spawn_inproc_server();
+    let mut you = server.connect(nickname);
+    tokio::spawn(partner(server.connect(PARTNER), nickname.to_string()));
+    writeln!(
+        output,
+        "Tutorial: {} steps with {PARTNER}, offline. Type {SKIP} to end it.",
+        STEPS.len()
+    )?;
+    for (number, step) in STEPS.iter().enumerate() {
+        writeln!(
+            output,
+            "\n{}/{}: {}",
+            number + 1,
+            STEPS.len(),
+            step.instruction(&sample)
+        )?;
+        let message = loop {
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(false);
+            }
+            let line = line.trim();
+            if line == SKIP {
+                writeln!(output, "Tutorial skipped, .tutorial starts it again.")?;
+                return Ok(false);
+            }
+            match step.parse(line) {
+                Ok(message) => break message,
+                Err(hint) => writeln!(output, "{hint}")?,
+            }
+        };
+        you.send(message).await?;
+        let answer = tokio::time::timeout(ANSWER_TIMEOUT, you.recv())
+            .await
+            .map_err(|_| anyhow!("{PARTNER} didn't answer!"))??;
+        writeln!(output, "{}", render(&answer))?;
+    }
+    writeln!(
+        output,
+        "\nTutorial done! Your messages go to the server again."
+    )?;
+    Ok(true)
+}
+
+/// Renders the answer of the partner.
+fn render(answer: &Message) -> String {
+    match &answer.message {
+        MessageType::Whisper { text, .. } => format!("{} --> (whisper) {text}", answer.nickname),
+        MessageType::SearchResults(entries) => {
+            let mut lines = vec![format!("found {} messages:", entries.len())];
+            for entry in entries {
+                lines.push(format!(
+                    "#{} {} --> {}",
+                    entry.id, entry.nickname, entry.message
+                ));
+            }
+            lines.join("\n")
+        }
+        message => format!(
+            "{} --> {}",
+            answer.nickname,
+            message.get_type_and_message().1
+        ),
+    }
+}
+
+/// Answers the messages of the user like the chat would, remembering the texts for the search.
+async fn partner(mut client: InProcClient, nickname: String) {
+    let mut stored: Vec<HistoryEntry> = Vec::new();
+    while let Ok(message) = client.recv().await {
+        let answer = match message.message {
+            MessageType::Text(text) => {
+                let answer = format!("Hi {nickname}, everybody in the room sees: {text}");
+                stored.push(HistoryEntry {
+                    id: stored.len() as i64 + 1,
+                    nickname: nickname.clone(),
+                    msg_type: "Text".to_string(),
+                    message: text,
+                    timestamp: message.timestamp,
+                });
+                MessageType::text(answer)
+            }
+            MessageType::File { name, content } => MessageType::text(format!(
+                "Got {name} ({}), it is saved in the FILES folder of the receivers.",
+                files::format_size(content.len())
+            )),
+            MessageType::Whisper { .. } => MessageType::whisper(
+                &nickname,
+                "psst back! Only you see this and the server never stores whispers.",
+            ),
+            MessageType::Join { room } => MessageType::text(format!(
+                "Welcome in #{room}! You write there now, .room general switches back."
+            )),
+            MessageType::SearchRequest { query, .. } => {
+                let query = query.to_lowercase();
+                let found = stored
+                    .iter()
+                    .filter(|entry| entry.message.to_lowercase().contains(&query))
+                    .cloned()
+                    .collect();
+                MessageType::SearchResults(found)
+            }
+            _ => continue,
+        };
+        if client.send(answer).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Step::Text.parse("hi").unwrap(), MessageType::text("hi"));
+        assert!(Step::Text.parse(".who").is_err());
+        assert!(Step::File.parse(".file").is_err());
+        assert!(Step::File.parse(".file /no/such/file").is_err());
+        assert_eq!(
+            Step::Whisper.parse(".whisper echo psst").unwrap(),
+            MessageType::whisper(PARTNER, "psst")
+        );
+        assert!(Step::Whisper.parse(".whisper eva psst").is_err());
+        assert_eq!(
+            Step::Join.parse(".join #rust").unwrap(),
+            MessageType::join("rust")
+        );
+        assert!(Step::Join.parse(".join no spaces").is_err());
+        assert!(Step::Search.parse(".search").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let sample = std::env::temp_dir().join("chat-tutorial.txt");
+        let script = format!(
+            "hello everyone\n.file\n.file {}\n.whisper echo psst\n.join rust\n.search HELLO\n",
+            sample.display()
+        );
+        let mut output = Vec::new();
+        assert!(run("slava", &mut script.as_bytes(), &mut output)
+            .await
+            .unwrap());
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("echo --> Hi slava, everybody in the room sees: hello everyone"));
+        assert!(output.contains("Not quite, Share a file"));
+        assert!(output.contains("Got chat-tutorial.txt (30 B)"));
+        assert!(output.contains("echo --> (whisper) psst back!"));
+        assert!(output.contains("Welcome in #rust!"));
+        assert!(output.contains("found 1 messages:\n#1 slava --> hello everyone"));
+        assert!(output.ends_with("Tutorial done! Your messages go to the server again.\n"));
+
+        let mut output = Vec::new();
+        assert!(!run("slava", &mut "hi\n.skip\n".as_bytes(), &mut output)
+            .await
+            .unwrap());
+    }
+}
@@ -0,0 +1,293 @@
+//! Saving the received images and files without overwriting each other.
+//!
+//! Every save goes through the [`DownloadWriter`], a single task creating the files one at a time. A file is created
+//! only if it doesn't exist yet (`O_EXCL`), a taken name is numbered like `notes (1).txt`, so two attachments of the
+//! same name arriving at once, or a file received again, never write to the same file. The attachments downloaded over
+//! HTTP and the streamed files are written by their own tasks, the writer only creates their files, which reserves the
+//! names until they are complete. A renamed file is reported by [`render_conflict`].
+//!
+//! The names come from the other users, so only their last component is used, e.g. `../.bashrc` is saved as `.bashrc`
+//! in the folder, and a name without any, like `..`, is rejected.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot};
+
+/// Maximal number of the numbered copies of a name.
+const MAX_COPIES: u32 = 1000;
+
+enum Request {
+    /// Creates the file and writes the content.
+    Save {
+        folder: PathBuf,
+        name: String,
+        content: Vec<u8>,
+        reply: oneshot::Sender<Result<PathBuf>>,
+    },
+    /// Creates the empty file written by the caller.
+    Reserve {
+        folder: PathBuf,
+        name: String,
+        reply: oneshot::Sender<Result<(PathBuf, File)>>,
+    },
+}
+
+/// Handle of the task creating the downloaded files, shared by the reading loop and the downloads.
+#[derive(Clone)]
+pub struct DownloadWriter {
+    requests: mpsc::UnboundedSender<Request>,
+}
+
+impl DownloadWriter {
+    /// Spawns the writer task, it ends when the last handle is dropped.
+    pub fn spawn() -> DownloadWriter {
+        let (requests, received) = mpsc::unbounded_channel();
+        tokio::spawn(write(received));
+        DownloadWriter { requests }
+    }
+
+    /// Saves the content as a new file of the name in the folder.
+    ///
+    /// # Returns
+    ///
+    /// The path of the saved file, numbered if the name was taken.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the folder or the file can't be created or written.
+    pub async fn save(&self, folder: &str, name: &str, content: Vec<u8>) -> Result<PathBuf> {
+        let (reply, saved) = oneshot::channel();
+        self.send(Request::Save {
+            folder: PathBuf::from(folder),
+            name: name.to_string(),
+            content,
+            reply,
+        })?;
+        saved.await?
+    }
+
+    /// Creates a new empty file of the name in the folder for the caller to write.
+    ///
+    /// # Returns
+    ///
+    /// The path of the created file, numbered if the name was taken, and the file.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the folder or the file can't be created.
+    pub async fn reserve(&self, folder: &str, name: &str) -> Result<(PathBuf, File)> {
+        let (reply, reserved) = oneshot::channel();
+        self.send(Request::Reserve {
+            folder: PathBuf::from(folder),
+            name: name.to_string(),
+            reply,
+        })?;
+        reserved.await?
+    }
+
+    fn send(&self, request: Request) -> Result<()> {
+        self.requests
+            .send(request)
+            .map_err(|_| anyhow!("The download writer has stopped!"))
+    }
+}
+
+/// Handles the requests one by one, so no two of them pick the same name.
+async fn write(mut requests: mpsc::UnboundedReceiver<Request>) {
+    while let Some(request) = requests.recv().await {
+        // A caller which stopped waiting doesn't need the reply.
+        match request {
+            Request::Save {
+                folder,
+                name,
+                content,
+                reply,
+            } => {
+                let _ = reply.send(save(&folder, &name, &content).await);
+            }
+            Request::Reserve {
+                folder,
+                name,
+                reply,
+            } => {
+                let _ = reply.send(create(&folder, &name).await);
+            }
+        }
+    }
+}
+
+async fn save(folder: &Path, name: &str, content: &[u8]) -> Result<PathBuf> {
+    let (path, mut file) = create(folder, name).await?;
+    let written = async {
+        file.write_all(content).await?;
+        file.flush().await
+    };
+    if let Err(err_msg) = written.await {
+        // A partial file would look like a complete one.
+        let _ = fs::remove_file(&path).await;
+        return Err(err_msg).with_context(|| format!("Writing {} failed!", path.display()));
+    }
+    Ok(path)
+}
+
+/// Creates the first free file of the name or its numbered copies in the folder.
+async fn create(folder: &Path, name: &str) -> Result<(PathBuf, File)> {
+    let name = file_name(name)?;
+    fs::create_dir_all(folder)
+        .await
+        .with_context(|| format!("Creating dir {} failed!", folder.display()))?;
+    for copy in 0..=MAX_COPIES {
+        let path = folder.join(numbered(name, copy));
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+        {
+            Ok(file) => return Ok((path, file)),
+            Err(err_msg) if err_msg.kind() == ErrorKind::AlreadyExists => continue,
+            Err(err_msg) => {
+                return Err(err_msg).with_context(|| format!("Creating {} failed!", path.display()))
+            }
+        }
+    }
+    Err(anyhow!(
+        "There are {MAX_COPIES} copies of {name} in {} already!",
+        folder.display()
+    ))
+}
+
+/// Returns the last component of the received name, so the file can't be written outside the folder.
+///
+/// # Errors
+///
+/// This function will return an error if the name has no file name, e.g. it is empty or `..`.
+fn file_name(name: &str) -> Result<&str> {
+    match Path::new(name).file_name().and_then(|name| name.to_str()) {
+        Some(file_name) if !file_name.is_empty() && file_name != ".." => Ok(file_name),
+        _ => Err(anyhow!("Invalid file name {name:?}!")),
+    }
+}
+
+/// Returns the name of the numbered copy, the number goes before all the extensions, e.g. `dir (2).tar.gz`.
+fn numbered(name: &str, copy: u32) -> String {
+    if copy == 0 {
+        return name.to_string();
+    }
+    // The dot of a hidden file like `.env` doesn't start an extension.
+    match name.char_indices().skip(1).find(|(_, c)| *c == '.') {
+        Some((dot, _)) => format!("{} ({copy}){}", &name[..dot], &name[dot..]),
+        None => format!("{name} ({copy})"),
+    }
+}
+
+/// Renders the notice of a file saved under another name than it was sent with, `None` if the name was kept.
+pub fn render_conflict(name: &str, path: &Path) -> Option<String> {
+    let saved = path.file_name()?.to_string_lossy();
+    if saved == file_name(name).unwrap_or(name) {
+        return None;
+    }
+    Some(format!("{name} exists already, saving as {saved}."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numbered() {
+        assert_eq!(numbered("notes.txt", 0), "notes.txt");
+        assert_eq!(numbered("notes.txt", 1), "notes (1).txt");
+        assert_eq!(numbered("dir.tar.gz", 2), "dir (2).tar.gz");
+        assert_eq!(numbered("README", 1), "README (1)");
+        assert_eq!(numbered(".env", 1), ".env (1)");
+        assert_eq!(
+            render_conflict("notes.txt", Path::new("FILES/notes (1).txt")).unwrap(),
+            "notes.txt exists already, saving as notes (1).txt."
+        );
+        assert_eq!(
+            render_conflict("notes.txt", Path::new("FILES/notes.txt")),
+            None
+        );
+        assert_eq!(
+            render_conflict("../notes.txt", Path::new("FILES/notes.txt")),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_traversal() {
+        let root = std::env::temp_dir().join(format!("chat-traversal-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let folder = root.join("FILES");
+        let folder_name = folder.to_string_lossy().to_string();
+        let writer = DownloadWriter::spawn();
+
+        let outside = root.join("evil.txt").to_string_lossy().to_string();
+        for name in ["../evil.txt", "sub/../../evil.txt", &outside] {
+            let saved = writer
+                .save(&folder_name, name, b"x".to_vec())
+                .await
+                .unwrap();
+            assert_eq!(saved.parent(), Some(folder.as_path()), "{name}");
+        }
+        assert!(!root.join("evil.txt").exists());
+        let (reserved, _file) = writer
+            .reserve(&folder_name, "../../evil.txt")
+            .await
+            .unwrap();
+        assert_eq!(reserved, folder.join("evil (3).txt"));
+        for name in ["", "..", "../..", "/", "sub/.."] {
+            assert!(
+                writer.save(&folder_name, name, Vec::new()).await.is_err(),
+                "{name}"
+            );
+            assert!(writer.reserve(&folder_name, name).await.is_err(), "{name}");
+        }
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 1);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_saves() {
+        let folder = std::env::temp_dir().join(format!("chat-writer-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&folder);
+        let folder_name = folder.to_string_lossy().to_string();
+        let writer = DownloadWriter::spawn();
+
+        let saves = (0..5u8).map(|n| {
+            let (writer, folder_name) = (writer.clone(), folder_name.clone());
+            tokio::spawn(async move { writer.save(&folder_name, "notes.txt", vec![n; 100]).await })
+        });
+        let mut saved = Vec::new();
+        for save in saves.collect::<Vec<_>>() {
+            saved.push(save.await.unwrap().unwrap());
+        }
+        saved.sort();
+        let mut expected: Vec<PathBuf> = (0..5)
+            .map(|n| folder.join(numbered("notes.txt", n)))
+            .collect();
+        expected.sort();
+        assert_eq!(saved, expected);
+        // Every save wrote its own content to its own file.
+        let mut contents: Vec<u8> = saved
+            .iter()
+            .map(|path| {
+                let content = std::fs::read(path).unwrap();
+                assert!(content.iter().all(|byte| *byte == content[0]));
+                content[0]
+            })
+            .collect();
+        contents.sort();
+        assert_eq!(contents, [0, 1, 2, 3, 4]);
+
+        let (reserved, _file) = writer.reserve(&folder_name, "notes.txt").await.unwrap();
+        assert_eq!(reserved, folder.join("notes (5).txt"));
+        assert_eq!(std::fs::read(&reserved).unwrap(), b"");
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+}
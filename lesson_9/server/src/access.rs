@@ -0,0 +1,575 @@
+//! Connection access control.
+//!
+//! Every accepted connection is checked against the deny and allow lists of networks and the limit of concurrent
+//! connections from a single IP address before any task is spawned for it. The lists can be replaced at runtime
+//! through the `/access` endpoint of the metrics server, which only accepts requests from the loopback. The endpoint
+//! is a part of the `metrics` feature.
+//!
+//! The chat has no passwords, so a failed handshake is a connection sending an invalid message, posing as the server or
+//! closing before its first message. An address failing [`AccessConfig::max_failures`] times within the
+//! [`AccessConfig::failure_window`] is banned for the [`AccessConfig::ban_time`]. Every failure and every ban is
+//! logged with the [`AUTH_TARGET`] target in a fixed format for fail2ban, e.g.
+//! `AUTH_FAILURE ip=10.0.0.5 reason=invalid_message failures=3`. The bans are listed by the `/access/bans` endpoint and
+//! lifted by `DELETE /access/bans/<address>`.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+#[cfg(feature = "metrics")]
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+#[cfg(feature = "metrics")]
+use axum::extract::{ConnectInfo, Path, State};
+#[cfg(feature = "metrics")]
+use axum::http::StatusCode;
+#[cfg(feature = "metrics")]
+use axum::Json;
+#[cfg(feature = "metrics")]
+use log::info;
+use log::warn;
+#[cfg(feature = "metrics")]
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::{FAILED_HANDSHAKES, REJECTED_CONNECTIONS};
+
+/// Default maximal number of concurrent connections from a single IP address.
+pub const MAX_PER_IP: usize = 16;
+/// Default number of the failed handshakes banning the address.
+pub const MAX_FAILURES: usize = 10;
+/// Default time in which the failed handshakes are counted.
+pub const FAILURE_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// Default time of the ban.
+pub const BAN_TIME: Duration = Duration::from_secs(60 * 60);
+/// Log target of the failed handshakes and the bans, e.g. for `RUST_LOG=auth=warn`.
+pub const AUTH_TARGET: &str = "auth";
+/// Number of the tracked addresses from which the ones without a recent failure are forgotten.
+const MAX_TRACKED: usize = 1024;
+
+/// Network in the CIDR notation, e.g. `10.0.0.0/8`. A plain address is a network of a single host.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpNet {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    /// Returns true if the network contains the address.
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => prefix_matches(
+                network.to_bits().into(),
+                address.to_bits().into(),
+                self.prefix,
+                32,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                prefix_matches(network.to_bits(), address.to_bits(), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: u128, address: u128, prefix: u8, bits: u8) -> bool {
+    let shift = bits - prefix;
+    prefix == 0 || network >> shift == address >> shift
+}
+
+impl FromStr for IpNet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (address, prefix) = s.trim().split_once('/').unwrap_or((s.trim(), ""));
+        let address = IpAddr::from_str(address)
+            .with_context(|| format!("Invalid network {s}, use e.g. 10.0.0.0/8!"))?
+            .to_canonical();
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => bits,
+            prefix => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or_else(|| anyhow!("Invalid prefix of network {s}, use 0 to {bits}!"))?,
+        };
+        Ok(IpNet { address, prefix })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+/// Access rules from the `[access]` section of the server config.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessConfig {
+    pub max_per_ip: usize,
+    /// Networks allowed to connect, empty list allows everybody not denied.
+    pub allow: Vec<IpNet>,
+    /// Networks never allowed to connect.
+    pub deny: Vec<IpNet>,
+    /// Number of the failed handshakes within the window banning the address, `0` never bans.
+    pub max_failures: usize,
+    pub failure_window: Duration,
+    pub ban_time: Duration,
+}
+
+impl Default for AccessConfig {
+    fn default() -> Self {
+        AccessConfig {
+            max_per_ip: MAX_PER_IP,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            max_failures: MAX_FAILURES,
+            failure_window: FAILURE_WINDOW,
+            ban_time: BAN_TIME,
+        }
+    }
+}
+
+/// Reason of the rejected connection, used as the metrics label.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejection {
+    Banned,
+    Denied,
+    NotAllowed,
+    TooManyConnections,
+}
+
+impl Rejection {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Banned => "banned",
+            Self::Denied => "denied",
+            Self::NotAllowed => "not_allowed",
+            Self::TooManyConnections => "too_many_connections",
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Banned => write!(f, "address is banned after failed handshakes"),
+            Self::Denied => write!(f, "address is in the deny list"),
+            Self::NotAllowed => write!(f, "address is not in the allow list"),
+            Self::TooManyConnections => write!(f, "too many connections from the address"),
+        }
+    }
+}
+
+/// Temporary ban of an address.
+#[derive(Debug, Clone, Copy)]
+struct Ban {
+    until: Instant,
+    /// Number of the failed handshakes, listed by the `/access/bans` endpoint.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    failures: usize,
+}
+
+/// Recent failed handshakes and the bans of the addresses.
+#[derive(Debug, Default)]
+struct Failures {
+    attempts: HashMap<IpAddr, VecDeque<Instant>>,
+    bans: HashMap<IpAddr, Ban>,
+}
+
+impl Failures {
+    /// Returns true if the address is banned at the time, forgets an expired ban.
+    fn is_banned(&mut self, address: IpAddr, now: Instant) -> bool {
+        match self.bans.get(&address) {
+            Some(ban) if ban.until > now => true,
+            Some(_) => {
+                self.bans.remove(&address);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+/// Shared state of the access control.
+#[derive(Debug, Clone)]
+pub struct Access {
+    config: Arc<RwLock<AccessConfig>>,
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    failures: Arc<Mutex<Failures>>,
+}
+
+/// Connection admitted by [`Access::admit`], released on drop.
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    address: IpAddr,
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = connections.get_mut(&self.address) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.address);
+            }
+        }
+    }
+}
+
+impl Access {
+    pub fn new(config: AccessConfig) -> Access {
+        Access {
+            config: Arc::new(RwLock::new(config)),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            failures: Default::default(),
+        }
+    }
+
+    /// Checks the connection from the address.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(ConnectionSlot)` - If the connection is admitted, the slot must be kept until the connection ends.
+    /// - `Err(Rejection)` - If the connection must be closed, counted in the `rejected_connections` metric.
+    pub fn admit(&self, address: IpAddr) -> Result<ConnectionSlot, Rejection> {
+        let address = address.to_canonical();
+        let config = self.config.read().unwrap_or_else(|e| e.into_inner());
+        let mut connections = self.connections.lock().unwrap_or_else(|e| e.into_inner());
+        let count = connections.entry(address).or_default();
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        let rejection = if failures.is_banned(address, Instant::now()) {
+            Some(Rejection::Banned)
+        } else if config.deny.iter().any(|net| net.contains(address)) {
+            Some(Rejection::Denied)
+        } else if !config.allow.is_empty() && !config.allow.iter().any(|net| net.contains(address))
+        {
+            Some(Rejection::NotAllowed)
+        } else if *count >= config.max_per_ip {
+            Some(Rejection::TooManyConnections)
+        } else {
+            None
+        };
+        if let Some(rejection) = rejection {
+            if *count == 0 {
+                connections.remove(&address);
+            }
+            REJECTED_CONNECTIONS
+                .with_label_values(&[rejection.label()])
+                .inc();
+            return Err(rejection);
+        }
+        *count += 1;
+        Ok(ConnectionSlot {
+            address,
+            connections: self.connections.clone(),
+        })
+    }
+
+    /// Records a failed handshake of the address and bans it after too many failures.
+    ///
+    /// # Arguments
+    ///
+    /// - `address` - The address of the client.
+    /// - `reason` - The label of the failure in the log and the `failed_handshakes` metric, e.g. `invalid_message`.
+    ///
+    /// # Returns
+    ///
+    /// True if the address got banned, its connection should be closed.
+    pub fn record_failure(&self, address: IpAddr, reason: &str) -> bool {
+        self.record_failure_at(address.to_canonical(), reason, Instant::now())
+    }
+
+    fn record_failure_at(&self, address: IpAddr, reason: &str, now: Instant) -> bool {
+        FAILED_HANDSHAKES.with_label_values(&[reason]).inc();
+        let config = self.config.read().unwrap_or_else(|e| e.into_inner());
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        let window = config.failure_window;
+        if failures.attempts.len() >= MAX_TRACKED {
+            failures
+                .attempts
+                .retain(|_, attempts| attempts.back().is_some_and(|time| now - *time < window));
+        }
+        let attempts = failures.attempts.entry(address).or_default();
+        attempts.push_back(now);
+        while attempts.front().is_some_and(|time| now - *time >= window) {
+            attempts.pop_front();
+        }
+        let count = attempts.len();
+        warn!(target: AUTH_TARGET, "AUTH_FAILURE ip={address} reason={reason} failures={count}");
+        if config.max_failures == 0 || count < config.max_failures {
+            return false;
+        }
+        failures.attempts.remove(&address);
+        let until = now + config.ban_time;
+        failures.bans.insert(
+            address,
+            Ban {
+                until,
+                failures: count,
+            },
+        );
+        let seconds = config.ban_time.as_secs();
+        warn!(target: AUTH_TARGET, "AUTH_BAN ip={address} seconds={seconds} failures={count}");
+        true
+    }
+
+    /// Returns the active bans sorted by the address.
+    #[cfg(feature = "metrics")]
+    fn bans(&self) -> Vec<BanEntry> {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        failures.bans.retain(|_, ban| ban.until > now);
+        let mut bans: Vec<BanEntry> = failures
+            .bans
+            .iter()
+            .map(|(address, ban)| BanEntry {
+                address: address.to_string(),
+                remaining_secs: (ban.until - now).as_secs(),
+                failures: ban.failures,
+            })
+            .collect();
+        bans.sort_by(|a, b| a.address.cmp(&b.address));
+        bans
+    }
+
+    /// Lifts the ban of the address, returns false if it wasn't banned.
+    #[cfg(feature = "metrics")]
+    fn unban(&self, address: IpAddr) -> bool {
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        failures.bans.remove(&address.to_canonical()).is_some()
+    }
+
+    #[cfg(feature = "metrics")]
+    fn set_lists(&self, allow: Vec<IpNet>, deny: Vec<IpNet>) {
+        let mut config = self.config.write().unwrap_or_else(|e| e.into_inner());
+        config.allow = allow;
+        config.deny = deny;
+    }
+}
+
+/// Body of the `/access` endpoint.
+#[cfg(feature = "metrics")]
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct AccessLists {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+#[cfg(feature = "metrics")]
+fn parse_networks(networks: &[String]) -> Result<Vec<IpNet>> {
+    networks.iter().map(|network| network.parse()).collect()
+}
+
+/// Returns the current allow and deny lists.
+#[cfg(feature = "metrics")]
+pub async fn get_lists(
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    State(access): State<Access>,
+) -> Result<Json<AccessLists>, (StatusCode, String)> {
+    check_loopback(client, "Access lists")?;
+    let config = access.config.read().unwrap_or_else(|e| e.into_inner());
+    let to_strings = |networks: &[IpNet]| networks.iter().map(ToString::to_string).collect();
+    Ok(Json(AccessLists {
+        allow: to_strings(&config.allow),
+        deny: to_strings(&config.deny),
+    }))
+}
+
+/// Replaces the allow and deny lists, the change is not stored in the config file.
+#[cfg(feature = "metrics")]
+pub async fn set_lists(
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    State(access): State<Access>,
+    Json(lists): Json<AccessLists>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    check_loopback(client, "Access lists")?;
+    let parse = |networks: &[String]| {
+        parse_networks(networks).map_err(|err_msg| (StatusCode::BAD_REQUEST, err_msg.to_string()))
+    };
+    let (allow, deny) = (parse(&lists.allow)?, parse(&lists.deny)?);
+    info!(
+        "Access lists updated by {}: allow {:?}, deny {:?}.",
+        client, lists.allow, lists.deny
+    );
+    access.set_lists(allow, deny);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Ban listed by the `/access/bans` endpoint.
+#[cfg(feature = "metrics")]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct BanEntry {
+    pub address: String,
+    pub remaining_secs: u64,
+    /// Number of the failed handshakes that caused the ban.
+    pub failures: usize,
+}
+
+/// Returns the addresses banned after failed handshakes.
+#[cfg(feature = "metrics")]
+pub async fn get_bans(
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    State(access): State<Access>,
+) -> Result<Json<Vec<BanEntry>>, (StatusCode, String)> {
+    check_loopback(client, "Bans")?;
+    Ok(Json(access.bans()))
+}
+
+/// Lifts the ban of the address, the failures counted afterwards start from zero.
+#[cfg(feature = "metrics")]
+pub async fn delete_ban(
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    State(access): State<Access>,
+    Path(address): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    check_loopback(client, "Bans")?;
+    let address: IpAddr = address.parse().map_err(|_| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid address {address}!"),
+        )
+    })?;
+    if !access.unban(address) {
+        return Err((StatusCode::NOT_FOUND, format!("{address} is not banned!")));
+    }
+    info!("Ban of {} lifted by {}.", address, client);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Refuses the requests from other hosts than the localhost, `what` names the protected endpoint in the answer.
+#[cfg(feature = "metrics")]
+pub fn check_loopback(client: SocketAddr, what: &str) -> Result<(), (StatusCode, String)> {
+    if client.ip().to_canonical().is_loopback() {
+        Ok(())
+    } else {
+        Err((
+            StatusCode::FORBIDDEN,
+            format!("{what} can be accessed only from localhost!"),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn test_network_contains() {
+        let net: IpNet = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("11.0.0.1")));
+        let host: IpNet = "192.168.1.5".parse().unwrap();
+        assert_eq!(host.to_string(), "192.168.1.5/32");
+        assert!(host.contains(ip("192.168.1.5")));
+        assert!(!host.contains(ip("192.168.1.6")));
+        let any: IpNet = "::/0".parse().unwrap();
+        assert!(any.contains(ip("2001:db8::1")));
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("localhost".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn test_admit_lists() {
+        let access = Access::new(AccessConfig {
+            max_per_ip: 2,
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            deny: vec!["10.0.0.66".parse().unwrap()],
+            ..Default::default()
+        });
+        assert!(access.admit(ip("10.0.0.1")).is_ok());
+        assert_eq!(
+            access.admit(ip("10.0.0.66")).unwrap_err(),
+            Rejection::Denied
+        );
+        assert_eq!(
+            access.admit(ip("192.168.0.1")).unwrap_err(),
+            Rejection::NotAllowed
+        );
+    }
+
+    #[test]
+    fn test_record_failure() {
+        let access = Access::new(AccessConfig {
+            max_failures: 3,
+            failure_window: Duration::from_secs(60),
+            ban_time: Duration::from_secs(600),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        let address = ip("10.0.0.5");
+        let at = |secs| start + Duration::from_secs(secs);
+        assert!(!access.record_failure_at(address, "invalid_message", at(0)));
+        assert!(!access.record_failure_at(address, "invalid_message", at(30)));
+        // The first failure is out of the window.
+        assert!(!access.record_failure_at(address, "invalid_message", at(60)));
+        assert!(!access.record_failure_at(ip("10.0.0.6"), "impersonation", at(61)));
+        assert!(access.record_failure_at(address, "impersonation", at(61)));
+        assert_eq!(access.admit(address).unwrap_err(), Rejection::Banned);
+        assert!(access.admit(ip("10.0.0.6")).is_ok());
+
+        let mut failures = access.failures.lock().unwrap();
+        assert!(!failures.attempts.contains_key(&address));
+        assert!(failures.is_banned(address, at(660)));
+        assert!(!failures.is_banned(address, at(661)));
+        assert!(failures.bans.is_empty());
+    }
+
+    #[test]
+    fn test_never_ban() {
+        let access = Access::new(AccessConfig {
+            max_failures: 0,
+            ..Default::default()
+        });
+        for _ in 0..MAX_FAILURES * 2 {
+            assert!(!access.record_failure(ip("10.0.0.5"), "invalid_message"));
+        }
+        assert!(access.admit(ip("10.0.0.5")).is_ok());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_unban() {
+        let access = Access::new(AccessConfig {
+            max_failures: 1,
+            ..Default::default()
+        });
+        assert!(access.record_failure(ip("::ffff:10.0.0.5"), "disconnected"));
+        let bans = access.bans();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(
+            (bans[0].address.as_str(), bans[0].failures),
+            ("10.0.0.5", 1)
+        );
+        assert!(bans[0].remaining_secs > BAN_TIME.as_secs() - 5);
+        assert!(access.unban(ip("10.0.0.5")));
+        assert!(!access.unban(ip("10.0.0.5")));
+        assert!(access.admit(ip("10.0.0.5")).is_ok());
+    }
+
+    #[test]
+    fn test_admit_limit() {
+        let access = Access::new(AccessConfig {
+            max_per_ip: 2,
+            ..Default::default()
+        });
+        let first = access.admit(ip("127.0.0.1")).unwrap();
+        let _second = access.admit(ip("127.0.0.1")).unwrap();
+        assert_eq!(
+            access.admit(ip("127.0.0.1")).unwrap_err(),
+            Rejection::TooManyConnections
+        );
+        assert!(access.admit(ip("127.0.0.2")).is_ok());
+        drop(first);
+        assert!(access.admit(ip("127.0.0.1")).is_ok());
+    }
+}
@@ -1,31 +1,163 @@
 #[macro_use]
 extern crate rocket;
 
+mod bulk;
+mod console;
+mod moderation;
+mod usage;
+
+use std::io::{Cursor, Write};
+use std::str::FromStr;
+use std::time::Duration;
+
+use rocket::fairing::AdHoc;
 use rocket::form::Form;
-use rocket::Request;
+use rocket::http::{Header, Status};
+use rocket::response::Redirect;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::time::{Date, OffsetDateTime};
+use rocket::{Request, State};
+use rocket_db_pools::sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use rocket_db_pools::sqlx::SqliteConnection;
 use rocket_db_pools::{sqlx, Connection, Database};
-use rocket_dyn_templates::{context, Template};
+use rocket_dyn_templates::{context, Metadata, Template};
+
+use bulk::Report;
+use moderation::{Inbox, Resolution};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// Default maximal number of read-only connections.
+const READ_MAX_CONNECTIONS: u32 = 4;
+/// Name of the transcript in the exported archive.
+const TRANSCRIPT_FILE: &str = "transcript.html";
+/// Default directory of the attachments stored by the server.
+const ATTACHMENTS_DIR: &str = "attachments";
+/// Default maximal number of rows shown by the SQL console.
+const CONSOLE_ROWS: usize = 500;
+/// Default time after which a console query is interrupted, in milliseconds.
+const CONSOLE_TIMEOUT: u64 = 5000;
 
 #[derive(Database)]
 #[database("server_db")]
 struct Server(sqlx::SqlitePool);
 
+/// Read-only pool for the listing and search pages, so heavy queries don't contend with the writes.
+struct ReadPool(sqlx::SqlitePool);
+
+/// Settings of the read-only pool from `databases.server_db_read`, e.g. a replica of the database.
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ReadConfig {
+    url: String,
+    #[serde(default = "read_max_connections")]
+    max_connections: u32,
+}
+
+fn read_max_connections() -> u32 {
+    READ_MAX_CONNECTIONS
+}
+
+/// Settings of the admin panel besides the databases.
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct AdminConfig {
+    /// Directory of the attachments stored by the server, its `attachments.dir` setting.
+    #[serde(default = "attachments_dir")]
+    attachments_dir: String,
+    /// Maximal number of rows shown by the SQL console.
+    #[serde(default = "console_rows")]
+    console_rows: usize,
+    /// Time after which a console query is interrupted, in milliseconds.
+    #[serde(default = "console_timeout")]
+    console_timeout: u64,
+    /// Attachment MiB of a nickname flagged in the storage usage, the `storage.warn_size` of the server.
+    #[serde(default)]
+    storage_warn_mib: Option<u64>,
+}
+
+fn attachments_dir() -> String {
+    ATTACHMENTS_DIR.to_string()
+}
+
+fn console_rows() -> usize {
+    CONSOLE_ROWS
+}
+
+fn console_timeout() -> u64 {
+    CONSOLE_TIMEOUT
+}
+
+/// Opens the pool of connections which can't write, whatever the URL says.
+///
+/// # Errors
+///
+/// This function will return an error if the URL is invalid or the database doesn't exist.
+async fn open_read_pool(config: &ReadConfig) -> Result<sqlx::SqlitePool, sqlx::Error> {
+    let options = SqliteConnectOptions::from_str(&config.url)?
+        .read_only(true)
+        .create_if_missing(false);
+    SqlitePoolOptions::new()
+        .max_connections(config.max_connections)
+        .connect_with(options)
+        .await
+}
+
 #[derive(FromForm)]
 struct Query {
     nickname: String,
 }
 
+#[derive(FromForm)]
+struct Search {
+    text: String,
+}
+
+/// Query of the SQL console, a single SELECT statement.
+#[derive(FromForm)]
+struct ConsoleQuery {
+    sql: String,
+}
+
+/// Deletion of the messages of the nickname sent in the days (UTC) from `from` to `to`, like `2024-06-10`.
+#[derive(FromForm, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct BulkDelete {
+    nickname: String,
+    from: String,
+    to: String,
+    dry_run: bool,
+}
+
+/// Anonymization of the history of the nickname.
+#[derive(FromForm, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct BulkAnonymize {
+    nickname: String,
+    dry_run: bool,
+}
+
+/// Removal of the stored attachments bigger than `min_mib` MiB.
+#[derive(FromForm, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct BulkPurge {
+    min_mib: u64,
+    dry_run: bool,
+}
+
 #[get("/")]
 async fn index() -> Template {
     Template::render("index", context! {title: "Admin"})
 }
 
 #[get("/")]
-async fn messages(mut db: Connection<Server>) -> Template {
-    let rows: Vec<(i64, String, String, String)> = sqlx::query_as("SELECT * FROM messages;")
-        .fetch_all(&mut **db)
-        .await
-        .unwrap_or(Vec::new());
+async fn messages(db: &State<ReadPool>) -> Template {
+    let rows: Vec<(i64, String, String, String)> =
+        sqlx::query_as("SELECT id, nickname, msg_type, message FROM messages JOIN message_bodies ON hash = body_hash;")
+            .fetch_all(&db.0)
+            .await
+            .unwrap_or(Vec::new());
     Template::render("messages", context! {title: "Messages", rows: rows})
 }
 
@@ -35,17 +167,132 @@ async fn messages_form() -> Template {
 }
 
 #[post("/nickname", data = "<query_form>")]
-async fn messages_nickname(mut db: Connection<Server>, query_form: Form<Query>) -> Template {
+async fn messages_nickname(db: &State<ReadPool>, query_form: Form<Query>) -> Template {
     let nickname = &query_form.nickname;
-    let rows: Vec<(i64, String, String, String)> =
-        sqlx::query_as("SELECT * FROM messages WHERE nickname = ( ?1 );")
-            .bind(nickname)
-            .fetch_all(&mut **db)
-            .await
-            .unwrap_or(Vec::new());
+    let rows: Vec<(i64, String, String, String)> = sqlx::query_as(
+        "SELECT id, nickname, msg_type, message FROM messages JOIN message_bodies ON hash = body_hash WHERE nickname = ( ?1 );",
+    )
+    .bind(nickname)
+    .fetch_all(&db.0)
+    .await
+    .unwrap_or(Vec::new());
+    Template::render("messages", context! {title: "Messages", rows: rows})
+}
+
+#[get("/search")]
+async fn search_form() -> Template {
+    Template::render("search_form", context! {title: "Search Form"})
+}
+
+#[post("/search", data = "<search_form>")]
+async fn messages_search(db: &State<ReadPool>, search_form: Form<Search>) -> Template {
+    let pattern = chat::fold(&search_form.text)
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    let rows: Vec<(i64, String, String, String)> = sqlx::query_as(
+        "SELECT id, nickname, msg_type, message FROM messages JOIN message_bodies ON hash = body_hash WHERE search_text LIKE ?1 ESCAPE '\\';",
+    )
+    .bind(format!("%{pattern}%"))
+    .fetch_all(&db.0)
+    .await
+    .unwrap_or(Vec::new());
     Template::render("messages", context! {title: "Messages", rows: rows})
 }
 
+/// Stored message rendered in the transcript.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct TranscriptRow {
+    /// Time of sending in UTC, e.g. `2024-06-10 14:03`.
+    time: String,
+    nickname: String,
+    /// Kind of a message which is not a text, e.g. `image`.
+    kind: Option<String>,
+    message: String,
+}
+
+impl TranscriptRow {
+    fn new((nickname, msg_type, message, sent_at): (String, String, String, i64)) -> Self {
+        let time = OffsetDateTime::from_unix_timestamp(sent_at.div_euclid(1000))
+            .map(|time| format!("{} {:02}:{:02}", time.date(), time.hour(), time.minute()))
+            .unwrap_or_default();
+        let kind = (msg_type != "Text").then(|| msg_type.to_lowercase());
+        TranscriptRow {
+            time,
+            nickname,
+            kind,
+            message,
+        }
+    }
+}
+
+/// Downloaded ZIP archive.
+#[derive(Responder)]
+#[response(content_type = "application/zip")]
+struct ZipDownload {
+    content: Vec<u8>,
+    disposition: Header<'static>,
+}
+
+/// Compresses the transcript into a ZIP archive built in memory.
+fn zip_transcript(html: &str) -> zip::result::ZipResult<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    zip.start_file(TRANSCRIPT_FILE, options)?;
+    zip.write_all(html.as_bytes())?;
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Returns the Unix time of the midnight starting the day in UTC, in milliseconds.
+fn day_start(date: Date) -> i64 {
+    date.midnight().assume_utc().unix_timestamp() * 1000
+}
+
+#[get("/")]
+async fn export_form() -> Template {
+    Template::render("export_form", context! {title: "Export Transcript"})
+}
+
+#[get("/transcript?<from>&<to>")]
+async fn export_transcript(
+    db: &State<ReadPool>,
+    metadata: Metadata<'_>,
+    from: Date,
+    to: Date,
+) -> Result<ZipDownload, Status> {
+    if to < from {
+        return Err(Status::BadRequest);
+    }
+    let end = to.next_day().ok_or(Status::BadRequest)?;
+    let rows: Vec<(String, String, String, i64)> = sqlx::query_as(
+        "SELECT nickname, msg_type, message, sent_at FROM messages JOIN message_bodies ON hash = body_hash WHERE sent_at >= ?1 AND sent_at < ?2 ORDER BY id;",
+    )
+    .bind(day_start(from))
+    .bind(day_start(end))
+    .fetch_all(&db.0)
+    .await
+    .map_err(|err_msg| {
+        error!("Exporting transcript failed: {}", err_msg);
+        Status::InternalServerError
+    })?;
+    let rows: Vec<TranscriptRow> = rows.into_iter().map(TranscriptRow::new).collect();
+    let context =
+        context! {from: from.to_string(), to: to.to_string(), count: rows.len(), rows: rows};
+    let (_, html) = metadata
+        .render("transcript", context)
+        .ok_or(Status::InternalServerError)?;
+    let content = zip_transcript(&html).map_err(|err_msg| {
+        error!("Compressing transcript failed: {}", err_msg);
+        Status::InternalServerError
+    })?;
+    let disposition = format!("attachment; filename=\"transcript-{from}-{to}.zip\"");
+    Ok(ZipDownload {
+        content,
+        disposition: Header::new("Content-Disposition", disposition),
+    })
+}
+
 #[get("/form")]
 async fn delete_form() -> Template {
     Template::render("delete_form", context! {title: "Delete Form"})
@@ -62,10 +309,248 @@ async fn delete_nickname(mut db: Connection<Server>, query_form: Form<Query>) ->
         Ok(result) => result.rows_affected(),
         Err(_) => 0,
     };
+    if let Err(err_msg) = bulk::delete_orphan_bodies(&mut db).await {
+        error!("Deleting message bodies failed: {}", err_msg);
+    }
 
     Template::render("delete", context! {title: "Delete", rows: rows})
 }
 
+#[get("/")]
+async fn bulk_form() -> Template {
+    Template::render("bulk_form", context! {title: "Bulk Moderation"})
+}
+
+fn report_page(report: Report) -> Template {
+    Template::render(
+        "bulk_report",
+        context! {title: "Bulk Moderation", report: report},
+    )
+}
+
+fn bulk_error(err_msg: anyhow::Error) -> Status {
+    error!("Bulk operation failed: {:?}", err_msg);
+    Status::InternalServerError
+}
+
+async fn run_delete(db: &mut SqliteConnection, request: &BulkDelete) -> Result<Report, Status> {
+    let from = bulk::parse_date(&request.from).ok_or(Status::BadRequest)?;
+    let to = bulk::parse_date(&request.to).ok_or(Status::BadRequest)?;
+    if to < from {
+        return Err(Status::BadRequest);
+    }
+    bulk::delete_range(db, &request.nickname, (from, to), request.dry_run)
+        .await
+        .map_err(bulk_error)
+}
+
+async fn run_anonymize(
+    db: &mut SqliteConnection,
+    request: &BulkAnonymize,
+) -> Result<Report, Status> {
+    bulk::anonymize(db, &request.nickname, request.dry_run)
+        .await
+        .map_err(bulk_error)
+}
+
+async fn run_purge(
+    db: &mut SqliteConnection,
+    config: &AdminConfig,
+    request: &BulkPurge,
+) -> Result<Report, Status> {
+    let min_size = request.min_mib.saturating_mul(1024 * 1024);
+    let dir = std::path::Path::new(&config.attachments_dir);
+    bulk::purge_attachments(db, dir, min_size, request.dry_run)
+        .await
+        .map_err(bulk_error)
+}
+
+#[post("/delete", data = "<request>")]
+async fn bulk_delete(
+    mut db: Connection<Server>,
+    request: Form<BulkDelete>,
+) -> Result<Template, Status> {
+    run_delete(&mut db, &request).await.map(report_page)
+}
+
+#[post("/anonymize", data = "<request>")]
+async fn bulk_anonymize(
+    mut db: Connection<Server>,
+    request: Form<BulkAnonymize>,
+) -> Result<Template, Status> {
+    run_anonymize(&mut db, &request).await.map(report_page)
+}
+
+#[post("/purge", data = "<request>")]
+async fn bulk_purge(
+    mut db: Connection<Server>,
+    config: &State<AdminConfig>,
+    request: Form<BulkPurge>,
+) -> Result<Template, Status> {
+    run_purge(&mut db, config, &request).await.map(report_page)
+}
+
+#[post("/delete", format = "json", data = "<request>")]
+async fn api_bulk_delete(
+    mut db: Connection<Server>,
+    request: Json<BulkDelete>,
+) -> Result<Json<Report>, Status> {
+    run_delete(&mut db, &request).await.map(Json)
+}
+
+#[post("/anonymize", format = "json", data = "<request>")]
+async fn api_bulk_anonymize(
+    mut db: Connection<Server>,
+    request: Json<BulkAnonymize>,
+) -> Result<Json<Report>, Status> {
+    run_anonymize(&mut db, &request).await.map(Json)
+}
+
+#[post("/purge", format = "json", data = "<request>")]
+async fn api_bulk_purge(
+    mut db: Connection<Server>,
+    config: &State<AdminConfig>,
+    request: Json<BulkPurge>,
+) -> Result<Json<Report>, Status> {
+    run_purge(&mut db, config, &request).await.map(Json)
+}
+
+#[get("/")]
+async fn console_form() -> Template {
+    Template::render("console", context! {title: "SQL Console"})
+}
+
+#[post("/", data = "<query>")]
+async fn console_query(
+    db: &State<ReadPool>,
+    config: &State<AdminConfig>,
+    query: Form<ConsoleQuery>,
+) -> Result<Template, Status> {
+    let mut connection = db.0.acquire().await.map_err(|err_msg| {
+        error!("Console connection failed: {}", err_msg);
+        Status::ServiceUnavailable
+    })?;
+    let timeout = Duration::from_millis(config.console_timeout);
+    let (output, error) =
+        match console::run(&mut connection, &query.sql, config.console_rows, timeout).await {
+            Ok(output) => (Some(output), None),
+            Err(err_msg) => (None, Some(format!("{:#}", err_msg))),
+        };
+    Ok(Template::render(
+        "console",
+        context! {title: "SQL Console", sql: &query.sql, output: output, error: error},
+    ))
+}
+
+/// Downloaded CSV file.
+#[derive(Responder)]
+#[response(content_type = "text/csv")]
+struct CsvDownload {
+    content: String,
+    disposition: Header<'static>,
+}
+
+async fn storage_report(
+    db: &ReadPool,
+    config: &AdminConfig,
+    warn_mib: Option<u64>,
+) -> Result<usage::Report, Status> {
+    let mut connection = db.0.acquire().await.map_err(|err_msg| {
+        error!("Storage usage connection failed: {}", err_msg);
+        Status::ServiceUnavailable
+    })?;
+    usage::report(&mut connection, warn_mib.or(config.storage_warn_mib))
+        .await
+        .map_err(|err_msg| {
+            error!("Storage usage failed: {:?}", err_msg);
+            Status::InternalServerError
+        })
+}
+
+#[get("/?<warn_mib>")]
+async fn storage(
+    db: &State<ReadPool>,
+    config: &State<AdminConfig>,
+    warn_mib: Option<u64>,
+) -> Result<Template, Status> {
+    let report = storage_report(db, config, warn_mib).await?;
+    Ok(Template::render(
+        "storage",
+        context! {title: "Storage Usage", report: report},
+    ))
+}
+
+#[get("/usage.csv?<warn_mib>")]
+async fn storage_csv(
+    db: &State<ReadPool>,
+    config: &State<AdminConfig>,
+    warn_mib: Option<u64>,
+) -> Result<CsvDownload, Status> {
+    let report = storage_report(db, config, warn_mib).await?;
+    let today = OffsetDateTime::now_utc().date();
+    let disposition = format!("attachment; filename=\"storage-{today}.csv\"");
+    Ok(CsvDownload {
+        content: usage::to_csv(&report),
+        disposition: Header::new("Content-Disposition", disposition),
+    })
+}
+
+#[get("/?<warn_mib>")]
+async fn api_storage(
+    db: &State<ReadPool>,
+    config: &State<AdminConfig>,
+    warn_mib: Option<u64>,
+) -> Result<Json<usage::Report>, Status> {
+    storage_report(db, config, warn_mib).await.map(Json)
+}
+
+async fn moderation_inbox(db: &mut SqliteConnection) -> Result<Inbox, Status> {
+    moderation::inbox(db).await.map_err(|err_msg| {
+        error!("Moderation inbox failed: {:?}", err_msg);
+        Status::InternalServerError
+    })
+}
+
+async fn close_report(
+    db: &mut SqliteConnection,
+    id: i64,
+    resolution: Resolution,
+) -> Result<Redirect, Status> {
+    let closed_at = chat::unix_millis() as i64;
+    match moderation::close(db, id, resolution, closed_at).await {
+        Ok(true) => Ok(Redirect::to(uri!("/reports"))),
+        Ok(false) => Err(Status::NotFound),
+        Err(err_msg) => {
+            error!("Closing report #{} failed: {:?}", id, err_msg);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[get("/")]
+async fn reports(mut db: Connection<Server>) -> Result<Template, Status> {
+    let inbox = moderation_inbox(&mut db).await?;
+    Ok(Template::render(
+        "reports",
+        context! {title: "Moderation Inbox", inbox: inbox},
+    ))
+}
+
+#[post("/<id>/resolve")]
+async fn resolve_report(mut db: Connection<Server>, id: i64) -> Result<Redirect, Status> {
+    close_report(&mut db, id, Resolution::Resolve).await
+}
+
+#[post("/<id>/dismiss")]
+async fn dismiss_report(mut db: Connection<Server>, id: i64) -> Result<Redirect, Status> {
+    close_report(&mut db, id, Resolution::Dismiss).await
+}
+
+#[get("/")]
+async fn api_reports(mut db: Connection<Server>) -> Result<Json<Inbox>, Status> {
+    moderation_inbox(&mut db).await.map(Json)
+}
+
 #[catch(404)]
 async fn not_found(request: &Request<'_>) -> Template {
     Template::render(
@@ -76,16 +561,143 @@ async fn not_found(request: &Request<'_>) -> Template {
     )
 }
 
+/// Opens the read-only pool, from `databases.server_db_read` or the `server_db` database if it is not configured.
+fn read_pool() -> AdHoc {
+    AdHoc::try_on_ignite("Read-only database", |rocket| async {
+        let figment = rocket.figment();
+        let key = if figment.contains("databases.server_db_read") {
+            "databases.server_db_read"
+        } else {
+            "databases.server_db"
+        };
+        let config = match figment.extract_inner::<ReadConfig>(key) {
+            Ok(config) => config,
+            Err(err_msg) => {
+                error!("Missing read-only database config: {}", err_msg);
+                return Err(rocket);
+            }
+        };
+        match open_read_pool(&config).await {
+            Ok(pool) => Ok(rocket.manage(ReadPool(pool))),
+            Err(err_msg) => {
+                error!(
+                    "Opening read-only database {} failed: {}",
+                    config.url, err_msg
+                );
+                Err(rocket)
+            }
+        }
+    })
+}
+
 #[launch]
 async fn rocket() -> _ {
     rocket::build()
         .attach(Server::init())
+        .attach(read_pool())
+        .attach(AdHoc::config::<AdminConfig>())
         .mount("/", routes![index])
         .mount(
             "/messages",
-            routes![messages, messages_form, messages_nickname],
+            routes![
+                messages,
+                messages_form,
+                messages_nickname,
+                search_form,
+                messages_search
+            ],
         )
         .mount("/delete", routes![delete_form, delete_nickname])
+        .mount("/export", routes![export_form, export_transcript])
+        .mount(
+            "/bulk",
+            routes![bulk_form, bulk_delete, bulk_anonymize, bulk_purge],
+        )
+        .mount("/console", routes![console_form, console_query])
+        .mount("/storage", routes![storage, storage_csv])
+        .mount("/reports", routes![reports, resolve_report, dismiss_report])
+        .mount(
+            "/api/bulk",
+            routes![api_bulk_delete, api_bulk_anonymize, api_bulk_purge],
+        )
+        .mount("/api/storage", routes![api_storage])
+        .mount("/api/reports", routes![api_reports])
         .register("/", catchers![not_found])
         .attach(Template::fairing())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transcript_row() {
+        let row = TranscriptRow::new((
+            "slava".to_string(),
+            "Image".to_string(),
+            "cat.png".to_string(),
+            1_718_028_207_000,
+        ));
+        assert_eq!(row.time, "2024-06-10 14:03");
+        assert_eq!(row.kind.as_deref(), Some("image"));
+        let row = TranscriptRow::new(("eva".into(), "Text".into(), "hi".into(), 0));
+        assert_eq!(row.time, "1970-01-01 00:00");
+        assert_eq!(row.kind, None);
+        let from = Date::from_calendar_date(2024, rocket::time::Month::June, 10).unwrap();
+        assert_eq!(day_start(from), 1_717_977_600_000);
+    }
+
+    #[test]
+    fn test_zip_transcript() {
+        use std::io::Read;
+
+        let html = "<p>Ahoj světe</p>".repeat(100);
+        let archive = zip_transcript(&html).unwrap();
+        assert!(archive.len() < html.len());
+        let mut archive = zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+        assert_eq!(archive.len(), 1);
+        let mut file = archive.by_name(TRANSCRIPT_FILE).unwrap();
+        assert_eq!(file.compression(), CompressionMethod::Deflated);
+        let mut content = String::new();
+        file.read_to_string(&mut content).unwrap();
+        assert_eq!(content, html);
+    }
+
+    #[rocket::async_test]
+    async fn test_read_pool_is_read_only() {
+        let path = std::env::temp_dir().join(format!("chat-admin-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let url = format!("sqlite://{}?mode=rwc", path.display());
+        let writer = sqlx::SqlitePool::connect(&url).await.unwrap();
+        sqlx::query("CREATE TABLE messages (id INTEGER PRIMARY KEY, nickname TEXT);")
+            .execute(&writer)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO messages (nickname) VALUES ('slava');")
+            .execute(&writer)
+            .await
+            .unwrap();
+
+        let config = ReadConfig {
+            url,
+            max_connections: 1,
+        };
+        let reader = open_read_pool(&config).await.unwrap();
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM messages;")
+            .fetch_one(&reader)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+        assert!(sqlx::query("DELETE FROM messages;")
+            .execute(&reader)
+            .await
+            .is_err());
+
+        let missing = ReadConfig {
+            url: path.with_extension("missing").display().to_string(),
+            max_connections: 1,
+        };
+        assert!(open_read_pool(&missing).await.is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,427 @@
+//! Out-of-band delivery of big files.
+//!
+//! A file bigger than the `threshold` of the `[attachments]` config section isn't broadcast over the chat connections.
+//! The server stores it in the `dir` directory and broadcasts only a [`MessageType::Attachment`] with the name, the
+//! size and a download URL. The URL carries its expiry time and a token signed by HMAC-SHA256, the clients download
+//! the file from the `/attachments` endpoint of the HTTP server and resume broken downloads with range requests.
+//! Stored files are removed after the `ttl`. The endpoint is a part of the `metrics` feature, the server built
+//! without it broadcasts big files like the small ones.
+
+use std::time::Duration;
+
+#[cfg(feature = "metrics")]
+use std::io::SeekFrom;
+#[cfg(feature = "metrics")]
+use std::path::PathBuf;
+#[cfg(feature = "metrics")]
+use std::sync::Arc;
+#[cfg(feature = "metrics")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "metrics")]
+use anyhow::{Context, Result};
+#[cfg(feature = "metrics")]
+use axum::body::Body;
+#[cfg(feature = "metrics")]
+use axum::extract::{Path, Query, State};
+#[cfg(feature = "metrics")]
+use axum::http::{header, HeaderMap, StatusCode};
+#[cfg(feature = "metrics")]
+use axum::response::Response;
+#[cfg(feature = "metrics")]
+use chat::MessageType;
+#[cfg(feature = "metrics")]
+use hmac::{Hmac, Mac};
+#[cfg(feature = "metrics")]
+use log::{error, info};
+#[cfg(feature = "metrics")]
+use rand::RngCore;
+#[cfg(feature = "metrics")]
+use serde::Deserialize;
+#[cfg(feature = "metrics")]
+use sha2::Sha256;
+#[cfg(feature = "metrics")]
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+#[cfg(feature = "metrics")]
+use tokio_util::io::ReaderStream;
+
+/// Default size above which the files are delivered over HTTP.
+pub const THRESHOLD: usize = 1024 * 1024;
+/// Default directory of the stored files.
+pub const ATTACHMENTS_DIR: &str = "attachments";
+/// Default time the stored files and their download URLs are valid.
+pub const ATTACHMENTS_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Minimal length of the configured signing secret.
+pub const MIN_SECRET_LEN: usize = 16;
+/// Length of the random file id in bytes.
+#[cfg(feature = "metrics")]
+const ID_LEN: usize = 16;
+
+#[cfg(feature = "metrics")]
+type HmacSha256 = Hmac<Sha256>;
+
+/// Settings from the `[attachments]` section of the server config.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttachmentsConfig {
+    /// Files bigger than the threshold are delivered over HTTP.
+    pub threshold: usize,
+    pub dir: String,
+    /// Time the stored files and their download URLs are valid.
+    pub ttl: Duration,
+    /// Public address of the HTTP server, e.g. `http://chat.example.com:3001`, the URLs are only paths without it.
+    pub url: Option<String>,
+    /// Key signing the download URLs, a random key generated at the start by default.
+    pub secret: Option<String>,
+}
+
+impl Default for AttachmentsConfig {
+    fn default() -> Self {
+        AttachmentsConfig {
+            threshold: THRESHOLD,
+            dir: ATTACHMENTS_DIR.to_string(),
+            ttl: ATTACHMENTS_TTL,
+            url: None,
+            secret: None,
+        }
+    }
+}
+
+/// Checks the public address of the HTTP server from the config.
+///
+/// # Errors
+///
+/// This function will return an error if the address is not an `http://` URL, the client doesn't support TLS.
+pub fn check_url(url: &str) -> Result<(), String> {
+    match url.strip_prefix("http://") {
+        Some(host) if !host.is_empty() => Ok(()),
+        _ => Err(format!(
+            "expected an address like \"http://chat.example.com:3001\", found \"{url}\""
+        )),
+    }
+}
+
+/// Store of the big files served by the `/attachments` endpoint.
+#[cfg(feature = "metrics")]
+#[derive(Clone)]
+pub struct Attachments {
+    config: Arc<AttachmentsConfig>,
+    key: Arc<[u8]>,
+}
+
+#[cfg(feature = "metrics")]
+impl Attachments {
+    /// Creates the store, signing the URLs with the configured secret or a random key.
+    pub fn new(config: AttachmentsConfig) -> Attachments {
+        let key = match &config.secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => {
+                let mut key = vec![0; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                key
+            }
+        };
+        Attachments {
+            config: Arc::new(config),
+            key: key.into(),
+        }
+    }
+
+    /// Returns true if the message is a file delivered over HTTP.
+    pub fn offloads(&self, message: &MessageType) -> bool {
+        matches!(message, MessageType::File { content, .. } if content.len() > self.config.threshold)
+    }
+
+    /// Stores the file and returns the message announcing it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file can't be written.
+    pub async fn store(&self, name: &str, content: &[u8]) -> Result<MessageType> {
+        let mut id = [0; ID_LEN];
+        rand::thread_rng().fill_bytes(&mut id);
+        let id = hex::encode(id);
+        tokio::fs::create_dir_all(&self.config.dir)
+            .await
+            .with_context(|| format!("Creating dir {} failed!", self.config.dir))?;
+        tokio::fs::write(self.path(&id), content)
+            .await
+            .with_context(|| format!("Storing attachment {name} failed!"))?;
+        let expires = unix_time() + self.config.ttl.as_secs();
+        let path = format!(
+            "/attachments/{id}?expires={expires}&token={}",
+            self.sign(&id, expires)
+        );
+        let url = match &self.config.url {
+            Some(url) => format!("{}{path}", url.trim_end_matches('/')),
+            None => path,
+        };
+        info!(
+            "Stored attachment {} ({} bytes) as {}.",
+            name,
+            content.len(),
+            id
+        );
+        Ok(MessageType::Attachment {
+            name: name.to_string(),
+            size: content.len() as u64,
+            url,
+        })
+    }
+
+    /// Spawns the background task removing the files older than the `ttl`.
+    pub fn spawn_cleanup(&self) {
+        let attachments = self.clone();
+        tokio::spawn(async move {
+            let period = attachments.config.ttl.min(Duration::from_secs(60 * 60));
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                match attachments.remove_expired().await {
+                    Ok(0) => (),
+                    Ok(removed) => info!("Removed {} expired attachments.", removed),
+                    Err(err_msg) => error!("Attachments cleanup error: {:?}", err_msg),
+                }
+            }
+        });
+    }
+
+    async fn remove_expired(&self) -> Result<usize> {
+        let mut entries = match tokio::fs::read_dir(&self.config.dir).await {
+            Ok(entries) => entries,
+            Err(err_msg) if err_msg.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err_msg) => return Err(err_msg.into()),
+        };
+        let mut removed = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            let age = entry
+                .metadata()
+                .await?
+                .modified()?
+                .elapsed()
+                .unwrap_or_default();
+            if age > self.config.ttl {
+                tokio::fs::remove_file(entry.path()).await?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        PathBuf::from(&self.config.dir).join(id)
+    }
+
+    fn mac(&self, id: &str, expires: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(format!("{id}:{expires}").as_bytes());
+        mac
+    }
+
+    fn sign(&self, id: &str, expires: u64) -> String {
+        hex::encode(self.mac(id, expires).finalize().into_bytes())
+    }
+
+    fn verify(&self, id: &str, expires: u64, token: &str) -> bool {
+        match hex::decode(token) {
+            Ok(token) => self.mac(id, expires).verify_slice(&token).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Query of the download URL.
+#[cfg(feature = "metrics")]
+#[derive(Deserialize, Debug)]
+pub struct Link {
+    expires: u64,
+    token: String,
+}
+
+/// Part of the file requested by the `Range` header.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Range {
+    Full,
+    /// First and last byte, inclusive.
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses the `Range` header of a file of `len` bytes.
+///
+/// Only a single `bytes` range is supported, other and malformed ranges are ignored and the whole file is sent.
+#[cfg(feature = "metrics")]
+fn parse_range(header: Option<&str>, len: u64) -> Range {
+    let Some(spec) = header.and_then(|header| header.trim().strip_prefix("bytes=")) else {
+        return Range::Full;
+    };
+    let Some((start, end)) = spec.split_once('-').filter(|_| !spec.contains(',')) else {
+        return Range::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        return match end.parse::<u64>() {
+            Ok(0) => Range::Unsatisfiable,
+            Ok(_) if len == 0 => Range::Unsatisfiable,
+            Ok(suffix) => Range::Part(len - suffix.min(len), len - 1),
+            Err(_) => Range::Full,
+        };
+    }
+    let Ok(start) = start.parse::<u64>() else {
+        return Range::Full;
+    };
+    if start >= len {
+        return Range::Unsatisfiable;
+    }
+    match end {
+        "" => Range::Part(start, len - 1),
+        end => match end.parse::<u64>() {
+            Ok(end) if end >= start => Range::Part(start, end.min(len - 1)),
+            _ => Range::Full,
+        },
+    }
+}
+
+/// Serves the stored file if the download URL is valid, the whole file or the requested range.
+#[cfg(feature = "metrics")]
+pub async fn download(
+    State(attachments): State<Attachments>,
+    Path(id): Path<String>,
+    Query(link): Query<Link>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let valid_id = id.len() == ID_LEN * 2 && id.bytes().all(|byte| byte.is_ascii_hexdigit());
+    if !valid_id || !attachments.verify(&id, link.expires, &link.token) {
+        return Err((StatusCode::FORBIDDEN, "Invalid download link!".to_string()));
+    }
+    if link.expires < unix_time() {
+        return Err((StatusCode::GONE, "The download link expired!".to_string()));
+    }
+    let not_found = |_| (StatusCode::NOT_FOUND, "The file was removed!".to_string());
+    let mut file = tokio::fs::File::open(attachments.path(&id))
+        .await
+        .map_err(not_found)?;
+    let len = file.metadata().await.map_err(not_found)?.len();
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok());
+    let response = Response::builder()
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::CONTENT_TYPE, "application/octet-stream");
+    let (response, start, size) = match parse_range(range, len) {
+        Range::Full => (response.status(StatusCode::OK), 0, len),
+        Range::Part(start, end) => (
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}")),
+            start,
+            end - start + 1,
+        ),
+        Range::Unsatisfiable => {
+            return Ok(response
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{len}"))
+                .body(Body::empty())
+                .expect("valid response"))
+        }
+    };
+    file.seek(SeekFrom::Start(start))
+        .await
+        .map_err(|err_msg| (StatusCode::INTERNAL_SERVER_ERROR, err_msg.to_string()))?;
+    let body = Body::from_stream(ReaderStream::new(file.take(size)));
+    Ok(response
+        .header(header::CONTENT_LENGTH, size)
+        .body(body)
+        .expect("valid response"))
+}
+
+#[cfg(feature = "metrics")]
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_url() {
+        assert!(check_url("http://chat.example.com").is_ok());
+        assert!(check_url("https://chat.example.com").is_err());
+        assert!(check_url("http://").is_err());
+        assert!(check_url("chat.example.com:3001").is_err());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range(None, 100), Range::Full);
+        assert_eq!(parse_range(Some("bytes=0-9"), 100), Range::Part(0, 9));
+        assert_eq!(parse_range(Some("bytes=90-"), 100), Range::Part(90, 99));
+        assert_eq!(parse_range(Some("bytes=90-500"), 100), Range::Part(90, 99));
+        assert_eq!(parse_range(Some("bytes=-10"), 100), Range::Part(90, 99));
+        assert_eq!(parse_range(Some("bytes=-500"), 100), Range::Part(0, 99));
+        assert_eq!(parse_range(Some("bytes=100-"), 100), Range::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=-0"), 100), Range::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=0-1,5-6"), 100), Range::Full);
+        assert_eq!(parse_range(Some("items=0-1"), 100), Range::Full);
+        assert_eq!(parse_range(Some("bytes=9-0"), 100), Range::Full);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_download() {
+        use axum::routing::get;
+        use axum::Router;
+
+        let dir = std::env::temp_dir().join(format!("attachments-test-{}", std::process::id()));
+        let config = AttachmentsConfig {
+            threshold: 4,
+            dir: dir.to_string_lossy().into_owned(),
+            ..AttachmentsConfig::default()
+        };
+        let attachments = Attachments::new(config);
+        assert!(!attachments.offloads(&MessageType::file("a.txt", b"tiny")));
+        let content: Vec<u8> = (0..=255).collect();
+        assert!(attachments.offloads(&MessageType::file("a.bin", &content)));
+        let Ok(MessageType::Attachment { name, size, url }) =
+            attachments.store("a.bin", &content).await
+        else {
+            panic!("expected an attachment");
+        };
+        assert_eq!((name.as_str(), size), ("a.bin", 256));
+
+        let app = Router::new()
+            .route("/attachments/:id", get(download))
+            .with_state(attachments);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let client = reqwest::Client::new();
+
+        let response = client.get(format!("{base}{url}")).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.bytes().await.unwrap().to_vec(), content);
+        let response = client
+            .get(format!("{base}{url}"))
+            .header("Range", "bytes=250-")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.headers()["content-range"], "bytes 250-255/256");
+        assert_eq!(response.bytes().await.unwrap().to_vec(), &content[250..]);
+
+        let tampered = url.replace("expires=", "expires=1");
+        let response = client
+            .get(format!("{base}{tampered}"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 403);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
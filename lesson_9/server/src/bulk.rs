@@ -0,0 +1,405 @@
+//! Bulk moderation operations of the admin panel.
+//!
+//! Every operation runs in a transaction together with its record in the audit table and returns a [`Report`] of what
+//! it changed. A dry run executes the same statements and rolls the transaction back, so the preview counts exactly
+//! what the real run would change. Removing the stored attachments can't be rolled back, the files are removed one by
+//! one and the audit records the ones actually removed. The bodies of the deleted messages which no other message
+//! shares are deleted with them, so the text of a deleted message doesn't stay in the database.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use rocket::serde::Serialize;
+use rocket::time::{Date, Month};
+use rocket_db_pools::sqlx::{self, Connection, SqliteConnection};
+use sha2::{Digest, Sha256};
+
+/// Prefix of the anonymized nicknames.
+pub const ANONYMOUS_PREFIX: &str = "anon-";
+/// Number of hex digits of the nickname hash kept in the anonymized nickname.
+const HASH_LEN: usize = 12;
+/// Audit nickname of the operations not targeting a nickname.
+const ALL: &str = "*";
+
+/// Result of a bulk operation.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Report {
+    /// Action recorded in the audit table, e.g. `bulk-delete`.
+    pub action: &'static str,
+    /// Affected nickname, `*` for all of them.
+    pub target: String,
+    /// Nothing was changed, the report is a preview.
+    pub dry_run: bool,
+    /// Number of the changed messages or the removed files.
+    pub count: u64,
+    /// Description of the change, recorded as the reason in the audit table.
+    pub summary: String,
+}
+
+/// Parses a date like `2024-06-10`.
+pub fn parse_date(text: &str) -> Option<Date> {
+    let mut parts = text.trim().splitn(3, '-');
+    let year = parts.next()?.parse().ok()?;
+    let month: u8 = parts.next()?.parse().ok()?;
+    let day = parts.next()?.parse().ok()?;
+    Date::from_calendar_date(year, Month::try_from(month).ok()?, day).ok()
+}
+
+/// Returns the nickname replacing the given one, e.g. `anon-3f2a9c0d1e4b`.
+///
+/// The same nickname is always replaced by the same hash, so the anonymized history still shows who wrote what.
+pub fn anonymized(nickname: &str) -> String {
+    let hash = hex::encode(Sha256::digest(nickname.as_bytes()));
+    format!("{ANONYMOUS_PREFIX}{}", &hash[..HASH_LEN])
+}
+
+/// Deletes the messages of the nickname sent in the days from `from` to `to` (UTC), both included.
+///
+/// Messages stored before the server recorded the time of sending are never deleted.
+///
+/// # Errors
+///
+/// This function will return an error if the database fails, nothing is deleted then.
+pub async fn delete_range(
+    db: &mut SqliteConnection,
+    nickname: &str,
+    (from, to): (Date, Date),
+    dry_run: bool,
+) -> Result<Report> {
+    let end = to.next_day().context("Date out of range!")?;
+    let mut transaction = db.begin().await?;
+    let count =
+        sqlx::query("DELETE FROM messages WHERE nickname = ?1 AND sent_at >= ?2 AND sent_at < ?3;")
+            .bind(nickname)
+            .bind(crate::day_start(from))
+            .bind(crate::day_start(end))
+            .execute(&mut *transaction)
+            .await?
+            .rows_affected();
+    delete_orphan_bodies(&mut transaction).await?;
+    let report = Report {
+        action: "bulk-delete",
+        target: nickname.to_string(),
+        dry_run,
+        count,
+        summary: format!("deleted {count} messages sent from {from} to {to}"),
+    };
+    finish(transaction, &report).await?;
+    Ok(report)
+}
+
+/// Replaces the nickname by its [`anonymized`] form in the messages, the polls, the votes, the reports and the audit
+/// records.
+///
+/// The audit record of the anonymization carries the anonymized nickname only.
+///
+/// # Errors
+///
+/// This function will return an error if the database fails, nothing is changed then.
+pub async fn anonymize(db: &mut SqliteConnection, nickname: &str, dry_run: bool) -> Result<Report> {
+    let anonymous = anonymized(nickname);
+    let mut transaction = db.begin().await?;
+    let mut count = 0;
+    for (table, column) in [
+        ("messages", "nickname"),
+        ("polls", "nickname"),
+        ("poll_votes", "nickname"),
+        ("audit", "nickname"),
+        ("reports", "reporter"),
+        ("reports", "author"),
+    ] {
+        let changed = sqlx::query(&format!(
+            "UPDATE {table} SET {column} = ?2 WHERE {column} = ?1;"
+        ))
+        .bind(nickname)
+        .bind(&anonymous)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+        if table == "messages" {
+            count = changed;
+        }
+    }
+    let report = Report {
+        action: "anonymize",
+        target: anonymous.clone(),
+        dry_run,
+        count,
+        summary: format!("anonymized {count} messages as {anonymous}"),
+    };
+    finish(transaction, &report).await?;
+    Ok(report)
+}
+
+/// Removes the files stored in the attachments directory bigger than `min_size` bytes.
+///
+/// The broadcast download links of the removed files stop working, the messages announcing them stay in the history.
+///
+/// # Errors
+///
+/// This function will return an error if the directory can't be listed or the database fails. A file which can't be
+/// removed is logged and skipped.
+pub async fn purge_attachments(
+    db: &mut SqliteConnection,
+    dir: &Path,
+    min_size: u64,
+    dry_run: bool,
+) -> Result<Report> {
+    let transaction = db.begin().await?;
+    let (mut count, mut total) = (0, 0);
+    for (path, size) in files_over(dir, min_size).await? {
+        if !dry_run {
+            if let Err(err_msg) = tokio::fs::remove_file(&path).await {
+                error!("Removing {} failed: {}", path.display(), err_msg);
+                continue;
+            }
+        }
+        count += 1;
+        total += size;
+    }
+    let report = Report {
+        action: "purge-attachments",
+        target: ALL.to_string(),
+        dry_run,
+        count,
+        summary: format!(
+            "removed {count} attachments over {min_size} bytes, {total} bytes in total"
+        ),
+    };
+    finish(transaction, &report).await?;
+    Ok(report)
+}
+
+/// Lists the files in the directory bigger than `min_size` bytes with their sizes, none if the directory is missing.
+async fn files_over(dir: &Path, min_size: u64) -> Result<Vec<(PathBuf, u64)>> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(err_msg) if err_msg.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err_msg) => {
+            return Err(err_msg).with_context(|| format!("Listing {} failed!", dir.display()))
+        }
+    };
+    let mut files = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if metadata.is_file() && metadata.len() > min_size {
+            files.push((entry.path(), metadata.len()));
+        }
+    }
+    Ok(files)
+}
+
+/// Deletes the message bodies no message refers to.
+///
+/// # Returns
+///
+/// The number of the deleted bodies.
+///
+/// # Errors
+///
+/// This function will return an error if the database fails.
+pub async fn delete_orphan_bodies(db: &mut SqliteConnection) -> Result<u64> {
+    let deleted = sqlx::query(
+        "DELETE FROM message_bodies WHERE hash NOT IN (SELECT body_hash FROM messages);",
+    )
+    .execute(db)
+    .await?
+    .rows_affected();
+    Ok(deleted)
+}
+
+/// Records the operation in the audit table and commits it, or rolls everything back for a dry run.
+async fn finish(
+    mut transaction: sqlx::Transaction<'_, sqlx::Sqlite>,
+    report: &Report,
+) -> Result<()> {
+    if report.dry_run {
+        transaction.rollback().await?;
+        return Ok(());
+    }
+    sqlx::query("INSERT INTO audit ( nickname, action, reason ) VALUES ( ?1, ?2, ?3 );")
+        .bind(&report.target)
+        .bind(report.action)
+        .bind(&report.summary)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 24 * 60 * 60 * 1000;
+
+    async fn database() -> SqliteConnection {
+        let mut db = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        for table in [
+            "CREATE TABLE messages (id INTEGER PRIMARY KEY, nickname TEXT, body_hash BLOB, sent_at INTEGER);",
+            "CREATE TABLE message_bodies (hash BLOB PRIMARY KEY, message TEXT);",
+            "INSERT INTO message_bodies VALUES (x'01', 'hi'), (x'02', 'bye');",
+            "CREATE TABLE polls (id INTEGER PRIMARY KEY, nickname TEXT);",
+            "CREATE TABLE poll_votes (poll INTEGER, nickname TEXT);",
+            "CREATE TABLE audit (id INTEGER PRIMARY KEY, nickname TEXT, action TEXT, reason TEXT);",
+            "CREATE TABLE reports (id INTEGER PRIMARY KEY, reporter TEXT, author TEXT);",
+            "INSERT INTO reports (reporter, author) VALUES ('slava', 'eva'), ('eva', 'slava');",
+        ] {
+            sqlx::query(table).execute(&mut db).await.unwrap();
+        }
+        let june_10 = crate::day_start(parse_date("2024-06-10").unwrap());
+        for (nickname, body, sent_at) in [
+            ("slava", 1, Some(june_10 - DAY)),
+            ("slava", 2, Some(june_10 + 1)),
+            ("slava", 2, Some(june_10 + DAY - 1)),
+            ("slava", 1, None),
+            ("eva", 1, Some(june_10)),
+        ] {
+            sqlx::query("INSERT INTO messages (nickname, body_hash, sent_at) VALUES (?1, ?2, ?3);")
+                .bind(nickname)
+                .bind(vec![body])
+                .bind(sent_at)
+                .execute(&mut db)
+                .await
+                .unwrap();
+        }
+        sqlx::query("INSERT INTO poll_votes VALUES (1, 'slava');")
+            .execute(&mut db)
+            .await
+            .unwrap();
+        db
+    }
+
+    async fn count(db: &mut SqliteConnection, query: &str) -> i64 {
+        sqlx::query_scalar(query).fetch_one(db).await.unwrap()
+    }
+
+    #[test]
+    fn test_parse_date() {
+        let date = parse_date("2024-06-10").unwrap();
+        assert_eq!(
+            (date.year(), date.month(), date.day()),
+            (2024, Month::June, 10)
+        );
+        assert_eq!(parse_date("2024-13-01"), None);
+        assert_eq!(parse_date("10. 6. 2024"), None);
+        assert_eq!(anonymized("slava"), anonymized("slava"));
+        assert_ne!(anonymized("slava"), anonymized("eva"));
+        assert_eq!(anonymized("slava").len(), ANONYMOUS_PREFIX.len() + HASH_LEN);
+    }
+
+    #[rocket::async_test]
+    async fn test_delete_range() {
+        let mut db = database().await;
+        let june_10 = parse_date("2024-06-10").unwrap();
+        let preview = delete_range(&mut db, "slava", (june_10, june_10), true)
+            .await
+            .unwrap();
+        assert_eq!(preview.count, 2);
+        assert_eq!(count(&mut db, "SELECT COUNT(*) FROM messages;").await, 5);
+        assert_eq!(
+            count(&mut db, "SELECT COUNT(*) FROM message_bodies;").await,
+            2
+        );
+        assert_eq!(count(&mut db, "SELECT COUNT(*) FROM audit;").await, 0);
+
+        let report = delete_range(&mut db, "slava", (june_10, june_10), false)
+            .await
+            .unwrap();
+        assert_eq!(report.count, 2);
+        assert_eq!(count(&mut db, "SELECT COUNT(*) FROM messages;").await, 3);
+        assert_eq!(
+            count(&mut db, "SELECT COUNT(*) FROM message_bodies;").await,
+            1
+        );
+        let reason: String =
+            sqlx::query_scalar("SELECT reason FROM audit WHERE action = 'bulk-delete';")
+                .fetch_one(&mut db)
+                .await
+                .unwrap();
+        assert_eq!(
+            reason,
+            "deleted 2 messages sent from 2024-06-10 to 2024-06-10"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_anonymize() {
+        let mut db = database().await;
+        assert_eq!(anonymize(&mut db, "slava", true).await.unwrap().count, 4);
+        assert_eq!(
+            count(
+                &mut db,
+                "SELECT COUNT(*) FROM messages WHERE nickname = 'slava';"
+            )
+            .await,
+            4
+        );
+        let report = anonymize(&mut db, "slava", false).await.unwrap();
+        assert_eq!(report.target, anonymized("slava"));
+        assert_eq!(
+            count(
+                &mut db,
+                "SELECT COUNT(*) FROM messages WHERE nickname = 'slava';"
+            )
+            .await,
+            0
+        );
+        assert_eq!(
+            count(
+                &mut db,
+                "SELECT COUNT(*) FROM poll_votes WHERE nickname = 'slava';"
+            )
+            .await,
+            0
+        );
+        assert_eq!(
+            count(
+                &mut db,
+                "SELECT COUNT(*) FROM reports WHERE 'slava' IN (reporter, author);"
+            )
+            .await,
+            0
+        );
+        assert_eq!(
+            count(
+                &mut db,
+                "SELECT COUNT(*) FROM audit WHERE reason LIKE '%slava%';"
+            )
+            .await,
+            0
+        );
+    }
+
+    #[rocket::async_test]
+    async fn test_purge_attachments() {
+        let dir = std::env::temp_dir().join(format!("chat-purge-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("small"), [0; 10]).unwrap();
+        std::fs::write(dir.join("big"), [0; 100]).unwrap();
+        let mut db = database().await;
+
+        let preview = purge_attachments(&mut db, &dir, 50, true).await.unwrap();
+        assert_eq!(preview.count, 1);
+        assert!(dir.join("big").exists());
+        let report = purge_attachments(&mut db, &dir, 50, false).await.unwrap();
+        assert_eq!(
+            report.summary,
+            "removed 1 attachments over 50 bytes, 100 bytes in total"
+        );
+        assert!(!dir.join("big").exists() && dir.join("small").exists());
+        assert_eq!(count(&mut db, "SELECT COUNT(*) FROM audit;").await, 1);
+
+        let missing = dir.join("missing");
+        assert_eq!(
+            purge_attachments(&mut db, &missing, 0, true)
+                .await
+                .unwrap()
+                .count,
+            0
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+//! Server time and the clock skew of the clients.
+//!
+//! The server announces its time in the Welcome message and in a Ping to everybody every [`PING_INTERVAL`], so the
+//! clients know their clock skew and render the timestamps in their local time. The pings keep the connections alive
+//! too, see [`chat::keepalive`]. The timestamps set by the clients are
+//! trusted within [`MAX_CLOCK_SKEW`] (`limits.max_clock_skew` in the config) of the server time, others are replaced
+//! by the server time and flagged by the [`TIME_ADJUSTED_ANNOTATION`], so a client with a wrong clock can't reorder
+//! the history.
+
+use std::time::Duration;
+
+use chat::{keepalive, Message, TIME_ADJUSTED_ANNOTATION};
+use log::debug;
+
+use crate::fanout::FanOut;
+
+/// Default maximal difference of the client timestamps from the server time.
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+/// Interval of announcing the server time.
+pub use chat::keepalive::PING_INTERVAL;
+
+/// Checks the timestamp of the client message against the server time.
+///
+/// A missing timestamp (an older client) is set to the server time, a timestamp further than `max_skew` from it is
+/// replaced by the server time and flagged.
+///
+/// # Arguments
+///
+/// - `message` - The message received from the client.
+/// - `now` - The server time in Unix milliseconds.
+/// - `max_skew` - The tolerated clock skew of the client.
+///
+/// # Returns
+///
+/// The clock skew of the client in milliseconds if its timestamp was replaced.
+pub fn normalize(message: &mut Message, now: u64, max_skew: Duration) -> Option<i64> {
+    let timestamp = message.timestamp.replace(now)?;
+    let skew = timestamp as i64 - now as i64;
+    if skew.unsigned_abs() as u128 <= max_skew.as_millis() {
+        message.timestamp = Some(timestamp);
+        return None;
+    }
+    message.annotate(TIME_ADJUSTED_ANNOTATION, format!("{:+}s", skew / 1000));
+    Some(skew)
+}
+
+/// Spawns the task announcing the server time every [`PING_INTERVAL`].
+pub fn spawn_pings(fan_out: FanOut) {
+    tokio::spawn(keepalive::ping_loop(PING_INTERVAL, move |ping| {
+        debug!(
+            "Announcing server time {}.",
+            ping.message.get_type_and_message().1
+        );
+        fan_out.announce(ping);
+        true
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat::MessageType;
+
+    const NOW: u64 = 1_718_000_000_000;
+
+    fn stamped(timestamp: Option<u64>) -> Message {
+        let mut message = Message::from("slava", MessageType::text("hi"));
+        message.timestamp = timestamp;
+        message
+    }
+
+    #[test]
+    fn test_normalize() {
+        let mut message = stamped(Some(NOW - 5_000));
+        assert_eq!(normalize(&mut message, NOW, MAX_CLOCK_SKEW), None);
+        assert_eq!(message.timestamp, Some(NOW - 5_000));
+        assert!(message.annotations.is_empty());
+
+        let mut message = stamped(None);
+        assert_eq!(normalize(&mut message, NOW, MAX_CLOCK_SKEW), None);
+        assert_eq!(message.timestamp, Some(NOW));
+        assert!(message.annotations.is_empty());
+
+        let mut message = stamped(Some(NOW + 93_500));
+        assert_eq!(normalize(&mut message, NOW, MAX_CLOCK_SKEW), Some(93_500));
+        assert_eq!(message.timestamp, Some(NOW));
+        let flag = (TIME_ADJUSTED_ANNOTATION.to_string(), "+93s".to_string());
+        assert_eq!(message.annotations, [flag]);
+
+        let mut message = stamped(Some(NOW - 3_600_000));
+        assert_eq!(
+            normalize(&mut message, NOW, MAX_CLOCK_SKEW),
+            Some(-3_600_000)
+        );
+        assert_eq!(message.annotations[0].1, "-3600s");
+    }
+}
@@ -0,0 +1,111 @@
+//! Stable colors of the nicknames.
+//!
+//! The server assigns every nickname an index into the palette of [`NICKNAME_COLORS`] colors and attaches it to the
+//! broadcast messages as the [`COLOR_ANNOTATION`], so all the clients render a nickname in the same color. The index
+//! is derived from a hash of the nickname and stored in the `nickname_colors` table when the nickname is first seen,
+//! so it stays the same across restarts even if the hashing changes.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::Result;
+use chat::{Message, COLOR_ANNOTATION, NICKNAME_COLORS};
+use log::error;
+use parking_lot::Mutex;
+
+use crate::db::Database;
+
+/// Colors of the nicknames shared by the client connections.
+#[derive(Clone)]
+pub struct Colors {
+    database: Database,
+    assigned: Arc<Mutex<HashMap<String, u8>>>,
+}
+
+impl Colors {
+    /// Loads the assigned colors from the database.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the colors can't be fetched.
+    pub async fn load(database: Database) -> Result<Colors> {
+        let assigned = database.fetch_colors().await?.into_iter().collect();
+        Ok(Colors {
+            database,
+            assigned: Arc::new(Mutex::new(assigned)),
+        })
+    }
+
+    /// Returns the color of the nickname, assigning and storing a new one in the background.
+    pub fn color(&self, nickname: &str) -> u8 {
+        let mut assigned = self.assigned.lock();
+        if let Some(color) = assigned.get(nickname) {
+            return *color;
+        }
+        let color = hash_color(nickname);
+        assigned.insert(nickname.to_string(), color);
+        drop(assigned);
+        let database = self.database.clone();
+        let nickname = nickname.to_string();
+        tokio::spawn(async move {
+            if let Err(err_msg) = database.insert_color(&nickname, color).await {
+                error!("Storing color of {} error: {:?}", nickname, err_msg);
+            }
+        });
+        color
+    }
+
+    /// Attaches the color of the sender to the message.
+    pub fn annotate(&self, message: &mut Message) {
+        let color = self.color(&message.nickname);
+        message.annotate(COLOR_ANNOTATION, color.to_string());
+    }
+}
+
+/// Returns the color index from the FNV-1a hash of the nickname.
+fn hash_color(nickname: &str) -> u8 {
+    let hash = nickname.bytes().fold(0x811c_9dc5_u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    });
+    (hash % u32::from(NICKNAME_COLORS)) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat::MessageType;
+
+    #[test]
+    fn test_hash_color() {
+        assert_eq!(hash_color("slava"), hash_color("slava"));
+        assert!(["slava", "eva", "", "Žofie"]
+            .iter()
+            .all(|nickname| hash_color(nickname) < NICKNAME_COLORS));
+        let colors: std::collections::HashSet<u8> =
+            (0..100).map(|i| hash_color(&format!("user{i}"))).collect();
+        assert!(colors.len() > NICKNAME_COLORS as usize / 2);
+    }
+
+    #[tokio::test]
+    async fn test_colors_are_persisted() {
+        let path = std::env::temp_dir().join(format!("chat-colors-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let url = format!("sqlite://{}", path.display());
+        let database = Database::open(&url, Default::default()).await.unwrap();
+        database.insert_color("eva", 11).await.unwrap();
+
+        let colors = Colors::load(database.clone()).await.unwrap();
+        assert_eq!(colors.color("eva"), 11);
+        let mut message = Message::from("slava", MessageType::text("hi"));
+        colors.annotate(&mut message);
+        let color = hash_color("slava");
+        assert_eq!(
+            message.annotations,
+            [(COLOR_ANNOTATION.to_string(), color.to_string())]
+        );
+        database.insert_color("slava", color).await.unwrap();
+        let reloaded = Colors::load(database).await.unwrap();
+        assert_eq!(reloaded.color("slava"), color);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,270 @@
+//! Read-only SQL console of the admin panel.
+//!
+//! The console runs a single SELECT statement on the read-only pool, so even a statement slipping through the check
+//! can't change the database. The query is interrupted after the timeout by an SQLite progress handler, only the
+//! first rows up to the limit are fetched and the plan from `EXPLAIN QUERY PLAN` is shown next to the rows.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use rocket::futures::TryStreamExt;
+use rocket::serde::Serialize;
+use rocket_db_pools::sqlx::sqlite::SqliteRow;
+use rocket_db_pools::sqlx::{self, Column, Row, SqliteConnection, TypeInfo, ValueRef};
+
+/// Number of SQLite instructions between the checks of the timeout.
+const PROGRESS_STEPS: i32 = 1000;
+
+/// Result of a console query.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Output {
+    pub columns: Vec<String>,
+    /// Values rendered as text, `NULL` for the missing ones.
+    pub rows: Vec<Vec<String>>,
+    /// The query returned more rows than the limit.
+    pub truncated: bool,
+    /// Steps of the query plan, indented by their depth.
+    pub plan: Vec<String>,
+    pub elapsed_ms: u128,
+}
+
+/// Checks the query is a single SELECT statement and returns it without the trailing semicolon.
+///
+/// # Errors
+///
+/// This function will return an error for an empty query, another statement or more statements.
+pub fn check(sql: &str) -> Result<&str> {
+    let sql = sql.trim();
+    let sql = sql.strip_suffix(';').unwrap_or(sql).trim_end();
+    let keyword: String = sql.chars().take_while(char::is_ascii_alphabetic).collect();
+    if !keyword.eq_ignore_ascii_case("select") {
+        return Err(anyhow!("Only SELECT statements are allowed"));
+    }
+    if has_separator(sql) {
+        return Err(anyhow!("Only a single statement is allowed"));
+    }
+    Ok(sql)
+}
+
+/// Finds a semicolon outside the string literals, the quoted names and the comments.
+fn has_separator(sql: &str) -> bool {
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ';' => return true,
+            '\'' | '"' | '`' => {
+                // A doubled quote is an escaped one, it just closes and reopens the literal.
+                for inner in chars.by_ref() {
+                    if inner == c {
+                        break;
+                    }
+                }
+            }
+            '[' => {
+                for inner in chars.by_ref() {
+                    if inner == ']' {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                for inner in chars.by_ref() {
+                    if inner == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut star = false;
+                for inner in chars.by_ref() {
+                    if star && inner == '/' {
+                        break;
+                    }
+                    star = inner == '*';
+                }
+            }
+            _ => (),
+        }
+    }
+    false
+}
+
+/// Runs the checked query with its plan.
+///
+/// # Arguments
+///
+/// - `db` - Connection of the read-only pool.
+/// - `sql` - The query, see [`check`].
+/// - `max_rows` - Maximal number of the returned rows.
+/// - `timeout` - Time after which the query is interrupted.
+///
+/// # Errors
+///
+/// This function will return an error if the query isn't a single SELECT, it fails or it runs out of time.
+pub async fn run(
+    db: &mut SqliteConnection,
+    sql: &str,
+    max_rows: usize,
+    timeout: Duration,
+) -> Result<Output> {
+    let sql = check(sql)?;
+    let started = Instant::now();
+    let deadline = started + timeout;
+    db.lock_handle()
+        .await?
+        .set_progress_handler(PROGRESS_STEPS, move || Instant::now() < deadline);
+    let output = query(db, sql, max_rows).await;
+    // The connection returns to the pool, the next query gets its own deadline.
+    db.lock_handle().await?.remove_progress_handler();
+    let mut output = output.map_err(|err_msg| {
+        if Instant::now() >= deadline {
+            anyhow!("Query interrupted after {} ms", timeout.as_millis())
+        } else {
+            err_msg
+        }
+    })?;
+    output.elapsed_ms = started.elapsed().as_millis();
+    Ok(output)
+}
+
+async fn query(db: &mut SqliteConnection, sql: &str, max_rows: usize) -> Result<Output> {
+    let steps: Vec<(i64, i64, i64, String)> = sqlx::query_as(&format!("EXPLAIN QUERY PLAN {sql}"))
+        .fetch_all(&mut *db)
+        .await
+        .context("Explaining query failed")?;
+    let steps: Vec<(i64, i64, String)> = steps
+        .into_iter()
+        .map(|(id, parent, _, detail)| (id, parent, detail))
+        .collect();
+    let plan = indent_plan(&steps);
+
+    let mut columns = Vec::new();
+    let mut rows = Vec::new();
+    let mut truncated = false;
+    let mut stream = sqlx::query(sql).fetch(&mut *db);
+    while let Some(row) = stream.try_next().await.context("Query failed")? {
+        if rows.len() == max_rows {
+            truncated = true;
+            break;
+        }
+        if columns.is_empty() {
+            columns = row.columns().iter().map(|c| c.name().to_string()).collect();
+        }
+        rows.push((0..row.len()).map(|index| cell(&row, index)).collect());
+    }
+    Ok(Output {
+        columns,
+        rows,
+        truncated,
+        plan,
+        elapsed_ms: 0,
+    })
+}
+
+/// Indents the steps `(id, parent, detail)` of the query plan by their depth.
+fn indent_plan(steps: &[(i64, i64, String)]) -> Vec<String> {
+    let mut depths: HashMap<i64, usize> = HashMap::new();
+    steps
+        .iter()
+        .map(|(id, parent, detail)| {
+            let depth = depths.get(parent).map_or(0, |depth| depth + 1);
+            depths.insert(*id, depth);
+            format!("{}{detail}", "  ".repeat(depth))
+        })
+        .collect()
+}
+
+/// Renders the value of the column as text, a blob by its size.
+fn cell(row: &SqliteRow, index: usize) -> String {
+    let Ok(value) = row.try_get_raw(index) else {
+        return "?".to_string();
+    };
+    if value.is_null() {
+        return "NULL".to_string();
+    }
+    let rendered = match value.type_info().name() {
+        "INTEGER" => row.try_get::<i64, _>(index).map(|v| v.to_string()),
+        "REAL" => row.try_get::<f64, _>(index).map(|v| v.to_string()),
+        "BLOB" => row
+            .try_get::<Vec<u8>, _>(index)
+            .map(|v| format!("<{} bytes>", v.len())),
+        _ => row.try_get::<String, _>(index),
+    };
+    rendered.unwrap_or_else(|_| "?".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket_db_pools::sqlx::Connection;
+
+    #[test]
+    fn test_check() {
+        assert_eq!(check("  select 1; ").unwrap(), "select 1");
+        assert_eq!(
+            check("SELECT * FROM messages WHERE message LIKE '%;%' -- a; b\n").unwrap(),
+            "SELECT * FROM messages WHERE message LIKE '%;%' -- a; b"
+        );
+        assert!(check("SELECT 1; DELETE FROM messages").is_err());
+        assert!(check("DELETE FROM messages").is_err());
+        assert!(check("WITH x AS (SELECT 1) DELETE FROM messages").is_err());
+        assert!(check("selection").is_err());
+        assert!(check("").is_err());
+        assert!(check("SELECT 1 /* ; */").is_ok());
+    }
+
+    #[rocket::async_test]
+    async fn test_run() {
+        let mut db = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        sqlx::query(
+            "CREATE TABLE messages (id INTEGER PRIMARY KEY, nickname TEXT, size REAL, data BLOB);",
+        )
+        .execute(&mut db)
+        .await
+        .unwrap();
+        for nickname in ["slava", "eva", "petr"] {
+            sqlx::query("INSERT INTO messages (nickname, size, data) VALUES (?1, 1.5, x'0102');")
+                .bind(nickname)
+                .execute(&mut db)
+                .await
+                .unwrap();
+        }
+        let limit = Duration::from_secs(5);
+        let output = run(&mut db, "SELECT * FROM messages ORDER BY id", 2, limit)
+            .await
+            .unwrap();
+        assert_eq!(output.columns, ["id", "nickname", "size", "data"]);
+        assert_eq!(
+            output.rows,
+            [
+                ["1", "slava", "1.5", "<2 bytes>"],
+                ["2", "eva", "1.5", "<2 bytes>"]
+            ]
+        );
+        assert!(output.truncated);
+        assert!(!output.plan.is_empty());
+        assert_eq!(
+            indent_plan(&[(2, 0, "SCAN a".into()), (3, 2, "SEARCH b".into())]),
+            ["SCAN a", "  SEARCH b"]
+        );
+
+        let output = run(&mut db, "SELECT NULL AS missing", 10, limit)
+            .await
+            .unwrap();
+        assert_eq!(output.rows, [["NULL"]]);
+        assert!(!output.truncated);
+
+        let endless =
+            "SELECT (WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n) SELECT MAX(i) FROM n)";
+        let error = run(&mut db, endless, 10, Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("interrupted"), "{error}");
+        assert!(run(&mut db, "SELECT COUNT(*) FROM messages", 1, limit)
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,1078 @@
+//! Data access layer.
+//!
+//! All the queries of the server go through [`Database`]. The statements are prepared once per connection and kept
+//! in its cache of [`STATEMENT_CACHE_CAPACITY`] statements. Every query is timed in the `db_query_duration_seconds`
+//! histogram labeled by the query name, queries slower than the `[database] slow_query` threshold of the server
+//! config ([`SLOW_QUERY`] by default) are logged as warnings.
+//!
+//! The texts of the messages are interned in the `message_bodies` table, referenced from `messages` by their
+//! [`body_hash`], so the same text repeated by a chatty bot is stored once. The messages stored by an older version of
+//! the server with the text in every row are moved to the bodies when the database is opened.
+//!
+//! Every message is stored with its room, the history and the search return only the messages of the room of the
+//! request, by the `(room, id)` index. The messages stored before the rooms are in the [`chat::DEFAULT_ROOM`].
+
+use std::future::Future;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use chat::HistoryEntry;
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::digest::Subscriber;
+use crate::metrics::QUERY_DURATION;
+use crate::persistence::Record;
+use crate::polls::Poll;
+use crate::storage::Usage;
+
+/// Number of the prepared statements cached by every connection.
+pub const STATEMENT_CACHE_CAPACITY: usize = 100;
+/// Default duration of a query logged as slow.
+pub const SLOW_QUERY: Duration = Duration::from_millis(100);
+/// Length of the [`body_hash`] in bytes.
+pub const BODY_HASH_LEN: usize = 16;
+/// Number of the messages moved to the bodies in one transaction of the migration.
+const MIGRATION_BATCH: i64 = 1000;
+
+/// Settings from the `[database]` section of the server config.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DatabaseConfig {
+    pub slow_query: Duration,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            slow_query: SLOW_QUERY,
+        }
+    }
+}
+
+/// Connection pool of the server database.
+#[derive(Debug, Clone)]
+pub struct Database {
+    pool: SqlitePool,
+    slow_query: Duration,
+}
+
+/// Row of the messages table selected for [`HistoryEntry`].
+type EntryRow = (i64, String, String, String, Option<i64>);
+/// Row of the polls table, the options are stored as a JSON array.
+type PollRow = (i64, String, String, String, bool);
+/// Row of the users table selected for [`Subscriber`].
+type SubscriberRow = (String, String, i64, i64, i64, String);
+/// Row of the messages aggregated by nickname for [`Usage`].
+type UsageRow = (String, i64, i64, i64);
+
+impl Database {
+    /// Opens the database, creating it and its tables if they don't exist.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the database can't be opened or the tables can't be created.
+    pub async fn open(url: &str, config: DatabaseConfig) -> Result<Database> {
+        let options = SqliteConnectOptions::from_str(url)
+            .with_context(|| format!("Invalid database url: {url}"))?
+            .create_if_missing(true)
+            .statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        let pool = SqlitePoolOptions::new()
+            .connect_with(options)
+            .await
+            .context("Connecting database error!")?;
+        let database = Database {
+            pool,
+            slow_query: config.slow_query,
+        };
+        database.create_tables().await?;
+        Ok(database)
+    }
+
+    /// Runs the query, recording its duration and logging it if it is slow.
+    async fn timed<T>(
+        &self,
+        name: &str,
+        query: impl Future<Output = Result<T, sqlx::Error>>,
+    ) -> Result<T, sqlx::Error> {
+        let started = Instant::now();
+        let result = query.await;
+        let elapsed = started.elapsed();
+        QUERY_DURATION
+            .with_label_values(&[name])
+            .observe(elapsed.as_secs_f64());
+        if elapsed > self.slow_query {
+            warn!("Slow query {} took {:?}.", name, elapsed);
+        }
+        result
+    }
+
+    async fn create_tables(&self) -> Result<()> {
+        let messages = sqlx::query(
+            r#"
+        CREATE TABLE IF NOT EXISTS message_bodies (
+            hash BLOB PRIMARY KEY,
+            message TEXT NOT NULL,
+            search_text TEXT NOT NULL
+        ) WITHOUT ROWID;
+        CREATE TABLE IF NOT EXISTS messages (
+            id INTEGER PRIMARY KEY,
+            nickname TEXT NOT NULL,
+            msg_type TEXT NOT NULL,
+            body_hash BLOB NOT NULL REFERENCES message_bodies(hash),
+            lang TEXT,
+            sent_at INTEGER,
+            attachment_size INTEGER NOT NULL DEFAULT 0,
+            room TEXT NOT NULL DEFAULT 'general'
+        );
+        "#,
+        );
+        self.timed("create_messages", messages.execute(&self.pool))
+            .await
+            .context("Creating database table error!")?;
+        self.add_column("lang", "TEXT").await?;
+        self.add_column("sent_at", "INTEGER").await?;
+        self.add_column("attachment_size", "INTEGER NOT NULL DEFAULT 0")
+            .await?;
+        let room = format!("TEXT NOT NULL DEFAULT '{}'", chat::DEFAULT_ROOM);
+        self.add_column("room", &room).await?;
+        let index =
+            sqlx::query("CREATE INDEX IF NOT EXISTS messages_room_id ON messages (room, id);")
+                .execute(&self.pool);
+        self.timed("create_room_index", index)
+            .await
+            .context("Creating database index error!")?;
+        if self.has_column("message").await? {
+            self.migrate_bodies().await?;
+        }
+        let audit = sqlx::query(
+            r#"
+        CREATE TABLE IF NOT EXISTS audit (
+            id INTEGER PRIMARY KEY,
+            nickname TEXT NOT NULL,
+            action TEXT NOT NULL,
+            reason TEXT NOT NULL
+        );
+        "#,
+        );
+        self.timed("create_audit", audit.execute(&self.pool))
+            .await
+            .context("Creating audit table error!")?;
+        let polls = sqlx::query(
+            r#"
+        CREATE TABLE IF NOT EXISTS polls (
+            id INTEGER PRIMARY KEY,
+            nickname TEXT NOT NULL,
+            question TEXT NOT NULL,
+            options TEXT NOT NULL,
+            closed INTEGER NOT NULL DEFAULT 0
+        );
+        CREATE TABLE IF NOT EXISTS poll_votes (
+            poll_id INTEGER NOT NULL REFERENCES polls(id),
+            nickname TEXT NOT NULL,
+            option INTEGER NOT NULL,
+            PRIMARY KEY (poll_id, nickname)
+        );
+        "#,
+        );
+        self.timed("create_polls", polls.execute(&self.pool))
+            .await
+            .context("Creating poll tables error!")?;
+        let colors = sqlx::query(
+            r#"
+        CREATE TABLE IF NOT EXISTS nickname_colors (
+            nickname TEXT PRIMARY KEY,
+            color INTEGER NOT NULL
+        );
+        "#,
+        );
+        self.timed("create_nickname_colors", colors.execute(&self.pool))
+            .await
+            .context("Creating nickname colors table error!")?;
+        let users = sqlx::query(
+            r#"
+        CREATE TABLE IF NOT EXISTS users (
+            nickname TEXT PRIMARY KEY,
+            email TEXT,
+            digest_minute INTEGER,
+            last_seen INTEGER NOT NULL,
+            last_digest INTEGER NOT NULL,
+            unsubscribe_token TEXT UNIQUE
+        );
+        "#,
+        );
+        self.timed("create_users", users.execute(&self.pool))
+            .await
+            .context("Creating users table error!")?;
+        let reports = sqlx::query(
+            r#"
+        CREATE TABLE IF NOT EXISTS reports (
+            id INTEGER PRIMARY KEY,
+            message_id INTEGER NOT NULL,
+            reporter TEXT NOT NULL,
+            author TEXT NOT NULL,
+            reason TEXT NOT NULL,
+            status TEXT NOT NULL DEFAULT 'open',
+            created_at INTEGER NOT NULL,
+            closed_at INTEGER
+        );
+        "#,
+        );
+        self.timed("create_reports", reports.execute(&self.pool))
+            .await
+            .context("Creating reports table error!")?;
+        Ok(())
+    }
+
+    /// Returns true if the messages table has the column.
+    async fn has_column(&self, column: &str) -> Result<bool> {
+        let exists = sqlx::query_scalar(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('messages') WHERE name = ?1",
+        )
+        .bind(column)
+        .fetch_one(&self.pool);
+        self.timed("table_info", exists)
+            .await
+            .context("Reading database table info error!")
+    }
+
+    /// Adds the column to the messages table created by an older version of the server.
+    async fn add_column(&self, column: &str, definition: &str) -> Result<()> {
+        if !self.has_column(column).await? {
+            info!("Adding column {} to the messages table.", column);
+            let sql = format!("ALTER TABLE messages ADD COLUMN {column} {definition};");
+            let alter = sqlx::query(&sql).persistent(false).execute(&self.pool);
+            self.timed("add_column", alter)
+                .await
+                .with_context(|| format!("Adding database column {column} error!"))?;
+        }
+        Ok(())
+    }
+
+    /// Moves the texts of the messages stored by an older version of the server to the bodies.
+    ///
+    /// The messages are moved in batches, each one in its own transaction, so an interrupted migration continues with
+    /// the messages without a `body_hash` on the next start. The old `message` and `search_text` columns are dropped at
+    /// the end, their space is reclaimed by the next `VACUUM` of the maintenance.
+    async fn migrate_bodies(&self) -> Result<()> {
+        self.add_column("body_hash", "BLOB REFERENCES message_bodies(hash)")
+            .await?;
+        let mut moved = 0;
+        loop {
+            let select = sqlx::query_as(
+                "SELECT id, message FROM messages WHERE body_hash IS NULL ORDER BY id LIMIT ?1;",
+            )
+            .bind(MIGRATION_BATCH)
+            .fetch_all(&self.pool);
+            let rows: Vec<(i64, String)> = self
+                .timed("select_unmigrated", select)
+                .await
+                .context("Reading messages for the migration error!")?;
+            if rows.is_empty() {
+                break;
+            }
+            let mut transaction = self.pool.begin().await?;
+            for (id, message) in &rows {
+                let hash = self.insert_body(&mut transaction, message).await?;
+                let update = sqlx::query("UPDATE messages SET body_hash = ?1 WHERE id = ?2;")
+                    .bind(hash)
+                    .bind(id)
+                    .execute(&mut *transaction);
+                self.timed("update_body_hash", update)
+                    .await
+                    .context("Updating body hash error!")?;
+            }
+            transaction.commit().await?;
+            moved += rows.len();
+        }
+        for column in ["message", "search_text"] {
+            if self.has_column(column).await? {
+                let sql = format!("ALTER TABLE messages DROP COLUMN {column};");
+                let alter = sqlx::query(&sql).persistent(false).execute(&self.pool);
+                self.timed("drop_column", alter)
+                    .await
+                    .with_context(|| format!("Dropping database column {column} error!"))?;
+            }
+        }
+        let bodies: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM message_bodies;")
+            .fetch_one(&self.pool)
+            .await?;
+        info!(
+            "Moved {} messages to {} unique bodies, run maintain to reclaim the space.",
+            moved, bodies
+        );
+        Ok(())
+    }
+
+    /// Stores the body of the message unless it is already stored.
+    ///
+    /// # Returns
+    ///
+    /// The [`body_hash`] of the message.
+    async fn insert_body(
+        &self,
+        connection: &mut SqliteConnection,
+        message: &str,
+    ) -> Result<Vec<u8>> {
+        let hash = body_hash(message);
+        let insert = sqlx::query(
+            "INSERT OR IGNORE INTO message_bodies ( hash, message, search_text ) VALUES ( ?1, ?2, ?3 );",
+        )
+        .bind(&hash)
+        .bind(message)
+        .bind(chat::fold(message))
+        .execute(connection);
+        self.timed("insert_body", insert)
+            .await
+            .context("Inserting message body error!")?;
+        Ok(hash)
+    }
+
+    /// Stores the message.
+    ///
+    /// # Returns
+    ///
+    /// The id of the stored message.
+    pub async fn insert_message(&self, record: &Record) -> Result<i64> {
+        let mut transaction = self.pool.begin().await?;
+        let hash = self.insert_body(&mut transaction, &record.message).await?;
+        let insert = sqlx::query(
+            r#"
+            INSERT INTO messages ( nickname, msg_type, body_hash, lang, sent_at, attachment_size, room )
+            VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7 )
+            "#,
+        )
+        .bind(&record.nickname)
+        .bind(&record.msg_type)
+        .bind(hash)
+        .bind(&record.lang)
+        .bind(record.sent_at)
+        .bind(record.attachment_size)
+        .bind(&record.room)
+        .execute(&mut *transaction);
+        let id = self
+            .timed("insert_message", insert)
+            .await
+            .context("Inserting to the database error!")?
+            .last_insert_rowid();
+        transaction.commit().await?;
+        debug!("DB insert id: {}", id);
+        Ok(id)
+    }
+
+    /// Fetches a page of stored messages of the `room` older than `before`, ordered from the oldest.
+    ///
+    /// The page size is limited by `max_limit`.
+    pub async fn fetch_history(
+        &self,
+        room: &str,
+        before: Option<i64>,
+        limit: u32,
+        max_limit: u32,
+    ) -> Result<Vec<HistoryEntry>> {
+        let select = sqlx::query_as(
+            r#"
+            SELECT id, nickname, msg_type, message, sent_at FROM messages
+            JOIN message_bodies ON hash = body_hash
+            WHERE room = ?3 AND id < ?1
+            ORDER BY id DESC
+            LIMIT ?2
+            "#,
+        )
+        .bind(before.unwrap_or(i64::MAX))
+        .bind(limit.clamp(1, max_limit))
+        .bind(room)
+        .fetch_all(&self.pool);
+        let rows = self
+            .timed("fetch_history", select)
+            .await
+            .context("Fetching history error!")?;
+        Ok(history_entries(rows))
+    }
+
+    /// Fetches the latest stored messages of the `room` containing the `query`, ordered from the oldest.
+    ///
+    /// Both the query and the messages are folded by [`chat::fold`], so the search ignores case and accents.
+    pub async fn fetch_search(
+        &self,
+        room: &str,
+        query: &str,
+        limit: u32,
+        max_limit: u32,
+    ) -> Result<Vec<HistoryEntry>> {
+        let select = sqlx::query_as(
+            r#"
+            SELECT id, nickname, msg_type, message, sent_at FROM messages
+            JOIN message_bodies ON hash = body_hash
+            WHERE room = ?3 AND search_text LIKE ?1 ESCAPE '\'
+            ORDER BY id DESC
+            LIMIT ?2
+            "#,
+        )
+        .bind(like_pattern(query))
+        .bind(limit.clamp(1, max_limit))
+        .bind(room)
+        .fetch_all(&self.pool);
+        let rows = self
+            .timed("fetch_search", select)
+            .await
+            .context("Searching messages error!")?;
+        Ok(history_entries(rows))
+    }
+
+    /// Stores the poll of the `nickname`.
+    ///
+    /// # Returns
+    ///
+    /// The id of the stored poll.
+    pub async fn insert_poll(
+        &self,
+        nickname: &str,
+        question: &str,
+        options: &[String],
+    ) -> Result<i64> {
+        let insert = sqlx::query(
+            r#"
+            INSERT INTO polls ( nickname, question, options )
+            VALUES ( ?1, ?2, ?3 )
+            "#,
+        )
+        .bind(nickname)
+        .bind(question)
+        .bind(serde_json::to_string(options)?)
+        .execute(&self.pool);
+        let id = self
+            .timed("insert_poll", insert)
+            .await
+            .context("Inserting poll error!")?
+            .last_insert_rowid();
+        Ok(id)
+    }
+
+    /// Fetches the poll, `None` if no poll has the id.
+    pub async fn fetch_poll(&self, id: i64) -> Result<Option<Poll>> {
+        let select = sqlx::query_as(
+            "SELECT id, nickname, question, options, closed FROM polls WHERE id = ?1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool);
+        let row: Option<PollRow> = self
+            .timed("fetch_poll", select)
+            .await
+            .context("Fetching poll error!")?;
+        let Some((id, author, question, options, closed)) = row else {
+            return Ok(None);
+        };
+        Ok(Some(Poll {
+            id,
+            author,
+            question,
+            options: serde_json::from_str(&options).context("Invalid poll options!")?,
+            closed,
+        }))
+    }
+
+    /// Stores the vote of the `nickname` for the option with the index, replacing the previous vote.
+    pub async fn insert_vote(&self, poll: i64, nickname: &str, option: usize) -> Result<()> {
+        let insert = sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO poll_votes ( poll_id, nickname, option )
+            VALUES ( ?1, ?2, ?3 )
+            "#,
+        )
+        .bind(poll)
+        .bind(nickname)
+        .bind(option as i64)
+        .execute(&self.pool);
+        self.timed("insert_vote", insert)
+            .await
+            .context("Inserting vote error!")?;
+        Ok(())
+    }
+
+    /// Counts the votes for each of the `options` of the poll.
+    pub async fn count_votes(&self, poll: i64, options: usize) -> Result<Vec<u32>> {
+        let select = sqlx::query_as(
+            "SELECT option, COUNT(*) FROM poll_votes WHERE poll_id = ?1 GROUP BY option",
+        )
+        .bind(poll)
+        .fetch_all(&self.pool);
+        let rows: Vec<(i64, u32)> = self
+            .timed("count_votes", select)
+            .await
+            .context("Counting votes error!")?;
+        let mut votes = vec![0; options];
+        for (option, count) in rows {
+            if let Some(votes) = usize::try_from(option).ok().and_then(|i| votes.get_mut(i)) {
+                *votes = count;
+            }
+        }
+        Ok(votes)
+    }
+
+    /// Closes the poll, it doesn't accept any more votes.
+    pub async fn close_poll(&self, id: i64) -> Result<()> {
+        let update = sqlx::query("UPDATE polls SET closed = 1 WHERE id = ?1")
+            .bind(id)
+            .execute(&self.pool);
+        self.timed("close_poll", update)
+            .await
+            .context("Closing poll error!")?;
+        Ok(())
+    }
+
+    /// Returns the assigned colors of the nicknames.
+    pub async fn fetch_colors(&self) -> Result<Vec<(String, u8)>> {
+        let select =
+            sqlx::query_as("SELECT nickname, color FROM nickname_colors;").fetch_all(&self.pool);
+        self.timed("fetch_colors", select)
+            .await
+            .context("Fetching nickname colors error!")
+    }
+
+    /// Stores the color of the nickname, keeping the color assigned before.
+    pub async fn insert_color(&self, nickname: &str, color: u8) -> Result<()> {
+        let insert = sqlx::query(
+            r#"
+            INSERT OR IGNORE INTO nickname_colors ( nickname, color )
+            VALUES ( ?1, ?2 )
+            "#,
+        )
+        .bind(nickname)
+        .bind(color)
+        .execute(&self.pool);
+        self.timed("insert_color", insert)
+            .await
+            .context("Inserting nickname color error!")?;
+        Ok(())
+    }
+
+    /// Records the moderation action in the audit table.
+    pub async fn insert_audit(&self, nickname: &str, action: &str, reason: &str) -> Result<()> {
+        let insert = sqlx::query(
+            r#"
+            INSERT INTO audit ( nickname, action, reason )
+            VALUES ( ?1, ?2, ?3 )
+            "#,
+        )
+        .bind(nickname)
+        .bind(action)
+        .bind(reason)
+        .execute(&self.pool);
+        self.timed("insert_audit", insert)
+            .await
+            .context("Inserting to the audit table error!")?;
+        Ok(())
+    }
+
+    /// Stores the open report of the stored message by the `reporter`, created at the Unix time in milliseconds.
+    ///
+    /// # Returns
+    ///
+    /// The id of the report and the nickname of the author of the message, `None` if no message has the id.
+    pub async fn insert_report(
+        &self,
+        message: i64,
+        reporter: &str,
+        reason: &str,
+        created_at: u64,
+    ) -> Result<Option<(i64, String)>> {
+        let insert = sqlx::query_as(
+            r#"
+            INSERT INTO reports ( message_id, reporter, author, reason, created_at )
+            SELECT id, ?2, nickname, ?3, ?4 FROM messages WHERE id = ?1
+            RETURNING id, author
+            "#,
+        )
+        .bind(message)
+        .bind(reporter)
+        .bind(reason)
+        .bind(created_at as i64)
+        .fetch_optional(&self.pool);
+        self.timed("insert_report", insert)
+            .await
+            .context("Inserting report error!")
+    }
+
+    /// Opts the nickname in to the digests sent to the email, with a new unsubscribe token.
+    ///
+    /// The user is seen at `now` and no digest is due before the next scheduled time.
+    pub async fn set_digest_email(&self, nickname: &str, email: &str, now: u64) -> Result<()> {
+        let upsert = sqlx::query(
+            r#"
+            INSERT INTO users ( nickname, email, last_seen, last_digest, unsubscribe_token )
+            VALUES ( ?1, ?2, ?3, ?3, lower(hex(randomblob(16))) )
+            ON CONFLICT ( nickname ) DO UPDATE
+            SET email = ?2, last_seen = ?3, last_digest = ?3, unsubscribe_token = lower(hex(randomblob(16)))
+            "#,
+        )
+        .bind(nickname)
+        .bind(email)
+        .bind(now as i64)
+        .execute(&self.pool);
+        self.timed("set_digest_email", upsert)
+            .await
+            .context("Storing digest email error!")?;
+        Ok(())
+    }
+
+    /// Schedules the digest of the nickname at the minute of the day (UTC), starting after `now`.
+    ///
+    /// # Returns
+    ///
+    /// False if the nickname has no digest email.
+    pub async fn set_digest_minute(&self, nickname: &str, minute: u16, now: u64) -> Result<bool> {
+        let update = sqlx::query(
+            r#"
+            UPDATE users SET digest_minute = ?2, last_digest = ?3
+            WHERE nickname = ?1 AND email IS NOT NULL
+            "#,
+        )
+        .bind(nickname)
+        .bind(minute)
+        .bind(now as i64)
+        .execute(&self.pool);
+        let updated = self
+            .timed("set_digest_minute", update)
+            .await
+            .context("Scheduling digest error!")?;
+        Ok(updated.rows_affected() > 0)
+    }
+
+    /// Opts the nickname out of the digests, forgetting the email.
+    pub async fn disable_digest(&self, nickname: &str) -> Result<()> {
+        let update = sqlx::query(
+            r#"
+            UPDATE users SET email = NULL, digest_minute = NULL, unsubscribe_token = NULL
+            WHERE nickname = ?1
+            "#,
+        )
+        .bind(nickname)
+        .execute(&self.pool);
+        self.timed("disable_digest", update)
+            .await
+            .context("Disabling digest error!")?;
+        Ok(())
+    }
+
+    /// Opts out the user with the unsubscribe token.
+    ///
+    /// # Returns
+    ///
+    /// The nickname of the user, none for an unknown token.
+    #[cfg(feature = "metrics")]
+    pub async fn unsubscribe(&self, token: &str) -> Result<Option<String>> {
+        let update = sqlx::query_scalar(
+            r#"
+            UPDATE users SET email = NULL, digest_minute = NULL, unsubscribe_token = NULL
+            WHERE unsubscribe_token = ?1
+            RETURNING nickname
+            "#,
+        )
+        .bind(token)
+        .fetch_optional(&self.pool);
+        self.timed("unsubscribe", update)
+            .await
+            .context("Unsubscribing digest error!")
+    }
+
+    /// Records the users as seen at `now`, the ones without a digest are skipped.
+    pub async fn touch_users(&self, nicknames: &[String], now: u64) -> Result<()> {
+        let mut transaction = self.pool.begin().await?;
+        for nickname in nicknames {
+            let update = sqlx::query("UPDATE users SET last_seen = ?2 WHERE nickname = ?1;")
+                .bind(nickname)
+                .bind(now as i64)
+                .execute(&mut *transaction);
+            self.timed("touch_user", update)
+                .await
+                .context("Updating last seen error!")?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    /// Returns the users with a scheduled digest.
+    pub async fn fetch_subscribers(&self) -> Result<Vec<Subscriber>> {
+        let select = sqlx::query_as(
+            r#"
+            SELECT nickname, email, digest_minute, last_seen, last_digest, unsubscribe_token FROM users
+            WHERE email IS NOT NULL AND digest_minute IS NOT NULL
+            "#,
+        )
+        .fetch_all(&self.pool);
+        let rows: Vec<SubscriberRow> = self
+            .timed("fetch_subscribers", select)
+            .await
+            .context("Fetching digest subscribers error!")?;
+        let subscribers = rows
+            .into_iter()
+            .map(
+                |(nickname, email, minute, last_seen, last_digest, token)| Subscriber {
+                    nickname,
+                    email,
+                    minute: minute as u16,
+                    last_seen: last_seen as u64,
+                    last_digest: last_digest as u64,
+                    token,
+                },
+            )
+            .collect();
+        Ok(subscribers)
+    }
+
+    /// Records the digest of the nickname as sent at `sent_at`.
+    pub async fn mark_digest_sent(&self, nickname: &str, sent_at: u64) -> Result<()> {
+        let update = sqlx::query("UPDATE users SET last_digest = ?2 WHERE nickname = ?1;")
+            .bind(nickname)
+            .bind(sent_at as i64)
+            .execute(&self.pool);
+        self.timed("mark_digest_sent", update)
+            .await
+            .context("Marking digest sent error!")?;
+        Ok(())
+    }
+
+    /// Fetches the latest messages of the others sent after `since` containing `@nickname`, ordered from the oldest.
+    ///
+    /// A mention of a longer nickname starting the same way matches too, see [`crate::digest::mentions`].
+    pub async fn fetch_mentions(
+        &self,
+        nickname: &str,
+        since: u64,
+        limit: u32,
+    ) -> Result<Vec<HistoryEntry>> {
+        let select = sqlx::query_as(
+            r#"
+            SELECT id, nickname, msg_type, message, sent_at FROM messages
+            JOIN message_bodies ON hash = body_hash
+            WHERE sent_at > ?1 AND nickname != ?2 AND search_text LIKE ?3 ESCAPE '\'
+            ORDER BY id DESC
+            LIMIT ?4
+            "#,
+        )
+        .bind(since as i64)
+        .bind(nickname)
+        .bind(like_pattern(&format!("@{nickname}")))
+        .bind(limit)
+        .fetch_all(&self.pool);
+        let rows = self
+            .timed("fetch_mentions", select)
+            .await
+            .context("Fetching mentions error!")?;
+        Ok(history_entries(rows))
+    }
+
+    /// Returns the messages and the attachment bytes stored for each nickname, the heaviest users first.
+    pub async fn fetch_storage_usage(&self) -> Result<Vec<Usage>> {
+        let select = sqlx::query_as(
+            r#"
+            SELECT nickname, COUNT(*), SUM(msg_type IN ('Image', 'File', 'Attachment')), SUM(attachment_size)
+            FROM messages
+            GROUP BY nickname
+            ORDER BY SUM(attachment_size) DESC, nickname
+            "#,
+        )
+        .fetch_all(&self.pool);
+        let rows: Vec<UsageRow> = self
+            .timed("fetch_storage_usage", select)
+            .await
+            .context("Fetching storage usage error!")?;
+        let usage = rows
+            .into_iter()
+            .map(|(nickname, messages, attachments, bytes)| Usage {
+                nickname,
+                messages: messages as u64,
+                attachments: attachments as u64,
+                bytes: bytes as u64,
+            })
+            .collect();
+        Ok(usage)
+    }
+
+    /// Returns the problems found by `PRAGMA integrity_check`, empty if the database is fine.
+    pub async fn integrity_check(&self) -> Result<Vec<String>> {
+        let check = sqlx::query_scalar("PRAGMA integrity_check;").fetch_all(&self.pool);
+        let rows: Vec<String> = self
+            .timed("integrity_check", check)
+            .await
+            .context("Database integrity check error!")?;
+        Ok(rows.into_iter().filter(|row| row != "ok").collect())
+    }
+
+    /// Returns the size of the database file in bytes.
+    pub async fn size(&self) -> Result<i64> {
+        let size = sqlx::query_scalar(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size();",
+        )
+        .fetch_one(&self.pool);
+        self.timed("database_size", size)
+            .await
+            .context("Reading database size error!")
+    }
+
+    /// Runs `VACUUM` and `ANALYZE`.
+    pub async fn optimize(&self) -> Result<()> {
+        info!("Running database VACUUM and ANALYZE.");
+        let vacuum = sqlx::query("VACUUM;").execute(&self.pool);
+        self.timed("vacuum", vacuum)
+            .await
+            .context("Database VACUUM error!")?;
+        let analyze = sqlx::query("ANALYZE;").execute(&self.pool);
+        self.timed("analyze", analyze)
+            .await
+            .context("Database ANALYZE error!")?;
+        Ok(())
+    }
+}
+
+fn history_entries(rows: Vec<EntryRow>) -> Vec<HistoryEntry> {
+    rows.into_iter()
+        .rev()
+        .map(|(id, nickname, msg_type, message, sent_at)| HistoryEntry {
+            id,
+            nickname,
+            msg_type,
+            message,
+            timestamp: sent_at.map(|sent_at| sent_at as u64),
+        })
+        .collect()
+}
+
+/// Returns the hash of the message text referencing its body, the first [`BODY_HASH_LEN`] bytes of its SHA-256.
+pub fn body_hash(message: &str) -> Vec<u8> {
+    Sha256::digest(message.as_bytes())[..BODY_HASH_LEN].to_vec()
+}
+
+/// Returns the `LIKE` pattern matching the folded text anywhere in the column.
+fn like_pattern(text: &str) -> String {
+    let escaped = chat::fold(text)
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{escaped}%")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat::{Message, MessageType};
+
+    #[test]
+    fn test_like_pattern() {
+        assert_eq!(like_pattern("Uživatel"), "%uzivatel%");
+        assert_eq!(like_pattern("50%_off"), "%50\\%\\_off%");
+    }
+
+    #[tokio::test]
+    async fn test_queries_are_timed() {
+        let path = std::env::temp_dir().join(format!("chat-db-{}.db", std::process::id()));
+        let url = format!("sqlite://{}", path.display());
+        let database = Database::open(&url, DatabaseConfig::default())
+            .await
+            .unwrap();
+        for text in ["Ahoj Uživateli", "hello", "uzivatel 2"] {
+            let message = Message::from("slava", MessageType::text(text));
+            database
+                .insert_message(&Record::new(&message))
+                .await
+                .unwrap();
+        }
+        let room = chat::DEFAULT_ROOM;
+        let found = database
+            .fetch_search(room, "UŽIVATEL", 10, 100)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].message, "Ahoj Uživateli");
+        let page = database.fetch_history(room, None, 2, 100).await.unwrap();
+        assert_eq!(page.len(), 2);
+        assert_eq!(page[1].message, "uzivatel 2");
+
+        #[cfg(feature = "metrics")]
+        {
+            let count = |name| QUERY_DURATION.with_label_values(&[name]).get_sample_count();
+            assert!(count("insert_message") >= 3);
+            assert!(count("fetch_search") >= 1);
+        }
+        database.pool.close().await;
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rooms_are_separated() {
+        let path = std::env::temp_dir().join(format!("chat-rooms-{}.db", std::process::id()));
+        let url = format!("sqlite://{}", path.display());
+        let database = Database::open(&url, DatabaseConfig::default())
+            .await
+            .unwrap();
+        for (room, text) in [
+            (chat::DEFAULT_ROOM, "lunch at noon"),
+            ("rust", "borrowck at noon"),
+            ("secret", "plans at noon"),
+        ] {
+            let message = Message::in_room(room, "slava", MessageType::text(text));
+            database
+                .insert_message(&Record::new(&message))
+                .await
+                .unwrap();
+        }
+        let texts = |entries: Vec<HistoryEntry>| -> Vec<String> {
+            entries.into_iter().map(|entry| entry.message).collect()
+        };
+        let history = database
+            .fetch_history(chat::DEFAULT_ROOM, None, 10, 100)
+            .await;
+        assert_eq!(texts(history.unwrap()), ["lunch at noon"]);
+        let found = database
+            .fetch_search(chat::DEFAULT_ROOM, "noon", 10, 100)
+            .await;
+        assert_eq!(texts(found.unwrap()), ["lunch at noon"]);
+        let history = database.fetch_history("rust", None, 10, 100).await;
+        assert_eq!(texts(history.unwrap()), ["borrowck at noon"]);
+        let found = database.fetch_search("lobby", "noon", 10, 100).await;
+        assert!(found.unwrap().is_empty());
+        // The page of a room is read by the index, not by scanning the other rooms.
+        let plan: Vec<(i64, i64, i64, String)> = sqlx::query_as(
+            "EXPLAIN QUERY PLAN SELECT id FROM messages WHERE room = 'rust' AND id < 10 ORDER BY id DESC;",
+        )
+        .fetch_all(&database.pool)
+        .await
+        .unwrap();
+        assert!(plan[0].3.contains("messages_room_id"), "{plan:?}");
+        database.pool.close().await;
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_storage_usage() {
+        let path = std::env::temp_dir().join(format!("chat-usage-{}.db", std::process::id()));
+        let url = format!("sqlite://{}", path.display());
+        let database = Database::open(&url, DatabaseConfig::default())
+            .await
+            .unwrap();
+        let attachment = MessageType::Attachment {
+            name: "movie.mp4".to_string(),
+            size: 5000,
+            url: "/attachments/1".to_string(),
+        };
+        for (nickname, message) in [
+            ("slava", MessageType::text("hello")),
+            ("slava", MessageType::Image(vec![0; 100])),
+            ("eva", MessageType::text("hi")),
+            ("eva", attachment),
+        ] {
+            let message = Message::from(nickname, message);
+            database
+                .insert_message(&Record::new(&message))
+                .await
+                .unwrap();
+        }
+        let usage = database.fetch_storage_usage().await.unwrap();
+        let rows: Vec<_> = usage
+            .iter()
+            .map(|user| {
+                (
+                    user.nickname.as_str(),
+                    user.messages,
+                    user.attachments,
+                    user.bytes,
+                )
+            })
+            .collect();
+        assert_eq!(rows, [("eva", 2, 1, 5000), ("slava", 2, 1, 100)]);
+        database.pool.close().await;
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_insert_report() {
+        let path = std::env::temp_dir().join(format!("chat-reports-{}.db", std::process::id()));
+        let url = format!("sqlite://{}", path.display());
+        let database = Database::open(&url, DatabaseConfig::default())
+            .await
+            .unwrap();
+        let message = Message::from("spammer", MessageType::text("buy now"));
+        let id = database
+            .insert_message(&Record::new(&message))
+            .await
+            .unwrap();
+        let report = database.insert_report(id, "slava", "spam", 1000).await;
+        assert_eq!(report.unwrap(), Some((1, "spammer".to_string())));
+        let missing = database.insert_report(id + 1, "slava", "spam", 1000).await;
+        assert_eq!(missing.unwrap(), None);
+        let (status, reporter): (String, String) =
+            sqlx::query_as("SELECT status, reporter FROM reports WHERE id = 1;")
+                .fetch_one(&database.pool)
+                .await
+                .unwrap();
+        assert_eq!((status.as_str(), reporter.as_str()), ("open", "slava"));
+        database.pool.close().await;
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// Stores the messages of a chatty bot in the layout of an older version, then measures the migrated database.
+    #[tokio::test]
+    async fn test_migrate_bodies() {
+        let path = std::env::temp_dir().join(format!("chat-bodies-{}.db", std::process::id()));
+        let url = format!("sqlite://{}?mode=rwc", path.display());
+        let old = SqlitePool::connect(&url).await.unwrap();
+        sqlx::query(
+            "CREATE TABLE messages (id INTEGER PRIMARY KEY, nickname TEXT NOT NULL, msg_type TEXT NOT NULL, \
+             message TEXT NOT NULL, lang TEXT, search_text TEXT, sent_at INTEGER);",
+        )
+        .execute(&old)
+        .await
+        .unwrap();
+        let mut transaction = old.begin().await.unwrap();
+        for id in 0..10_000 {
+            let (nickname, message) = match id % 10 {
+                0 => ("slava", format!("Ahoj Uživateli number {id}")),
+                n => (
+                    "bot",
+                    format!("Build #{} passed, all the tests are green.", n % 3),
+                ),
+            };
+            sqlx::query(
+                "INSERT INTO messages (nickname, msg_type, message, search_text) VALUES (?1, 'Text', ?2, ?3);",
+            )
+            .bind(nickname)
+            .bind(&message)
+            .bind(chat::fold(&message))
+            .execute(&mut *transaction)
+            .await
+            .unwrap();
+        }
+        transaction.commit().await.unwrap();
+        sqlx::query("VACUUM;").execute(&old).await.unwrap();
+        old.close().await;
+        let old_size = std::fs::metadata(&path).unwrap().len();
+
+        let database = Database::open(&url, DatabaseConfig::default())
+            .await
+            .unwrap();
+        assert!(!database.has_column("message").await.unwrap());
+        let bodies: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM message_bodies;")
+            .fetch_one(&database.pool)
+            .await
+            .unwrap();
+        assert_eq!(bodies, 1000 + 3);
+        // The messages stored before the rooms are in the default room.
+        let room = chat::DEFAULT_ROOM;
+        let page = database.fetch_history(room, None, 2, 100).await.unwrap();
+        assert_eq!(page[0].message, "Build #2 passed, all the tests are green.");
+        assert_eq!(page[1].nickname, "bot");
+        let found = database
+            .fetch_search(room, "uzivateli number 9990", 10, 100)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        database.optimize().await.unwrap();
+        let new_size = database.size().await.unwrap() as u64;
+        assert!(new_size * 10 < old_size * 6, "{new_size} of {old_size}");
+        database.pool.close().await;
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+//! Port selection and discovery of the server for the local use.
+//!
+//! The `--port N` option overrides the port of the address, `--port 0` lets the system pick a free port, so several
+//! servers run side by side. The bound port is logged and written to the [`chat::discovery::port_file`], where the
+//! clients of the same machine find it by `--discover`. Built with the `mdns` feature the server is advertised on the
+//! LAN under its `server.name` too, see [`chat::discovery`].
+
+use anyhow::{anyhow, Context, Result};
+use chat::discovery;
+use chat::Address;
+use log::{info, warn};
+
+/// Splits the `--port N` option from the arguments.
+///
+/// # Returns
+///
+/// The port, `None` without the option, and the remaining arguments.
+///
+/// # Errors
+///
+/// This function will return an error if the value is missing or isn't a port.
+pub fn parse_port(arguments: &[String]) -> Result<(Option<u16>, Vec<String>)> {
+    let mut remaining = Vec::new();
+    let mut port = None;
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        if argument != "--port" {
+            remaining.push(argument.clone());
+            continue;
+        }
+        let value = arguments
+            .next()
+            .ok_or_else(|| anyhow!("Missing value of --port!"))?;
+        let value: u16 = value
+            .parse()
+            .with_context(|| format!("Invalid value of --port: {value}, use e.g. 11111 or 0!"))?;
+        port = Some(value);
+    }
+    Ok((port, remaining))
+}
+
+/// Returns the address from the arguments with the port of the `--port` option, which allows the hostname alone.
+pub fn address(arguments: &[String], port: Option<u16>) -> Address {
+    let Some(port) = port else {
+        return Address::from_arguments(arguments);
+    };
+    let hostname = match arguments.get(1) {
+        Some(hostname) if arguments.len() <= 3 => hostname.clone(),
+        _ => Address::default().hostname().to_string(),
+    };
+    Address::new(hostname, port.to_string())
+}
+
+/// Publication of the bound port, the advertisement on the LAN ends when it is dropped.
+pub struct Published {
+    #[cfg(feature = "mdns")]
+    _advertisement: Option<discovery::Advertisement>,
+}
+
+/// Writes the bound port to the port file and advertises the server on the LAN, a failure is only logged.
+pub fn publish(
+    #[cfg_attr(not(feature = "mdns"), allow(unused_variables))] name: &str,
+    port: u16,
+) -> Published {
+    let path = discovery::port_file();
+    match discovery::write_port(&path, port) {
+        Ok(()) => info!("Port {} written to {}.", port, path.display()),
+        Err(err_msg) => warn!("Writing the port to {} failed: {}", path.display(), err_msg),
+    }
+    #[cfg(feature = "mdns")]
+    let advertisement = match discovery::advertise(name, port) {
+        Ok(advertisement) => {
+            info!(
+                "Advertising {} on the LAN as {}.",
+                name,
+                discovery::SERVICE_TYPE
+            );
+            Some(advertisement)
+        }
+        Err(err_msg) => {
+            warn!("Advertising on the LAN failed: {}", err_msg);
+            None
+        }
+    };
+    Published {
+        #[cfg(feature = "mdns")]
+        _advertisement: advertisement,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arguments(arguments: &[&str]) -> Vec<String> {
+        arguments.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse_port() {
+        let (port, remaining) =
+            parse_port(&arguments(&["server", "--port", "0", "0.0.0.0"])).unwrap();
+        assert_eq!(port, Some(0));
+        assert_eq!(address(&remaining, port).to_string(), "0.0.0.0:0");
+        let (port, remaining) = parse_port(&arguments(&["server", "0.0.0.0", "10000"])).unwrap();
+        assert_eq!(port, None);
+        assert_eq!(address(&remaining, port).to_string(), "0.0.0.0:10000");
+        let remaining = arguments(&["server", "0.0.0.0", "10000"]);
+        assert_eq!(address(&remaining, Some(0)).to_string(), "0.0.0.0:0");
+        assert_eq!(address(&remaining[..1], Some(0)).to_string(), "localhost:0");
+        assert!(parse_port(&arguments(&["server", "--port"])).is_err());
+        assert!(parse_port(&arguments(&["server", "--port", "70000"])).is_err());
+    }
+}
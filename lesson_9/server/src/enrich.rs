@@ -0,0 +1,154 @@
+//! Server-side enrichment of the messages.
+//!
+//! Registered [`Enricher`]s attach annotations to every delivered message before it is broadcast. Clients render
+//! the annotations next to the message or ignore them.
+
+use chat::{Message, MessageType};
+
+/// Minimal number of characters for the language detection.
+pub const LANGUAGE_MIN_CHARS: usize = 20;
+/// Maximal number of annotated links of a single message.
+pub const MAX_LINKS: usize = 3;
+
+/// Source of the message annotations.
+pub trait Enricher: Send + Sync {
+    /// Returns the annotations of the message, empty if there is nothing to add.
+    fn enrich(&self, message: &MessageType) -> Vec<(String, String)>;
+}
+
+/// Ordered list of the registered enrichers.
+#[derive(Default)]
+pub struct Pipeline {
+    enrichers: Vec<Box<dyn Enricher>>,
+}
+
+impl Pipeline {
+    /// Creates the pipeline with the built-in enrichers.
+    pub fn with_defaults() -> Pipeline {
+        let mut pipeline = Pipeline::default();
+        pipeline.register(Box::new(LanguageEnricher));
+        pipeline.register(Box::new(LinkEnricher));
+        pipeline
+    }
+
+    /// Registers the enricher, it runs after the already registered ones.
+    pub fn register(&mut self, enricher: Box<dyn Enricher>) {
+        self.enrichers.push(enricher);
+    }
+
+    /// Attaches the annotations of all the enrichers to the message.
+    pub fn enrich(&self, message: &mut Message) {
+        for enricher in &self.enrichers {
+            for (key, value) in enricher.enrich(&message.message) {
+                message.annotate(key, value);
+            }
+        }
+    }
+}
+
+/// Detects the language of text messages, e.g. `("lang", "eng")`.
+pub struct LanguageEnricher;
+
+impl Enricher for LanguageEnricher {
+    fn enrich(&self, message: &MessageType) -> Vec<(String, String)> {
+        let MessageType::Text(text) = message else {
+            return Vec::new();
+        };
+        if text.chars().filter(|c| c.is_alphabetic()).count() < LANGUAGE_MIN_CHARS {
+            return Vec::new();
+        }
+        match whatlang::detect(text) {
+            Some(info) if info.is_reliable() => {
+                vec![("lang".to_string(), info.lang().code().to_string())]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Annotates the hosts of the links in text messages, e.g. `("link", "github.com")`.
+pub struct LinkEnricher;
+
+impl Enricher for LinkEnricher {
+    fn enrich(&self, message: &MessageType) -> Vec<(String, String)> {
+        let MessageType::Text(text) = message else {
+            return Vec::new();
+        };
+        text.split_whitespace()
+            .filter_map(link_host)
+            .take(MAX_LINKS)
+            .map(|host| ("link".to_string(), host.to_string()))
+            .collect()
+    }
+}
+
+fn link_host(word: &str) -> Option<&str> {
+    let rest = word
+        .strip_prefix("https://")
+        .or_else(|| word.strip_prefix("http://"))?;
+    let host = rest
+        .split(['/', '?', '#'])
+        .next()?
+        .rsplit('@')
+        .next()?
+        .split(':')
+        .next()?;
+    (!host.is_empty()).then_some(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Constant;
+
+    impl Enricher for Constant {
+        fn enrich(&self, _message: &MessageType) -> Vec<(String, String)> {
+            vec![("bot".to_string(), "yes".to_string())]
+        }
+    }
+
+    #[test]
+    fn test_links() {
+        let message = MessageType::text(
+            "see https://github.com/rust-lang and http://user@example.com:8080?q=1 or ftp://x",
+        );
+        assert_eq!(
+            LinkEnricher.enrich(&message),
+            [
+                ("link".to_string(), "github.com".to_string()),
+                ("link".to_string(), "example.com".to_string()),
+            ]
+        );
+        assert!(LinkEnricher
+            .enrich(&MessageType::text("https://"))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_language() {
+        let message = MessageType::text(
+            "I think we should meet tomorrow morning to discuss the new release plan.",
+        );
+        assert_eq!(
+            LanguageEnricher.enrich(&message),
+            [("lang".to_string(), "eng".to_string())]
+        );
+        assert!(LanguageEnricher.enrich(&MessageType::text("ok")).is_empty());
+    }
+
+    #[test]
+    fn test_pipeline_order() {
+        let mut pipeline = Pipeline::with_defaults();
+        pipeline.register(Box::new(Constant));
+        let mut message = Message::from("slava", MessageType::text("look at https://crates.io"));
+        pipeline.enrich(&mut message);
+        assert_eq!(
+            message.annotations,
+            [
+                ("link".to_string(), "crates.io".to_string()),
+                ("bot".to_string(), "yes".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,251 @@
+//! Structured events of the server activity for bots and bridges.
+//!
+//! Joins, leaves, messages and moderation actions are published as JSON lines like
+//! `{"at":1718000000,"event":"join","nickname":"slava","addr":"127.0.0.1:4000"}`. Bots read them from the `/events`
+//! endpoint of the HTTP server with the `token` of the `[events]` config section as a bearer token, so they don't
+//! have to pretend to be chat clients. The endpoint is disabled without a token and is a part of the `metrics`
+//! feature. A bot reading too slowly gets a `lagged` event with the number of the missed events.
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "metrics")]
+use axum::body::Body;
+#[cfg(feature = "metrics")]
+use axum::extract::State;
+#[cfg(feature = "metrics")]
+use axum::http::{header, HeaderMap, StatusCode};
+#[cfg(feature = "metrics")]
+use axum::response::Response;
+use chat::Message;
+use log::error;
+use serde::Serialize;
+use tokio::sync::broadcast;
+#[cfg(feature = "metrics")]
+use tokio::sync::broadcast::error::RecvError;
+
+/// Minimal length of the configured token.
+pub const MIN_TOKEN_LEN: usize = 16;
+/// Number of events buffered for every reader.
+const BUFFER: usize = 1024;
+
+/// Settings from the `[events]` section of the server config.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct EventsConfig {
+    /// Bearer token of the `/events` endpoint, the endpoint is disabled without it.
+    pub token: Option<String>,
+}
+
+/// Event of the server activity.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ServerEvent {
+    /// The client sent its first message.
+    Join { nickname: String, addr: String },
+    /// The client disconnected or changed the nickname.
+    Leave { nickname: String, reason: String },
+    /// The client sent a chat message.
+    Message {
+        nickname: String,
+        msg_type: String,
+        text: String,
+    },
+    /// The server restricted the client, e.g. muted it for spamming.
+    Moderation {
+        nickname: String,
+        action: String,
+        reason: String,
+    },
+    /// The reader missed events because it was too slow.
+    #[cfg(feature = "metrics")]
+    Lagged { missed: u64 },
+}
+
+impl ServerEvent {
+    /// Returns the event of the chat message.
+    pub fn message(message: &Message) -> ServerEvent {
+        let (msg_type, text) = message.message.get_type_and_message();
+        ServerEvent::Message {
+            nickname: message.nickname.clone(),
+            msg_type: msg_type.to_string(),
+            text,
+        }
+    }
+
+    /// Returns the event as a JSON line with the current time.
+    fn to_line(&self) -> serde_json::Result<String> {
+        #[derive(Serialize)]
+        struct Envelope<'a> {
+            at: u64,
+            #[serde(flatten)]
+            event: &'a ServerEvent,
+        }
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        let mut line = serde_json::to_string(&Envelope { at, event: self })?;
+        line.push('\n');
+        Ok(line)
+    }
+}
+
+/// Publisher of the server events shared by the client connections.
+#[derive(Clone)]
+pub struct Events {
+    sender: broadcast::Sender<Arc<str>>,
+    #[cfg(feature = "metrics")]
+    token: Option<Arc<str>>,
+}
+
+impl Events {
+    /// Creates the publisher, the endpoint accepts the configured token.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub fn new(config: EventsConfig) -> Events {
+        Events {
+            sender: broadcast::channel(BUFFER).0,
+            #[cfg(feature = "metrics")]
+            token: config.token.map(Arc::from),
+        }
+    }
+
+    /// Sends the event to the connected readers, the event is not even serialized without them.
+    pub fn publish(&self, event: ServerEvent) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        match event.to_line() {
+            Ok(line) => {
+                let _ = self.sender.send(Arc::from(line));
+            }
+            Err(err_msg) => error!("Serializing event error: {:?}", err_msg),
+        }
+    }
+
+    /// Returns true if the request carries the configured bearer token.
+    #[cfg(feature = "metrics")]
+    fn authorized(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = &self.token else {
+            return false;
+        };
+        let Some(bearer) = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+        else {
+            return false;
+        };
+        // Compares all the bytes, so the time doesn't reveal the matching prefix.
+        bearer.len() == token.len()
+            && bearer
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+/// Handler of `GET /events` streaming the events as JSON lines until the reader disconnects.
+#[cfg(feature = "metrics")]
+pub async fn stream(State(events): State<Events>, headers: HeaderMap) -> Response {
+    if events.token.is_none() {
+        return status(StatusCode::NOT_FOUND);
+    }
+    if !events.authorized(&headers) {
+        return status(StatusCode::UNAUTHORIZED);
+    }
+    let receiver = events.sender.subscribe();
+    let lines = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let line = match receiver.recv().await {
+            Ok(line) => line.to_string(),
+            Err(RecvError::Lagged(missed)) => {
+                ServerEvent::Lagged { missed }.to_line().unwrap_or_default()
+            }
+            Err(RecvError::Closed) => return None,
+        };
+        Some((Ok::<_, std::convert::Infallible>(line), receiver))
+    });
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .body(Body::from_stream(lines))
+        .unwrap_or_else(|_| status(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+#[cfg(feature = "metrics")]
+fn status(status: StatusCode) -> Response {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat::MessageType;
+
+    #[test]
+    fn test_event_line() {
+        let message = Message::from("slava", MessageType::Text("hello".to_string()));
+        let line = ServerEvent::message(&message).to_line().unwrap();
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["event"], "message");
+        assert_eq!(value["nickname"], "slava");
+        assert_eq!(value["msg_type"], "Text");
+        assert_eq!(value["text"], "hello");
+        assert!(value["at"].as_u64().unwrap() > 0);
+        assert!(line.ends_with('\n'));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_stream() {
+        use axum::routing::get;
+        use axum::Router;
+
+        let events = Events::new(EventsConfig {
+            token: Some("0123456789abcdef".to_string()),
+        });
+        let app = Router::new()
+            .route("/events", get(stream))
+            .with_state(events.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/events", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let client = reqwest::Client::new();
+
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), 401);
+        let response = client
+            .get(&url)
+            .bearer_auth("wrong-token-0123")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 401);
+
+        let mut response = client
+            .get(&url)
+            .bearer_auth("0123456789abcdef")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        events.publish(ServerEvent::Moderation {
+            nickname: "eva".to_string(),
+            action: "auto-mute".to_string(),
+            reason: "flooding".to_string(),
+        });
+        let chunk = response.chunk().await.unwrap().unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&chunk).unwrap();
+        assert_eq!(value["event"], "moderation");
+        assert_eq!(value["nickname"], "eva");
+
+        let disabled = Router::new()
+            .route("/events", get(stream))
+            .with_state(Events::new(EventsConfig::default()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/events", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, disabled).await });
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), 404);
+    }
+}
@@ -0,0 +1,877 @@
+//! Fan-out of the broadcast messages to the connected clients.
+//!
+//! The connections don't deliver the messages themselves. Every connection registers in [`FanOut`] and gets a
+//! [`Connection`] handle for its reader and an [`Outbox`] for its writer. A broadcast message is queued in the
+//! connection's own queue and a pool of delivery workers takes the queues from a shared injector. A worker drains
+//! up to [`MAX_JOBS_PER_TURN`] messages of one connection, so the messages of one sender stay in order, serializes
+//! every message once and pushes the frame to the outboxes of all the other clients in the room of the message. Idle
+//! workers steal the queued connections from the busy ones. The announcements of the server go to every client, a
+//! [`MessageType::Whisper`] only to the clients identified as its recipient, in any room.
+//!
+//! The writer of every connection drains its outbox in batches and writes the small frames with a single vectored
+//! write. A client whose outbox is full can't keep up with the chat and gets disconnected. The checksum of every frame
+//! is computed once too, the writer of a client verifying the checksums puts it into the header of the frame. A big
+//! message is compressed once as well, by the first writer of a client decompressing the frames.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, IoSlice};
+use std::iter;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use crossbeam_deque::{Injector, Stealer, Worker};
+use log::{error, warn};
+use parking_lot::{Mutex, RwLock};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::Notify;
+
+use chat::codec::{self, CHECKSUM_FLAG, COMPRESSED_FLAG, COMPRESSION_THRESHOLD, LENGTH_PREFIX};
+use chat::{Message, MessageError, MessageType, WireFormat};
+
+use crate::log_broadcasting;
+use crate::memory::InFlight;
+use crate::metrics::{DELIVERY_LATENCY, FANOUT_PENDING, SLOW_CLIENTS};
+
+/// Default number of the delivery workers.
+pub const WORKERS: usize = 4;
+/// Number of frames waiting for a client in each priority lane before it is disconnected as too slow.
+pub const OUTBOX_SIZE: usize = 1024;
+/// Maximal number of messages of one connection delivered before the worker moves to another one.
+pub const MAX_JOBS_PER_TURN: usize = 16;
+/// Maximal number of frames written at once.
+pub const MAX_BATCH_FRAMES: usize = 64;
+/// Maximal size of the frames written at once, bigger frames are written alone.
+pub const MAX_BATCH_BYTES: usize = 64 * 1024;
+
+const DIRECT_SIZE: usize = 16;
+
+/// Settings from the `[delivery]` section of the server config.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeliveryConfig {
+    pub workers: usize,
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> Self {
+        DeliveryConfig { workers: WORKERS }
+    }
+}
+
+/// Priority lane of the delivered message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lane {
+    High,
+    Low,
+}
+
+/// Serialized message ready to be written, holding the in-flight reservation of its attachment.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    bytes: Arc<[u8]>,
+    /// CRC32 of the serialized message.
+    checksum: u32,
+    /// The compressed message of a big frame, compressed by the first writer which needs it.
+    compressed: Arc<OnceLock<Option<Compressed>>>,
+    _in_flight: Option<Arc<InFlight>>,
+}
+
+/// Compressed message of a frame.
+#[derive(Debug)]
+struct Compressed {
+    bytes: Vec<u8>,
+    /// CRC32 of the compressed message.
+    checksum: u32,
+}
+
+impl Frame {
+    fn new(message: &Message, in_flight: Option<Arc<InFlight>>) -> Result<Frame> {
+        let bytes: Arc<[u8]> = message.frame()?.into();
+        Ok(Frame {
+            checksum: crc32fast::hash(&bytes[LENGTH_PREFIX..]),
+            bytes,
+            compressed: Arc::new(OnceLock::new()),
+            _in_flight: in_flight,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns the compressed message, `None` for a small message or one which doesn't compress.
+    fn compressed(&self) -> Option<&Compressed> {
+        self.compressed
+            .get_or_init(|| {
+                let message = &self.bytes[LENGTH_PREFIX..];
+                if message.len() < COMPRESSION_THRESHOLD {
+                    return None;
+                }
+                let bytes = codec::compress(message)?;
+                let checksum = crc32fast::hash(&bytes);
+                Some(Compressed { bytes, checksum })
+            })
+            .as_ref()
+    }
+
+    /// Returns the header of the frame for the client, the flagged length prefix followed by the checksum, with the
+    /// length of the header used, and the message.
+    fn parts(&self, checksum: bool, compression: bool) -> ([u8; 8], usize, &[u8]) {
+        let (mut flags, message, crc) = match self.compressed().filter(|_| compression) {
+            Some(compressed) => (COMPRESSED_FLAG, &compressed.bytes[..], compressed.checksum),
+            None => (0, &self.bytes[LENGTH_PREFIX..], self.checksum),
+        };
+        let mut header_len = LENGTH_PREFIX;
+        if checksum {
+            flags |= CHECKSUM_FLAG;
+            header_len += codec::CHECKSUM_LEN;
+        }
+        let mut header = [0; 8];
+        header[..4].copy_from_slice(&(message.len() as u32 | flags).to_be_bytes());
+        header[4..].copy_from_slice(&crc.to_be_bytes());
+        (header, header_len, message)
+    }
+}
+
+/// Message waiting for the fan-out.
+struct Job {
+    message: Message,
+    lane: Lane,
+    in_flight: InFlight,
+    received: Instant,
+}
+
+/// Messages of one connection waiting for the fan-out.
+struct Source {
+    addr: SocketAddr,
+    /// The queued messages and whether the queue is in the injector or taken by a worker.
+    jobs: Mutex<(VecDeque<Job>, bool)>,
+}
+
+/// Outbox queues of a client, the delivery workers write to them.
+struct Mailbox {
+    high: mpsc::Sender<Frame>,
+    low: mpsc::Sender<Frame>,
+    /// Nickname of the identified client, shown by the REPL.
+    nickname: Option<String>,
+    /// Rooms joined by the client, it receives the messages of the other clients sent to them.
+    rooms: HashSet<String>,
+}
+
+impl Mailbox {
+    fn lane(&self, lane: Lane) -> &mpsc::Sender<Frame> {
+        match lane {
+            Lane::High => &self.high,
+            Lane::Low => &self.low,
+        }
+    }
+
+    /// Returns true if the client receives the message of another client.
+    fn receives(&self, message: &Message) -> bool {
+        match &message.message {
+            MessageType::Whisper { to, .. } => self.nickname.as_deref() == Some(to.as_str()),
+            _ => self.rooms.contains(&message.room),
+        }
+    }
+}
+
+/// Frames waiting in the outbox of a registered client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueDepth {
+    pub addr: SocketAddr,
+    pub nickname: Option<String>,
+    pub high: usize,
+    pub low: usize,
+}
+
+fn queued(sender: &mpsc::Sender<Frame>) -> usize {
+    sender.max_capacity() - sender.capacity()
+}
+
+/// Handle of the delivery worker pool.
+#[derive(Clone)]
+pub struct FanOut {
+    injector: Arc<Injector<Arc<Source>>>,
+    wakeup: Arc<Notify>,
+    recipients: Arc<RwLock<HashMap<SocketAddr, Mailbox>>>,
+}
+
+impl FanOut {
+    /// Spawns the delivery workers.
+    pub fn spawn(config: DeliveryConfig) -> FanOut {
+        let fan_out = FanOut {
+            injector: Arc::new(Injector::new()),
+            wakeup: Arc::new(Notify::new()),
+            recipients: Arc::new(RwLock::new(HashMap::new())),
+        };
+        let locals: Vec<Worker<Arc<Source>>> = (0..config.workers.max(1))
+            .map(|_| Worker::new_fifo())
+            .collect();
+        let stealers: Arc<[Stealer<Arc<Source>>]> = locals.iter().map(Worker::stealer).collect();
+        for local in locals {
+            tokio::spawn(deliver(fan_out.clone(), local, stealers.clone()));
+        }
+        fan_out
+    }
+
+    /// Registers the client as a recipient of the broadcast messages.
+    ///
+    /// # Returns
+    ///
+    /// The [`Connection`] for the reader of the client and the [`Outbox`] for its writer. The client stays
+    /// registered until the [`Connection`] is dropped.
+    pub fn register(&self, addr: SocketAddr) -> (Connection, Outbox) {
+        let (high, high_receive) = mpsc::channel(OUTBOX_SIZE);
+        let (low, low_receive) = mpsc::channel(OUTBOX_SIZE);
+        let (direct, direct_receive) = mpsc::channel(DIRECT_SIZE);
+        let mailbox = Mailbox {
+            high,
+            low,
+            nickname: None,
+            rooms: HashSet::from([chat::DEFAULT_ROOM.to_string()]),
+        };
+        self.recipients.write().insert(addr, mailbox);
+        let source = Arc::new(Source {
+            addr,
+            jobs: Mutex::new((VecDeque::new(), false)),
+        });
+        let connection = Connection {
+            fan_out: self.clone(),
+            source,
+            direct,
+        };
+        let outbox = Outbox {
+            direct: direct_receive,
+            high: high_receive,
+            low: low_receive,
+            held: None,
+        };
+        (connection, outbox)
+    }
+
+    /// Delivers the message of the server to all the clients, ahead of the queued messages of the clients.
+    ///
+    /// The message is stamped with the server time unless it already has a timestamp.
+    pub fn announce(&self, mut message: Message) {
+        message.timestamp.get_or_insert_with(chat::unix_millis);
+        let job = Job {
+            message,
+            lane: Lane::High,
+            in_flight: InFlight::reserve(0, 0).expect("empty reservation always fits"),
+            received: Instant::now(),
+        };
+        FANOUT_PENDING.inc();
+        self.fan_out(None, job);
+    }
+
+    /// Delivers the message of the server only to the clients identified as the nickname, ahead of their queued
+    /// messages.
+    ///
+    /// # Returns
+    ///
+    /// The number of the connections the message was queued for, zero if the nickname is offline.
+    pub fn notify(&self, nickname: &str, mut message: Message) -> usize {
+        message.timestamp.get_or_insert_with(chat::unix_millis);
+        let frame = match Frame::new(&message, None) {
+            Ok(frame) => frame,
+            Err(err_msg) => {
+                error!("Serializing message error: {:?}", err_msg);
+                return 0;
+            }
+        };
+        self.recipients
+            .read()
+            .values()
+            .filter(|mailbox| mailbox.nickname.as_deref() == Some(nickname))
+            .filter(|mailbox| mailbox.high.try_send(frame.clone()).is_ok())
+            .count()
+    }
+
+    /// Returns the sorted nicknames of the identified clients in the room.
+    pub fn members(&self, room: &str) -> Vec<String> {
+        let mut members: Vec<String> = self
+            .recipients
+            .read()
+            .values()
+            .filter(|mailbox| mailbox.rooms.contains(room))
+            .filter_map(|mailbox| mailbox.nickname.clone())
+            .collect();
+        members.sort();
+        members.dedup();
+        members
+    }
+
+    /// Returns the registered clients and the frames waiting in their outboxes, sorted by the address.
+    pub fn queue_depths(&self) -> Vec<QueueDepth> {
+        let mut depths: Vec<QueueDepth> = self
+            .recipients
+            .read()
+            .iter()
+            .map(|(addr, mailbox)| QueueDepth {
+                addr: *addr,
+                nickname: mailbox.nickname.clone(),
+                high: queued(&mailbox.high),
+                low: queued(&mailbox.low),
+            })
+            .collect();
+        depths.sort_by_key(|depth| depth.addr);
+        depths
+    }
+
+    /// Delivers the message to all the clients except its sender, the message of a client only to the clients in its
+    /// room or to the recipient of the whisper.
+    fn fan_out(&self, sender: Option<SocketAddr>, job: Job) {
+        FANOUT_PENDING.dec();
+        let frame = match Frame::new(&job.message, Some(Arc::new(job.in_flight))) {
+            Ok(frame) => frame,
+            Err(err_msg) => {
+                error!("Serializing message error: {:?}", err_msg);
+                return;
+            }
+        };
+        let mut too_slow = Vec::new();
+        for (recipient, mailbox) in self.recipients.read().iter() {
+            if sender == Some(*recipient) {
+                continue;
+            }
+            if sender.is_some() && !mailbox.receives(&job.message) {
+                continue;
+            }
+            if let Some(sender) = &sender {
+                log_broadcasting(&job.message, sender, recipient);
+            }
+            if let Err(TrySendError::Full(_)) = mailbox.lane(job.lane).try_send(frame.clone()) {
+                too_slow.push(*recipient);
+            }
+        }
+        for recipient in too_slow {
+            warn!("Client {:?} can't keep up, disconnecting it.", recipient);
+            SLOW_CLIENTS.inc();
+            self.recipients.write().remove(&recipient);
+        }
+        DELIVERY_LATENCY.observe(job.received.elapsed().as_secs_f64());
+    }
+}
+
+/// Returns the next connection with queued messages, from the own queue of the worker, the injector or the other
+/// workers.
+fn find_source(
+    local: &Worker<Arc<Source>>,
+    injector: &Injector<Arc<Source>>,
+    stealers: &[Stealer<Arc<Source>>],
+) -> Option<Arc<Source>> {
+    local.pop().or_else(|| {
+        iter::repeat_with(|| {
+            injector
+                .steal_batch_and_pop(local)
+                .or_else(|| stealers.iter().map(Stealer::steal).collect())
+        })
+        .find(|stolen| !stolen.is_retry())
+        .and_then(|stolen| stolen.success())
+    })
+}
+
+async fn deliver(
+    fan_out: FanOut,
+    local: Worker<Arc<Source>>,
+    stealers: Arc<[Stealer<Arc<Source>>]>,
+) {
+    loop {
+        let Some(source) = find_source(&local, &fan_out.injector, &stealers) else {
+            fan_out.wakeup.notified().await;
+            continue;
+        };
+        if !local.is_empty() || !fan_out.injector.is_empty() {
+            fan_out.wakeup.notify_one();
+        }
+        let mut delivered = 0;
+        loop {
+            let mut jobs = source.jobs.lock();
+            if delivered == MAX_JOBS_PER_TURN {
+                drop(jobs);
+                fan_out.injector.push(source);
+                fan_out.wakeup.notify_one();
+                break;
+            }
+            let Some(job) = jobs.0.pop_front() else {
+                jobs.1 = false;
+                break;
+            };
+            drop(jobs);
+            fan_out.fan_out(Some(source.addr), job);
+            delivered += 1;
+        }
+        tokio::task::yield_now().await;
+    }
+}
+
+/// Handle of a registered client for its reader.
+pub struct Connection {
+    fan_out: FanOut,
+    source: Arc<Source>,
+    direct: mpsc::Sender<Message>,
+}
+
+impl Connection {
+    /// Queues the message for the delivery to all the other clients.
+    ///
+    /// # Arguments
+    ///
+    /// - `message` - The delivered message.
+    /// - `lane` - The priority lane of the message.
+    /// - `in_flight` - The reservation of the attachment data, released when the message is written to all clients.
+    /// - `received` - The time the message was received, used for the latency metrics.
+    pub fn broadcast(&self, message: Message, lane: Lane, in_flight: InFlight, received: Instant) {
+        let job = Job {
+            message,
+            lane,
+            in_flight,
+            received,
+        };
+        FANOUT_PENDING.inc();
+        let mut jobs = self.source.jobs.lock();
+        jobs.0.push_back(job);
+        if !jobs.1 {
+            jobs.1 = true;
+            drop(jobs);
+            self.fan_out.injector.push(self.source.clone());
+            self.fan_out.wakeup.notify_one();
+        }
+    }
+
+    /// Sends the message only to this client, ahead of the broadcast messages.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the writer of the client has stopped.
+    pub async fn reply(&self, message: Message) -> Result<()> {
+        self.direct
+            .send(message)
+            .await
+            .map_err(|_| anyhow!("Writer of client {:?} stopped!", self.source.addr))
+    }
+
+    /// Names the client in the [`FanOut::queue_depths`].
+    pub fn identify(&self, nickname: &str) {
+        if let Some(mailbox) = self.fan_out.recipients.write().get_mut(&self.source.addr) {
+            mailbox.nickname = Some(nickname.to_string());
+        }
+    }
+
+    /// Adds the room to the rooms of the client.
+    ///
+    /// # Returns
+    ///
+    /// False if the client was in the room already.
+    pub fn join(&self, room: &str) -> bool {
+        self.fan_out
+            .recipients
+            .write()
+            .get_mut(&self.source.addr)
+            .is_some_and(|mailbox| mailbox.rooms.insert(room.to_string()))
+    }
+
+    /// Removes the room from the rooms of the client.
+    ///
+    /// # Returns
+    ///
+    /// False if the client wasn't in the room.
+    pub fn leave(&self, room: &str) -> bool {
+        self.fan_out
+            .recipients
+            .write()
+            .get_mut(&self.source.addr)
+            .is_some_and(|mailbox| mailbox.rooms.remove(room))
+    }
+
+    /// Returns true if the client has joined the room.
+    pub fn in_room(&self, room: &str) -> bool {
+        self.fan_out
+            .recipients
+            .read()
+            .get(&self.source.addr)
+            .is_some_and(|mailbox| mailbox.rooms.contains(room))
+    }
+
+    /// Returns the sender of the replies to this client which doesn't keep the client's writer running.
+    pub fn replies(&self) -> mpsc::WeakSender<Message> {
+        self.direct.downgrade()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.fan_out.recipients.write().remove(&self.source.addr);
+    }
+}
+
+/// Frames waiting to be written to a client.
+pub struct Outbox {
+    direct: mpsc::Receiver<Message>,
+    high: mpsc::Receiver<Frame>,
+    low: mpsc::Receiver<Frame>,
+    /// Frame which didn't fit into the previous batch.
+    held: Option<Frame>,
+}
+
+impl Outbox {
+    /// Waits for the next batch of frames, direct replies first, then the high and the low priority lane.
+    ///
+    /// The batch has at most [`MAX_BATCH_FRAMES`] frames of [`MAX_BATCH_BYTES`], unless its first frame is bigger.
+    ///
+    /// # Returns
+    ///
+    /// False when the client was unregistered.
+    pub async fn next_batch(&mut self, batch: &mut Vec<Frame>) -> bool {
+        let first = match self.held.take() {
+            Some(frame) => frame,
+            None => {
+                let received = tokio::select! {
+                    biased;
+                    Some(message) = self.direct.recv() => Some(Frame::new(&message, None)),
+                    frame = self.high.recv() => frame.map(Ok),
+                    frame = self.low.recv() => frame.map(Ok),
+                };
+                match received {
+                    Some(Ok(frame)) => frame,
+                    Some(Err(err_msg)) => {
+                        error!("Serializing message error: {:?}", err_msg);
+                        return true;
+                    }
+                    None => return false,
+                }
+            }
+        };
+        let mut size = first.len();
+        batch.push(first);
+        while batch.len() < MAX_BATCH_FRAMES && size < MAX_BATCH_BYTES {
+            let frame = match self.direct.try_recv() {
+                Ok(message) => match Frame::new(&message, None) {
+                    Ok(frame) => frame,
+                    Err(_) => continue,
+                },
+                Err(_) => match self.high.try_recv().or_else(|_| self.low.try_recv()) {
+                    Ok(frame) => frame,
+                    Err(_) => break,
+                },
+            };
+            if size + frame.len() > MAX_BATCH_BYTES {
+                self.held = Some(frame);
+                break;
+            }
+            size += frame.len();
+            batch.push(frame);
+        }
+        true
+    }
+}
+
+/// Writes the frames with vectored writes, usually a single one.
+///
+/// # Arguments
+///
+/// * `writer` - The connection of the client.
+/// * `batch` - The frames to write.
+/// * `checksum` - Whether the client verifies the checksums, see [`chat::codec`].
+/// * `compression` - Whether the client decompresses the frames, the big messages are sent compressed.
+///
+/// # Errors
+///
+/// This function will return an error if the writer fails.
+pub async fn write_batch<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    batch: &[Frame],
+    checksum: bool,
+    compression: bool,
+) -> io::Result<()> {
+    let plain = !checksum && !compression;
+    let parts: Vec<([u8; 8], usize, &[u8])> = match plain {
+        true => Vec::new(),
+        false => batch
+            .iter()
+            .map(|frame| frame.parts(checksum, compression))
+            .collect(),
+    };
+    let segments: Vec<&[u8]> = match plain {
+        true => batch.iter().map(|frame| &frame.bytes[..]).collect(),
+        false => parts
+            .iter()
+            .flat_map(|(header, header_len, message)| [&header[..*header_len], *message])
+            .collect(),
+    };
+    let mut first = 0;
+    let mut offset = 0;
+    while first < segments.len() {
+        let slices: Vec<IoSlice> = iter::once(&segments[first][offset..])
+            .chain(segments[first + 1..].iter().copied())
+            .map(IoSlice::new)
+            .collect();
+        let mut written = writer.write_vectored(&slices).await?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        while first < segments.len() && written >= segments[first].len() - offset {
+            written -= segments[first].len() - offset;
+            offset = 0;
+            first += 1;
+        }
+        offset += written;
+    }
+    writer.flush().await
+}
+
+/// Writes the frames re-encoded as JSON lines, for a client connected to the JSON listener.
+///
+/// The frames stay serialized once as bincode for the other clients, only the JSON clients pay for the conversion.
+///
+/// # Errors
+///
+/// This function will return an error if the writer fails.
+pub async fn write_json_batch<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    batch: &[Frame],
+) -> io::Result<()> {
+    let mut lines = Vec::new();
+    for frame in batch {
+        let line = Message::deserialized_message(&frame.bytes[LENGTH_PREFIX..])
+            .map_err(MessageError::from)
+            .and_then(|message| WireFormat::Json.serialize(&message));
+        match line {
+            Ok(line) => {
+                lines.extend_from_slice(&line);
+                lines.push(b'\n');
+            }
+            Err(err_msg) => error!("Converting a frame to JSON failed: {}", err_msg),
+        }
+    }
+    writer.write_all(&lines).await?;
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    async fn next_text(outbox: &mut Outbox) -> Vec<String> {
+        let mut batch = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), outbox.next_batch(&mut batch))
+            .await
+            .unwrap();
+        let mut written = Vec::new();
+        write_batch(&mut written, &batch, false, false)
+            .await
+            .unwrap();
+        let mut reader = &written[..];
+        let mut texts = Vec::new();
+        while !reader.is_empty() {
+            match Message::read(&mut reader).await.unwrap().message {
+                MessageType::Text(text) | MessageType::Whisper { text, .. } => texts.push(text),
+                other => panic!("unexpected message {other:?}"),
+            }
+        }
+        texts
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_fan_out_in_order() {
+        let fan_out = FanOut::spawn(DeliveryConfig { workers: 3 });
+        let (sender, mut sender_outbox) = fan_out.register(addr(1));
+        let (_receiver, mut outbox) = fan_out.register(addr(2));
+        for i in 0..100 {
+            let message = Message::from("slava", MessageType::text(i.to_string()));
+            let in_flight = InFlight::reserve(0, 0).unwrap();
+            sender.broadcast(message, Lane::High, in_flight, Instant::now());
+        }
+        let mut texts = Vec::new();
+        while texts.len() < 100 {
+            texts.extend(next_text(&mut outbox).await);
+        }
+        let expected: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+        assert_eq!(texts, expected);
+
+        sender
+            .reply(Message::from("server", MessageType::text("direct")))
+            .await
+            .unwrap();
+        assert_eq!(next_text(&mut sender_outbox).await, ["direct"]);
+        drop(sender);
+        assert!(!sender_outbox.next_batch(&mut Vec::new()).await);
+    }
+
+    #[tokio::test]
+    async fn test_write_json() {
+        let message = Message::from("slava", MessageType::text("hello"));
+        let batch = [
+            Frame::new(&message, None).unwrap(),
+            Frame::new(&message, None).unwrap(),
+        ];
+        let mut written = Vec::new();
+        write_json_batch(&mut written, &batch).await.unwrap();
+        let line = WireFormat::Json.serialize(&message).unwrap();
+        assert_eq!(written, [&line[..], b"\n", &line[..], b"\n"].concat());
+    }
+
+    #[tokio::test]
+    async fn test_write_checksummed() {
+        use chat::codec::MessageCodec;
+        use tokio_util::codec::Decoder;
+
+        let messages: Vec<Message> = ["hello", "world"]
+            .into_iter()
+            .map(|text| Message::from("slava", MessageType::text(text)))
+            .collect();
+        let batch: Vec<Frame> = messages
+            .iter()
+            .map(|message| Frame::new(message, None).unwrap())
+            .collect();
+        let mut written = Vec::new();
+        write_batch(&mut written, &batch, true, false)
+            .await
+            .unwrap();
+        let mut buffer = written[..].into();
+        let mut codec = MessageCodec::default();
+        for message in &messages {
+            assert_eq!(
+                &codec.decode(&mut buffer).unwrap().unwrap().unwrap(),
+                message
+            );
+        }
+        assert!(codec.received_checksum());
+        assert!(buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_write_compressed() {
+        use chat::codec::MessageCodec;
+        use tokio_util::codec::Decoder;
+
+        let big = Message::from("slava", MessageType::text("meow ".repeat(20_000)));
+        let small = Message::from("slava", MessageType::text("hello"));
+        let batch = [
+            Frame::new(&big, None).unwrap(),
+            Frame::new(&small, None).unwrap(),
+        ];
+        let mut written = Vec::new();
+        write_batch(&mut written, &batch, true, true).await.unwrap();
+        assert!(written.len() < batch[0].len() / 10);
+        let mut buffer = written[..].into();
+        let mut codec = MessageCodec::default();
+        assert_eq!(codec.decode(&mut buffer).unwrap().unwrap().unwrap(), big);
+        assert!(codec.received_compressed());
+        assert_eq!(codec.decode(&mut buffer).unwrap().unwrap().unwrap(), small);
+        assert!(buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_queue_depths() {
+        // The announcements go to the outboxes right away and nobody reads them.
+        let fan_out = FanOut::spawn(DeliveryConfig { workers: 1 });
+        let (first, _first_outbox) = fan_out.register(addr(2));
+        let (_second, _second_outbox) = fan_out.register(addr(1));
+        first.identify("slava");
+        fan_out.announce(Message::from("server", MessageType::text("hello")));
+        fan_out.announce(Message::from("server", MessageType::text("again")));
+        let depths = fan_out.queue_depths();
+        assert_eq!(depths.len(), 2);
+        assert_eq!(
+            (depths[0].addr, depths[0].nickname.as_deref()),
+            (addr(1), None)
+        );
+        assert_eq!(depths[1].nickname.as_deref(), Some("slava"));
+        assert_eq!((depths[1].high, depths[1].low), (2, 0));
+    }
+
+    #[tokio::test]
+    async fn test_notify() {
+        let fan_out = FanOut::spawn(DeliveryConfig::default());
+        let (first, mut first_outbox) = fan_out.register(addr(1));
+        let (second, _second_outbox) = fan_out.register(addr(2));
+        let (other, _other_outbox) = fan_out.register(addr(3));
+        first.identify("slava");
+        second.identify("slava");
+        other.identify("eva");
+        let notice = Message::system(MessageType::text("too many files"));
+        assert_eq!(fan_out.notify("slava", notice.clone()), 2);
+        assert_eq!(fan_out.notify("nobody", notice), 0);
+        assert_eq!(next_text(&mut first_outbox).await, ["too many files"]);
+        let depths = fan_out.queue_depths();
+        assert_eq!((depths[1].high, depths[2].high), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn test_rooms() {
+        let fan_out = FanOut::spawn(DeliveryConfig::default());
+        let (slava, _slava_outbox) = fan_out.register(addr(1));
+        let (eva, mut eva_outbox) = fan_out.register(addr(2));
+        let (adam, mut adam_outbox) = fan_out.register(addr(3));
+        slava.identify("slava");
+        eva.identify("eva");
+        assert!(slava.join("rust"));
+        assert!(!slava.join("rust"));
+        assert!(eva.join("rust"));
+        assert!(adam.leave(chat::DEFAULT_ROOM));
+        assert!(!adam.leave("rust"));
+        assert!(eva.in_room("rust") && !adam.in_room("rust"));
+        assert_eq!(fan_out.members("rust"), ["eva", "slava"]);
+        assert_eq!(fan_out.members(chat::DEFAULT_ROOM), ["eva", "slava"]);
+
+        for (room, text) in [("rust", "borrowck"), (chat::DEFAULT_ROOM, "lunch")] {
+            let message = Message::in_room(room, "slava", MessageType::text(text));
+            let in_flight = InFlight::reserve(0, 0).unwrap();
+            slava.broadcast(message, Lane::High, in_flight, Instant::now());
+        }
+        fan_out.announce(Message::system(MessageType::text("restart")));
+        let mut texts = Vec::new();
+        while texts.len() < 3 {
+            texts.extend(next_text(&mut eva_outbox).await);
+        }
+        texts.sort();
+        assert_eq!(texts, ["borrowck", "lunch", "restart"]);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(next_text(&mut adam_outbox).await, ["restart"]);
+    }
+
+    #[tokio::test]
+    async fn test_whisper() {
+        let fan_out = FanOut::spawn(DeliveryConfig::default());
+        let (slava, _slava_outbox) = fan_out.register(addr(1));
+        let (eva, mut eva_outbox) = fan_out.register(addr(2));
+        let (adam, mut adam_outbox) = fan_out.register(addr(3));
+        slava.identify("slava");
+        eva.identify("eva");
+        adam.identify("adam");
+        assert!(eva.leave(chat::DEFAULT_ROOM));
+
+        let whisper = Message::from("slava", MessageType::whisper("eva", "psst"));
+        let in_flight = InFlight::reserve(0, 0).unwrap();
+        slava.broadcast(whisper, Lane::High, in_flight, Instant::now());
+        let message = Message::from("slava", MessageType::text("hi all"));
+        let in_flight = InFlight::reserve(0, 0).unwrap();
+        slava.broadcast(message, Lane::High, in_flight, Instant::now());
+        assert_eq!(next_text(&mut eva_outbox).await, ["psst"]);
+        assert_eq!(next_text(&mut adam_outbox).await, ["hi all"]);
+    }
+
+    #[tokio::test]
+    async fn test_batches_are_limited() {
+        let fan_out = FanOut::spawn(DeliveryConfig::default());
+        let (sender, _outbox) = fan_out.register(addr(1));
+        let (_receiver, mut outbox) = fan_out.register(addr(2));
+        let big = "x".repeat(MAX_BATCH_BYTES / 2);
+        for text in ["a", big.as_str(), big.as_str(), "b"] {
+            let message = Message::from("slava", MessageType::text(text));
+            let in_flight = InFlight::reserve(0, 0).unwrap();
+            sender.broadcast(message, Lane::High, in_flight, Instant::now());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(next_text(&mut outbox).await, ["a", big.as_str()]);
+        assert_eq!(next_text(&mut outbox).await, [big.as_str(), "b"]);
+    }
+}
@@ -0,0 +1,237 @@
+//! Import of chat history exported from other chat applications.
+//!
+//! Usage:
+//!
+//! ```sh
+//! server import-history --format whatsapp|irc-log|json --file dump.txt
+//! ```
+//!
+//! The messages are stored in the order of the export, so the database ids follow the original conversation.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use log::info;
+use serde_json::Value;
+
+use chat::{Message, MessageType};
+
+use crate::db::Database;
+use crate::persistence::Record;
+
+/// Supported formats of the chat export.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportFormat {
+    /// WhatsApp "Export chat" text file, e.g. `31/12/2023, 22:15 - alice: Hello`.
+    Whatsapp,
+    /// IRC log, e.g. `[22:15] <alice> Hello`.
+    IrcLog,
+    /// JSON array of objects with `nickname` and `message` fields.
+    Json,
+}
+
+impl FromStr for ImportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "whatsapp" => Ok(ImportFormat::Whatsapp),
+            "irc-log" => Ok(ImportFormat::IrcLog),
+            "json" => Ok(ImportFormat::Json),
+            _ => Err(anyhow!("Unknown import format: {s}!")),
+        }
+    }
+}
+
+/// Runs the `import-history` subcommand.
+///
+/// # Arguments
+///
+/// - `arguments` - Arguments following the subcommand name.
+///
+/// # Errors
+///
+/// This function will return an error if the arguments are invalid, the export can't be read or parsed,
+/// or the messages can't be stored.
+pub async fn run_import(database: &Database, arguments: &[String]) -> Result<()> {
+    let mut format = None;
+    let mut file = None;
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        let value = arguments
+            .next()
+            .ok_or(anyhow!("Missing value for {argument}!"))?;
+        match argument.as_str() {
+            "--format" => format = Some(ImportFormat::from_str(value)?),
+            "--file" => file = Some(value),
+            _ => return Err(anyhow!("Unknown argument: {argument}!")),
+        }
+    }
+    let format = format.ok_or(anyhow!("Missing --format argument!"))?;
+    let file = file.ok_or(anyhow!("Missing --file argument!"))?;
+
+    let input = tokio::fs::read_to_string(file)
+        .await
+        .with_context(|| format!("Reading {file} failed!"))?;
+    let messages = parse_export(&input, format)?;
+    for message in &messages {
+        database.insert_message(&Record::new(message)).await?;
+    }
+    info!("Imported {} messages from {}.", messages.len(), file);
+    Ok(())
+}
+
+/// Parses the chat export to text messages.
+///
+/// Lines which don't start a new message are appended to the previous one, system lines without a sender are skipped.
+pub fn parse_export(input: &str, format: ImportFormat) -> Result<Vec<Message>> {
+    let parse_line = match format {
+        ImportFormat::Whatsapp => parse_whatsapp_line,
+        ImportFormat::IrcLog => parse_irc_line,
+        ImportFormat::Json => return parse_json(input),
+    };
+    let mut messages: Vec<(String, String)> = Vec::new();
+    for line in input.lines() {
+        match parse_line(line) {
+            Some(LineKind::Message(nickname, text)) => messages.push((nickname, text)),
+            Some(LineKind::Notice) => (),
+            None => {
+                if let Some((_, text)) = messages.last_mut() {
+                    text.push('\n');
+                    text.push_str(line);
+                }
+            }
+        }
+    }
+    Ok(messages
+        .into_iter()
+        .map(|(nickname, text)| imported(nickname, text))
+        .collect())
+}
+
+/// Creates the imported text message without a timestamp, the time of the import isn't the time it was sent.
+fn imported<S: AsRef<str>>(nickname: S, text: S) -> Message {
+    Message {
+        timestamp: None,
+        ..Message::from(nickname, MessageType::text(text))
+    }
+}
+
+enum LineKind {
+    Message(String, String),
+    Notice,
+}
+
+fn parse_whatsapp_line(line: &str) -> Option<LineKind> {
+    let line = line.trim_start_matches('\u{200e}');
+    if !line.starts_with(|c: char| c.is_ascii_digit() || c == '[') {
+        return None;
+    }
+    let (timestamp, rest) = match line.strip_prefix('[') {
+        Some(line) => line.split_once("] ")?,
+        None => line.split_once(" - ")?,
+    };
+    if !timestamp.contains(',') {
+        return None;
+    }
+    Some(match rest.split_once(": ") {
+        Some((nickname, text)) => LineKind::Message(nickname.to_string(), text.to_string()),
+        None => LineKind::Notice,
+    })
+}
+
+fn parse_irc_line(line: &str) -> Option<LineKind> {
+    let message = line
+        .split_once('<')
+        .and_then(|(_, rest)| rest.split_once("> "));
+    Some(match message {
+        Some((nickname, text)) => {
+            let nickname = nickname.trim_start_matches(['@', '+']);
+            LineKind::Message(nickname.to_string(), text.to_string())
+        }
+        None => LineKind::Notice,
+    })
+}
+
+fn parse_json(input: &str) -> Result<Vec<Message>> {
+    let value: Value = serde_json::from_str(input).context("Parsing JSON export failed!")?;
+    let entries = value
+        .as_array()
+        .ok_or(anyhow!("JSON export must be an array!"))?;
+    entries
+        .iter()
+        .map(|entry| {
+            let nickname = entry["nickname"]
+                .as_str()
+                .ok_or(anyhow!("Missing nickname in {entry}!"))?;
+            let text = entry["message"]
+                .as_str()
+                .ok_or(anyhow!("Missing message in {entry}!"))?;
+            Ok(imported(nickname, text))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(messages: Vec<Message>) -> Vec<(String, MessageType)> {
+        messages
+            .into_iter()
+            .map(|m| (m.nickname, m.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_whatsapp() {
+        let input = "31/12/2023, 22:15 - Messages are end-to-end encrypted.\n\
+                     31/12/2023, 22:15 - alice: Hello\n\
+                     second line\n\
+                     [31.12.23, 22:16:03] bob: Hi: there";
+        let messages = parse_export(input, ImportFormat::Whatsapp).unwrap();
+        assert_eq!(
+            texts(messages),
+            vec![
+                ("alice".into(), MessageType::text("Hello\nsecond line")),
+                ("bob".into(), MessageType::text("Hi: there")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_irc_log() {
+        let input = "[22:15] *** alice has joined #rust\n\
+                     [22:15] <alice> Hello\n\
+                     2023-12-31 22:16:03 <@bob> Hi";
+        let messages = parse_export(input, ImportFormat::IrcLog).unwrap();
+        assert_eq!(
+            texts(messages),
+            vec![
+                ("alice".into(), MessageType::text("Hello")),
+                ("bob".into(), MessageType::text("Hi")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let input = r#"[{"nickname": "alice", "message": "Hello"}]"#;
+        let messages = parse_export(input, ImportFormat::Json).unwrap();
+        assert_eq!(messages[0].timestamp, None);
+        assert_eq!(
+            texts(messages),
+            vec![("alice".into(), MessageType::text("Hello"))]
+        );
+        assert!(parse_export(r#"[{"nickname": "alice"}]"#, ImportFormat::Json).is_err());
+    }
+
+    #[test]
+    fn test_import_format_from_str() {
+        assert_eq!(
+            ImportFormat::from_str("irc-log").unwrap(),
+            ImportFormat::IrcLog
+        );
+        assert!(ImportFormat::from_str("telegram").is_err());
+    }
+}
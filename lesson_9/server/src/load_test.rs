@@ -0,0 +1,231 @@
+//! # Load test
+//!
+//! Connects many clients to a running chat server, lets every client send text messages and measures how fast the
+//! server delivers them to all the others.
+//!
+//! # Arguments:
+//!
+//! - **hostname** default: localhost
+//! - **port** default: 11111
+//! - **--clients** N number of the connected clients, default: 50
+//! - **--messages** M number of messages sent by every client, default: 20
+//! - **--timeout** S seconds to wait for the deliveries, default: 30
+//!
+//! The server limits the connections from one address (`[access] max_per_ip`), raise it to test more clients.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio_util::codec::Framed;
+
+use chat::codec::{self, MessageCodec};
+use chat::{Address, Message, MessageType};
+
+const CLIENTS: usize = 50;
+const MESSAGES: usize = 20;
+const TIMEOUT: Duration = Duration::from_secs(30);
+const PREFIX: &str = "load-test";
+
+struct Options {
+    address: Address,
+    clients: usize,
+    messages: usize,
+    timeout: Duration,
+}
+
+fn parse_options(arguments: &[String]) -> Result<Options> {
+    let mut positional = Vec::new();
+    let mut options = Options {
+        address: Address::default(),
+        clients: CLIENTS,
+        messages: MESSAGES,
+        timeout: TIMEOUT,
+    };
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        if !argument.starts_with("--") {
+            positional.push(argument.clone());
+            continue;
+        }
+        let value: u64 = arguments
+            .next()
+            .and_then(|value| value.parse().ok())
+            .filter(|value| *value > 0)
+            .ok_or(anyhow!("Missing or invalid value of {argument}!"))?;
+        match argument.as_str() {
+            "--clients" => options.clients = value as usize,
+            "--messages" => options.messages = value as usize,
+            "--timeout" => options.timeout = Duration::from_secs(value),
+            _ => return Err(anyhow!("Unknown option {argument}!")),
+        }
+    }
+    if options.clients < 2 {
+        return Err(anyhow!("The load test needs at least 2 clients!"));
+    }
+    options.address = Address::from_arguments(&positional);
+    Ok(options)
+}
+
+/// Returns the text of the message sent by the client, carrying the time it was sent.
+fn load_text(client: usize, index: usize, sent: Duration) -> String {
+    format!("{PREFIX} {client} {index} {}", sent.as_micros())
+}
+
+/// Returns the time the load test message was sent.
+fn sent_at(text: &str) -> Option<Duration> {
+    let mut parts = text.split(' ');
+    if parts.next() != Some(PREFIX) {
+        return None;
+    }
+    let micros = parts.nth(2)?.parse().ok()?;
+    Some(Duration::from_micros(micros))
+}
+
+/// Returns the value at the `quantile` of the sorted durations.
+fn percentile(sorted: &[Duration], quantile: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let index = ((sorted.len() - 1) as f64 * quantile).round() as usize;
+    sorted[index]
+}
+
+/// Connects the client and waits for the server to admit it.
+async fn connect(address: &Address) -> Result<Framed<TcpStream, MessageCodec>> {
+    let stream = TcpStream::connect(address.to_string())
+        .await
+        .with_context(|| format!("Connecting to {} failed!", address.to_string()))?;
+    let mut stream = Framed::new(stream, MessageCodec::default());
+    loop {
+        match codec::flatten(stream.next().await)?.message {
+            MessageType::Welcome { .. } => return Ok(stream),
+            MessageType::ServerFull { .. } => {
+                return Err(anyhow!("The server is full, raise its --max-clients!"))
+            }
+            _ => (),
+        }
+    }
+}
+
+async fn run(options: Options) -> Result<()> {
+    let mut streams = Vec::new();
+    for _ in 0..options.clients {
+        streams.push(connect(&options.address).await?);
+    }
+    println!(
+        "{} clients connected, each sends {} messages",
+        options.clients, options.messages
+    );
+
+    let expected = (options.clients - 1) * options.messages;
+    let (latencies, mut received) = mpsc::unbounded_channel();
+    let start = Instant::now();
+    let mut tasks = JoinSet::new();
+    for (client, stream) in streams.into_iter().enumerate() {
+        let (mut writer, mut reader) = stream.split();
+        let latencies = latencies.clone();
+        tasks.spawn(async move {
+            let mut delivered = 0;
+            while delivered < expected {
+                let Ok(message) = codec::flatten(reader.next().await) else {
+                    break;
+                };
+                if let MessageType::Text(text) = message.message {
+                    if let Some(sent) = sent_at(&text) {
+                        let _ = latencies.send(start.elapsed().saturating_sub(sent));
+                        delivered += 1;
+                    }
+                }
+            }
+        });
+        let messages = options.messages;
+        tokio::spawn(async move {
+            for index in 0..messages {
+                let text = load_text(client, index, start.elapsed());
+                let message = Message::from(format!("load{client}"), MessageType::text(text));
+                if writer.send(message).await.is_err() {
+                    break;
+                }
+            }
+            // Keeps the connection open until the test ends.
+            std::future::pending::<()>().await;
+        });
+    }
+    drop(latencies);
+    let finished = tokio::time::timeout(options.timeout, async {
+        while tasks.join_next().await.is_some() {}
+    })
+    .await;
+    let elapsed = start.elapsed();
+
+    let mut durations = Vec::new();
+    while let Ok(latency) = received.try_recv() {
+        durations.push(latency);
+    }
+    durations.sort();
+    let total = expected * options.clients;
+    println!(
+        "delivered {} of {} messages in {:.2} s, {:.0} messages/s",
+        durations.len(),
+        total,
+        elapsed.as_secs_f64(),
+        durations.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "latency p50/p99/max {:.1}/{:.1}/{:.1} ms",
+        percentile(&durations, 0.5).as_secs_f64() * 1000.0,
+        percentile(&durations, 0.99).as_secs_f64() * 1000.0,
+        durations.last().copied().unwrap_or_default().as_secs_f64() * 1000.0
+    );
+    if finished.is_err() || durations.len() < total {
+        return Err(anyhow!(
+            "{} messages were not delivered!",
+            total - durations.len()
+        ));
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let arguments: Vec<String> = std::env::args().collect();
+    let result = match parse_options(&arguments) {
+        Ok(options) => run(options).await,
+        Err(err_msg) => Err(err_msg),
+    };
+    if let Err(err_msg) = result {
+        eprintln!("{err_msg}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_options() {
+        let arguments: Vec<String> = ["load-test", "0.0.0.0", "10000", "--clients", "200"]
+            .map(String::from)
+            .to_vec();
+        let options = parse_options(&arguments).unwrap();
+        assert_eq!(options.address.to_string(), "0.0.0.0:10000");
+        assert_eq!(options.clients, 200);
+        assert_eq!(options.messages, MESSAGES);
+        let arguments = ["load-test", "--clients", "1"].map(String::from);
+        assert!(parse_options(&arguments).is_err());
+        let arguments = ["load-test", "--rate", "1"].map(String::from);
+        assert!(parse_options(&arguments).is_err());
+    }
+
+    #[test]
+    fn test_sent_at() {
+        let text = load_text(3, 7, Duration::from_micros(1500));
+        assert_eq!(sent_at(&text), Some(Duration::from_micros(1500)));
+        assert_eq!(sent_at("hello there"), None);
+    }
+}
@@ -0,0 +1,424 @@
+//! Logger with log levels adjustable at runtime.
+//!
+//! The records are filtered by `RUST_LOG` (`info` by default) like with the plain `env_logger`, but a module can be
+//! bumped to another level for a while, e.g. `server::fanout` to `debug` for 10 minutes, through the
+//! `/admin/log-level` endpoint of the metrics server or the `log-level` subcommand. The bump reverts by itself, so a
+//! forgotten one doesn't flood the log, and the connections survive the diagnosis. The trace level is compiled out.
+//! The endpoint and the subcommand are a part of the `metrics` feature, without it the levels are fixed.
+
+#[cfg(feature = "metrics")]
+use std::net::SocketAddr;
+#[cfg(feature = "metrics")]
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+#[cfg(feature = "metrics")]
+use std::time::Duration;
+use std::time::Instant;
+
+#[cfg(feature = "metrics")]
+use anyhow::{anyhow, Context, Result};
+#[cfg(feature = "metrics")]
+use axum::extract::{ConnectInfo, State};
+#[cfg(feature = "metrics")]
+use axum::http::StatusCode;
+#[cfg(feature = "metrics")]
+use axum::Json;
+use env_logger::{Builder, Env};
+#[cfg(feature = "metrics")]
+use log::info;
+use log::{LevelFilter, Log, Metadata, Record};
+#[cfg(feature = "metrics")]
+use serde::{Deserialize, Serialize};
+
+/// Path of the endpoint adjusting the log levels.
+#[cfg(feature = "metrics")]
+pub const LOG_LEVEL_PATH: &str = "/admin/log-level";
+/// Duration of a bump without an explicit one.
+#[cfg(feature = "metrics")]
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(10 * 60);
+/// Longest duration of a bump.
+#[cfg(feature = "metrics")]
+pub const MAX_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Module logging at another level until the time.
+struct Bump {
+    /// Module path, e.g. `server::fanout`, empty for all the modules.
+    module: String,
+    level: LevelFilter,
+    until: Instant,
+}
+
+/// Bumped levels shared by the logger and the endpoint.
+#[derive(Clone)]
+pub struct LogLevels {
+    /// Most detailed level of `RUST_LOG`.
+    default: LevelFilter,
+    bumps: Arc<RwLock<Vec<Bump>>>,
+}
+
+/// Body of the `/admin/log-level` endpoint, e.g. `{"module": "server::fanout", "level": "debug", "duration": "10m"}`.
+#[cfg(feature = "metrics")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LevelRequest {
+    /// Module path, `all` for all the modules.
+    pub module: String,
+    pub level: String,
+    /// Duration like `"30s"` or `"1h"`, [`DEFAULT_DURATION`] without it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<String>,
+}
+
+/// Active bump listed by the `/admin/log-level` endpoint.
+#[cfg(feature = "metrics")]
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ActiveBump {
+    pub module: String,
+    pub level: String,
+    /// Seconds until the bump reverts.
+    pub remaining_s: u64,
+}
+
+/// Levels listed by the `/admin/log-level` endpoint.
+#[cfg(feature = "metrics")]
+#[derive(Serialize, Debug)]
+pub struct Levels {
+    pub default: String,
+    pub bumps: Vec<ActiveBump>,
+}
+
+impl LogLevels {
+    fn new(default: LevelFilter) -> LogLevels {
+        LogLevels {
+            default,
+            bumps: Default::default(),
+        }
+    }
+
+    /// Returns the bumped level of the target, the bump of the longest matching module wins.
+    fn level(&self, target: &str) -> Option<LevelFilter> {
+        let bumps = self.bumps.read().unwrap_or_else(|e| e.into_inner());
+        if bumps.is_empty() {
+            return None;
+        }
+        let now = Instant::now();
+        bumps
+            .iter()
+            .filter(|bump| bump.until > now && matches_module(target, &bump.module))
+            .max_by_key(|bump| bump.module.len())
+            .map(|bump| bump.level)
+    }
+
+    /// Bumps the module to the level for the duration and schedules the revert.
+    #[cfg(feature = "metrics")]
+    pub fn bump(&self, module: &str, level: LevelFilter, duration: Duration) {
+        self.insert(module, level, Instant::now() + duration);
+        let levels = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+            levels.revert(Instant::now());
+        });
+        info!(
+            "Log level of {} is {level} for {duration:?}.",
+            describe(module)
+        );
+    }
+
+    /// Replaces the bump of the module.
+    #[cfg(feature = "metrics")]
+    fn insert(&self, module: &str, level: LevelFilter, until: Instant) {
+        let mut bumps = self.bumps.write().unwrap_or_else(|e| e.into_inner());
+        bumps.retain(|bump| bump.module != module);
+        bumps.push(Bump {
+            module: module.to_string(),
+            level,
+            until,
+        });
+        log::set_max_level(self.max_level(&bumps));
+    }
+
+    /// Removes the bumps expired at `now`.
+    #[cfg(feature = "metrics")]
+    fn revert(&self, now: Instant) {
+        let mut reverted = Vec::new();
+        {
+            let mut bumps = self.bumps.write().unwrap_or_else(|e| e.into_inner());
+            bumps.retain(|bump| {
+                let active = bump.until > now;
+                if !active {
+                    reverted.push(describe(&bump.module).to_string());
+                }
+                active
+            });
+            log::set_max_level(self.max_level(&bumps));
+        }
+        // Logged after releasing the lock, the logger reads the bumps.
+        for module in reverted {
+            info!("Log level of {module} reverted.");
+        }
+    }
+
+    /// Returns the most detailed level the logger needs to see.
+    #[cfg(feature = "metrics")]
+    fn max_level(&self, bumps: &[Bump]) -> LevelFilter {
+        bumps
+            .iter()
+            .map(|bump| bump.level)
+            .fold(self.default, Ord::max)
+    }
+
+    /// Returns the default level and the active bumps.
+    #[cfg(feature = "metrics")]
+    pub fn list(&self) -> Levels {
+        let bumps = self.bumps.read().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        Levels {
+            default: self.default.to_string().to_lowercase(),
+            bumps: bumps
+                .iter()
+                .filter(|bump| bump.until > now)
+                .map(|bump| ActiveBump {
+                    module: describe(&bump.module).to_string(),
+                    level: bump.level.to_string().to_lowercase(),
+                    remaining_s: (bump.until - now).as_secs(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Returns true if the target is the module or one of its submodules.
+fn matches_module(target: &str, module: &str) -> bool {
+    module.is_empty()
+        || target
+            .strip_prefix(module)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+#[cfg(feature = "metrics")]
+fn describe(module: &str) -> &str {
+    if module.is_empty() {
+        "all"
+    } else {
+        module
+    }
+}
+
+/// `env_logger` filtered by `RUST_LOG`, except the bumped modules.
+struct Logger {
+    levels: LogLevels,
+    default: env_logger::Logger,
+    /// Writes the records of the bumped modules, which are filtered by [`LogLevels`] instead of `RUST_LOG`.
+    bumped: env_logger::Logger,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match self.levels.level(metadata.target()) {
+            Some(level) => metadata.level() <= level,
+            None => self.default.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        match self.levels.level(record.target()) {
+            Some(level) if record.level() <= level => self.bumped.log(record),
+            Some(_) => (),
+            None => self.default.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.default.flush();
+    }
+}
+
+/// Installs the logger filtered by `RUST_LOG`, `info` by default.
+///
+/// # Returns
+///
+/// The levels adjusted by the `/admin/log-level` endpoint.
+pub fn init() -> LogLevels {
+    let env = Env::default().filter_or("RUST_LOG", "info");
+    let default = Builder::from_env(env).build();
+    let levels = LogLevels::new(default.filter());
+    let logger = Logger {
+        levels: levels.clone(),
+        default,
+        bumped: Builder::new().filter_level(LevelFilter::Trace).build(),
+    };
+    log::set_max_level(levels.default);
+    if log::set_boxed_logger(Box::new(logger)).is_err() {
+        eprintln!("Logger already initialized!");
+    }
+    levels
+}
+
+/// Parses the request into the module, the level and the duration of the bump.
+///
+/// # Errors
+///
+/// This function will return an error for an unknown level, a level compiled out of the binary or a duration which
+/// is invalid, zero or longer than [`MAX_DURATION`].
+#[cfg(feature = "metrics")]
+pub fn parse_request(request: &LevelRequest) -> Result<(String, LevelFilter, Duration)> {
+    let module = match request.module.trim() {
+        "all" | "" => "",
+        module => module,
+    };
+    let level = LevelFilter::from_str(&request.level).map_err(|_| {
+        anyhow!(
+            "Invalid level {}, use off, error, warn, info or debug!",
+            request.level
+        )
+    })?;
+    if level > log::STATIC_MAX_LEVEL {
+        return Err(anyhow!(
+            "Level {} is compiled out, the most detailed one is {}!",
+            request.level,
+            log::STATIC_MAX_LEVEL.to_string().to_lowercase()
+        ));
+    }
+    let duration = match &request.duration {
+        Some(duration) => crate::config::parse_duration(&toml::Value::String(duration.clone()))
+            .map_err(|err_msg| anyhow!("Invalid duration: {err_msg}!"))?,
+        None => DEFAULT_DURATION,
+    };
+    if duration.is_zero() || duration > MAX_DURATION {
+        return Err(anyhow!("The duration must be between 1ms and 1d!"));
+    }
+    Ok((module.to_string(), level, duration))
+}
+
+/// Returns the default level and the active bumps.
+#[cfg(feature = "metrics")]
+pub async fn get_levels(
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    State(levels): State<LogLevels>,
+) -> Result<Json<Levels>, (StatusCode, String)> {
+    crate::access::check_loopback(client, "Log levels")?;
+    Ok(Json(levels.list()))
+}
+
+/// Bumps the level of a module, the bump reverts after its duration.
+#[cfg(feature = "metrics")]
+pub async fn set_level(
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    State(levels): State<LogLevels>,
+    Json(request): Json<LevelRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    crate::access::check_loopback(client, "Log levels")?;
+    let (module, level, duration) = parse_request(&request)
+        .map_err(|err_msg| (StatusCode::BAD_REQUEST, err_msg.to_string()))?;
+    info!("Log level change requested by {client}.");
+    levels.bump(&module, level, duration);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Runs the `log-level` subcommand, which sends the bump to the running server on the loopback.
+///
+/// # Arguments
+///
+/// * `url` - The `/admin/log-level` endpoint of the running server.
+/// * `arguments` - The module (`all` for all of them), the level and the optional duration, no arguments list the
+///   current levels.
+///
+/// # Errors
+///
+/// This function will return an error for invalid arguments or if the server is unreachable or refuses the change.
+#[cfg(feature = "metrics")]
+pub async fn run_log_level(url: &str, arguments: &[String]) -> Result<()> {
+    let client = reqwest::Client::new();
+    let request = match arguments {
+        [] => client.get(url),
+        [module, level, duration @ ..] if duration.len() <= 1 => {
+            let request = LevelRequest {
+                module: module.clone(),
+                level: level.clone(),
+                duration: duration.first().cloned(),
+            };
+            parse_request(&request)?;
+            client
+                .put(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_string(&request)?)
+        }
+        _ => return Err(anyhow!("Usage: log-level [module|all level [duration]]")),
+    };
+    let response = request.send().await.context("Server unreachable!")?;
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    if !status.is_success() {
+        return Err(anyhow!("Server answered {status}: {body}"));
+    }
+    if !body.is_empty() {
+        println!("{body}");
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        let levels = LogLevels::new(LevelFilter::Info);
+        assert_eq!(levels.level("server::fanout"), None);
+        let now = Instant::now();
+        let later = now + Duration::from_secs(60);
+        levels.insert("server", LevelFilter::Warn, later);
+        levels.insert("server::fanout", LevelFilter::Debug, later);
+        assert_eq!(levels.level("server::fanout"), Some(LevelFilter::Debug));
+        assert_eq!(
+            levels.level("server::fanout::lane"),
+            Some(LevelFilter::Debug)
+        );
+        assert_eq!(levels.level("server::fanouts"), Some(LevelFilter::Warn));
+        assert_eq!(levels.level("sqlx::query"), None);
+        {
+            let bumps = levels.bumps.read().unwrap();
+            assert_eq!(levels.max_level(&bumps), LevelFilter::Debug);
+        }
+        let listed = levels.list();
+        assert_eq!(listed.default, "info");
+        assert_eq!(listed.bumps[1].module, "server::fanout");
+        assert!(listed.bumps[1].remaining_s <= 60);
+
+        levels.insert(
+            "server::fanout",
+            LevelFilter::Error,
+            now + Duration::from_secs(120),
+        );
+        levels.revert(later);
+        assert_eq!(levels.level("server::fanout"), Some(LevelFilter::Error));
+        assert_eq!(levels.level("server::db"), None);
+        levels.revert(later + Duration::from_secs(60));
+        assert!(levels.bumps.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let request = |module: &str, level: &str, duration: Option<&str>| LevelRequest {
+            module: module.into(),
+            level: level.into(),
+            duration: duration.map(Into::into),
+        };
+        assert_eq!(
+            parse_request(&request("server::fanout", "DEBUG", None)).unwrap(),
+            (
+                "server::fanout".into(),
+                LevelFilter::Debug,
+                DEFAULT_DURATION
+            )
+        );
+        assert_eq!(
+            parse_request(&request("all", "warn", Some("30s"))).unwrap(),
+            (String::new(), LevelFilter::Warn, Duration::from_secs(30))
+        );
+        assert!(parse_request(&request("all", "trace", None)).is_err());
+        assert!(parse_request(&request("all", "loud", None)).is_err());
+        assert!(parse_request(&request("all", "info", Some("2d"))).is_err());
+        assert!(parse_request(&request("all", "info", Some("0s"))).is_err());
+        assert!(parse_request(&request("all", "info", Some("10"))).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+//! Database maintenance.
+//!
+//! The server checks the database integrity and size every [`CHECK_INTERVAL`] and runs `VACUUM` and `ANALYZE` once
+//! a day at [`MAINTENANCE_HOUR`] (UTC), when the traffic is expected to be low. Both can be changed in the
+//! `[maintenance]` section of the server config. The `maintain` subcommand runs all the tasks on demand.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+
+use crate::db::Database;
+use crate::metrics::{DB_INTEGRITY_ERRORS, DB_SIZE};
+
+/// Interval of the integrity and size checks.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Hour of the day (UTC) when `VACUUM` and `ANALYZE` run.
+pub const MAINTENANCE_HOUR: u64 = 3;
+
+/// Schedule of the maintenance tasks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MaintenanceConfig {
+    pub check_interval: Duration,
+    pub hour: u64,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        MaintenanceConfig {
+            check_interval: CHECK_INTERVAL,
+            hour: MAINTENANCE_HOUR,
+        }
+    }
+}
+
+/// Runs all the maintenance tasks.
+///
+/// # Errors
+///
+/// This function will return an error if any of the tasks fails or the integrity check finds a problem.
+pub async fn run_maintenance(database: &Database) -> Result<()> {
+    let problems = check_integrity(database).await?;
+    if !problems.is_empty() {
+        return Err(anyhow!("Integrity check failed: {}", problems.join("; ")));
+    }
+    let size = database_size(database).await?;
+    database.optimize().await?;
+    let optimized = database_size(database).await?;
+    info!(
+        "Database size: {} bytes, before maintenance {} bytes.",
+        optimized, size
+    );
+    Ok(())
+}
+
+/// Spawns the background task running the scheduled maintenance.
+pub fn spawn_scheduler(database: Database, config: MaintenanceConfig) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.check_interval);
+        let mut last_day = None;
+        loop {
+            interval.tick().await;
+            match check_integrity(&database).await {
+                Ok(problems) if problems.is_empty() => (),
+                Ok(problems) => error!("Database integrity problems: {:?}", problems),
+                Err(err_msg) => error!("Database integrity check error: {:?}", err_msg),
+            }
+            if let Err(err_msg) = database_size(&database).await {
+                error!("Database size error: {:?}", err_msg);
+            }
+            let (day, hour) = utc_day_and_hour();
+            if hour == config.hour && last_day != Some(day) {
+                last_day = Some(day);
+                if let Err(err_msg) = database.optimize().await {
+                    error!("Database maintenance error: {:?}", err_msg);
+                }
+            }
+        }
+    });
+}
+
+async fn check_integrity(database: &Database) -> Result<Vec<String>> {
+    let problems = database.integrity_check().await?;
+    DB_INTEGRITY_ERRORS.set(problems.len() as i64);
+    if problems.is_empty() {
+        info!("Database integrity check passed.");
+    } else {
+        warn!(
+            "Database integrity check found {} problems.",
+            problems.len()
+        );
+    }
+    Ok(problems)
+}
+
+async fn database_size(database: &Database) -> Result<i64> {
+    let size = database.size().await?;
+    DB_SIZE.set(size);
+    Ok(size)
+}
+
+fn utc_day_and_hour() -> (u64, u64) {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    (seconds / 86400, seconds % 86400 / 3600)
+}
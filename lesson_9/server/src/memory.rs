@@ -0,0 +1,50 @@
+//! Accounting of the attachment data buffered on the server.
+//!
+//! Every incoming image or file reserves its size until it is delivered to all clients. When the total would exceed
+//! the limit ([`MAX_IN_FLIGHT_BYTES`] by default), the message is rejected instead.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::metrics::ATTACHMENT_BYTES;
+
+/// Default maximal size of attachment data buffered on the server.
+pub const MAX_IN_FLIGHT_BYTES: usize = 256 * 1024 * 1024;
+
+static IN_FLIGHT_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Reservation of the attachment data in flight, released on drop.
+#[derive(Debug)]
+pub struct InFlight {
+    size: usize,
+}
+
+impl InFlight {
+    /// Reserves `size` bytes of attachment data.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(InFlight)` - If the reservation fits into the `limit`.
+    /// - `None` - If the server is overloaded.
+    pub fn reserve(size: usize, limit: usize) -> Option<InFlight> {
+        if size == 0 {
+            return Some(InFlight { size });
+        }
+        IN_FLIGHT_BYTES
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+                current.checked_add(size).filter(|total| *total <= limit)
+            })
+            .ok()?;
+        ATTACHMENT_BYTES.add(size as i64);
+        Some(InFlight { size })
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.size == 0 {
+            return;
+        }
+        IN_FLIGHT_BYTES.fetch_sub(self.size, Ordering::SeqCst);
+        ATTACHMENT_BYTES.sub(self.size as i64);
+    }
+}
@@ -0,0 +1,264 @@
+//! Prometheus metrics of the server.
+//!
+//! The metrics are registered in [`REGISTRY`], served at `/metrics` and optionally pushed to a push gateway. The
+//! server built without the `metrics` feature doesn't depend on prometheus, the metrics are replaced by no-op
+//! stand-ins with the same methods, so the code recording them doesn't change.
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+
+#[cfg(feature = "metrics")]
+use axum::http::StatusCode;
+#[cfg(feature = "metrics")]
+use log::error;
+#[cfg(feature = "metrics")]
+use prometheus::{
+    Counter, Encoder, Gauge, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, Opts, Registry, TextEncoder,
+};
+
+#[cfg(not(feature = "metrics"))]
+use noop::{
+    Counter, Gauge, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    Opts, Registry,
+};
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new();
+    pub static ref MESSAGE_COUNTER: Counter =
+        Counter::new("message_counter", "counts number of messages send")
+            .expect("Counter metrics init failed!");
+    pub static ref USER_COUNTER: Gauge =
+        Gauge::new("user_counter", "counts number of connected users")
+            .expect("Gauge metrics init failed!");
+    pub static ref ATTACHMENT_BYTES: IntGauge = IntGauge::new(
+        "attachment_bytes",
+        "bytes of attachment data buffered on the server"
+    )
+    .expect("Gauge metrics init failed!");
+    pub static ref DELIVERY_LATENCY: Histogram = Histogram::with_opts(HistogramOpts::new(
+        "delivery_latency_seconds",
+        "time from receiving a message to broadcasting it"
+    ))
+    .expect("Histogram metrics init failed!");
+    pub static ref PERSISTENCE_LATENCY: Histogram = Histogram::with_opts(HistogramOpts::new(
+        "persistence_latency_seconds",
+        "time from receiving a message to storing it in the database"
+    ))
+    .expect("Histogram metrics init failed!");
+    pub static ref DB_SIZE: IntGauge = IntGauge::new("db_size_bytes", "size of the database file")
+        .expect("Gauge metrics init failed!");
+    pub static ref DB_INTEGRITY_ERRORS: IntGauge = IntGauge::new(
+        "db_integrity_errors",
+        "number of problems found by the last database integrity check"
+    )
+    .expect("Gauge metrics init failed!");
+    pub static ref DEAD_LETTER_COUNTER: IntCounter = IntCounter::new(
+        "dead_letter_counter",
+        "counts number of messages which failed to be stored"
+    )
+    .expect("Counter metrics init failed!");
+    pub static ref REJECTED_CONNECTIONS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "rejected_connections",
+            "counts number of connections rejected by the access control"
+        ),
+        &["reason"]
+    )
+    .expect("Counter metrics init failed!");
+    pub static ref FAILED_HANDSHAKES: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "failed_handshakes",
+            "counts number of connections failing the handshake"
+        ),
+        &["reason"]
+    )
+    .expect("Counter metrics init failed!");
+    pub static ref QUERY_DURATION: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "db_query_duration_seconds",
+            "duration of the database queries"
+        ),
+        &["query"]
+    )
+    .expect("Histogram metrics init failed!");
+    pub static ref PUSH_FAILURES: IntCounter = IntCounter::new(
+        "metrics_push_failures",
+        "counts number of failed pushes to the metrics push gateway"
+    )
+    .expect("Counter metrics init failed!");
+    pub static ref FANOUT_PENDING: IntGauge = IntGauge::new(
+        "fanout_pending_messages",
+        "number of messages waiting for the delivery workers"
+    )
+    .expect("Gauge metrics init failed!");
+    pub static ref CORRUPTED_FRAMES: IntCounter = IntCounter::new(
+        "corrupted_frames",
+        "counts number of received messages failing the checksum verification"
+    )
+    .expect("Counter metrics init failed!");
+    pub static ref SLOW_CLIENTS: IntCounter = IntCounter::new(
+        "slow_client_disconnects",
+        "counts number of clients disconnected for not keeping up with the messages"
+    )
+    .expect("Counter metrics init failed!");
+    pub static ref WAITING_CLIENTS: IntGauge = IntGauge::new(
+        "waiting_clients",
+        "number of connections waiting for a free slot of a full server"
+    )
+    .expect("Gauge metrics init failed!");
+}
+
+/// Registers all the metrics in the [`REGISTRY`].
+pub fn register_metrics() -> Result<()> {
+    REGISTRY
+        .register(Box::new(MESSAGE_COUNTER.clone()))
+        .context("message counter metric registering error!")?;
+    REGISTRY
+        .register(Box::new(USER_COUNTER.clone()))
+        .context("counter metric registering error!")?;
+    REGISTRY
+        .register(Box::new(ATTACHMENT_BYTES.clone()))
+        .context("attachment bytes metric registering error!")?;
+    REGISTRY
+        .register(Box::new(DELIVERY_LATENCY.clone()))
+        .context("delivery latency metric registering error!")?;
+    REGISTRY
+        .register(Box::new(PERSISTENCE_LATENCY.clone()))
+        .context("persistence latency metric registering error!")?;
+    REGISTRY
+        .register(Box::new(DEAD_LETTER_COUNTER.clone()))
+        .context("dead letter counter metric registering error!")?;
+    REGISTRY
+        .register(Box::new(DB_SIZE.clone()))
+        .context("database size metric registering error!")?;
+    REGISTRY
+        .register(Box::new(DB_INTEGRITY_ERRORS.clone()))
+        .context("database integrity metric registering error!")?;
+    REGISTRY
+        .register(Box::new(REJECTED_CONNECTIONS.clone()))
+        .context("rejected connections metric registering error!")?;
+    REGISTRY
+        .register(Box::new(FAILED_HANDSHAKES.clone()))
+        .context("failed handshakes metric registering error!")?;
+    REGISTRY
+        .register(Box::new(WAITING_CLIENTS.clone()))
+        .context("waiting clients metric registering error!")?;
+    REGISTRY
+        .register(Box::new(QUERY_DURATION.clone()))
+        .context("query duration metric registering error!")?;
+    REGISTRY
+        .register(Box::new(PUSH_FAILURES.clone()))
+        .context("metrics push failures metric registering error!")?;
+    REGISTRY
+        .register(Box::new(FANOUT_PENDING.clone()))
+        .context("fan-out pending metric registering error!")?;
+    REGISTRY
+        .register(Box::new(SLOW_CLIENTS.clone()))
+        .context("slow clients metric registering error!")?;
+    REGISTRY
+        .register(Box::new(CORRUPTED_FRAMES.clone()))
+        .context("corrupted frames metric registering error!")?;
+    Ok(())
+}
+
+/// Renders the metrics for the `/metrics` endpoint.
+#[cfg(feature = "metrics")]
+pub async fn render() -> (StatusCode, String) {
+    let encoder = TextEncoder::new();
+    let mut buf = vec![];
+
+    if let Err(err_msg) = encoder.encode(&REGISTRY.gather(), &mut buf) {
+        error!("Metrics encoding error: {}", err_msg);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Metrics encoding error!".to_string(),
+        );
+    }
+    if let Ok(body) = String::from_utf8(buf) {
+        return (StatusCode::OK, body);
+    }
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Unknow error!".to_string(),
+    )
+}
+
+/// No-op stand-ins of the prometheus metrics.
+#[cfg(not(feature = "metrics"))]
+mod noop {
+    use std::convert::Infallible;
+
+    /// Metric recording nothing.
+    #[derive(Clone)]
+    pub struct Metric;
+
+    pub type Counter = Metric;
+    pub type Gauge = Metric;
+    pub type Histogram = Metric;
+    pub type IntCounter = Metric;
+    pub type IntGauge = Metric;
+
+    impl Metric {
+        pub fn new(_name: &str, _help: &str) -> Result<Metric, Infallible> {
+            Ok(Metric)
+        }
+
+        pub fn with_opts(_opts: Opts) -> Result<Metric, Infallible> {
+            Ok(Metric)
+        }
+
+        pub fn inc(&self) {}
+
+        pub fn dec(&self) {}
+
+        pub fn add(&self, _value: i64) {}
+
+        pub fn sub(&self, _value: i64) {}
+
+        pub fn set(&self, _value: i64) {}
+
+        pub fn observe(&self, _value: f64) {}
+    }
+
+    /// Labeled metric recording nothing.
+    #[derive(Clone)]
+    pub struct MetricVec;
+
+    pub type HistogramVec = MetricVec;
+    pub type IntCounterVec = MetricVec;
+
+    impl MetricVec {
+        pub fn new(_opts: Opts, _labels: &[&str]) -> Result<MetricVec, Infallible> {
+            Ok(MetricVec)
+        }
+
+        pub fn with_label_values(&self, _values: &[&str]) -> Metric {
+            Metric
+        }
+    }
+
+    pub struct Opts;
+
+    pub type HistogramOpts = Opts;
+
+    impl Opts {
+        pub fn new(_name: &str, _help: &str) -> Opts {
+            Opts
+        }
+    }
+
+    pub struct Registry;
+
+    impl Registry {
+        pub fn new() -> Registry {
+            Registry
+        }
+
+        // Takes the box like the prometheus registry.
+        #[allow(clippy::boxed_local)]
+        pub fn register<T>(&self, _metric: Box<T>) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! Moderation inbox of the admin panel.
+//!
+//! The reports of the messages sent by the clients with `.report` are stored open by the server. The inbox lists the
+//! open ones, oldest first, with the text of the reported message, followed by the recently closed ones. A moderator
+//! resolves a report after acting on it, e.g. deleting the message in the bulk moderation, or dismisses it. Closing a
+//! report runs in a transaction together with its record in the audit table, under the nickname of the reported
+//! author.
+
+use anyhow::{Context, Result};
+use rocket::serde::Serialize;
+use rocket_db_pools::sqlx::{self, Connection, SqliteConnection};
+
+/// Number of the closed reports listed under the open ones.
+const CLOSED_SHOWN: i64 = 20;
+
+/// Report of a message, as listed by the inbox.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AbuseReport {
+    pub id: i64,
+    pub message_id: i64,
+    pub reporter: String,
+    /// Nickname of the author of the reported message.
+    pub author: String,
+    pub reason: String,
+    /// `open`, `resolved` or `dismissed`.
+    pub status: String,
+    /// Text of the reported message, `None` if it was deleted.
+    pub message: Option<String>,
+}
+
+/// Reports waiting for a moderator and the last closed ones.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Inbox {
+    pub open: Vec<AbuseReport>,
+    pub closed: Vec<AbuseReport>,
+}
+
+/// Way of closing a report.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    /// The report was right and the moderator acted on it.
+    Resolve,
+    /// The report was unfounded.
+    Dismiss,
+}
+
+impl Resolution {
+    /// Returns the status of the closed report.
+    fn status(self) -> &'static str {
+        match self {
+            Resolution::Resolve => "resolved",
+            Resolution::Dismiss => "dismissed",
+        }
+    }
+
+    /// Returns the action recorded in the audit table.
+    fn action(self) -> &'static str {
+        match self {
+            Resolution::Resolve => "report-resolve",
+            Resolution::Dismiss => "report-dismiss",
+        }
+    }
+}
+
+/// Row of the reports joined with the text of the message for [`AbuseReport`].
+type ReportRow = (i64, i64, String, String, String, String, Option<String>);
+
+/// Lists the open reports and the [`CLOSED_SHOWN`] recently closed ones.
+///
+/// # Errors
+///
+/// This function will return an error if the database fails.
+pub async fn inbox(db: &mut SqliteConnection) -> Result<Inbox> {
+    let select = |condition: &str| {
+        format!(
+            r#"
+            SELECT reports.id, message_id, reporter, author, reason, status, message_bodies.message
+            FROM reports
+            LEFT JOIN messages ON messages.id = message_id
+            LEFT JOIN message_bodies ON hash = body_hash
+            WHERE {condition}
+            "#
+        )
+    };
+    let open: Vec<ReportRow> = sqlx::query_as(&select("status = 'open' ORDER BY reports.id"))
+        .fetch_all(&mut *db)
+        .await
+        .context("Fetching open reports failed!")?;
+    let closed: Vec<ReportRow> = sqlx::query_as(&select(
+        "status != 'open' ORDER BY closed_at DESC, reports.id DESC LIMIT ?1",
+    ))
+    .bind(CLOSED_SHOWN)
+    .fetch_all(&mut *db)
+    .await
+    .context("Fetching closed reports failed!")?;
+    Ok(Inbox {
+        open: open.into_iter().map(abuse_report).collect(),
+        closed: closed.into_iter().map(abuse_report).collect(),
+    })
+}
+
+fn abuse_report(
+    (id, message_id, reporter, author, reason, status, message): ReportRow,
+) -> AbuseReport {
+    AbuseReport {
+        id,
+        message_id,
+        reporter,
+        author,
+        reason,
+        status,
+        message,
+    }
+}
+
+/// Closes the open report at the Unix time in milliseconds, recording it in the audit table.
+///
+/// # Returns
+///
+/// False if no open report has the id, nothing is changed then.
+///
+/// # Errors
+///
+/// This function will return an error if the database fails, nothing is changed then.
+pub async fn close(
+    db: &mut SqliteConnection,
+    id: i64,
+    resolution: Resolution,
+    closed_at: i64,
+) -> Result<bool> {
+    let mut transaction = db.begin().await?;
+    let closed: Option<(String, i64, String)> = sqlx::query_as(
+        r#"
+        UPDATE reports SET status = ?2, closed_at = ?3
+        WHERE id = ?1 AND status = 'open'
+        RETURNING author, message_id, reason
+        "#,
+    )
+    .bind(id)
+    .bind(resolution.status())
+    .bind(closed_at)
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Closing report failed!")?;
+    let Some((author, message_id, reason)) = closed else {
+        return Ok(false);
+    };
+    sqlx::query("INSERT INTO audit ( nickname, action, reason ) VALUES ( ?1, ?2, ?3 );")
+        .bind(author)
+        .bind(resolution.action())
+        .bind(format!("report #{id} of message #{message_id}: {reason}"))
+        .execute(&mut *transaction)
+        .await
+        .context("Recording report in the audit failed!")?;
+    transaction.commit().await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rocket::async_test]
+    async fn test_inbox() {
+        let mut db = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        for statement in [
+            "CREATE TABLE messages (id INTEGER PRIMARY KEY, nickname TEXT, body_hash BLOB);",
+            "CREATE TABLE message_bodies (hash BLOB PRIMARY KEY, message TEXT);",
+            "CREATE TABLE audit (id INTEGER PRIMARY KEY, nickname TEXT, action TEXT, reason TEXT);",
+            "CREATE TABLE reports (id INTEGER PRIMARY KEY, message_id INTEGER, reporter TEXT, author TEXT, \
+             reason TEXT, status TEXT NOT NULL DEFAULT 'open', created_at INTEGER, closed_at INTEGER);",
+            "INSERT INTO message_bodies VALUES (x'01', 'buy now');",
+            "INSERT INTO messages VALUES (1, 'spammer', x'01');",
+            "INSERT INTO reports (message_id, reporter, author, reason, created_at) \
+             VALUES (1, 'slava', 'spammer', 'spam', 0), (1, 'eva', 'spammer', 'ads', 0), \
+             (2, 'slava', 'eva', 'rude', 0);",
+        ] {
+            sqlx::query(statement).execute(&mut db).await.unwrap();
+        }
+
+        let listed = inbox(&mut db).await.unwrap();
+        assert_eq!(listed.open.len(), 3);
+        assert!(listed.closed.is_empty());
+        assert_eq!(listed.open[0].message.as_deref(), Some("buy now"));
+        assert_eq!(listed.open[2].message, None);
+
+        assert!(close(&mut db, 1, Resolution::Resolve, 10).await.unwrap());
+        assert!(close(&mut db, 3, Resolution::Dismiss, 20).await.unwrap());
+        assert!(!close(&mut db, 3, Resolution::Resolve, 30).await.unwrap());
+        assert!(!close(&mut db, 4, Resolution::Resolve, 30).await.unwrap());
+        let listed = inbox(&mut db).await.unwrap();
+        let ids = |reports: &[AbuseReport]| -> Vec<i64> {
+            reports.iter().map(|report| report.id).collect()
+        };
+        assert_eq!(ids(&listed.open), [2]);
+        assert_eq!(ids(&listed.closed), [3, 1]);
+        assert_eq!(listed.closed[0].status, "dismissed");
+
+        let audit: Vec<(String, String, String)> =
+            sqlx::query_as("SELECT nickname, action, reason FROM audit ORDER BY id;")
+                .fetch_all(&mut db)
+                .await
+                .unwrap();
+        assert_eq!(
+            audit,
+            [
+                (
+                    "spammer".to_string(),
+                    "report-resolve".to_string(),
+                    "report #1 of message #1: spam".to_string()
+                ),
+                (
+                    "eva".to_string(),
+                    "report-dismiss".to_string(),
+                    "report #3 of message #2: rude".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,280 @@
+//! Persistence of the messages off the delivery path.
+//!
+//! Incoming messages are broadcast immediately and handed over to a pool of persistence workers through a channel.
+//! Failed inserts land in the dead-letter queue, which retries them after [`RETRY_DELAY`] until [`MAX_ATTEMPTS`]
+//! is reached, then the sender is told that its message is missing in the history. The constants are the defaults of
+//! [`PersistenceConfig`]. A sender asking for it by the [`chat::ACK_ANNOTATION`] gets a [`MessageType::Ack`] with the
+//! id once its message is stored, it was broadcast before being submitted.
+//!
+//! The messages flagged no-persist, see [`MessageType::is_no_persist`], are dropped here, whatever the caller, so a
+//! whisper never reaches the database. It carries no attachment, so it never reaches the blob store either.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, warn};
+use tokio::sync::{mpsc, Mutex};
+
+use chat::{ErrorCode, Message, MessageType};
+
+use crate::db::Database;
+use crate::metrics::{DEAD_LETTER_COUNTER, PERSISTENCE_LATENCY};
+
+/// Number of the persistence workers.
+pub const WORKERS: usize = 4;
+/// Maximal number of insert attempts for a single message.
+pub const MAX_ATTEMPTS: u32 = 5;
+/// Delay between the insert attempts.
+pub const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+const QUEUE_SIZE: usize = 1024;
+
+/// Settings of the persistence workers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PersistenceConfig {
+    pub workers: usize,
+    pub max_attempts: u32,
+    pub retry_delay: Duration,
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        PersistenceConfig {
+            workers: WORKERS,
+            max_attempts: MAX_ATTEMPTS,
+            retry_delay: RETRY_DELAY,
+        }
+    }
+}
+
+/// Row of the messages table.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub nickname: String,
+    pub msg_type: String,
+    pub message: String,
+    pub lang: Option<String>,
+    /// Unix time of sending in milliseconds.
+    pub sent_at: Option<i64>,
+    /// Bytes of the image or file, the announced size of an attachment stored by the server.
+    pub attachment_size: i64,
+    /// Room of the message, the history and the search show it only to the clients in the room.
+    pub room: String,
+    /// The message must not be stored.
+    pub no_persist: bool,
+}
+
+impl Record {
+    /// Creates the database row for the Message.
+    pub fn new(message: &Message) -> Record {
+        let (msg_type, value) = message.message.get_type_and_message();
+        let lang = match &message.message {
+            MessageType::Code { lang, .. } => Some(lang.clone()),
+            _ => None,
+        };
+        let attachment_size = match &message.message {
+            MessageType::Attachment { size, .. } | MessageType::FileStart { size, .. } => {
+                *size as i64
+            }
+            other => other.attachment_size() as i64,
+        };
+        Record {
+            nickname: message.nickname.clone(),
+            msg_type: msg_type.to_string(),
+            message: value,
+            lang,
+            sent_at: message.timestamp.map(|timestamp| timestamp as i64),
+            attachment_size,
+            room: message.room.clone(),
+            no_persist: message.message.is_no_persist(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Job {
+    record: Record,
+    received: Instant,
+    attempts: u32,
+    /// Replies of the sender, not keeping its connection open.
+    sender: Option<mpsc::WeakSender<Message>>,
+    /// Timestamp of the message acknowledged to the sender once stored.
+    ack: Option<u64>,
+}
+
+/// Handle for submitting messages to the persistence workers.
+#[derive(Clone)]
+pub struct Persistence {
+    queue: mpsc::Sender<Job>,
+}
+
+impl Persistence {
+    /// Spawns the persistence workers and the dead-letter queue task.
+    pub fn spawn(database: Database, config: PersistenceConfig) -> Persistence {
+        let (queue, receiver) = mpsc::channel(QUEUE_SIZE);
+        let (dead_letters, dead_letters_receiver) = mpsc::unbounded_channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..config.workers {
+            tokio::spawn(worker(
+                database.clone(),
+                receiver.clone(),
+                dead_letters.clone(),
+            ));
+        }
+        tokio::spawn(dead_letter_queue(
+            dead_letters_receiver,
+            queue.clone(),
+            config,
+        ));
+        Persistence { queue }
+    }
+
+    /// Submits the message to be stored, a record flagged no-persist is dropped.
+    ///
+    /// # Arguments
+    ///
+    /// - `record` - The row to store.
+    /// - `received` - The time the message was received, used for the latency metrics.
+    /// - `sender` - The replies of the sender, notified if the message can't be stored.
+    /// - `ack` - The timestamp the sender stamped the message with, if it asked for an acknowledgement.
+    pub async fn persist(
+        &self,
+        record: Record,
+        received: Instant,
+        sender: Option<mpsc::WeakSender<Message>>,
+        ack: Option<u64>,
+    ) {
+        if record.no_persist {
+            debug!(
+                "Not storing {} message of {}.",
+                record.msg_type, record.nickname
+            );
+            return;
+        }
+        let job = Job {
+            record,
+            received,
+            attempts: 0,
+            sender,
+            ack,
+        };
+        if self.queue.send(job).await.is_err() {
+            error!("Persistence workers are gone, message not stored!");
+        }
+    }
+}
+
+async fn worker(
+    database: Database,
+    receiver: Arc<Mutex<mpsc::Receiver<Job>>>,
+    dead_letters: mpsc::UnboundedSender<Job>,
+) {
+    loop {
+        let Some(mut job) = receiver.lock().await.recv().await else {
+            break;
+        };
+        job.attempts += 1;
+        match database.insert_message(&job.record).await {
+            Ok(message_id) => {
+                PERSISTENCE_LATENCY.observe(job.received.elapsed().as_secs_f64());
+                acknowledge(&job, message_id);
+            }
+            Err(err_msg) => {
+                warn!(
+                    "Insert database error (attempt {}): {:?}",
+                    job.attempts, err_msg
+                );
+                let _ = dead_letters.send(job);
+            }
+        }
+    }
+}
+
+/// Tells the sender asking for it that its message is stored.
+fn acknowledge(job: &Job, message_id: i64) {
+    let Some(sent_at) = job.ack else {
+        return;
+    };
+    if let Some(sender) = job.sender.as_ref().and_then(|sender| sender.upgrade()) {
+        let ack = MessageType::Ack {
+            message_id,
+            sent_at,
+        };
+        let _ = sender.try_send(Message::system(ack));
+    }
+}
+
+async fn dead_letter_queue(
+    mut dead_letters: mpsc::UnboundedReceiver<Job>,
+    queue: mpsc::Sender<Job>,
+    config: PersistenceConfig,
+) {
+    while let Some(job) = dead_letters.recv().await {
+        if job.attempts >= config.max_attempts {
+            error!("Giving up storing message: {:?}", job.record);
+            DEAD_LETTER_COUNTER.inc();
+            if let Some(sender) = job.sender.and_then(|sender| sender.upgrade()) {
+                let error = MessageType::ServerError {
+                    code: ErrorCode::NotStored,
+                };
+                let _ = sender.try_send(Message::system(error));
+            }
+            continue;
+        }
+        let queue = queue.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(config.retry_delay).await;
+            let _ = queue.send(job).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::DatabaseConfig;
+
+    #[tokio::test]
+    async fn test_whispers_are_not_stored() {
+        let path = std::env::temp_dir().join(format!("chat-whisper-{}.db", std::process::id()));
+        let url = format!("sqlite://{}", path.display());
+        let database = Database::open(&url, DatabaseConfig::default())
+            .await
+            .unwrap();
+        let persistence = Persistence::spawn(database.clone(), PersistenceConfig::default());
+        let (replies, mut replies_receiver) = mpsc::channel(8);
+        // The whisper is dropped before the queue, the text is the only message to wait for.
+        for (sent_at, message) in [
+            (
+                1,
+                Message::from("slava", MessageType::whisper("eva", "psst")),
+            ),
+            (2, Message::from("slava", MessageType::text("hello"))),
+        ] {
+            let sender = Some(replies.downgrade());
+            persistence
+                .persist(Record::new(&message), Instant::now(), sender, Some(sent_at))
+                .await;
+        }
+        let ack = tokio::time::timeout(Duration::from_secs(5), replies_receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let history = database
+            .fetch_history(chat::DEFAULT_ROOM, None, 10, 100)
+            .await
+            .unwrap();
+        let stored: Vec<(i64, &str, &str)> = history
+            .iter()
+            .map(|entry| (entry.id, entry.msg_type.as_str(), entry.message.as_str()))
+            .collect();
+        assert_eq!(stored, [(1, "Text", "hello")]);
+        let acked = MessageType::Ack {
+            message_id: 1,
+            sent_at: 2,
+        };
+        assert_eq!(ack.message, acked);
+        assert!(replies_receiver.try_recv().is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
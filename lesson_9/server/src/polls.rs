@@ -0,0 +1,294 @@
+//! Polls created by the clients.
+//!
+//! The server stores a new poll, assigns its id and announces it to everybody. Every nickname has one vote per poll,
+//! voting again replaces the previous vote. The voter gets the current results right away, everybody gets them every
+//! [`RESULTS_INTERVAL`] if the poll got new votes and the final ones when its author closes it.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use chat::{fold, ErrorCode, Message, MessageType, MAX_POLL_OPTIONS};
+use log::{error, info};
+use parking_lot::Mutex;
+
+use crate::db::Database;
+use crate::fanout::FanOut;
+
+/// Interval of announcing the results of the polls with new votes.
+pub const RESULTS_INTERVAL: Duration = Duration::from_secs(30);
+/// Maximal length of the question and of the options in characters.
+pub const MAX_POLL_TEXT: usize = 200;
+
+/// Stored poll.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Poll {
+    pub id: i64,
+    /// Nickname of the client who created the poll.
+    pub author: String,
+    pub question: String,
+    pub options: Vec<String>,
+    pub closed: bool,
+}
+
+/// Reply to the sender of a poll message, or the reason of its rejection.
+type Reply = Result<Option<MessageType>, ErrorCode>;
+
+/// Handler of the poll messages shared by the client connections.
+#[derive(Clone)]
+pub struct Polls {
+    database: Database,
+    fan_out: FanOut,
+    /// Open polls with votes not announced yet.
+    changed: Arc<Mutex<HashSet<i64>>>,
+}
+
+impl Polls {
+    /// Creates the handler and spawns the task announcing the results.
+    pub fn spawn(database: Database, fan_out: FanOut) -> Polls {
+        let polls = Polls {
+            database,
+            fan_out,
+            changed: Arc::new(Mutex::new(HashSet::new())),
+        };
+        let announcer = polls.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RESULTS_INTERVAL);
+            loop {
+                interval.tick().await;
+                announcer.announce_changed().await;
+            }
+        });
+        polls
+    }
+
+    /// Returns true for the messages handled by [`Polls::handle`].
+    pub fn is_poll_message(message: &MessageType) -> bool {
+        matches!(
+            message,
+            MessageType::Poll { .. } | MessageType::Vote { .. } | MessageType::ClosePoll { .. }
+        )
+    }
+
+    /// Creates the poll, votes or closes it.
+    ///
+    /// # Returns
+    ///
+    /// The reply to the sender, the current results after a vote or a server error if the message was rejected or
+    /// the database failed.
+    pub async fn handle(&self, nickname: &str, message: &MessageType) -> Option<Message> {
+        let result = match message {
+            MessageType::Poll {
+                question, options, ..
+            } => self.create(nickname, question, options).await,
+            MessageType::Vote { poll, option } => self.vote(nickname, *poll, option).await,
+            MessageType::ClosePoll { poll } => self.close(nickname, *poll).await,
+            _ => return None,
+        };
+        let reply = match result {
+            Ok(Ok(reply)) => reply?,
+            Ok(Err(code)) => MessageType::ServerError { code },
+            Err(err_msg) => {
+                error!("Poll error: {:?}", err_msg);
+                MessageType::ServerError {
+                    code: ErrorCode::Unavailable,
+                }
+            }
+        };
+        Some(Message::system(reply))
+    }
+
+    async fn create(&self, nickname: &str, question: &str, options: &[String]) -> Result<Reply> {
+        let question = question.trim();
+        let options: Vec<String> = options
+            .iter()
+            .map(|option| option.trim().to_string())
+            .collect();
+        if let Err(code) = validate(question, &options) {
+            return Ok(Err(code));
+        }
+        let id = self
+            .database
+            .insert_poll(nickname, question, &options)
+            .await?;
+        info!("Poll #{} created by {}.", id, nickname);
+        let poll = MessageType::Poll {
+            id,
+            question: question.to_string(),
+            options,
+        };
+        self.fan_out.announce(Message::from(nickname, poll));
+        Ok(Ok(None))
+    }
+
+    async fn vote(&self, nickname: &str, id: i64, option: &str) -> Result<Reply> {
+        let poll = match self.open_poll(id).await? {
+            Ok(poll) => poll,
+            Err(code) => return Ok(Err(code)),
+        };
+        let Some(index) = option_index(&poll.options, option) else {
+            return Ok(Err(ErrorCode::InvalidVote { id }));
+        };
+        self.database.insert_vote(id, nickname, index).await?;
+        self.changed.lock().insert(id);
+        Ok(Ok(Some(self.results(&poll).await?)))
+    }
+
+    async fn close(&self, nickname: &str, id: i64) -> Result<Reply> {
+        let mut poll = match self.open_poll(id).await? {
+            Ok(poll) => poll,
+            Err(code) => return Ok(Err(code)),
+        };
+        if poll.author != nickname {
+            return Ok(Err(ErrorCode::NotPollAuthor { id }));
+        }
+        self.database.close_poll(id).await?;
+        self.changed.lock().remove(&id);
+        poll.closed = true;
+        info!("Poll #{} closed by {}.", id, nickname);
+        let results = self.results(&poll).await?;
+        self.fan_out.announce(Message::system(results));
+        Ok(Ok(None))
+    }
+
+    /// Fetches the poll accepting the votes.
+    async fn open_poll(&self, id: i64) -> Result<Result<Poll, ErrorCode>> {
+        Ok(match self.database.fetch_poll(id).await? {
+            None => Err(ErrorCode::UnknownPoll { id }),
+            Some(poll) if poll.closed => Err(ErrorCode::PollClosed { id }),
+            Some(poll) => Ok(poll),
+        })
+    }
+
+    async fn results(&self, poll: &Poll) -> Result<MessageType> {
+        let votes = self
+            .database
+            .count_votes(poll.id, poll.options.len())
+            .await?;
+        Ok(MessageType::PollResults {
+            id: poll.id,
+            question: poll.question.clone(),
+            votes: poll.options.iter().cloned().zip(votes).collect(),
+            closed: poll.closed,
+        })
+    }
+
+    /// Announces the results of the open polls with new votes.
+    async fn announce_changed(&self) {
+        let changed: Vec<i64> = self.changed.lock().drain().collect();
+        for id in changed {
+            let results = match self.open_poll(id).await {
+                Ok(Ok(poll)) => self.results(&poll).await,
+                Ok(Err(_)) => continue,
+                Err(err_msg) => Err(err_msg),
+            };
+            match results {
+                Ok(results) => self.fan_out.announce(Message::system(results)),
+                Err(err_msg) => error!("Poll #{} results error: {:?}", id, err_msg),
+            }
+        }
+    }
+}
+
+/// Checks the question and the options of a new poll.
+fn validate(question: &str, options: &[String]) -> Result<(), ErrorCode> {
+    let too_long = |text: &str| text.chars().count() > MAX_POLL_TEXT;
+    let mut folded = HashSet::new();
+    let valid = !question.is_empty()
+        && !too_long(question)
+        && (2..=MAX_POLL_OPTIONS).contains(&options.len())
+        && options
+            .iter()
+            .all(|option| !option.is_empty() && !too_long(option) && folded.insert(fold(option)));
+    if valid {
+        Ok(())
+    } else {
+        Err(ErrorCode::InvalidPoll)
+    }
+}
+
+/// Returns the index of the option given by its number starting at 1 or by its text, ignoring case and accents.
+fn option_index(options: &[String], option: &str) -> Option<usize> {
+    let option = option.trim();
+    if let Ok(number) = option.parse::<usize>() {
+        return number.checked_sub(1).filter(|index| *index < options.len());
+    }
+    let option = fold(option);
+    options.iter().position(|known| fold(known) == option)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(options: &[&str]) -> Vec<String> {
+        options.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate("Lunch?", &options(&["pizza", "sushi"])).is_ok());
+        assert!(validate("", &options(&["pizza", "sushi"])).is_err());
+        assert!(validate("Lunch?", &options(&["pizza"])).is_err());
+        assert!(validate("Lunch?", &options(&["pizza", "Pizza"])).is_err());
+        assert!(validate("Lunch?", &options(&["pizza", ""])).is_err());
+        let many: Vec<String> = (0..=MAX_POLL_OPTIONS).map(|i| i.to_string()).collect();
+        assert!(validate("Number?", &many).is_err());
+    }
+
+    #[test]
+    fn test_option_index() {
+        let lunch = options(&["pizza", "sushi", "salát"]);
+        assert_eq!(option_index(&lunch, "2"), Some(1));
+        assert_eq!(option_index(&lunch, "SALAT"), Some(2));
+        assert_eq!(option_index(&lunch, "0"), None);
+        assert_eq!(option_index(&lunch, "4"), None);
+        assert_eq!(option_index(&lunch, "burger"), None);
+    }
+
+    #[tokio::test]
+    async fn test_poll_votes() {
+        let path = std::env::temp_dir().join(format!("chat-polls-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let url = format!("sqlite://{}", path.display());
+        let database = Database::open(&url, Default::default()).await.unwrap();
+        let polls = Polls {
+            database: database.clone(),
+            fan_out: FanOut::spawn(Default::default()),
+            changed: Default::default(),
+        };
+        let poll = MessageType::poll("Lunch?", &["pizza", "sushi"]);
+        assert!(polls.handle("slava", &poll).await.is_none());
+
+        let tally = |reply: Option<Message>| match reply.map(|reply| reply.message) {
+            Some(MessageType::PollResults { votes, .. }) => votes,
+            other => panic!("unexpected reply {other:?}"),
+        };
+        let votes = tally(polls.handle("slava", &MessageType::vote(1, "pizza")).await);
+        assert_eq!(votes, [("pizza".to_string(), 1), ("sushi".to_string(), 0)]);
+        polls.handle("eva", &MessageType::vote(1, "2")).await;
+        let votes = tally(polls.handle("slava", &MessageType::vote(1, "sushi")).await);
+        assert_eq!(votes, [("pizza".to_string(), 0), ("sushi".to_string(), 2)]);
+
+        let error = |reply: Option<Message>| match reply.map(|reply| reply.message) {
+            Some(MessageType::ServerError { code }) => code,
+            other => panic!("unexpected reply {other:?}"),
+        };
+        let close = MessageType::ClosePoll { poll: 1 };
+        assert_eq!(
+            error(polls.handle("eva", &close).await),
+            ErrorCode::NotPollAuthor { id: 1 }
+        );
+        assert!(polls.handle("slava", &close).await.is_none());
+        assert_eq!(
+            error(polls.handle("eva", &MessageType::vote(1, "pizza")).await),
+            ErrorCode::PollClosed { id: 1 }
+        );
+        assert_eq!(
+            error(polls.handle("eva", &MessageType::vote(2, "pizza")).await),
+            ErrorCode::UnknownPoll { id: 2 }
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
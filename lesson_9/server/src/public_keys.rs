@@ -0,0 +1,93 @@
+//! Public keys of the end-to-end encryption.
+//!
+//! A client encrypting the messages announces its public key by a [`MessageType::PublicKey`]. The server keeps the
+//! latest key of every nickname, announces a new or changed one to everybody and sends all of them to a joining
+//! client, so it can encrypt for the users who announced their keys before. The encrypted messages themselves are
+//! relayed and stored like any other, the server never sees their content. The keys are kept in memory only, the
+//! clients announce them again after a restart.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use chat::{Message, MessageType};
+
+use crate::fanout::FanOut;
+
+/// Latest public keys of the nicknames shared by the sessions.
+#[derive(Clone)]
+pub struct PublicKeys {
+    keys: Arc<Mutex<BTreeMap<String, [u8; 32]>>>,
+    fan_out: FanOut,
+}
+
+impl PublicKeys {
+    /// Creates an empty directory announcing the keys through the fan-out.
+    pub fn new(fan_out: FanOut) -> PublicKeys {
+        PublicKeys {
+            keys: Default::default(),
+            fan_out,
+        }
+    }
+
+    /// Remembers the key of the nickname and announces it, unless it is known already.
+    pub fn publish(&self, nickname: &str, key: [u8; 32]) {
+        let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        if keys.insert(nickname.to_string(), key) == Some(key) {
+            return;
+        }
+        self.fan_out
+            .announce(Message::from(nickname, MessageType::PublicKey { key }));
+    }
+
+    /// Returns the messages announcing the known keys, sent by their owners.
+    pub fn all(&self) -> Vec<Message> {
+        let keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        keys.iter()
+            .map(|(nickname, key)| Message::from(nickname, MessageType::PublicKey { key: *key }))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fanout;
+
+    #[tokio::test]
+    async fn test_publish() {
+        let fan_out = FanOut::spawn(Default::default());
+        let addr = "127.0.0.1:4000".parse().unwrap();
+        let (_connection, mut outbox) = fan_out.register(addr);
+        let keys = PublicKeys::new(fan_out);
+        keys.publish("slava", [1; 32]);
+        // A reconnecting client announces the same key again.
+        keys.publish("slava", [1; 32]);
+        keys.publish("eva", [2; 32]);
+        keys.publish("slava", [3; 32]);
+
+        let mut announced = Vec::new();
+        while announced.len() < 3 {
+            let mut batch = Vec::new();
+            assert!(outbox.next_batch(&mut batch).await);
+            let mut written = Vec::new();
+            fanout::write_batch(&mut written, &batch, false, false)
+                .await
+                .unwrap();
+            let mut reader = &written[..];
+            while !reader.is_empty() {
+                let message = Message::read(&mut reader).await.unwrap();
+                announced.push((message.nickname, message.message));
+            }
+        }
+        let expected = [("slava", [1; 32]), ("eva", [2; 32]), ("slava", [3; 32])]
+            .map(|(nickname, key)| (nickname.to_string(), MessageType::PublicKey { key }));
+        assert_eq!(announced, expected);
+
+        let all: Vec<(String, MessageType)> = keys
+            .all()
+            .into_iter()
+            .map(|message| (message.nickname, message.message))
+            .collect();
+        assert_eq!(all, [expected[1].clone(), expected[2].clone()]);
+    }
+}
@@ -0,0 +1,179 @@
+//! Pushing the metrics to a Prometheus push gateway.
+//!
+//! Where Prometheus can't scrape the `/metrics` endpoint, set `url` in the `[push]` section of the server config and
+//! a background task pushes the whole registry every `interval` to `<url>/metrics/job/<job>/instance/<instance>`.
+//! When the gateway is down, the pushes back off up to [`MAX_BACKOFF`] times the interval, the outage and the
+//! recovery are logged once and the failed pushes are counted in `metrics_push_failures`. The pushes are a part of
+//! the `metrics` feature.
+
+use std::time::Duration;
+
+#[cfg(feature = "metrics")]
+use anyhow::{anyhow, Context, Result};
+#[cfg(feature = "metrics")]
+use log::{info, warn};
+#[cfg(feature = "metrics")]
+use prometheus::{Encoder, TextEncoder};
+
+#[cfg(feature = "metrics")]
+use crate::metrics::{PUSH_FAILURES, REGISTRY};
+
+/// Default interval of the pushes.
+pub const PUSH_INTERVAL: Duration = Duration::from_secs(15);
+/// Default job label of the pushed metrics.
+pub const PUSH_JOB: &str = "chat_server";
+/// Maximal multiple of the interval between the pushes during an outage.
+#[cfg(feature = "metrics")]
+pub const MAX_BACKOFF: u32 = 8;
+/// Timeout of a single push.
+#[cfg(feature = "metrics")]
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Settings from the `[push]` section of the server config.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PushConfig {
+    /// Address of the gateway, e.g. `http://pushgateway:9091`, the pushes are off without it.
+    pub url: Option<String>,
+    pub interval: Duration,
+    pub job: String,
+    /// Instance label, the listening address of the server by default.
+    pub instance: Option<String>,
+}
+
+impl Default for PushConfig {
+    fn default() -> Self {
+        PushConfig {
+            url: None,
+            interval: PUSH_INTERVAL,
+            job: PUSH_JOB.to_string(),
+            instance: None,
+        }
+    }
+}
+
+/// Checks the gateway address from the config.
+///
+/// # Errors
+///
+/// This function will return an error if the address is not an `http://` URL.
+pub fn check_url(url: &str) -> Result<(), String> {
+    match url.strip_prefix("http://") {
+        Some(host) if !host.is_empty() => Ok(()),
+        _ => Err(format!(
+            "expected a gateway address like \"http://localhost:9091\", found \"{url}\""
+        )),
+    }
+}
+
+/// Returns the URL of the metrics group of the job and instance.
+#[cfg(feature = "metrics")]
+pub fn group_url(url: &str, job: &str, instance: &str) -> String {
+    format!(
+        "{}/metrics/job/{}/instance/{}",
+        url.trim_end_matches('/'),
+        encode_label(job),
+        encode_label(instance)
+    )
+}
+
+/// Percent-encodes the label value for the URL path.
+#[cfg(feature = "metrics")]
+fn encode_label(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b':' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// Spawns the background task pushing the metrics, if the gateway is configured.
+///
+/// # Arguments
+///
+/// - `config` - The push settings.
+/// - `default_instance` - The instance label used if the config doesn't set one.
+#[cfg(feature = "metrics")]
+pub fn spawn(config: PushConfig, default_instance: String) {
+    let Some(url) = &config.url else {
+        return;
+    };
+    let instance = config.instance.clone().unwrap_or(default_instance);
+    let target = group_url(url, &config.job, &instance);
+    info!("Pushing metrics to {} every {:?}.", target, config.interval);
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        let mut failures = 0;
+        loop {
+            let backoff = 2u32.saturating_pow(failures).min(MAX_BACKOFF);
+            tokio::time::sleep(config.interval * backoff).await;
+            match push(&client, &target).await {
+                Ok(()) if failures > 0 => {
+                    info!("Metrics push gateway is back after {} failures.", failures);
+                    failures = 0;
+                }
+                Ok(()) => (),
+                Err(err_msg) => {
+                    PUSH_FAILURES.inc();
+                    if failures == 0 {
+                        warn!("Metrics push failed, backing off: {:?}", err_msg);
+                    }
+                    failures += 1;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(feature = "metrics")]
+async fn push(client: &reqwest::Client, target: &str) -> Result<()> {
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder
+        .encode(&REGISTRY.gather(), &mut body)
+        .context("Metrics encoding error!")?;
+    let response = client
+        .put(target)
+        .header(reqwest::header::CONTENT_TYPE, encoder.format_type())
+        .timeout(PUSH_TIMEOUT)
+        .body(body)
+        .send()
+        .await
+        .context("Metrics push gateway unreachable!")?;
+    if !response.status().is_success() {
+        return Err(anyhow!(
+            "Metrics push gateway answered {}!",
+            response.status()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_url() {
+        assert!(check_url("http://localhost:9091").is_ok());
+        assert!(check_url("https://localhost:9091").is_err());
+        assert!(check_url("localhost:9091").is_err());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_group_url() {
+        assert_eq!(
+            group_url("http://gateway:9091/", "chat_server", "0.0.0.0:11111"),
+            "http://gateway:9091/metrics/job/chat_server/instance/0.0.0.0:11111"
+        );
+        assert_eq!(
+            group_url("http://gateway:9091", "chat server", "a/b"),
+            "http://gateway:9091/metrics/job/chat%20server/instance/a%2Fb"
+        );
+    }
+}
@@ -0,0 +1,352 @@
+//! Interactive prompt for the developers.
+//!
+//! The server started with [`REPL`] reads commands from the terminal it runs in, so its state can be inspected
+//! without the admin panel or the metrics server:
+//!
+//! - `connections` lists the registered clients with the frames waiting in their outboxes,
+//! - `queue <nickname|address>` shows the outbox of one client,
+//! - `inject <nickname> <text>` delivers a test message to everybody, it isn't stored,
+//! - `chaos on [drop_percent [max_delay]]` drops and delays the received messages, `chaos off` stops it,
+//! - `help` lists the commands.
+//!
+//! The [`Chaos`] mode is checked for every message read from a client. A dropped message is neither handled nor
+//! answered, as if it was lost on the way.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use chat::{Message, MessageType};
+use log::{info, warn};
+use parking_lot::Mutex;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use toml::Value;
+
+use crate::config;
+use crate::fanout::{FanOut, QueueDepth};
+
+/// Argument starting the prompt.
+pub const REPL: &str = "--repl";
+/// Default share of the dropped messages in the chaos mode, in percent.
+pub const DROP_PERCENT: u8 = 10;
+/// Default maximal delay of a message in the chaos mode.
+pub const MAX_DELAY: Duration = Duration::from_millis(500);
+
+const HELP: &str = "connections
+queue <nickname|address>
+inject <nickname> <text>
+chaos [on [drop_percent [max_delay]]|off]
+help";
+
+/// Removes the [`REPL`] argument.
+///
+/// # Returns
+///
+/// True if the argument was present.
+pub fn take_repl(arguments: &mut Vec<String>) -> bool {
+    let count = arguments.len();
+    arguments.retain(|argument| argument != REPL);
+    arguments.len() != count
+}
+
+/// Disruption of the received messages.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChaosConfig {
+    pub drop_percent: u8,
+    pub max_delay: Duration,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        ChaosConfig {
+            drop_percent: DROP_PERCENT,
+            max_delay: MAX_DELAY,
+        }
+    }
+}
+
+impl fmt::Display for ChaosConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "dropping {}% of the messages, delaying them up to {} ms",
+            self.drop_percent,
+            self.max_delay.as_millis()
+        )
+    }
+}
+
+/// Chaos mode shared by the client connections, off by default.
+#[derive(Debug, Clone, Default)]
+pub struct Chaos {
+    config: Arc<Mutex<Option<ChaosConfig>>>,
+}
+
+impl Chaos {
+    /// Turns the chaos mode on with the config or off with `None`.
+    pub fn set(&self, config: Option<ChaosConfig>) {
+        *self.config.lock() = config;
+    }
+
+    /// Returns the config of the chaos mode, `None` when it is off.
+    pub fn get(&self) -> Option<ChaosConfig> {
+        *self.config.lock()
+    }
+
+    /// Delays the received message by a random time when the chaos mode is on.
+    ///
+    /// # Returns
+    ///
+    /// True if the message should be dropped.
+    pub async fn disrupt(&self) -> bool {
+        let Some(config) = self.get() else {
+            return false;
+        };
+        let delay = random() % (config.max_delay.as_millis() as u64 + 1);
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+        random() % 100 < config.drop_percent as u64
+    }
+}
+
+/// Returns a random number good enough for the chaos, without a dependency on a random number generator.
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// Command typed to the prompt.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Connections,
+    Queue(String),
+    Inject { nickname: String, text: String },
+    Chaos(Option<ChaosConfig>),
+    ChaosStatus,
+    Help,
+}
+
+/// Parses the line typed to the prompt, e.g. `inject slava hello` or `chaos on 20 1s`.
+///
+/// # Errors
+///
+/// This function will return an error for an unknown command, a missing argument or an invalid chaos setting.
+pub fn parse_command(line: &str) -> Result<Command> {
+    let (command, arguments) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+    let arguments = arguments.trim();
+    let command = match (command, arguments) {
+        ("connections", "") => Command::Connections,
+        ("queue", client) if !client.is_empty() => Command::Queue(client.to_string()),
+        ("inject", arguments) => {
+            let (nickname, text) = arguments
+                .split_once(' ')
+                .ok_or_else(|| anyhow!("Usage: inject <nickname> <text>"))?;
+            Command::Inject {
+                nickname: nickname.to_string(),
+                text: text.trim().to_string(),
+            }
+        }
+        ("chaos", "") => Command::ChaosStatus,
+        ("chaos", "off") => Command::Chaos(None),
+        ("chaos", arguments) => Command::Chaos(Some(parse_chaos(arguments)?)),
+        ("help", "") => Command::Help,
+        _ => return Err(anyhow!("Unknown command {line}, type help!")),
+    };
+    Ok(command)
+}
+
+fn parse_chaos(arguments: &str) -> Result<ChaosConfig> {
+    let mut config = ChaosConfig::default();
+    let mut arguments = arguments.split_whitespace();
+    if arguments.next() != Some("on") {
+        return Err(anyhow!("Usage: chaos [on [drop_percent [max_delay]]|off]"));
+    }
+    if let Some(percent) = arguments.next() {
+        config.drop_percent = percent
+            .parse()
+            .ok()
+            .filter(|percent| *percent <= 100)
+            .with_context(|| format!("Invalid drop percent {percent}, use 0 to 100!"))?;
+    }
+    if let Some(delay) = arguments.next() {
+        config.max_delay = config::parse_duration(&Value::String(delay.to_string()))
+            .map_err(|err_msg| anyhow!("Invalid max delay {delay}: {err_msg}!"))?;
+    }
+    Ok(config)
+}
+
+fn describe(depth: &QueueDepth) -> String {
+    format!(
+        "{} {}: {} high, {} low queued",
+        depth.addr,
+        depth.nickname.as_deref().unwrap_or("(not identified)"),
+        depth.high,
+        depth.low
+    )
+}
+
+/// Runs the command and returns its output.
+pub fn execute(command: Command, fan_out: &FanOut, chaos: &Chaos) -> String {
+    match command {
+        Command::Connections => {
+            let depths = fan_out.queue_depths();
+            let mut lines = vec![format!("{} connections", depths.len())];
+            lines.extend(depths.iter().map(describe));
+            lines.join("\n")
+        }
+        Command::Queue(client) => fan_out
+            .queue_depths()
+            .iter()
+            .filter(|depth| {
+                depth.nickname.as_deref() == Some(&client) || depth.addr.to_string() == client
+            })
+            .map(describe)
+            .reduce(|lines, line| lines + "\n" + &line)
+            .unwrap_or_else(|| format!("No connection {client}.")),
+        Command::Inject { nickname, text } => {
+            fan_out.announce(Message::from(&nickname, MessageType::text(text)));
+            format!("Injected a message from {nickname}.")
+        }
+        Command::Chaos(config) => {
+            chaos.set(config);
+            match config {
+                Some(config) => {
+                    warn!("Chaos mode on, {}.", config);
+                    format!("Chaos mode on, {config}.")
+                }
+                None => {
+                    info!("Chaos mode off.");
+                    "Chaos mode off.".to_string()
+                }
+            }
+        }
+        Command::ChaosStatus => match chaos.get() {
+            Some(config) => format!("Chaos mode on, {config}."),
+            None => "Chaos mode off.".to_string(),
+        },
+        Command::Help => HELP.to_string(),
+    }
+}
+
+/// Reads the commands from the standard input until it closes.
+pub fn spawn(fan_out: FanOut, chaos: Chaos) {
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        loop {
+            print!("> ");
+            let _ = std::io::stdout().flush();
+            let Ok(Some(line)) = lines.next_line().await else {
+                break;
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match parse_command(&line) {
+                Ok(command) => println!("{}", execute(command, &fan_out, &chaos)),
+                Err(err_msg) => println!("{err_msg}"),
+            }
+        }
+        info!("The REPL input closed.");
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fanout::DeliveryConfig;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            parse_command(" connections ").unwrap(),
+            Command::Connections
+        );
+        assert_eq!(
+            parse_command("inject slava hello there").unwrap(),
+            Command::Inject {
+                nickname: "slava".into(),
+                text: "hello there".into()
+            }
+        );
+        assert_eq!(
+            parse_command("chaos on 20 1s").unwrap(),
+            Command::Chaos(Some(ChaosConfig {
+                drop_percent: 20,
+                max_delay: Duration::from_secs(1)
+            }))
+        );
+        assert_eq!(
+            parse_command("chaos on").unwrap(),
+            Command::Chaos(Some(ChaosConfig::default()))
+        );
+        assert_eq!(parse_command("chaos off").unwrap(), Command::Chaos(None));
+        assert!(parse_command("chaos on 101").is_err());
+        assert!(parse_command("chaos on 10 soon").is_err());
+        assert!(parse_command("inject slava").is_err());
+        assert!(parse_command("queue").is_err());
+        assert!(parse_command("quit").is_err());
+    }
+
+    #[test]
+    fn test_take_repl() {
+        let mut arguments = vec![
+            "server".to_string(),
+            REPL.to_string(),
+            "localhost".to_string(),
+        ];
+        assert!(take_repl(&mut arguments));
+        assert_eq!(arguments, ["server", "localhost"]);
+        assert!(!take_repl(&mut arguments));
+    }
+
+    #[tokio::test]
+    async fn test_chaos() {
+        let chaos = Chaos::default();
+        assert!(!chaos.disrupt().await);
+        chaos.set(Some(ChaosConfig {
+            drop_percent: 100,
+            max_delay: Duration::ZERO,
+        }));
+        assert!(chaos.disrupt().await);
+        chaos.set(Some(ChaosConfig {
+            drop_percent: 0,
+            max_delay: Duration::from_millis(5),
+        }));
+        assert!(!chaos.disrupt().await);
+    }
+
+    #[tokio::test]
+    async fn test_execute() {
+        let fan_out = FanOut::spawn(DeliveryConfig { workers: 1 });
+        let chaos = Chaos::default();
+        let addr = "127.0.0.1:4000".parse().unwrap();
+        let (connection, _outbox) = fan_out.register(addr);
+        connection.identify("slava");
+        let injected = Command::Inject {
+            nickname: "eva".into(),
+            text: "test".into(),
+        };
+        assert_eq!(
+            execute(injected, &fan_out, &chaos),
+            "Injected a message from eva."
+        );
+        assert_eq!(
+            execute(Command::Connections, &fan_out, &chaos),
+            "1 connections\n127.0.0.1:4000 slava: 1 high, 0 low queued"
+        );
+        let queue = execute(Command::Queue("127.0.0.1:4000".into()), &fan_out, &chaos);
+        assert_eq!(queue, "127.0.0.1:4000 slava: 1 high, 0 low queued");
+        assert_eq!(
+            execute(Command::Queue("eva".into()), &fan_out, &chaos),
+            "No connection eva."
+        );
+        execute(
+            Command::Chaos(Some(ChaosConfig::default())),
+            &fan_out,
+            &chaos,
+        );
+        assert_eq!(chaos.get(), Some(ChaosConfig::default()));
+    }
+}
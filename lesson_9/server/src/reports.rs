@@ -0,0 +1,148 @@
+//! Reports of abusive messages.
+//!
+//! A client reports a stored message by its id with a reason, e.g. `.report 42 spam`. The report is stored open in the
+//! `reports` table with the author of the message, and the moderators listed in the `[moderation]` section of the
+//! server config who are connected get a system notice about it. The nicknames are not authenticated, so the
+//! moderators are only told, they handle the reports in the moderation inbox of the admin panel, which records
+//! resolving or dismissing a report in the audit table.
+
+use std::sync::Arc;
+
+use chat::{ErrorCode, Message, MessageType, MAX_REPORT_REASON};
+use log::{error, info};
+
+use crate::db::Database;
+use crate::fanout::FanOut;
+
+/// Settings from the `[moderation]` section of the server config.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ModerationConfig {
+    /// Nicknames notified about the new reports.
+    pub moderators: Vec<String>,
+}
+
+/// Handler of the reports shared by the client connections.
+#[derive(Clone)]
+pub struct Reports {
+    database: Database,
+    fan_out: FanOut,
+    moderators: Arc<Vec<String>>,
+}
+
+impl Reports {
+    /// Creates the handler notifying the moderators of the config.
+    pub fn new(database: Database, fan_out: FanOut, config: &ModerationConfig) -> Reports {
+        Reports {
+            database,
+            fan_out,
+            moderators: Arc::new(config.moderators.clone()),
+        }
+    }
+
+    /// Stores the report of the message by the `reporter` and notifies the connected moderators.
+    ///
+    /// # Returns
+    ///
+    /// The reply to the reporter, a confirmation or a server error if the report was rejected or the database failed.
+    pub async fn handle(&self, reporter: &str, message: i64, reason: &str) -> Message {
+        let reason = reason.trim();
+        if reason.is_empty() || reason.chars().count() > MAX_REPORT_REASON {
+            return server_error(ErrorCode::InvalidReport);
+        }
+        let created_at = chat::unix_millis();
+        let (id, author) = match self
+            .database
+            .insert_report(message, reporter, reason, created_at)
+            .await
+        {
+            Ok(Some(report)) => report,
+            Ok(None) => return server_error(ErrorCode::UnknownMessage { id: message }),
+            Err(err_msg) => {
+                error!("Insert report error: {:?}", err_msg);
+                return server_error(ErrorCode::Unavailable);
+            }
+        };
+        info!(
+            "Report #{} of message #{} by {}: {}",
+            id, message, reporter, reason
+        );
+        let notice = notice(id, reporter, message, &author, reason);
+        let notified: usize = self
+            .moderators
+            .iter()
+            .map(|moderator| {
+                let notice = Message::system(MessageType::Text(notice.clone()));
+                self.fan_out.notify(moderator, notice)
+            })
+            .sum();
+        if notified == 0 {
+            info!("No moderator is connected to see report #{}.", id);
+        }
+        Message::system(MessageType::Text(format!(
+            "Thank you, message #{message} was reported to the moderators as report #{id}."
+        )))
+    }
+}
+
+/// Returns the notice of the moderators, e.g. `Report #3: slava reported message #42 of spammer: "spam"`.
+fn notice(id: i64, reporter: &str, message: i64, author: &str, reason: &str) -> String {
+    format!("Report #{id}: {reporter} reported message #{message} of {author}: \"{reason}\"")
+}
+
+fn server_error(code: ErrorCode) -> Message {
+    Message::system(MessageType::ServerError { code })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::DatabaseConfig;
+    use crate::fanout::DeliveryConfig;
+    use crate::persistence::Record;
+
+    #[tokio::test]
+    async fn test_handle() {
+        let path = std::env::temp_dir().join(format!("chat-report-{}.db", std::process::id()));
+        let url = format!("sqlite://{}", path.display());
+        let database = Database::open(&url, DatabaseConfig::default())
+            .await
+            .unwrap();
+        let spam = Message::from("spammer", MessageType::text("buy now"));
+        let id = database.insert_message(&Record::new(&spam)).await.unwrap();
+        let fan_out = FanOut::spawn(DeliveryConfig { workers: 1 });
+        let (moderator, _outbox) = fan_out.register("127.0.0.1:1".parse().unwrap());
+        moderator.identify("eva");
+        let config = ModerationConfig {
+            moderators: vec!["eva".to_string(), "adam".to_string()],
+        };
+        let reports = Reports::new(database, fan_out.clone(), &config);
+        let queued = |fan_out: &FanOut| fan_out.queue_depths()[0].high;
+
+        let reply = reports.handle("slava", id, " spam ").await;
+        let MessageType::Text(text) = reply.message else {
+            panic!("expected a confirmation, found {:?}", reply.message);
+        };
+        assert!(text.contains("report #1"), "{text}");
+        assert_eq!(queued(&fan_out), 1);
+
+        let reply = reports.handle("slava", id + 1, "spam").await;
+        let code = ErrorCode::UnknownMessage { id: id + 1 };
+        assert_eq!(reply.message, MessageType::ServerError { code });
+        let reason = "x".repeat(MAX_REPORT_REASON + 1);
+        for reason in ["  ", reason.as_str()] {
+            let reply = reports.handle("slava", id, reason).await;
+            let code = ErrorCode::InvalidReport;
+            assert_eq!(reply.message, MessageType::ServerError { code });
+        }
+        assert_eq!(queued(&fan_out), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_notice() {
+        assert_eq!(
+            notice(3, "slava", 42, "spammer", "spam"),
+            "Report #3: slava reported message #42 of spammer: \"spam\""
+        );
+    }
+}
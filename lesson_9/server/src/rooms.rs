@@ -0,0 +1,92 @@
+//! Rooms of the chat.
+//!
+//! Every client starts in the [`chat::DEFAULT_ROOM`], joins more rooms with [`MessageType::Join`] and leaves them with
+//! [`MessageType::Leave`], even the default one. The rooms aren't created or configured, a room exists while a client
+//! is in it. A message is delivered only to the other clients in its room and the server rejects a message sent to a
+//! room the sender hasn't joined. The announcements of the server, e.g. the polls, go to all the clients.
+//!
+//! Every message is stored with its room. The history and the search requests are answered from the room of the
+//! request, which the client must have joined, see [`crate::db`].
+
+use chat::{ErrorCode, Message, MessageType};
+use log::info;
+
+use crate::fanout::{Connection, FanOut};
+
+/// Joins the client to the room.
+///
+/// # Returns
+///
+/// The reply to the client listing the users in the room, or a server error if the room name is invalid.
+pub fn join(fan_out: &FanOut, connection: &Connection, nickname: &str, room: &str) -> Message {
+    if !chat::is_valid_room(room) {
+        return server_error(ErrorCode::InvalidRoom);
+    }
+    if connection.join(room) {
+        info!("{} joined room {}.", nickname, room);
+    }
+    let members = fan_out.members(room);
+    Message::system(MessageType::Text(format!(
+        "You are in #{room} with {}.",
+        members.join(", ")
+    )))
+}
+
+/// Removes the client from the room.
+///
+/// # Returns
+///
+/// The confirmation, or a server error if the client isn't in the room.
+pub fn leave(connection: &Connection, nickname: &str, room: &str) -> Message {
+    if !connection.leave(room) {
+        return server_error(ErrorCode::NotInRoom);
+    }
+    info!("{} left room {}.", nickname, room);
+    Message::system(MessageType::Text(format!("You left #{room}.")))
+}
+
+fn server_error(code: ErrorCode) -> Message {
+    Message::system(MessageType::ServerError { code })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fanout::DeliveryConfig;
+
+    fn text(message: Message) -> String {
+        match message.message {
+            MessageType::Text(text) => text,
+            other => panic!("expected a text, found {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_join_and_leave() {
+        let fan_out = FanOut::spawn(DeliveryConfig { workers: 1 });
+        let (slava, _slava_outbox) = fan_out.register("127.0.0.1:1".parse().unwrap());
+        let (eva, _eva_outbox) = fan_out.register("127.0.0.1:2".parse().unwrap());
+        slava.identify("slava");
+        eva.identify("eva");
+
+        assert_eq!(
+            text(join(&fan_out, &slava, "slava", "rust")),
+            "You are in #rust with slava."
+        );
+        assert_eq!(
+            text(join(&fan_out, &eva, "eva", "rust")),
+            "You are in #rust with eva, slava."
+        );
+        let code = ErrorCode::InvalidRoom;
+        let reply = join(&fan_out, &eva, "eva", "#rust");
+        assert_eq!(reply.message, MessageType::ServerError { code });
+
+        assert_eq!(text(leave(&eva, "eva", "rust")), "You left #rust.");
+        let code = ErrorCode::NotInRoom;
+        assert_eq!(
+            leave(&eva, "eva", "rust").message,
+            MessageType::ServerError { code }
+        );
+        assert_eq!(fan_out.members("rust"), ["slava"]);
+    }
+}
@@ -0,0 +1,183 @@
+//! Roster of the active users kept in sync on the clients.
+//!
+//! A client joining gets a [`MessageType::RosterSnapshot`] and everybody gets a [`MessageType::RosterDelta`] for every
+//! join, leave or rename. Every delta increments the version, so a client noticing a gap in the versions requests a
+//! fresh snapshot. A nickname used by more connections is in the roster once, it joins with the first of them and
+//! leaves with the last.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use chat::{Message, MessageType, RosterChange};
+
+use crate::fanout::FanOut;
+
+#[derive(Default)]
+struct Users {
+    version: u64,
+    /// Number of the active connections of every nickname.
+    connections: BTreeMap<String, usize>,
+}
+
+impl Users {
+    fn join(&mut self, nickname: &str) -> bool {
+        let count = self.connections.entry(nickname.to_string()).or_default();
+        *count += 1;
+        *count == 1
+    }
+
+    fn leave(&mut self, nickname: &str) -> bool {
+        let Some(count) = self.connections.get_mut(nickname) else {
+            return false;
+        };
+        *count -= 1;
+        if *count > 0 {
+            return false;
+        }
+        self.connections.remove(nickname);
+        true
+    }
+}
+
+/// Active users shared by the sessions.
+#[derive(Clone)]
+pub struct Roster {
+    users: Arc<Mutex<Users>>,
+    fan_out: FanOut,
+}
+
+impl Roster {
+    /// Creates an empty roster announcing the deltas through the fan-out.
+    pub fn new(fan_out: FanOut) -> Roster {
+        Roster {
+            users: Default::default(),
+            fan_out,
+        }
+    }
+
+    /// Returns the snapshot of the active users.
+    pub fn snapshot(&self) -> Message {
+        let users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        Message::system(MessageType::RosterSnapshot {
+            version: users.version,
+            users: users.connections.keys().cloned().collect(),
+        })
+    }
+
+    /// Returns the nicknames of the active users.
+    pub fn users(&self) -> Vec<String> {
+        let users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        users.connections.keys().cloned().collect()
+    }
+
+    /// Counts a connection becoming active as the nickname.
+    pub fn join(&self, nickname: &str) {
+        self.update(|users| {
+            let joined = users.join(nickname);
+            joined.then(|| RosterChange::Joined(nickname.to_string()))
+        });
+    }
+
+    /// Counts a connection of the nickname closing.
+    pub fn leave(&self, nickname: &str) {
+        self.update(|users| {
+            let left = users.leave(nickname);
+            left.then(|| RosterChange::Left(nickname.to_string()))
+        });
+    }
+
+    /// Moves a connection from the nickname to another one.
+    pub fn rename(&self, from: &str, to: &str) {
+        self.update(|users| match (users.leave(from), users.join(to)) {
+            (true, true) => Some(RosterChange::Renamed {
+                from: from.to_string(),
+                to: to.to_string(),
+            }),
+            (true, false) => Some(RosterChange::Left(from.to_string())),
+            (false, true) => Some(RosterChange::Joined(to.to_string())),
+            (false, false) => None,
+        });
+    }
+
+    /// Applies the change and announces it while holding the lock, so the deltas are delivered in their order.
+    fn update<F: FnOnce(&mut Users) -> Option<RosterChange>>(&self, change: F) {
+        let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(change) = change(&mut users) {
+            users.version += 1;
+            let version = users.version;
+            self.fan_out
+                .announce(Message::system(MessageType::RosterDelta {
+                    version,
+                    change,
+                }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fanout;
+
+    #[test]
+    fn test_users() {
+        let mut users = Users::default();
+        assert!(users.join("slava"));
+        assert!(!users.join("slava"));
+        assert!(users.join("eva"));
+        assert!(!users.leave("slava"));
+        assert!(users.leave("slava"));
+        assert!(!users.leave("slava"));
+        assert_eq!(users.connections.keys().collect::<Vec<_>>(), ["eva"]);
+    }
+
+    #[tokio::test]
+    async fn test_roster() {
+        let fan_out = FanOut::spawn(Default::default());
+        let addr = "127.0.0.1:4000".parse().unwrap();
+        let (_connection, mut outbox) = fan_out.register(addr);
+        let roster = Roster::new(fan_out);
+        roster.join("slava");
+        roster.join("slava");
+        // Only the last connection of a nickname leaves it.
+        roster.rename("slava", "eva");
+        roster.rename("slava", "eva");
+        roster.leave("eva");
+        roster.rename("eva", "ana");
+
+        let mut deltas = Vec::new();
+        while deltas.len() < 4 {
+            let mut batch = Vec::new();
+            assert!(outbox.next_batch(&mut batch).await);
+            let mut written = Vec::new();
+            fanout::write_batch(&mut written, &batch, false, false)
+                .await
+                .unwrap();
+            let mut reader = &written[..];
+            while !reader.is_empty() {
+                deltas.push(Message::read(&mut reader).await.unwrap());
+            }
+        }
+        let expected = [
+            (1, RosterChange::Joined("slava".into())),
+            (2, RosterChange::Joined("eva".into())),
+            (3, RosterChange::Left("slava".into())),
+            (
+                4,
+                RosterChange::Renamed {
+                    from: "eva".into(),
+                    to: "ana".into(),
+                },
+            ),
+        ];
+        for (delta, (version, change)) in deltas.into_iter().zip(expected) {
+            assert!(delta.system);
+            assert_eq!(delta.message, MessageType::RosterDelta { version, change });
+        }
+        let MessageType::RosterSnapshot { version, users } = roster.snapshot().message else {
+            panic!("Expected MessageType::RosterSnapshot");
+        };
+        assert_eq!((version, users), (4, vec!["ana".to_string()]));
+        assert_eq!(roster.users(), ["ana"]);
+    }
+}
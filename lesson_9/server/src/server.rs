@@ -6,30 +6,146 @@
 //!
 //! - **hostname** default: localhost
 //! - **port** default: 11111
+//! - **--port** N overrides the port, `0` picks a free one written to the port file, see [`discovery`]
+//! - **--max-clients** N limits the served clients, the others wait in a queue
+//! - **--verbose** shows the raw causes of the errors
+//! - **--repl** reads developer commands from the terminal, see [`repl`]
+//! - **--print-config** prints the effective configuration with the secrets redacted instead of starting the server
+//!
+//! # Subcommands:
+//!
+//! - **import-history** --format whatsapp|irc-log|json --file dump.txt
+//! - **maintain** checks the database integrity and runs VACUUM and ANALYZE
+//! - **config check** [server.toml] validates the config without starting the server
+//! - **log-level** [module|all level [duration]] bumps the log level of the running server for a while, no arguments
+//!   list the current levels
 
 extern crate chat;
 
-use anyhow::{Context, Result};
-use axum::{http::StatusCode, routing::get, Router};
-use env_logger::{Builder, Env};
-use lazy_static::lazy_static;
-use log::{debug, error, info};
-use prometheus::{Counter, Encoder, Gauge, Registry, TextEncoder};
-use sqlx::{migrate::MigrateDatabase, Sqlite, SqlitePool};
-use tokio::net::TcpListener;
-use tokio::sync::broadcast;
+mod access;
+mod attachments;
+mod clock;
+mod colors;
+mod config;
+mod db;
+mod digest;
+mod discovery;
+mod enrich;
+mod events;
+mod fanout;
+mod import;
+mod logging;
+mod maintenance;
+mod memory;
+mod metrics;
+mod persistence;
+mod polls;
+mod public_keys;
+mod push;
+mod repl;
+mod reports;
+mod rooms;
+mod roster;
+mod session;
+mod spam;
+mod storage;
+mod waiting;
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{anyhow, Context, Result};
+#[cfg(feature = "metrics")]
+use axum::routing::{delete, get};
+#[cfg(feature = "metrics")]
+use axum::Router;
+use futures_util::StreamExt;
+use log::{debug, error, info, warn};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::FramedRead;
 
-use chat::{Message, MessageError};
+use access::{Access, ConnectionSlot};
+#[cfg(feature = "metrics")]
+use attachments::Attachments;
+use chat::codec::{self, MessageCodec};
+use chat::keepalive::{self, Idle};
+use chat::report::{self, Report};
+use chat::{
+    ErrorCode, Message, MessageError, MessageType, ServerLimits, WireFormat, ACK_ANNOTATION,
+};
+use colors::Colors;
+use config::{Config, CONFIG_FILE};
+use db::Database;
+use digest::Digests;
+use enrich::Pipeline;
+use events::{Events, ServerEvent};
+use fanout::{Connection, FanOut, Lane};
+use logging::LogLevels;
+use memory::InFlight;
+use metrics::{CORRUPTED_FRAMES, MESSAGE_COUNTER};
+use persistence::{Persistence, Record};
+use polls::Polls;
+use public_keys::PublicKeys;
+use repl::Chaos;
+use reports::Reports;
+use roster::Roster;
+use session::{CloseReason, Event, Session};
+use spam::Verdict;
+use waiting::{ClientSlot, WaitingRoom};
 
 const DB: &str = "sqlite://server.db";
+/// Address of the HTTP endpoints for the metrics, the access lists, the attachments, the events, the digests and the
+/// log levels.
+#[cfg(feature = "metrics")]
+const METRICS_ADDRESS: &str = "0.0.0.0:3001";
+/// Default maximal number of messages in a history page.
+const MAX_HISTORY_LIMIT: u32 = 100;
+/// Allowance for the serialization of a message on top of the attachment limit.
+const FRAME_OVERHEAD: usize = 64 * 1024;
+const COMMANDS: [&str; 4] = ["import-history", "maintain", "config", "log-level"];
+/// Features announced to the clients in the Welcome message.
+const CAPABILITIES: [&str; 16] = [
+    "history",
+    "search",
+    "bench",
+    "annotations",
+    "polls",
+    "sync",
+    "roster",
+    "checksum",
+    "compression",
+    "chunks",
+    "reports",
+    "rooms",
+    "whisper",
+    "acks",
+    "keepalive",
+    "e2e",
+];
+/// Size of an encrypted message delivered as an attachment, the server doesn't see what it carries.
+const ENCRYPTED_ATTACHMENT: usize = 64 * 1024;
 
-lazy_static! {
-    static ref REGISTRY: Registry = Registry::new();
-    static ref MESSAGE_COUNTER: Counter =
-        Counter::new("message_counter", "counts number of messages send")
-            .expect("Counter metrics init failed!");
-    static ref USER_COUNTER: Gauge = Gauge::new("user_counter", "counts number of connected users")
-        .expect("Gauge metrics init failed!");
+/// State shared by the client connections.
+#[derive(Clone)]
+struct Shared {
+    config: Config,
+    access: Access,
+    database: Database,
+    persistence: Persistence,
+    enrichers: Arc<Pipeline>,
+    fan_out: FanOut,
+    polls: Polls,
+    reports: Reports,
+    roster: Roster,
+    public_keys: PublicKeys,
+    digests: Digests,
+    colors: Colors,
+    events: Events,
+    #[cfg(feature = "metrics")]
+    attachments: Attachments,
+    chaos: Chaos,
 }
 
 fn log_broadcasting(
@@ -57,9 +173,19 @@ fn log_incoming(message: &Message, client_addr: &std::net::SocketAddr) {
 
 /// Runs the chat server.
 ///
-/// This function initializes the database, parses the server address arguments, binds the server to the given address,
-/// sets up a broadcast channel for message broadcasting, and enters a loop to accept and handle incoming client
-/// connections.
+/// This function starts the HTTP endpoints, initializes the database, binds the server to the given address, spawns
+/// the delivery workers broadcasting the messages, and enters a loop to accept incoming client connections. Admitted
+/// connections pass the waiting room before they are served by [`serve_client`].
+///
+/// # Arguments
+///
+/// - `config` - The server config.
+/// - `access` - The access control checking the accepted connections.
+/// - `address` - The address to listen on.
+/// - `room` - The waiting room limiting the number of served clients.
+/// - `log_levels` - The log levels adjusted through the HTTP endpoints.
+/// - `effective` - The effective configuration served by the `/admin/config` endpoint, see [`effective_config`].
+/// - `repl` - Whether to read the developer commands from the terminal, see [`repl`].
 ///
 /// # Returns
 ///
@@ -69,181 +195,777 @@ fn log_incoming(message: &Message, client_addr: &std::net::SocketAddr) {
 ///
 /// This function will return an error if:
 ///
+/// - The HTTP endpoints fail to bind to [`METRICS_ADDRESS`].
 /// - There is an issue initializing the database.
 /// - The server fails to bind to the specified address.
-async fn run_server() -> Result<()> {
-    let pool = init_db().await?;
-    let address = chat::Address::parse_arguments();
-    get_metrics()?;
+async fn run_server(
+    config: Config,
+    access: Access,
+    address: chat::Address,
+    room: WaitingRoom,
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))] log_levels: LogLevels,
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))] effective: String,
+    repl: bool,
+) -> Result<()> {
+    #[cfg(feature = "metrics")]
+    let attachments = Attachments::new(config.attachments.clone());
+    let events = Events::new(config.events.clone());
+    #[cfg(feature = "metrics")]
+    attachments.spawn_cleanup();
+    let database = Database::open(DB, config.database).await?;
+    let transport = digest::transport(&config.digest)?;
+    let digests = Digests::new(
+        database.clone(),
+        config.digest.clone(),
+        &config.server.name,
+        transport,
+    );
+    #[cfg(feature = "metrics")]
+    serve_http(
+        access.clone(),
+        attachments.clone(),
+        events.clone(),
+        digests.clone(),
+        log_levels,
+        effective,
+    )
+    .await?;
+    let persistence = Persistence::spawn(database.clone(), config.persistence);
+    maintenance::spawn_scheduler(database.clone(), config.maintenance);
+    metrics::register_metrics()?;
+    #[cfg(not(feature = "metrics"))]
+    if config.push.url.is_some() {
+        warn!("Built without the metrics feature, the [push] section is ignored.");
+    }
+    #[cfg(not(feature = "metrics"))]
+    if config.events.token.is_some() {
+        warn!("Built without the metrics feature, the [events] section is ignored.");
+    }
+    #[cfg(not(feature = "metrics"))]
+    if config.digest.url.is_some() {
+        warn!("Built without the metrics feature, the digests have no unsubscribe links.");
+    }
     let listener = TcpListener::bind(address.to_string())
         .await
         .with_context(|| format!("Binding error for address: {}", address.to_string()))?;
-    info!("Server listen on: {}", address.to_string());
+    let port = listener
+        .local_addr()
+        .context("Reading the bound port failed!")?
+        .port();
+    let bound = format!("{}:{port}", address.hostname());
+    info!("Server listen on: {}", bound);
+    let _published = discovery::publish(&config.server.name, port);
+    #[cfg(feature = "metrics")]
+    push::spawn(config.push.clone(), bound);
+    let json_listener = match config.server.json_port {
+        Some(port) => {
+            let json_address = format!("{}:{port}", address.hostname());
+            let listener = TcpListener::bind(&json_address)
+                .await
+                .with_context(|| format!("Binding error for JSON address: {json_address}"))?;
+            info!("Server listen for JSON lines on: {}", json_address);
+            Some(listener)
+        }
+        None => None,
+    };
 
-    let (broadcast_send, _broadcast_revice) = broadcast::channel(1024);
+    let fan_out = FanOut::spawn(config.delivery);
+    let polls = Polls::spawn(database.clone(), fan_out.clone());
+    let reports = Reports::new(database.clone(), fan_out.clone(), &config.moderation);
+    let roster = Roster::new(fan_out.clone());
+    let public_keys = PublicKeys::new(fan_out.clone());
+    clock::spawn_pings(fan_out.clone());
+    let colors = Colors::load(database.clone()).await?;
+    digests.clone().spawn_scheduler(roster.clone());
+    storage::spawn_checker(database.clone(), fan_out.clone(), config.storage);
+    let shared = Shared {
+        config,
+        access: access.clone(),
+        database,
+        persistence,
+        enrichers: Arc::new(Pipeline::with_defaults()),
+        fan_out,
+        polls,
+        reports,
+        roster,
+        public_keys,
+        digests,
+        colors,
+        events,
+        #[cfg(feature = "metrics")]
+        attachments,
+        chaos: Chaos::default(),
+    };
+    if repl {
+        repl::spawn(shared.fan_out.clone(), shared.chaos.clone());
+    }
     loop {
-        let Ok((stream, addr)) = listener.accept().await else {
+        let (accepted, format) = tokio::select! {
+            accepted = listener.accept() => (accepted, WireFormat::Bincode),
+            accepted = accept(json_listener.as_ref()) => (accepted, WireFormat::Json),
+        };
+        let Ok((stream, addr)) = accepted else {
             error!("Failed to accept connection!");
             continue;
         };
-        USER_COUNTER.inc();
-        let sender = broadcast_send.clone();
-        let mut receiver = broadcast_send.subscribe();
-        let (mut stream_read, mut stream_writer) = stream.into_split();
-        let pool_clone = pool.clone();
-
+        let mut session = Session::new(addr, shared.config.spam, shared.events.clone())
+            .with_notices(shared.fan_out.clone())
+            .with_roster(shared.roster.clone());
+        let slot = match access.admit(addr.ip()) {
+            Ok(slot) => slot,
+            Err(rejection) => {
+                warn!("Rejecting connection from {:?}: {}.", addr, rejection);
+                session.close(CloseReason::Rejected);
+                continue;
+            }
+        };
+        let shared = shared.clone();
+        let room = room.clone();
         tokio::spawn(async move {
-            loop {
-                match Message::read(&mut stream_read).await {
-                    Ok(msg) => {
-                        log_incoming(&msg, &addr);
-                        MESSAGE_COUNTER.inc();
-                        if let Err(err_msg) = insert_db(&pool_clone, &msg).await {
-                            error!("Insert database error: {:?}", err_msg);
-                        };
-                        if sender.send((msg, addr)).is_err() {
-                            break;
-                        }
+            let mut stream = stream;
+            match room.enter(&mut stream, format).await {
+                Ok(Some(client_slot)) => {
+                    let slots = (slot, client_slot);
+                    serve_client(stream, format, session, slots, shared).await
+                }
+                Ok(None) => session.close(CloseReason::LeftQueue),
+                Err(err_msg) => {
+                    error!("Waiting room error: {:?}", err_msg);
+                    session.close(CloseReason::Error);
+                }
+            }
+        });
+    }
+}
+
+/// Serves the admitted client until it disconnects.
+///
+/// The Welcome message is sent before any other message, then the messages of the client are read and handled by
+/// [`handle_message`] until the session closes.
+///
+/// # Arguments
+///
+/// - `stream` - The connection of the client.
+/// - `format` - The wire format of the listener the client connected to.
+/// - `session` - The session of the connection admitted by the access control and the waiting room.
+/// - `slots` - The slots of the access control and the waiting room, released when the client disconnects.
+/// - `shared` - The state shared by the client connections.
+async fn serve_client(
+    mut stream: TcpStream,
+    format: WireFormat,
+    mut session: Session,
+    slots: (ConnectionSlot, ClientSlot),
+    shared: Shared,
+) {
+    let _slots = slots;
+    let addr = session.addr();
+    if let Err(err_msg) = session.transition(Event::Admitted) {
+        error!("Session error: {}", err_msg);
+        return;
+    }
+    let (connection, mut outbox) = shared.fan_out.register(addr);
+    let motd = shared.config.server.motd.clone();
+    let greeting = motd.map(|motd| Message::system(MessageType::Text(motd)));
+    for message in std::iter::once(welcome_message(&shared.config)).chain(greeting) {
+        if let Err(err_msg) = message.send_as(&mut stream, format).await {
+            error!("Welcome Error: {:?}", err_msg);
+            session.close(CloseReason::Error);
+            return;
+        }
+    }
+    if let Err(err_msg) = session.transition(Event::Welcomed) {
+        error!("Session error: {}", err_msg);
+        return;
+    }
+    let (stream_read, mut stream_writer) = stream.into_split();
+    // The frames for the client carry the checksums once it sends a checksummed frame, the big messages are
+    // compressed once it sends a compressed frame.
+    let checksum = Arc::new(AtomicBool::new(false));
+    let writer_checksum = checksum.clone();
+    let compression = Arc::new(AtomicBool::new(false));
+    let writer_compression = compression.clone();
+
+    tokio::spawn(async move {
+        let mut batch = Vec::new();
+        while outbox.next_batch(&mut batch).await {
+            let written = match format {
+                WireFormat::Bincode => {
+                    let checksum = writer_checksum.load(Ordering::Relaxed);
+                    let compression = writer_compression.load(Ordering::Relaxed);
+                    fanout::write_batch(&mut stream_writer, &batch, checksum, compression).await
+                }
+                WireFormat::Json => fanout::write_json_batch(&mut stream_writer, &batch).await,
+            };
+            if let Err(err_msg) = written {
+                error!("Reciever Error: {:?}", err_msg);
+                break;
+            }
+            batch.clear();
+        }
+    });
+
+    let max_in_flight = shared.config.limits.max_in_flight;
+    let max_frame = max_in_flight.saturating_add(FRAME_OVERHEAD);
+    let codec = MessageCodec::with_max(max_frame).with_format(format);
+    let idle = Idle::default();
+    let mut frames = FramedRead::new(idle.watch(stream_read), codec);
+    // Closing the connection is a failed handshake only before sending anything, an invalid message is counted already.
+    let mut silent = true;
+    // A client which answered a Ping answers all of them, so its silence means a dead connection. Older clients don't.
+    let mut answers_pings = false;
+    loop {
+        let window = answers_pings.then_some(keepalive::DEAD_AFTER);
+        let read = tokio::select! {
+            read = frames.next() => codec::flatten(read),
+            stall = idle.stalled(window) => {
+                warn!("No data from {:?} for {} s, dropping the dead connection.", addr, stall.as_secs());
+                session.close(CloseReason::TimedOut);
+                break;
+            }
+        };
+        if frames.decoder().received_checksum() && !checksum.swap(true, Ordering::Relaxed) {
+            debug!("Client {:?} verifies the checksums.", addr);
+        }
+        if frames.decoder().received_compressed() && !compression.swap(true, Ordering::Relaxed) {
+            debug!("Client {:?} decompresses the frames.", addr);
+        }
+        let was_silent = std::mem::replace(&mut silent, false);
+        let reply = match read {
+            Ok(msg) => {
+                if shared.chaos.disrupt().await {
+                    debug!("Chaos mode dropped a message from {:?}.", addr);
+                    continue;
+                }
+                let received = Instant::now();
+                log_incoming(&msg, &addr);
+                let pong = matches!(msg.message, MessageType::Pong { .. });
+                if pong && !std::mem::replace(&mut answers_pings, true) {
+                    debug!("Client {:?} answers the pings.", addr);
+                }
+                let impersonating = msg.system || matches!(msg.message, MessageType::System(_));
+                if impersonating || chat::is_reserved(&msg.nickname) {
+                    // Nobody can pose as the server, the message isn't delivered and the client isn't identified.
+                    warn!(
+                        "Rejecting message impersonating the server from {:?}.",
+                        addr
+                    );
+                    if handshake_failed(&session, &shared.access, "impersonation") {
+                        session.close(CloseReason::Rejected);
+                        break;
+                    }
+                    Some(server_error(ErrorCode::ReservedNickname))
+                } else {
+                    let joined = !session.is_active();
+                    let renamed = session.nickname() != Some(msg.nickname.as_str());
+                    if let Err(err_msg) =
+                        session.transition(Event::Identified(msg.nickname.clone()))
+                    {
+                        error!("Session error: {}", err_msg);
+                        break;
+                    }
+                    if renamed {
+                        connection.identify(&msg.nickname);
                     }
-                    Err(MessageError::UnexpectedEof) => {
-                        info!("Connection from {:?} terminated.", addr);
-                        USER_COUNTER.dec();
+                    // A joined client gets the roster right away, unless it is just asking for it.
+                    let asked = matches!(msg.message, MessageType::RosterRequest);
+                    if joined && !asked && connection.reply(shared.roster.snapshot()).await.is_err()
+                    {
+                        session.close(CloseReason::Error);
                         break;
                     }
-                    Err(err_msg) => {
-                        error!("Sender Error: {:?}", err_msg);
+                    if joined
+                        && send_public_keys(&connection, &shared.public_keys)
+                            .await
+                            .is_err()
+                    {
+                        session.close(CloseReason::Error);
                         break;
                     }
+                    handle_message(msg, received, &mut session, &connection, &shared).await
                 }
             }
-        });
-
-        tokio::spawn(async move {
-            while let Ok((message, sender_addr)) = receiver.recv().await {
-                if sender_addr == addr {
-                    continue;
+            Err(MessageError::UnexpectedEof) => {
+                if was_silent {
+                    handshake_failed(&session, &shared.access, "disconnected");
                 }
-                log_broadcasting(&message, &sender_addr, &addr);
-                if let Err(err_msg) = message.send(&mut stream_writer).await {
-                    error!("Reciever Error: {:?}", err_msg);
+                session.close(CloseReason::Disconnected);
+                break;
+            }
+            Err(MessageError::TooLarge { length, .. }) => {
+                warn!("Rejecting message of {} bytes from {:?}.", length, addr);
+                if handshake_failed(&session, &shared.access, "too_large") {
+                    session.close(CloseReason::Rejected);
                     break;
                 }
+                let size = length as u64;
+                let max = max_in_flight as u64;
+                Some(server_error(ErrorCode::TooLarge { size, max }))
             }
-        });
+            Err(MessageError::ChecksumMismatch { expected, actual }) => {
+                // Corrupted on the way, e.g. by a proxy, so it isn't a failed handshake.
+                warn!(
+                    "Corrupted message from {:?}, checksum {:08x} instead of {:08x}.",
+                    addr, actual, expected
+                );
+                CORRUPTED_FRAMES.inc();
+                Some(server_error(ErrorCode::InvalidMessage))
+            }
+            Err(
+                err_msg @ (MessageError::DeSerializationError(_)
+                | MessageError::JsonError(_)
+                | MessageError::Decompression(_)),
+            ) => {
+                warn!("Invalid message from {:?}: {:?}", addr, err_msg);
+                if handshake_failed(&session, &shared.access, "invalid_message") {
+                    session.close(CloseReason::Rejected);
+                    break;
+                }
+                Some(server_error(ErrorCode::InvalidMessage))
+            }
+            Err(err_msg) => {
+                error!("Sender Error: {:?}", err_msg);
+                if was_silent {
+                    handshake_failed(&session, &shared.access, "error");
+                }
+                session.close(CloseReason::Error);
+                break;
+            }
+        };
+        let Some(reply) = reply else {
+            continue;
+        };
+        if connection.reply(reply).await.is_err() {
+            session.close(CloseReason::Error);
+            break;
+        }
     }
 }
 
-fn logger_init() {
-    let env = Env::default().filter_or("RUST_LOG", "info");
-    Builder::from_env(env).init();
+/// Accepts a connection of the optional listener, never completes without one.
+async fn accept(listener: Option<&TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
 }
 
-/// Initializes the SQLite database.
-///
-/// This function checks if the database exists. If it does not, it creates the database.
-/// It then connects to the database and ensures that the necessary tables are created.
+/// Sends the public keys of the end-to-end encryption announced so far to the joined client.
+async fn send_public_keys(connection: &Connection, public_keys: &PublicKeys) -> Result<()> {
+    for message in public_keys.all() {
+        connection.reply(message).await?;
+    }
+    Ok(())
+}
+
+/// Records a failed handshake of the client which hasn't sent any valid message yet.
 ///
 /// # Returns
 ///
-/// - `Result<SqlitePool>`: A result containing the SQLite connection pool if successful,
-///   or an error if there was an issue creating or connecting to the database.
+/// True if the address of the client got banned by the access control, the connection should be closed.
+fn handshake_failed(session: &Session, access: &Access, reason: &str) -> bool {
+    !session.is_active() && access.record_failure(session.addr().ip(), reason)
+}
+
+/// Handles a message of the active client.
 ///
-/// # Errors
+/// Requests are answered right away, chat messages are checked by the rate limiting and broadcast to the other
+/// clients.
 ///
-/// This function will return an error if:
+/// # Returns
 ///
-/// - There is an issue creating the database.
-/// - There is an issue connecting to the database.
-/// - There is an issue creating the required tables in the database.
-async fn init_db() -> Result<SqlitePool> {
-    if !Sqlite::database_exists(DB).await.unwrap_or(false) {
-        info!("Creating database: {}", DB);
-        Sqlite::create_database(DB)
-            .await
-            .context("Creating database error!")?;
-    }
-    let pool = SqlitePool::connect(DB)
-        .await
-        .context("Connecting database error!")?;
-    create_table(&pool).await?;
-    Ok(pool)
-}
-
-async fn create_table(pool: &SqlitePool) -> Result<()> {
-    sqlx::query(
-        r#"
-    CREATE TABLE IF NOT EXISTS messages (
-        id INTEGER PRIMARY KEY,
-        nickname TEXT NOT NULL,
-        msg_type TEXT NOT NULL,
-        message TEXT NOT NULL
+/// The reply to the client, e.g. the requested history or the reason why the message was not delivered.
+async fn handle_message(
+    mut msg: Message,
+    received: Instant,
+    session: &mut Session,
+    connection: &Connection,
+    shared: &Shared,
+) -> Option<Message> {
+    let max_history = shared.config.limits.max_history;
+    match &msg.message {
+        MessageType::Bench { id, payload } => {
+            let size = payload.len() as u64;
+            let ack = MessageType::BenchAck { id: *id, size };
+            return Some(Message::system(ack));
+        }
+        MessageType::Sync { id } => {
+            // The messages of the connection are read in order, so all the ones sent before the Sync were received.
+            return Some(Message::system(MessageType::SyncAck { id: *id }));
+        }
+        // The answer to a Ping only keeps the connection alive, see serve_client.
+        MessageType::Pong { .. } => return None,
+        MessageType::RosterRequest => return Some(shared.roster.snapshot()),
+        MessageType::PublicKey { key } => {
+            shared.public_keys.publish(&msg.nickname, *key);
+            return None;
+        }
+        MessageType::Digest(setting) => {
+            return Some(shared.digests.handle(&msg.nickname, setting).await)
+        }
+        // The stored messages are read only from a joined room.
+        MessageType::SearchRequest { .. } | MessageType::HistoryRequest { .. }
+            if !connection.in_room(&msg.room) =>
+        {
+            return Some(server_error(ErrorCode::NotInRoom));
+        }
+        MessageType::SearchRequest { query, limit } => {
+            return match shared
+                .database
+                .fetch_search(&msg.room, query, *limit, max_history)
+                .await
+            {
+                Ok(entries) => {
+                    let results = MessageType::SearchResults(entries);
+                    Some(Message::system(results))
+                }
+                Err(err_msg) => {
+                    error!("Searching messages error: {:?}", err_msg);
+                    Some(server_error(ErrorCode::Unavailable))
+                }
+            };
+        }
+        MessageType::HistoryRequest { before, limit } => {
+            return match shared
+                .database
+                .fetch_history(&msg.room, *before, *limit, max_history)
+                .await
+            {
+                Ok(entries) => {
+                    let history = MessageType::History(entries);
+                    Some(Message::system(history))
+                }
+                Err(err_msg) => {
+                    error!("Fetching history error: {:?}", err_msg);
+                    Some(server_error(ErrorCode::Unavailable))
+                }
+            };
+        }
+        MessageType::Report { message, reason } => {
+            return Some(shared.reports.handle(&msg.nickname, *message, reason).await);
+        }
+        MessageType::Join { room } => {
+            return Some(rooms::join(
+                &shared.fan_out,
+                connection,
+                &msg.nickname,
+                room,
+            ));
+        }
+        MessageType::Leave { room } => return Some(rooms::leave(connection, &msg.nickname, room)),
+        message if Polls::is_poll_message(message) => {
+            return shared.polls.handle(&msg.nickname, message).await;
+        }
+        _ => (),
+    }
+    if !connection.in_room(&msg.room) {
+        return Some(server_error(ErrorCode::NotInRoom));
+    }
+    MESSAGE_COUNTER.inc();
+    let muted = match session.rate_limit(&msg.message, received) {
+        Verdict::Allow => None,
+        Verdict::Muted(remaining) => Some(remaining),
+        Verdict::Violation(violation, duration) => {
+            let reason = format!("{violation}, muted for {duration:?}");
+            warn!("Muting client {:?}: {}.", session.addr(), reason);
+            if let Err(err_msg) = shared
+                .database
+                .insert_audit(&msg.nickname, "auto-mute", &reason)
+                .await
+            {
+                error!("Insert audit error: {:?}", err_msg);
+            }
+            shared.events.publish(ServerEvent::Moderation {
+                nickname: msg.nickname.clone(),
+                action: "auto-mute".to_string(),
+                reason,
+            });
+            Some(duration)
+        }
+    };
+    if let Some(duration) = muted {
+        let seconds = duration.as_secs().max(1);
+        return Some(server_error(ErrorCode::Muted { seconds }));
+    }
+    if let MessageType::Whisper { to, .. } = &msg.message {
+        if !shared.roster.users().contains(to) {
+            let text = format!("{to} isn't online, the whisper wasn't delivered.");
+            return Some(Message::system(MessageType::Text(text)));
+        }
+    }
+    #[cfg(feature = "metrics")]
+    if shared.attachments.offloads(&msg.message) {
+        let MessageType::File { name, content } = &msg.message else {
+            unreachable!("only files are offloaded");
+        };
+        match shared.attachments.store(name, content).await {
+            Ok(attachment) => msg.message = attachment,
+            Err(err_msg) => error!("Attachment error: {:?}", err_msg),
+        }
+    }
+    let size = msg.message.attachment_size();
+    let max_in_flight = shared.config.limits.max_in_flight;
+    if size > max_in_flight {
+        warn!(
+            "Rejecting attachment of {} bytes from {:?}.",
+            size,
+            session.addr()
+        );
+        let (size, max) = (size as u64, max_in_flight as u64);
+        return Some(server_error(ErrorCode::TooLarge { size, max }));
+    }
+    let Some(in_flight) = InFlight::reserve(size, max_in_flight) else {
+        warn!(
+            "Server overloaded, rejecting message from {:?}.",
+            session.addr()
+        );
+        return Some(server_error(ErrorCode::Overloaded));
+    };
+    // The sender asking for an acknowledgement is told the timestamp it has sent, even if it gets replaced.
+    let asked_ack = msg.annotations.iter().any(|(key, _)| key == ACK_ANNOTATION);
+    msg.annotations.retain(|(key, _)| key != ACK_ANNOTATION);
+    let ack = msg.timestamp.filter(|_| asked_ack);
+    shared.enrichers.enrich(&mut msg);
+    shared.colors.annotate(&mut msg);
+    let max_skew = shared.config.limits.max_clock_skew;
+    if let Some(skew) = clock::normalize(&mut msg, chat::unix_millis(), max_skew) {
+        debug!(
+            "Replaced timestamp of {:?} skewed by {} ms.",
+            session.addr(),
+            skew
+        );
+    }
+    // A streamed file is stored and published once by its start, the chunks and the end are only relayed. A whisper
+    // isn't published to the bots, the persistence drops its record.
+    let relayed = matches!(
+        msg.message,
+        MessageType::FileChunk { .. } | MessageType::FileEnd { .. }
     );
-    "#,
-    )
-    .execute(pool)
-    .await
-    .context("Creating database table error!")?;
-    Ok(())
+    let record = (!relayed).then(|| Record::new(&msg));
+    if !relayed && !msg.message.is_no_persist() {
+        shared.events.publish(ServerEvent::message(&msg));
+    }
+    let lane = if is_low_priority(&msg.message) {
+        Lane::Low
+    } else {
+        Lane::High
+    };
+    connection.broadcast(msg, lane, in_flight, received);
+    if let Some(record) = record {
+        let sender = Some(connection.replies());
+        shared
+            .persistence
+            .persist(record, received, sender, ack)
+            .await;
+    }
+    None
 }
 
-async fn insert_db(pool: &SqlitePool, message: &Message) -> Result<()> {
-    let (msg_type, message_value) = message.message.get_type_and_message();
-    let mut connection = pool.acquire().await?;
-    let id = sqlx::query(
-        r#"
-        INSERT INTO messages ( nickname, msg_type, message )
-        VALUES ( ?1, ?2, ?3 )
-        "#,
-    )
-    .bind(&message.nickname)
-    .bind(msg_type)
-    .bind(message_value)
-    .execute(&mut *connection)
-    .await
-    .context("Inserting to the database error!")?
-    .last_insert_rowid();
-    debug!("DB insert id: {}", id);
-    Ok(())
+/// Returns true for messages delivered in the low priority lane.
+///
+/// Attachments use the low priority lane, so a big file doesn't delay text messages and server errors. All the parts
+/// of a streamed file share it, so they arrive in order. An encrypted message is an attachment by its size.
+fn is_low_priority(message: &MessageType) -> bool {
+    match message {
+        MessageType::Image(_)
+        | MessageType::File { .. }
+        | MessageType::FileStart { .. }
+        | MessageType::FileChunk { .. }
+        | MessageType::FileEnd { .. } => true,
+        MessageType::Encrypted(sealed) => sealed.ciphertext.len() > ENCRYPTED_ATTACHMENT,
+        _ => false,
+    }
 }
 
-fn get_metrics() -> Result<()> {
-    REGISTRY
-        .register(Box::new(MESSAGE_COUNTER.clone()))
-        .context("message counter metric registering error!")?;
-    REGISTRY
-        .register(Box::new(USER_COUNTER.clone()))
-        .context("counter metric registering error!")?;
-    Ok(())
+/// Returns the first message of every connection describing the server and its limits.
+///
+/// The message of the day follows it as a system notice, so the Welcome doesn't carry it.
+fn welcome_message(config: &Config) -> Message {
+    // Big files are delivered by the HTTP server.
+    let attachments = cfg!(feature = "metrics").then_some("attachments");
+    let digest = config.digest.from.is_some().then_some("digest");
+    let welcome = MessageType::Welcome {
+        server_name: config.server.name.clone(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        motd: None,
+        capabilities: CAPABILITIES
+            .iter()
+            .copied()
+            .chain(attachments)
+            .chain(digest)
+            .map(ToString::to_string)
+            .collect(),
+        limits: ServerLimits {
+            max_attachment: config.limits.max_in_flight as u64,
+            max_history: config.limits.max_history,
+        },
+        server_time: chat::unix_millis(),
+    };
+    Message::system(welcome)
 }
 
-async fn metrics() -> (StatusCode, String) {
-    let encoder = TextEncoder::new();
-    let mut buf = vec![];
+fn server_error(code: ErrorCode) -> Message {
+    Message::system(MessageType::ServerError { code })
+}
 
-    if let Err(err_msg) = encoder.encode(&REGISTRY.gather(), &mut buf) {
-        error!("Metrics encoding error: {}", err_msg);
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Metrics encoding error!".to_string(),
-        );
+/// Renders the effective configuration, the config with the defaults followed by the `[runtime]` section with the
+/// command line and the `RUST_LOG` filter, with the secrets redacted.
+fn effective_config(
+    config: &Config,
+    address: &chat::Address,
+    max_clients: Option<usize>,
+    verbose: bool,
+    repl: bool,
+) -> String {
+    let mut runtime = toml::Table::new();
+    runtime.insert("address".to_string(), address.to_string().into());
+    if let Some(max_clients) = max_clients {
+        runtime.insert("max_clients".to_string(), (max_clients as i64).into());
     }
-    if let Ok(body) = String::from_utf8(buf) {
-        return (StatusCode::OK, body);
+    runtime.insert("verbose".to_string(), verbose.into());
+    runtime.insert("repl".to_string(), repl.into());
+    if let Ok(filter) = std::env::var("RUST_LOG") {
+        runtime.insert("rust_log".to_string(), filter.into());
     }
-    (
-        StatusCode::INTERNAL_SERVER_ERROR,
-        "Unknow error!".to_string(),
-    )
+    let mut table = config.render();
+    table.insert("runtime".to_string(), toml::Value::Table(runtime));
+    table.to_string()
+}
+
+/// Runs the subcommand instead of the server.
+async fn run_command(command: &str, arguments: &[String]) -> Result<()> {
+    if command == "config" {
+        return config::run_config(arguments);
+    }
+    if command == "log-level" {
+        #[cfg(feature = "metrics")]
+        {
+            let port = METRICS_ADDRESS.rsplit(':').next().unwrap_or_default();
+            let url = format!("http://127.0.0.1:{port}{}", logging::LOG_LEVEL_PATH);
+            return logging::run_log_level(&url, arguments).await;
+        }
+        #[cfg(not(feature = "metrics"))]
+        return Err(anyhow!(
+            "Built without the metrics feature, the log levels can't be changed at runtime!"
+        ));
+    }
+    let config = Config::load(CONFIG_FILE)?;
+    let database = Database::open(DB, config.database).await?;
+    match command {
+        "import-history" => import::run_import(&database, arguments).await,
+        "maintain" => maintenance::run_maintenance(&database).await,
+        _ => Err(anyhow!("Unknown command: {command}!")),
+    }
+}
+
+/// Serves the metrics, the access lists, the attachments, the events, the digest unsubscribe links, the log levels and
+/// the effective configuration over HTTP on [`METRICS_ADDRESS`].
+///
+/// # Errors
+///
+/// This function will return an error if the address can't be bound.
+#[cfg(feature = "metrics")]
+async fn serve_http(
+    access: Access,
+    attachments: Attachments,
+    events: Events,
+    digests: Digests,
+    log_levels: LogLevels,
+    effective: String,
+) -> Result<()> {
+    let downloads = Router::new()
+        .route("/attachments/:id", get(attachments::download))
+        .with_state(attachments);
+    let event_stream = Router::new()
+        .route("/events", get(events::stream))
+        .with_state(events);
+    let unsubscribe = Router::new()
+        .route(digest::UNSUBSCRIBE_PATH, get(digest::unsubscribe))
+        .with_state(digests);
+    let levels = Router::new()
+        .route(
+            logging::LOG_LEVEL_PATH,
+            get(logging::get_levels).put(logging::set_level),
+        )
+        .with_state(log_levels);
+    let admin_config = Router::new()
+        .route("/admin/config", get(config::get_effective))
+        .with_state(Arc::<str>::from(effective));
+    let app = Router::new()
+        .route("/metrics", get(metrics::render))
+        .route("/access", get(access::get_lists).put(access::set_lists))
+        .route("/access/bans", get(access::get_bans))
+        .route("/access/bans/:address", delete(access::delete_ban))
+        .with_state(access)
+        .merge(downloads)
+        .merge(event_stream)
+        .merge(unsubscribe)
+        .merge(levels)
+        .merge(admin_config);
+    let listener = TcpListener::bind(METRICS_ADDRESS)
+        .await
+        .with_context(|| format!("Binding metrics to {METRICS_ADDRESS}"))?;
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+    });
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() {
-    logger_init();
-    let app = Router::new().route("/metrics", get(metrics));
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3001").await.unwrap();
-    tokio::spawn(async move { axum::serve(listener, app).await });
-    match run_server().await {
+    let log_levels = logging::init();
+    let mut arguments: Vec<String> = std::env::args().collect();
+    let verbose = report::take_verbose(&mut arguments);
+    let repl = repl::take_repl(&mut arguments);
+    let print_config = config::take_print_config(&mut arguments);
+    if let Some(command) = arguments.get(1).filter(|a| COMMANDS.contains(&a.as_str())) {
+        if let Err(err_msg) = run_command(command, &arguments[2..]).await {
+            error!(
+                "Command {} error: {}",
+                command,
+                Report::new(err_msg.as_ref())
+            );
+            std::process::exit(1);
+        }
+        return;
+    }
+    let config = match Config::load(CONFIG_FILE) {
+        Ok(config) => config,
+        Err(err_msg) => {
+            error!("Error: {}", Report::new(err_msg.as_ref()));
+            std::process::exit(1);
+        }
+    };
+    let (max_clients, arguments) = match waiting::parse_max_clients(&arguments) {
+        Ok(parsed) => parsed,
+        Err(err_msg) => {
+            error!("Error: {}", err_msg);
+            std::process::exit(1);
+        }
+    };
+    let (port, arguments) = match discovery::parse_port(&arguments) {
+        Ok(parsed) => parsed,
+        Err(err_msg) => {
+            error!("Error: {}", err_msg);
+            std::process::exit(1);
+        }
+    };
+    let address = discovery::address(&arguments, port);
+    let effective = effective_config(&config, &address, max_clients, verbose, repl);
+    if print_config {
+        print!("{effective}");
+        return;
+    }
+    let room = WaitingRoom::new(max_clients.unwrap_or(usize::MAX), waiting::MAX_WAITING);
+    let access = Access::new(config.access.clone());
+    match run_server(config, access, address, room, log_levels, effective, repl).await {
         Ok(_) => (),
-        Err(err_msg) => error!("Error: {}", err_msg),
+        Err(err_msg) => {
+            error!("Error: {}", Report::new(err_msg.as_ref()));
+            std::process::exit(1);
+        }
     }
 }
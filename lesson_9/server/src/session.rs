@@ -0,0 +1,361 @@
+//! Lifecycle of a client connection.
+//!
+//! Every connection goes through the states Connecting → Handshaking → Authenticated → Active → Closing and every
+//! change of the state is an [`Event`] checked by [`State::next`]. The chat has no accounts, so a connection is
+//! authenticated by passing the access control and the waiting room and receiving the Welcome message, and becomes
+//! active with its first message naming the client. The connection can close in any state but the closing one.
+//! Activating and leaving the active state are published as the join and leave [`ServerEvent`]s, announced to the
+//! clients as [`MessageType::System`] notices when the session has the [`FanOut`] and counted in the [`Roster`] if it
+//! has one. A connection breaking without a goodbye, e.g. an unexpected end of the stream, leaves the chat too.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use chat::{Message, MessageType};
+use log::{debug, info};
+
+use crate::events::{Events, ServerEvent};
+use crate::fanout::FanOut;
+use crate::metrics::USER_COUNTER;
+use crate::roster::Roster;
+use crate::spam::{SpamConfig, SpamFilter, Verdict};
+
+/// Reason of closing the connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseReason {
+    /// Rejected by the access control.
+    Rejected,
+    /// Left the waiting room before the admission or the queue was full.
+    LeftQueue,
+    /// The client disconnected.
+    Disconnected,
+    /// Reading or writing the connection failed.
+    Error,
+    /// The client answering the pings went silent, see [`chat::keepalive`].
+    TimedOut,
+}
+
+impl CloseReason {
+    /// Returns the reason used in the leave event.
+    pub fn label(&self) -> &'static str {
+        match self {
+            CloseReason::Rejected => "rejected",
+            CloseReason::LeftQueue => "left_queue",
+            CloseReason::Disconnected => "disconnected",
+            CloseReason::Error => "error",
+            CloseReason::TimedOut => "timed_out",
+        }
+    }
+}
+
+/// State of a client connection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum State {
+    /// Accepted, checked by the access control and waiting in the waiting room.
+    Connecting,
+    /// Admitted, the Welcome message is being sent.
+    Handshaking,
+    /// Welcomed, waiting for the first message of the client.
+    Authenticated,
+    /// Sending messages under the nickname.
+    Active { nickname: String },
+    /// Closed, no more messages are handled.
+    Closing(CloseReason),
+}
+
+/// Event changing the state of a connection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// Passed the access control and the waiting room.
+    Admitted,
+    /// The Welcome message was sent.
+    Welcomed,
+    /// Received a message from the nickname.
+    Identified(String),
+    /// The connection ended.
+    Closed(CloseReason),
+}
+
+/// Event not allowed in the state of the connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidTransition {
+    pub state: State,
+    pub event: Event,
+}
+
+impl fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "event {:?} is not allowed in state {:?}",
+            self.event, self.state
+        )
+    }
+}
+
+impl std::error::Error for InvalidTransition {}
+
+impl State {
+    /// Returns the state after the event.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the event is not allowed in the state, e.g. a message before the
+    /// Welcome or anything after closing.
+    pub fn next(self, event: Event) -> Result<State, InvalidTransition> {
+        match (self, event) {
+            (State::Connecting, Event::Admitted) => Ok(State::Handshaking),
+            (State::Handshaking, Event::Welcomed) => Ok(State::Authenticated),
+            (State::Authenticated | State::Active { .. }, Event::Identified(nickname)) => {
+                Ok(State::Active { nickname })
+            }
+            (state @ State::Closing(_), event) => Err(InvalidTransition { state, event }),
+            (_, Event::Closed(reason)) => Ok(State::Closing(reason)),
+            (state, event) => Err(InvalidTransition { state, event }),
+        }
+    }
+
+    /// Returns true if the client is counted in the connected users.
+    fn is_connected(&self) -> bool {
+        matches!(self, State::Authenticated | State::Active { .. })
+    }
+}
+
+/// Client connection, its state and its rate limiting.
+pub struct Session {
+    addr: SocketAddr,
+    state: State,
+    spam_filter: SpamFilter,
+    events: Events,
+    notices: Option<FanOut>,
+    roster: Option<Roster>,
+}
+
+impl Session {
+    /// Creates the session of the accepted connection.
+    pub fn new(addr: SocketAddr, spam: SpamConfig, events: Events) -> Session {
+        Session {
+            addr,
+            state: State::Connecting,
+            spam_filter: SpamFilter::new(spam),
+            events,
+            notices: None,
+            roster: None,
+        }
+    }
+
+    /// Announces the joins, the leaves and the renames of the client to everybody as system notices.
+    pub fn with_notices(mut self, fan_out: FanOut) -> Session {
+        self.notices = Some(fan_out);
+        self
+    }
+
+    /// Counts the client in the roster of the active users while it is active.
+    pub fn with_roster(mut self, roster: Roster) -> Session {
+        self.roster = Some(roster);
+        self
+    }
+
+    /// Applies the event, logs the change of the state, counts the connected users and publishes the joins and the
+    /// leaves.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the event is not allowed in the current state, the state is unchanged.
+    pub fn transition(&mut self, event: Event) -> Result<(), InvalidTransition> {
+        let next = self.state.clone().next(event)?;
+        if next == self.state {
+            return Ok(());
+        }
+        match (self.state.is_connected(), next.is_connected()) {
+            (false, true) => USER_COUNTER.inc(),
+            (true, false) => USER_COUNTER.dec(),
+            _ => (),
+        }
+        if let State::Active { nickname } = &self.state {
+            let reason = match &next {
+                State::Closing(reason) => reason.label(),
+                _ => "renamed",
+            };
+            self.events.publish(ServerEvent::Leave {
+                nickname: nickname.clone(),
+                reason: reason.to_string(),
+            });
+        }
+        if let State::Active { nickname } = &next {
+            self.events.publish(ServerEvent::Join {
+                nickname: nickname.clone(),
+                addr: self.addr.to_string(),
+            });
+        }
+        if let (Some(fan_out), Some(text)) = (&self.notices, notice(&self.state, &next)) {
+            fan_out.announce(Message::system(MessageType::System(text)));
+        }
+        if let Some(roster) = &self.roster {
+            match (&self.state, &next) {
+                (State::Active { nickname: old }, State::Active { nickname }) => {
+                    roster.rename(old, nickname)
+                }
+                (State::Active { nickname }, _) => roster.leave(nickname),
+                (_, State::Active { nickname }) => roster.join(nickname),
+                _ => (),
+            }
+        }
+        match &next {
+            State::Active { nickname } => {
+                info!("Client {:?} is active as {}.", self.addr, nickname)
+            }
+            State::Closing(reason) => {
+                info!("Connection from {:?} closed: {:?}.", self.addr, reason)
+            }
+            state => debug!("Connection from {:?} is {:?}.", self.addr, state),
+        }
+        self.state = next;
+        Ok(())
+    }
+
+    /// Closes the session, closing it again is ignored.
+    pub fn close(&mut self, reason: CloseReason) {
+        let _ = self.transition(Event::Closed(reason));
+    }
+
+    /// Checks the rate and the content of the message sent by the active client.
+    pub fn rate_limit(&mut self, message: &MessageType, received: Instant) -> Verdict {
+        self.spam_filter.check(message, received)
+    }
+
+    /// Returns true if the client has sent a message under a nickname and hasn't left.
+    pub fn is_active(&self) -> bool {
+        matches!(self.state, State::Active { .. })
+    }
+
+    /// Returns the nickname of the active client.
+    pub fn nickname(&self) -> Option<&str> {
+        match &self.state {
+            State::Active { nickname } => Some(nickname),
+            _ => None,
+        }
+    }
+
+    /// Returns the address of the client.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+/// Returns the notice about the change of the state, e.g. `slava joined the chat`, if the clients should know about it.
+fn notice(state: &State, next: &State) -> Option<String> {
+    match (state, next) {
+        (State::Active { nickname: old }, State::Active { nickname }) => {
+            Some(format!("{old} is now known as {nickname}"))
+        }
+        (State::Active { nickname }, _) => Some(format!("{nickname} left the chat")),
+        (_, State::Active { nickname }) => Some(format!("{nickname} joined the chat")),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn active(nickname: &str) -> State {
+        State::Active {
+            nickname: nickname.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let state = State::Connecting.next(Event::Admitted).unwrap();
+        assert_eq!(state, State::Handshaking);
+        let state = state.next(Event::Welcomed).unwrap();
+        assert_eq!(state, State::Authenticated);
+        let state = state.next(Event::Identified("slava".into())).unwrap();
+        assert_eq!(state, active("slava"));
+        let state = state.next(Event::Identified("eva".into())).unwrap();
+        assert_eq!(state, active("eva"));
+        let state = state
+            .next(Event::Closed(CloseReason::Disconnected))
+            .unwrap();
+        assert_eq!(state, State::Closing(CloseReason::Disconnected));
+    }
+
+    #[test]
+    fn test_closing_from_every_state() {
+        let open = [
+            State::Connecting,
+            State::Handshaking,
+            State::Authenticated,
+            active("slava"),
+        ];
+        for state in open {
+            let closed = state.next(Event::Closed(CloseReason::Error));
+            assert_eq!(closed, Ok(State::Closing(CloseReason::Error)));
+        }
+        let closing = State::Closing(CloseReason::Rejected);
+        assert!(closing
+            .clone()
+            .next(Event::Closed(CloseReason::Error))
+            .is_err());
+        assert!(closing.clone().next(Event::Admitted).is_err());
+        assert!(closing.next(Event::Identified("slava".into())).is_err());
+    }
+
+    #[test]
+    fn test_invalid_transitions() {
+        let invalid = [
+            (State::Connecting, Event::Welcomed),
+            (State::Connecting, Event::Identified("slava".into())),
+            (State::Handshaking, Event::Admitted),
+            (State::Handshaking, Event::Identified("slava".into())),
+            (State::Authenticated, Event::Admitted),
+            (State::Authenticated, Event::Welcomed),
+            (active("slava"), Event::Admitted),
+            (active("slava"), Event::Welcomed),
+        ];
+        for (state, event) in invalid {
+            let error = state.clone().next(event.clone()).unwrap_err();
+            assert_eq!(error, InvalidTransition { state, event });
+        }
+    }
+
+    #[test]
+    fn test_notice() {
+        assert_eq!(
+            notice(&State::Authenticated, &active("slava")).as_deref(),
+            Some("slava joined the chat")
+        );
+        assert_eq!(
+            notice(&active("slava"), &active("eva")).as_deref(),
+            Some("slava is now known as eva")
+        );
+        let closing = State::Closing(CloseReason::Disconnected);
+        assert_eq!(
+            notice(&active("eva"), &closing).as_deref(),
+            Some("eva left the chat")
+        );
+        assert_eq!(notice(&State::Authenticated, &closing), None);
+    }
+
+    #[test]
+    fn test_session() {
+        let addr = "127.0.0.1:4000".parse().unwrap();
+        let mut session =
+            Session::new(addr, SpamConfig::default(), Events::new(Default::default()));
+        assert!(session.transition(Event::Welcomed).is_err());
+        assert_eq!(session.state, State::Connecting);
+        session.transition(Event::Admitted).unwrap();
+        session.transition(Event::Welcomed).unwrap();
+        session
+            .transition(Event::Identified("slava".into()))
+            .unwrap();
+        let now = Instant::now();
+        let text = MessageType::Text("hello".into());
+        assert_eq!(session.rate_limit(&text, now), Verdict::Allow);
+        session.close(CloseReason::Disconnected);
+        session.close(CloseReason::Error);
+        assert_eq!(session.state, State::Closing(CloseReason::Disconnected));
+    }
+}
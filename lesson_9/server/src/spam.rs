@@ -0,0 +1,228 @@
+//! Anti-spam heuristics.
+//!
+//! Each connection keeps its own [`SpamFilter`]. Repeated identical messages, shouting in capital letters or too many
+//! mentions within [`WINDOW`] mute the sender. Every further violation doubles the mute duration up to [`MAX_MUTE`].
+//! The constants are the defaults of [`SpamConfig`], which can be changed in the `[spam]` section of the server
+//! config.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use chat::MessageType;
+
+/// Time window of the heuristics.
+pub const WINDOW: Duration = Duration::from_secs(30);
+/// Number of identical messages within the window triggering the mute.
+pub const REPEAT_LIMIT: usize = 3;
+/// Minimal number of letters for the capital letters check.
+pub const CAPITALS_MIN_LETTERS: usize = 12;
+/// Ratio of capital letters triggering the mute.
+pub const CAPITALS_RATIO: f64 = 0.7;
+/// Number of mentions within the window triggering the mute.
+pub const MENTION_LIMIT: usize = 6;
+/// Duration of the first mute.
+pub const BASE_MUTE: Duration = Duration::from_secs(30);
+/// Maximal duration of the mute.
+pub const MAX_MUTE: Duration = Duration::from_secs(60 * 60);
+
+/// Thresholds of the heuristics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpamConfig {
+    pub window: Duration,
+    pub repeat_limit: usize,
+    pub capitals_min_letters: usize,
+    pub capitals_ratio: f64,
+    pub mention_limit: usize,
+    pub base_mute: Duration,
+    pub max_mute: Duration,
+}
+
+impl Default for SpamConfig {
+    fn default() -> Self {
+        SpamConfig {
+            window: WINDOW,
+            repeat_limit: REPEAT_LIMIT,
+            capitals_min_letters: CAPITALS_MIN_LETTERS,
+            capitals_ratio: CAPITALS_RATIO,
+            mention_limit: MENTION_LIMIT,
+            base_mute: BASE_MUTE,
+            max_mute: MAX_MUTE,
+        }
+    }
+}
+
+/// Reason of the mute.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Violation {
+    Repeated,
+    Capitals,
+    Mentions,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Repeated => write!(f, "repeated identical messages"),
+            Self::Capitals => write!(f, "excessive capital letters"),
+            Self::Mentions => write!(f, "too many mentions"),
+        }
+    }
+}
+
+/// Result of the spam check.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    /// The message can be delivered.
+    Allow,
+    /// The sender is still muted for the given time.
+    Muted(Duration),
+    /// The message violated a heuristic and the sender got muted for the given time.
+    Violation(Violation, Duration),
+}
+
+/// Per-connection state of the anti-spam heuristics.
+#[derive(Debug, Default)]
+pub struct SpamFilter {
+    config: SpamConfig,
+    recent: VecDeque<(Instant, String, usize)>,
+    strikes: u32,
+    muted_until: Option<Instant>,
+}
+
+impl SpamFilter {
+    /// Creates the filter with the given thresholds.
+    pub fn new(config: SpamConfig) -> SpamFilter {
+        SpamFilter {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Checks the message received at `now`.
+    pub fn check(&mut self, message: &MessageType, now: Instant) -> Verdict {
+        if let Some(until) = self.muted_until.filter(|until| *until > now) {
+            return Verdict::Muted(until - now);
+        }
+        let MessageType::Text(text) = message else {
+            return Verdict::Allow;
+        };
+        while self
+            .recent
+            .front()
+            .is_some_and(|(time, _, _)| now.duration_since(*time) > self.config.window)
+        {
+            self.recent.pop_front();
+        }
+        self.recent
+            .push_back((now, text.clone(), count_mentions(text)));
+
+        let repeated = self.recent.iter().filter(|(_, t, _)| t == text).count();
+        let mentions: usize = self.recent.iter().map(|(_, _, m)| m).sum();
+        let violation = if repeated >= self.config.repeat_limit {
+            Some(Violation::Repeated)
+        } else if self.is_shouting(text) {
+            Some(Violation::Capitals)
+        } else if mentions >= self.config.mention_limit {
+            Some(Violation::Mentions)
+        } else {
+            None
+        };
+        match violation {
+            Some(violation) => {
+                let duration = self
+                    .config
+                    .base_mute
+                    .saturating_mul(2u32.saturating_pow(self.strikes))
+                    .min(self.config.max_mute);
+                self.strikes += 1;
+                self.muted_until = Some(now + duration);
+                self.recent.clear();
+                Verdict::Violation(violation, duration)
+            }
+            None => Verdict::Allow,
+        }
+    }
+
+    fn is_shouting(&self, text: &str) -> bool {
+        let letters = text.chars().filter(|c| c.is_alphabetic()).count();
+        let capitals = text.chars().filter(|c| c.is_uppercase()).count();
+        letters >= self.config.capitals_min_letters
+            && capitals as f64 / letters as f64 >= self.config.capitals_ratio
+    }
+}
+
+fn count_mentions(text: &str) -> usize {
+    text.split_whitespace()
+        .filter(|word| word.len() > 1 && word.starts_with('@'))
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allow_normal_messages() {
+        let mut filter = SpamFilter::default();
+        let now = Instant::now();
+        for text in ["Hello", "How are you?", "OK", "@alice see you"] {
+            assert_eq!(filter.check(&MessageType::text(text), now), Verdict::Allow);
+        }
+    }
+
+    #[test]
+    fn test_repeated_messages() {
+        let mut filter = SpamFilter::default();
+        let now = Instant::now();
+        let message = MessageType::text("buy now");
+        assert_eq!(filter.check(&message, now), Verdict::Allow);
+        assert_eq!(filter.check(&message, now), Verdict::Allow);
+        assert_eq!(
+            filter.check(&message, now),
+            Verdict::Violation(Violation::Repeated, BASE_MUTE)
+        );
+        assert_eq!(
+            filter.check(&message, now + Duration::from_secs(10)),
+            Verdict::Muted(BASE_MUTE - Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn test_repeated_messages_outside_window() {
+        let mut filter = SpamFilter::default();
+        let now = Instant::now();
+        let message = MessageType::text("hello");
+        for i in 0..5 {
+            let time = now + WINDOW * i;
+            assert_eq!(filter.check(&message, time), Verdict::Allow);
+        }
+    }
+
+    #[test]
+    fn test_capitals_and_mentions() {
+        let mut filter = SpamFilter::default();
+        let now = Instant::now();
+        assert_eq!(
+            filter.check(&MessageType::text("WHY IS NOBODY ANSWERING"), now),
+            Verdict::Violation(Violation::Capitals, BASE_MUTE)
+        );
+        let later = now + BASE_MUTE;
+        assert_eq!(
+            filter.check(&MessageType::text("@a @b @c @d @e @f"), later),
+            Verdict::Violation(Violation::Mentions, BASE_MUTE * 2)
+        );
+    }
+
+    #[test]
+    fn test_escalation_is_capped() {
+        let mut filter = SpamFilter::default();
+        let mut now = Instant::now();
+        let mut last = Verdict::Allow;
+        for _ in 0..12 {
+            last = filter.check(&MessageType::text("STOP SHOUTING AT ME"), now);
+            now += MAX_MUTE;
+        }
+        assert_eq!(last, Verdict::Violation(Violation::Capitals, MAX_MUTE));
+    }
+}
@@ -0,0 +1,159 @@
+//! Storage usage of the users.
+//!
+//! Every stored message records the bytes of its image, file or attachment, [`Database::fetch_storage_usage`]
+//! aggregates them with the message counts by nickname. The rooms aren't stored with the messages, so there is nothing
+//! to group by besides the nickname. The usage is shown by the admin panel, the server only warns the heavy users: every
+//! [`CHECK_INTERVAL`] the users over the `[storage] warn_size` of the server config get a system notice. A user is
+//! warned once, again only after the usage drops below the threshold and crosses it again, or after a restart. Users
+//! who are offline during the check are warned at the first check they are connected.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use log::{error, info};
+
+use chat::{Message, MessageType};
+
+use crate::db::Database;
+use crate::fanout::FanOut;
+
+/// Bytes in a MiB, the unit of the sizes in the notice.
+const MIB: u64 = 1024 * 1024;
+/// Interval of the storage usage checks.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Settings from the `[storage]` section of the server config.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StorageConfig {
+    /// Attachment bytes of a nickname over which its user is warned, no warnings without it.
+    pub warn_size: Option<usize>,
+    pub check_interval: Duration,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            warn_size: None,
+            check_interval: CHECK_INTERVAL,
+        }
+    }
+}
+
+/// Messages and attachment bytes stored for a nickname.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Usage {
+    pub nickname: String,
+    pub messages: u64,
+    /// Number of the images, files and attachments.
+    pub attachments: u64,
+    /// Bytes of the images, files and attachments.
+    pub bytes: u64,
+}
+
+/// Spawns the background task warning the heavy users, nothing if the warnings are off.
+pub fn spawn_checker(database: Database, fan_out: FanOut, config: StorageConfig) {
+    let Some(warn_size) = config.warn_size else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.check_interval);
+        let mut warned = HashSet::new();
+        loop {
+            interval.tick().await;
+            match database.fetch_storage_usage().await {
+                Ok(usage) => warn_heavy_users(&fan_out, &usage, warn_size as u64, &mut warned),
+                Err(err_msg) => error!("Storage usage check error: {:?}", err_msg),
+            }
+        }
+    });
+}
+
+/// Sends the notice to the connected users over the `warn_size` who were not `warned` yet.
+///
+/// The users back under the threshold are forgotten, so they are warned again when they cross it.
+fn warn_heavy_users(
+    fan_out: &FanOut,
+    usage: &[Usage],
+    warn_size: u64,
+    warned: &mut HashSet<String>,
+) {
+    let heavy: Vec<&Usage> = usage.iter().filter(|user| user.bytes > warn_size).collect();
+    warned.retain(|nickname| heavy.iter().any(|user| &user.nickname == nickname));
+    for user in heavy {
+        if warned.contains(&user.nickname) {
+            continue;
+        }
+        let notice = Message::system(MessageType::Text(notice(user, warn_size)));
+        if fan_out.notify(&user.nickname, notice) > 0 {
+            info!(
+                "Warned {} about storing {} bytes.",
+                user.nickname, user.bytes
+            );
+            warned.insert(user.nickname.clone());
+        }
+    }
+}
+
+/// Returns the text of the warning, e.g. `Your 12 attachments take 1210 MiB, over the limit of 1024 MiB...`.
+fn notice(user: &Usage, warn_size: u64) -> String {
+    format!(
+        "Your {} attachments take {} MiB, over the limit of {} MiB of this server. Please share big files elsewhere.",
+        user.attachments,
+        user.bytes.div_ceil(MIB),
+        warn_size / MIB
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fanout::DeliveryConfig;
+
+    fn usage(nickname: &str, bytes: u64) -> Usage {
+        Usage {
+            nickname: nickname.to_string(),
+            messages: 10,
+            attachments: 2,
+            bytes,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_warn_heavy_users() {
+        let fan_out = FanOut::spawn(DeliveryConfig { workers: 1 });
+        let (heavy, _heavy_outbox) = fan_out.register("127.0.0.1:1".parse().unwrap());
+        let (light, _light_outbox) = fan_out.register("127.0.0.1:2".parse().unwrap());
+        heavy.identify("slava");
+        light.identify("eva");
+        let queued = |fan_out: &FanOut| -> Vec<usize> {
+            fan_out
+                .queue_depths()
+                .iter()
+                .map(|depth| depth.high)
+                .collect()
+        };
+        let mut warned = HashSet::new();
+        let mut usage = vec![usage("slava", 2048), usage("eva", 1024), usage("bot", 4096)];
+
+        warn_heavy_users(&fan_out, &usage, 1024, &mut warned);
+        assert_eq!(queued(&fan_out), [1, 0]);
+        assert_eq!(warned, HashSet::from(["slava".to_string()]));
+        warn_heavy_users(&fan_out, &usage, 1024, &mut warned);
+        assert_eq!(queued(&fan_out), [1, 0]);
+
+        usage[0].bytes = 512;
+        warn_heavy_users(&fan_out, &usage, 1024, &mut warned);
+        assert!(warned.is_empty());
+        usage[0].bytes = 4096;
+        warn_heavy_users(&fan_out, &usage, 1024, &mut warned);
+        assert_eq!(queued(&fan_out), [2, 0]);
+    }
+
+    #[test]
+    fn test_notice() {
+        assert_eq!(
+            notice(&usage("slava", 3 * MIB - 1), MIB),
+            "Your 2 attachments take 3 MiB, over the limit of 1 MiB of this server. Please share big files elsewhere."
+        );
+    }
+}
@@ -0,0 +1,167 @@
+//! Storage usage report of the admin panel.
+//!
+//! The stored messages are aggregated by nickname: their count, the number of the images, files and attachments and
+//! the bytes of them recorded by the server. The rooms aren't stored, so the nickname is the only grouping. Users
+//! over the warning threshold, `storage_warn_mib` of `Rocket.toml` unless the page asks for another one, are flagged.
+//! Keep it at the `storage.warn_size` of the server config, which warns the same users in the chat.
+
+use anyhow::{Context, Result};
+use rocket::serde::Serialize;
+use rocket_db_pools::sqlx::{self, SqliteConnection};
+
+/// Bytes in a MiB, the unit of the threshold.
+const MIB: u64 = 1024 * 1024;
+/// Header of the exported CSV.
+const CSV_HEADER: &str = "nickname,messages,attachments,bytes,over_threshold";
+
+/// Messages and attachment bytes stored for a nickname.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Usage {
+    pub nickname: String,
+    pub messages: u64,
+    /// Number of the images, files and attachments.
+    pub attachments: u64,
+    /// Bytes of the images, files and attachments.
+    pub bytes: u64,
+    /// Bytes in MiB with one decimal, e.g. `12.5`.
+    pub mib: String,
+    /// The bytes are over the warning threshold.
+    pub over: bool,
+}
+
+/// Usage of all the nicknames, the heaviest users first, with the totals.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Report {
+    pub users: Vec<Usage>,
+    pub messages: u64,
+    pub attachments: u64,
+    pub bytes: u64,
+    pub mib: String,
+    /// Warning threshold in MiB, nobody is flagged without it.
+    pub warn_mib: Option<u64>,
+}
+
+/// Aggregates the stored messages by nickname.
+///
+/// # Errors
+///
+/// This function will return an error if the database fails.
+pub async fn report(db: &mut SqliteConnection, warn_mib: Option<u64>) -> Result<Report> {
+    let rows: Vec<(String, i64, i64, i64)> = sqlx::query_as(
+        r#"
+        SELECT nickname, COUNT(*), SUM(msg_type IN ('Image', 'File', 'Attachment')), SUM(attachment_size)
+        FROM messages
+        GROUP BY nickname
+        ORDER BY SUM(attachment_size) DESC, nickname
+        "#,
+    )
+    .fetch_all(db)
+    .await
+    .context("Aggregating storage usage failed!")?;
+    let warn_bytes = warn_mib.map(|warn_mib| warn_mib.saturating_mul(MIB));
+    let users: Vec<Usage> = rows
+        .into_iter()
+        .map(|(nickname, messages, attachments, bytes)| Usage {
+            nickname,
+            messages: messages as u64,
+            attachments: attachments as u64,
+            bytes: bytes as u64,
+            mib: format_mib(bytes as u64),
+            over: warn_bytes.is_some_and(|warn_bytes| bytes as u64 > warn_bytes),
+        })
+        .collect();
+    let bytes = users.iter().map(|user| user.bytes).sum();
+    Ok(Report {
+        messages: users.iter().map(|user| user.messages).sum(),
+        attachments: users.iter().map(|user| user.attachments).sum(),
+        bytes,
+        mib: format_mib(bytes),
+        warn_mib,
+        users,
+    })
+}
+
+/// Renders the usage as CSV, a row per nickname with the header.
+pub fn to_csv(report: &Report) -> String {
+    let mut csv = format!("{CSV_HEADER}\r\n");
+    for user in &report.users {
+        csv.push_str(&format!(
+            "{},{},{},{},{}\r\n",
+            csv_field(&user.nickname),
+            user.messages,
+            user.attachments,
+            user.bytes,
+            user.over
+        ));
+    }
+    csv
+}
+
+/// Quotes the field if it contains a separator, a quote or a line break.
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+fn format_mib(bytes: u64) -> String {
+    format!("{:.1}", bytes as f64 / MIB as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket_db_pools::sqlx::Connection;
+
+    #[rocket::async_test]
+    async fn test_report() {
+        let mut db = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        sqlx::query(
+            "CREATE TABLE messages (id INTEGER PRIMARY KEY, nickname TEXT, msg_type TEXT, attachment_size INTEGER);",
+        )
+        .execute(&mut db)
+        .await
+        .unwrap();
+        for (nickname, msg_type, size) in [
+            ("slava", "Text", 0),
+            ("slava", "Image", 3 * MIB),
+            ("eva, the \"bot\"", "Attachment", MIB / 2),
+            ("eva, the \"bot\"", "Text", 0),
+            ("adam", "Text", 0),
+        ] {
+            sqlx::query(
+                "INSERT INTO messages (nickname, msg_type, attachment_size) VALUES (?1, ?2, ?3);",
+            )
+            .bind(nickname)
+            .bind(msg_type)
+            .bind(size as i64)
+            .execute(&mut db)
+            .await
+            .unwrap();
+        }
+
+        let report = report(&mut db, Some(1)).await.unwrap();
+        let nicknames: Vec<&str> = report
+            .users
+            .iter()
+            .map(|user| user.nickname.as_str())
+            .collect();
+        assert_eq!(nicknames, ["slava", "eva, the \"bot\"", "adam"]);
+        assert_eq!((report.messages, report.attachments), (5, 2));
+        assert_eq!(report.mib, "3.5");
+        assert_eq!(report.users[0].mib, "3.0");
+        let over: Vec<bool> = report.users.iter().map(|user| user.over).collect();
+        assert_eq!(over, [true, false, false]);
+        assert_eq!(
+            to_csv(&report),
+            "nickname,messages,attachments,bytes,over_threshold\r\n\
+             slava,2,1,3145728,true\r\n\
+             \"eva, the \"\"bot\"\"\",2,1,524288,false\r\n\
+             adam,1,0,0,false\r\n"
+        );
+    }
+}
@@ -0,0 +1,331 @@
+//! Capacity limit of the server with a waiting room.
+//!
+//! With the `--max-clients N` option at most `N` clients are served at once. New connections over the limit wait in
+//! a bounded queue, they receive [`MessageType::ServerFull`] with their position whenever it changes and
+//! [`MessageType::Admitted`] once a slot frees up. Connections arriving to a full queue get
+//! [`ErrorCode::QueueFull`] and are closed.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use chat::{ErrorCode, Message, MessageType, WireFormat};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+
+use crate::metrics::WAITING_CLIENTS;
+
+/// Maximal number of connections in the waiting queue.
+pub const MAX_WAITING: usize = 64;
+
+/// Splits the `--max-clients N` option from the arguments.
+///
+/// # Returns
+///
+/// The maximal number of clients, `None` for unlimited, and the remaining arguments.
+///
+/// # Errors
+///
+/// This function will return an error if the value is missing or isn't a positive number.
+pub fn parse_max_clients(arguments: &[String]) -> Result<(Option<usize>, Vec<String>)> {
+    let mut remaining = Vec::new();
+    let mut max_clients = None;
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        if argument != "--max-clients" {
+            remaining.push(argument.clone());
+            continue;
+        }
+        let value = arguments
+            .next()
+            .ok_or_else(|| anyhow!("Missing value of --max-clients!"))?;
+        let value: usize = value
+            .parse()
+            .ok()
+            .filter(|value| *value > 0)
+            .with_context(|| format!("Invalid value of --max-clients: {value}, use e.g. 100!"))?;
+        max_clients = Some(value);
+    }
+    Ok((max_clients, remaining))
+}
+
+#[derive(Debug, Default)]
+struct State {
+    active: usize,
+    queue: VecDeque<u64>,
+    next_ticket: u64,
+}
+
+#[derive(Debug)]
+struct Inner {
+    max_clients: usize,
+    max_waiting: usize,
+    state: Mutex<State>,
+    changed: watch::Sender<()>,
+}
+
+impl Inner {
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn notify(&self, state: &State) {
+        WAITING_CLIENTS.set(state.queue.len() as i64);
+        self.changed.send_replace(());
+    }
+}
+
+/// Shared counter of the served clients with the queue of the waiting ones.
+#[derive(Debug, Clone)]
+pub struct WaitingRoom {
+    inner: Arc<Inner>,
+}
+
+/// Result of [`WaitingRoom::try_enter`].
+#[derive(Debug)]
+pub enum Entry {
+    /// The client can be served right away.
+    Admitted(ClientSlot),
+    /// The client has to wait in the queue.
+    Queued(Ticket),
+    /// The queue is full, the connection must be closed.
+    Full,
+}
+
+/// Change of the waiting client reported by [`Ticket::next`].
+#[derive(Debug)]
+pub enum Progress {
+    /// New position in the queue, `1` is the first one.
+    Position(usize),
+    /// The client can be served.
+    Admitted(ClientSlot),
+}
+
+/// Slot of a served client, released on drop.
+#[derive(Debug)]
+pub struct ClientSlot {
+    inner: Arc<Inner>,
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        let mut state = self.inner.state();
+        state.active -= 1;
+        self.inner.notify(&state);
+    }
+}
+
+/// Place in the waiting queue, left on drop.
+#[derive(Debug)]
+pub struct Ticket {
+    id: u64,
+    inner: Arc<Inner>,
+    changed: watch::Receiver<()>,
+    position: Option<usize>,
+}
+
+impl Ticket {
+    /// Waits for the next change of the position or the admission.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if it is called again after the admission.
+    pub async fn next(&mut self) -> Progress {
+        loop {
+            self.changed.borrow_and_update();
+            {
+                let mut state = self.inner.state();
+                let index = state
+                    .queue
+                    .iter()
+                    .position(|id| *id == self.id)
+                    .expect("Ticket is in the queue until it is admitted!");
+                if index == 0 && state.active < self.inner.max_clients {
+                    state.queue.pop_front();
+                    state.active += 1;
+                    self.inner.notify(&state);
+                    self.position = None;
+                    return Progress::Admitted(ClientSlot {
+                        inner: self.inner.clone(),
+                    });
+                }
+                if self.position != Some(index + 1) {
+                    self.position = Some(index + 1);
+                    return Progress::Position(index + 1);
+                }
+            }
+            // The sender lives in `inner`, so the channel is never closed.
+            let _ = self.changed.changed().await;
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut state = self.inner.state();
+        if let Some(index) = state.queue.iter().position(|id| *id == self.id) {
+            state.queue.remove(index);
+            self.inner.notify(&state);
+        }
+    }
+}
+
+impl WaitingRoom {
+    pub fn new(max_clients: usize, max_waiting: usize) -> WaitingRoom {
+        WaitingRoom {
+            inner: Arc::new(Inner {
+                max_clients,
+                max_waiting,
+                state: Mutex::new(State::default()),
+                changed: watch::Sender::new(()),
+            }),
+        }
+    }
+
+    /// Admits the client if there is a free slot and nobody is waiting, otherwise queues it.
+    pub fn try_enter(&self) -> Entry {
+        let mut state = self.inner.state();
+        if state.queue.is_empty() && state.active < self.inner.max_clients {
+            state.active += 1;
+            return Entry::Admitted(ClientSlot {
+                inner: self.inner.clone(),
+            });
+        }
+        if state.queue.len() >= self.inner.max_waiting {
+            return Entry::Full;
+        }
+        let id = state.next_ticket;
+        state.next_ticket += 1;
+        state.queue.push_back(id);
+        self.inner.notify(&state);
+        Entry::Queued(Ticket {
+            id,
+            inner: self.inner.clone(),
+            changed: self.inner.changed.subscribe(),
+            position: None,
+        })
+    }
+
+    /// Admits the connected client, keeping it in the queue with position updates while the server is full.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The connection of the client.
+    /// * `format` - The wire format of the connection, the position updates are sent in.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(Some(ClientSlot))` - If the client can be served, the slot must be kept until the connection ends.
+    /// - `Ok(None)` - If the queue is full or the client disconnected while waiting.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if sending the queue position fails.
+    pub async fn enter(
+        &self,
+        stream: &mut TcpStream,
+        format: WireFormat,
+    ) -> Result<Option<ClientSlot>> {
+        let mut ticket = match self.try_enter() {
+            Entry::Admitted(slot) => return Ok(Some(slot)),
+            Entry::Queued(ticket) => ticket,
+            Entry::Full => {
+                let error = MessageType::ServerError {
+                    code: ErrorCode::QueueFull,
+                };
+                Message::system(error).send_as(stream, format).await?;
+                return Ok(None);
+            }
+        };
+        let mut buffer = [0u8; 1];
+        let mut watch_disconnect = true;
+        loop {
+            tokio::select! {
+                progress = ticket.next() => {
+                    let (message, slot) = match progress {
+                        Progress::Position(position) => (MessageType::ServerFull { position }, None),
+                        Progress::Admitted(slot) => (MessageType::Admitted, Some(slot)),
+                    };
+                    Message::system(message).send_as(&mut *stream, format).await?;
+                    if slot.is_some() {
+                        return Ok(slot);
+                    }
+                }
+                peeked = stream.peek(&mut buffer), if watch_disconnect => match peeked {
+                    Ok(0) | Err(_) => return Ok(None),
+                    // Messages typed while waiting are read after the admission.
+                    Ok(_) => watch_disconnect = false,
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arguments(arguments: &[&str]) -> Vec<String> {
+        arguments.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse_max_clients() {
+        let (max_clients, remaining) = parse_max_clients(&arguments(&[
+            "server",
+            "--max-clients",
+            "5",
+            "0.0.0.0",
+            "11111",
+        ]))
+        .unwrap();
+        assert_eq!(max_clients, Some(5));
+        assert_eq!(remaining, ["server", "0.0.0.0", "11111"]);
+        assert_eq!(parse_max_clients(&arguments(&["server"])).unwrap().0, None);
+        assert!(parse_max_clients(&arguments(&["server", "--max-clients"])).is_err());
+        assert!(parse_max_clients(&arguments(&["server", "--max-clients", "0"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_queue_positions() {
+        let room = WaitingRoom::new(1, 2);
+        let Entry::Admitted(slot) = room.try_enter() else {
+            panic!("First client must be admitted!");
+        };
+        let Entry::Queued(mut first) = room.try_enter() else {
+            panic!("Second client must wait!");
+        };
+        let Entry::Queued(mut second) = room.try_enter() else {
+            panic!("Third client must wait!");
+        };
+        assert!(matches!(room.try_enter(), Entry::Full));
+        assert!(matches!(first.next().await, Progress::Position(1)));
+        assert!(matches!(second.next().await, Progress::Position(2)));
+
+        drop(slot);
+        let Progress::Admitted(_slot) = first.next().await else {
+            panic!("First waiting client must be admitted!");
+        };
+        drop(first);
+        assert!(matches!(second.next().await, Progress::Position(1)));
+    }
+
+    #[tokio::test]
+    async fn test_leaving_queue() {
+        let room = WaitingRoom::new(1, 2);
+        let Entry::Admitted(slot) = room.try_enter() else {
+            panic!("First client must be admitted!");
+        };
+        let Entry::Queued(first) = room.try_enter() else {
+            panic!("Second client must wait!");
+        };
+        let Entry::Queued(mut second) = room.try_enter() else {
+            panic!("Third client must wait!");
+        };
+        assert!(matches!(second.next().await, Progress::Position(2)));
+        drop(first);
+        assert!(matches!(second.next().await, Progress::Position(1)));
+        drop(slot);
+        assert!(matches!(second.next().await, Progress::Admitted(_)));
+    }
+}